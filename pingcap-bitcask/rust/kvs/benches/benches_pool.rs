@@ -14,9 +14,13 @@ use crossbeam_utils::sync::WaitGroup;
 use rand::{distributions::Alphanumeric, prelude::*};
 use rand_chacha::ChaCha20Rng;
 use tempfile::TempDir;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::oneshot;
 
-use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::{KvsEngine, KvsServer, Result, Store};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{AsyncKvsServer, KvsEngine, KvsServer, Result, Store};
 
 const RANDOM_SEED: u64 = 42;
 
@@ -56,6 +60,13 @@ enum StoreType {
     Sled,
 }
 
+fn open_store(store_type: StoreType, path: &Path) -> Result<Store> {
+    match store_type {
+        StoreType::Kvs => Store::open_with_kvs(path),
+        StoreType::Sled => Store::open_with_sled(path),
+    }
+}
+
 fn server_factory<P>(
     nthreads: u32,
     store_type: StoreType,
@@ -64,10 +75,7 @@ fn server_factory<P>(
 where
     P: ThreadPool,
 {
-    let store = match store_type {
-        StoreType::Kvs => Store::open_with_kvs(path)?,
-        StoreType::Sled => Store::open_with_sled(path)?,
-    };
+    let store = open_store(store_type, path)?;
 
     let pool = P::new(nthreads)?;
     let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
@@ -75,6 +83,47 @@ where
     Ok(server)
 }
 
+/// Async server running on its own runtime with `nthreads` workers.
+struct AsyncServer {
+    address: SocketAddr,
+    runtime: Runtime,
+    shutdown: oneshot::Sender<()>,
+    job: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl AsyncServer {
+    fn serve(nthreads: u32, store_type: StoreType, path: &Path) -> Result<AsyncServer> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(nthreads as usize)
+            .enable_all()
+            .build()?;
+        let store = open_store(store_type, path)?;
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+        let server = runtime.block_on(AsyncKvsServer::open(address, store))?;
+        let address = server.address;
+
+        let (shutdown, signal) = oneshot::channel();
+        let job = runtime.spawn(server.serve_with_shutdown(async {
+            let _ = signal.await;
+        }));
+
+        Ok(AsyncServer {
+            address,
+            runtime,
+            shutdown,
+            job,
+        })
+    }
+
+    fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        self.runtime
+            .block_on(self.job)
+            .unwrap()
+            .expect("async server failed");
+    }
+}
+
 fn write_to_server<E, P1, P2, Pth>(
     path: Pth,
     server: KvsServer<E, P1>,
@@ -87,8 +136,19 @@ fn write_to_server<E, P1, P2, Pth>(
     P2: ThreadPool,
 {
     let server = server.serve();
-    let address = server.address;
+    write_to_address(path, server.address, client_pool, sample);
+    server.shutdown();
+}
 
+fn write_to_address<P, Pth>(
+    path: Pth,
+    address: SocketAddr,
+    client_pool: &P,
+    sample: Vec<(String, String)>,
+) where
+    Pth: AsRef<Path>,
+    P: ThreadPool,
+{
     let wg = WaitGroup::new();
     for (key, value) in sample {
         let path = path.as_ref().to_path_buf().clone();
@@ -104,7 +164,6 @@ fn write_to_server<E, P1, P2, Pth>(
         });
     }
     wg.wait();
-    server.shutdown();
 }
 
 fn read_from_server<P, Pth>(
@@ -165,6 +224,35 @@ fn run_bench_write<P>(
     );
 }
 
+fn run_bench_write_async(
+    group: &mut BenchmarkGroup<WallTime>,
+    ncpu: u32,
+    store_type: StoreType,
+    sample: Vec<(String, String)>,
+) {
+    let client_pool = SharedQueueThreadPool::new(NCLIENTS).unwrap();
+
+    let bench_mark_name = match store_type {
+        StoreType::Kvs => format!("tokio-kvs-ncpus-{:02}", ncpu),
+        StoreType::Sled => format!("tokio-sled-ncpus-{:02}", ncpu),
+    };
+
+    group.bench_with_input(
+        BenchmarkId::from_parameter(&bench_mark_name),
+        &ncpu,
+        |b, &ncpu| {
+            b.iter(|| {
+                let temp_dir = TempDir::new().unwrap();
+                let path = temp_dir.path().join(&bench_mark_name);
+                fs::create_dir_all(&path).unwrap();
+                let server = AsyncServer::serve(ncpu, store_type, &path).unwrap();
+                write_to_address(&path, server.address, &client_pool, sample.clone());
+                server.shutdown();
+            });
+        },
+    );
+}
+
 fn write_pool(c: &mut Criterion) {
     let sample = unique_sample(WRITE_SIZE);
 
@@ -188,6 +276,21 @@ fn write_pool(c: &mut Criterion) {
                 store_type,
                 sample.clone(),
             );
+            run_bench_write::<WorkStealingThreadPool>(
+                &mut group,
+                "stealing",
+                ncpu,
+                store_type,
+                sample.clone(),
+            );
+            run_bench_write::<NaiveThreadPool>(
+                &mut group,
+                "naive",
+                ncpu,
+                store_type,
+                sample.clone(),
+            );
+            run_bench_write_async(&mut group, ncpu, store_type, sample.clone());
         }
         ncpu *= 2;
     }
@@ -233,6 +336,37 @@ fn run_bench_read<P>(
     server.shutdown();
 }
 
+fn run_bench_read_async(
+    group: &mut BenchmarkGroup<WallTime>,
+    ncpu: u32,
+    store_type: StoreType,
+    sample: Vec<(String, String)>,
+) {
+    let client_pool = SharedQueueThreadPool::new(NCLIENTS).unwrap();
+
+    let bench_mark_name = match store_type {
+        StoreType::Kvs => format!("tokio-kvs-ncpus-{:02}", ncpu),
+        StoreType::Sled => format!("tokio-sled-ncpus-{:02}", ncpu),
+    };
+
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join(format!("ncpus-{}", &ncpu));
+    fs::create_dir_all(&path).unwrap();
+    let server = AsyncServer::serve(ncpu, store_type, &path).unwrap();
+    write_to_address(&path, server.address, &client_pool, sample.clone());
+    let address = server.address;
+
+    group.bench_with_input(
+        BenchmarkId::from_parameter(bench_mark_name),
+        &ncpu,
+        |b, _| {
+            b.iter(|| read_from_server(&path, &client_pool, sample.clone(), address));
+        },
+    );
+
+    server.shutdown();
+}
+
 fn read_pool(c: &mut Criterion) {
     let sample = unique_sample(WRITE_SIZE);
 
@@ -256,6 +390,21 @@ fn read_pool(c: &mut Criterion) {
                 store_type,
                 sample.clone(),
             );
+            run_bench_read::<WorkStealingThreadPool>(
+                &mut group,
+                "stealing",
+                ncpu,
+                store_type,
+                sample.clone(),
+            );
+            run_bench_read::<NaiveThreadPool>(
+                &mut group,
+                "naive",
+                ncpu,
+                store_type,
+                sample.clone(),
+            );
+            run_bench_read_async(&mut group, ncpu, store_type, sample.clone());
         }
         ncpu *= 2;
     }
@@ -0,0 +1,149 @@
+use std::{io, path::PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{admin, EngineKind, KvError, Result, Store};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: CliCommands,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommands {
+    /// List log files with their sizes, record counts and live ratios.
+    LsLogs {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Decode every record of a log file.
+    Dump {
+        log_id: u64,
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Check that every log file is readable.
+    Verify {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Merge all log files, the database must not be opened.
+    Compact {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Truncate unreadable log tails and rebuild the index.
+    Repair {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Convert a database folder from one engine to another.
+    Migrate {
+        #[arg(long)]
+        from: Engine,
+        #[arg(long)]
+        to: Engine,
+        dir: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl From<Engine> for EngineKind {
+    fn from(engine: Engine) -> EngineKind {
+        match engine {
+            Engine::Kvs => EngineKind::Kvs,
+            Engine::Sled => EngineKind::Sled,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
+
+    let cli = Cli::parse();
+
+    match cli.command {
+        CliCommands::LsLogs { dir } => {
+            println!(
+                "{:>10} {:>10} {:>8} {:>8} {:>7}",
+                "id", "size", "records", "live", "ratio"
+            );
+            for log in admin::list_logs(dir)? {
+                println!(
+                    "{:>10} {:>10} {:>8} {:>8} {:>6.1}%",
+                    log.id,
+                    log.size,
+                    log.records,
+                    log.live_records,
+                    log.live_ratio() * 100.0
+                );
+            }
+        }
+        CliCommands::Dump { log_id, dir } => {
+            for record in admin::dump(dir, log_id)? {
+                let timestamp = record.timestamp.as_micros();
+                match record.value {
+                    Some(value) => println!(
+                        "{:>10} {:>6} {} set {} {}",
+                        record.offset, record.size, timestamp, record.key, value
+                    ),
+                    None => println!(
+                        "{:>10} {:>6} {} rm {}",
+                        record.offset, record.size, timestamp, record.key
+                    ),
+                }
+            }
+        }
+        CliCommands::Verify { dir } => {
+            let verification = admin::verify(dir)?;
+            println!(
+                "{} logs, {} records, {} keys",
+                verification.logs, verification.records, verification.keys
+            );
+            for torn in &verification.torn {
+                println!(
+                    "log {} is torn, {} of {} bytes readable",
+                    torn.id, torn.valid_len, torn.size
+                );
+            }
+            if !verification.is_ok() {
+                return Err(KvError::Corrupted(format!(
+                    "{} torn logs",
+                    verification.torn.len()
+                )));
+            }
+        }
+        CliCommands::Compact { dir } => {
+            let size = |logs: Vec<admin::LogInfo>| logs.iter().map(|log| log.size).sum::<u64>();
+            let before = size(admin::list_logs(&dir)?);
+            admin::compact(&dir)?;
+            let after = size(admin::list_logs(&dir)?);
+            println!("compacted {} bytes into {} bytes", before, after);
+        }
+        CliCommands::Repair { dir } => {
+            let repair = admin::repair(dir)?;
+            for torn in &repair.truncated {
+                println!(
+                    "log {} truncated from {} to {} bytes",
+                    torn.id, torn.size, torn.valid_len
+                );
+            }
+            println!("rebuilt index with {} keys", repair.keys);
+        }
+        CliCommands::Migrate { from, to, dir } => {
+            let migration = Store::migrate(dir, from.into(), to.into())?;
+            println!(
+                "migrated {} keys, checksum {:x}",
+                migration.keys, migration.checksum
+            );
+        }
+    }
+
+    Ok(())
+}
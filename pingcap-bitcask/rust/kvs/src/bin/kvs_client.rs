@@ -1,7 +1,18 @@
-use std::{io, net::SocketAddr};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+};
 
-use clap::{Parser, Subcommand};
-use kvs::{KvsClient, KvsRequest, Result};
+use clap::{error::ErrorKind, Parser, Subcommand, ValueEnum};
+use kvs::{ClientOption, KvError, KvsClient, Result};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+use serde_json::{json, Value};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -11,57 +22,373 @@ struct Cli {
 
     #[arg(long, global = true, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
+
+    /// Connect with TLS, trusting the certificate authorities in this PEM file.
+    #[arg(long, global = true, env = "KVS_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+
+    /// Name the server certificate is checked against, defaults to the server's ip address.
+    #[arg(long, global = true, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// Authenticate with this token.
+    #[arg(long, global = true, env = "KVS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 enum CliCommands {
-    Set {
-        key: String,
-        value: String,
+    #[command(flatten)]
+    Request(Request),
+    /// Run commands typed interactively, over one connection.
+    Shell {
+        /// File commands are saved to and recalled from, `~/.kvs_history` by default.
+        #[arg(long)]
+        history: Option<PathBuf>,
     },
-    Get {
-        key: String,
+    /// Run the commands of a file, one per line, over one connection.
+    Batch {
+        /// File to read commands from, `-` for stdin.
+        input: String,
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
     },
+}
+
+/// Commands sent to the server, from the command line, the shell or a batch.
+#[derive(Subcommand, Debug)]
+enum Request {
+    /// Set the value of a key.
+    Set { key: String, value: String },
+    /// Print the value of a key.
+    Get { key: String },
+    /// Remove a key.
     #[command(name = "rm")]
-    Remove {
-        key: String,
-    },
+    Remove { key: String },
+    /// Print server stats in Prometheus text format.
+    Stats,
+    /// Turn a replica into a primary, accepting writes.
+    Promote,
+}
+
+// A line of the shell or of a batch.
+#[derive(Parser, Debug)]
+#[command(
+    no_binary_name = true,
+    disable_version_flag = true,
+    disable_help_flag = true,
+    help_template = "Commands:\n{subcommands}\n  quit     Leave the shell"
+)]
+struct Line {
+    #[command(subcommand)]
+    request: Request,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Results as the single commands print them, errors on stderr.
+    Text,
+    /// One JSON object per command.
+    Json,
+}
+
+/// Words completed at the start of a shell line.
+const COMMANDS: &[&str] = &[
+    "set", "get", "rm", "stats", "promote", "help", "quit", "exit",
+];
+
 fn main() -> Result<()> {
     tracing_subscriber::fmt().with_writer(io::stderr).init();
 
     let cli = Cli::parse();
-    let mut client = KvsClient::connect(cli.addr)?;
+    let mut options = ClientOption::new();
+    if let Some(ca) = cli.tls_ca {
+        options.tls_ca(ca);
+    }
+    if let Some(name) = cli.tls_server_name {
+        options.server_name(name);
+    }
+    if let Some(token) = cli.token {
+        options.token(token);
+    }
+    let mut client = KvsClient::connect_with_options(cli.addr, &options)?;
 
     match cli.command {
-        CliCommands::Set { key, value } => {
-            client.send(KvsRequest::Set { key, value })?;
-            let resp = client.recv()?;
-            if !matches!(resp, kvs::KvsResponse::Ok(_)) {
-                return Err(kvs::KvError::Unknown);
+        CliCommands::Request(Request::Remove { key }) => {
+            if let Err(e) = client.remove(key) {
+                if let KvError::KeyNotFound(_) = e {
+                    eprintln!("Key not found");
+                }
+                return Err(e);
             }
         }
-        CliCommands::Get { key } => {
-            client.send(KvsRequest::Get { key })?;
-            match client.recv()? {
-                kvs::KvsResponse::Ok(Some(v)) => println!("{v}"),
-                kvs::KvsResponse::Ok(None) => println!("Key not found"),
-                _ => return Err(kvs::KvError::Unknown),
+        CliCommands::Request(request) => {
+            if let Some(text) = execute(&mut client, request)?.text() {
+                println!("{text}");
             }
         }
-        CliCommands::Remove { key } => {
-            client.send(KvsRequest::Remove { key })?;
-            match client.recv()? {
-                kvs::KvsResponse::Ok(_) => {}
-                kvs::KvsResponse::KeyNotFound(key) => {
-                    eprintln!("Key not found");
-                    return Err(kvs::KvError::KeyNotFound(key));
+        CliCommands::Shell { history } => shell(&mut client, cli.addr, history)?,
+        CliCommands::Batch { input, format } => {
+            let input: Box<dyn BufRead> = if input == "-" {
+                Box::new(io::stdin().lock())
+            } else {
+                Box::new(BufReader::new(File::open(input)?))
+            };
+            let failed = batch(&mut client, input, format)?;
+            if failed > 0 {
+                eprintln!("{failed} command(s) failed");
+                process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What a request which succeeded returns.
+enum Output {
+    Done,
+    Value(Option<String>),
+    Text(String),
+}
+
+impl Output {
+    /// Printed as the single commands do, nothing for a write.
+    fn text(self) -> Option<String> {
+        match self {
+            Output::Done => None,
+            Output::Value(value) => Some(value.unwrap_or_else(|| "Key not found".to_owned())),
+            Output::Text(text) => Some(text),
+        }
+    }
+}
+
+/// Send a request to the server.
+fn execute(client: &mut KvsClient, request: Request) -> Result<Output> {
+    Ok(match request {
+        Request::Set { key, value } => {
+            client.set(key, value)?;
+            Output::Done
+        }
+        Request::Get { key } => Output::Value(client.get(key)?),
+        Request::Remove { key } => {
+            client.remove(key)?;
+            Output::Done
+        }
+        Request::Stats => Output::Text(client.stats()?.to_prometheus().trim_end().to_owned()),
+        Request::Promote => {
+            client.promote()?;
+            Output::Done
+        }
+    })
+}
+
+/// Parse a line of the shell or of a batch and send it, `None` if it has no command.
+fn run_line(client: &mut KvsClient, line: &str) -> Option<Result<Output>> {
+    let words = match split(line) {
+        Ok(words) => words,
+        Err(e) => return Some(Err(e)),
+    };
+    if words.first().is_none_or(|word| word.starts_with('#')) {
+        return None;
+    }
+    Some(match Line::try_parse_from(&words) {
+        Ok(Line { request }) => execute(client, request),
+        Err(e) if e.kind() == ErrorKind::DisplayHelp => {
+            Ok(Output::Text(e.to_string().trim_end().to_owned()))
+        }
+        // The problem comes before a blank line, then usage.
+        Err(e) => {
+            let message = e.to_string();
+            let problem: Vec<&str> = message
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .map(str::trim)
+                .collect();
+            Err(KvError::InvalidArgument(
+                problem.join(" ").trim_start_matches("error: ").to_owned(),
+            ))
+        }
+    })
+}
+
+/// Split a line into words on whitespace. Words may be quoted with `'` or `"`,
+/// `\` escapes the next character outside single quotes.
+fn split(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => {
+                    return Err(KvError::InvalidArgument(
+                        "line ends with an escape".to_owned(),
+                    ))
+                }
+            },
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(KvError::InvalidArgument("unterminated quote".to_owned()));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Failures after which the connection cannot be used anymore.
+fn is_broken(error: &KvError) -> bool {
+    matches!(
+        error,
+        KvError::Io(_) | KvError::Protocol(_) | KvError::Tls(_)
+    )
+}
+
+/// Run the commands of `input`, printing each result in `format`.
+/// Returns the number of commands which failed, stops early once the connection breaks.
+fn batch(client: &mut KvsClient, input: Box<dyn BufRead>, format: Format) -> Result<usize> {
+    let mut out = io::stdout().lock();
+    let mut failed = 0;
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let result = match run_line(client, &line) {
+            Some(result) => result,
+            None => continue,
+        };
+        let number = number + 1;
+        let broken = result.as_ref().is_err_and(is_broken);
+
+        match (format, result) {
+            (Format::Text, Ok(output)) => {
+                if let Some(text) = output.text() {
+                    writeln!(out, "{text}")?;
+                }
+            }
+            (Format::Text, Err(e)) => {
+                failed += 1;
+                out.flush()?;
+                eprintln!("line {number}: {e}");
+            }
+            (Format::Json, result) => {
+                let mut object = json!({ "line": number, "ok": result.is_ok() });
+                match result {
+                    Ok(Output::Done) => {}
+                    Ok(Output::Value(value)) => object["value"] = json!(value),
+                    Ok(Output::Text(text)) => object["value"] = Value::String(text),
+                    Err(e) => {
+                        failed += 1;
+                        object["error"] = Value::String(e.to_string());
+                    }
+                }
+                writeln!(out, "{object}")?;
+            }
+        }
+        if broken {
+            break;
+        }
+    }
+    out.flush()?;
+    Ok(failed)
+}
+
+/// Completes command names in the shell.
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = &line[..pos];
+        let start = typed.len() - typed.trim_start().len();
+        let word = &typed[start..];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Read commands interactively until `quit` or end of input, printing their results.
+fn shell(client: &mut KvsClient, address: SocketAddr, history: Option<PathBuf>) -> Result<()> {
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper));
+    let history = history
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history")));
+    if let Some(history) = &history {
+        // Missing on first use.
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = format!("{address}> ");
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        if !line.trim().is_empty() {
+            editor
+                .add_history_entry(line.as_str())
+                .map_err(readline_error)?;
+        }
+        if matches!(line.trim(), "quit" | "exit") {
+            break;
+        }
+
+        match run_line(client, &line) {
+            Some(Ok(output)) => {
+                if let Some(text) = output.text() {
+                    println!("{text}");
                 }
-                _ => return Err(kvs::KvError::Unknown),
             }
+            Some(Err(e)) if is_broken(&e) => return Err(e),
+            Some(Err(e)) => eprintln!("{e}"),
+            None => {}
         }
     }
 
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("cannot save history to {}: {e}", history.display());
+        }
+    }
     Ok(())
 }
+
+fn readline_error(error: ReadlineError) -> KvError {
+    match error {
+        ReadlineError::Io(e) => KvError::Io(e),
+        e => KvError::Io(io::Error::other(e)),
+    }
+}
@@ -1,7 +1,15 @@
-use std::{env, io, net::SocketAddr, sync::mpsc};
+use std::{
+    env, io, net::SocketAddr, num::NonZeroUsize, path::PathBuf, sync::mpsc, thread, time::Duration,
+};
 
-use clap::{Parser, ValueEnum};
-use kvs::{thread_pool, thread_pool::ThreadPool, KvsServer, Result, Store};
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use kvs::{
+    thread_pool::{
+        NaiveThreadPool, QueuePolicy, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+        WorkStealingThreadPool,
+    },
+    ClientOption, KvsServer, Protocol, Result, Store, ThreadPoolOption,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -10,35 +18,245 @@ struct Cli {
     addr: SocketAddr,
     #[arg(long)]
     engine: Engine,
+    /// Protocol spoken with clients, `resp` lets Redis clients connect.
+    #[arg(long, value_enum, default_value_t = ClientProtocol::Kvs)]
+    protocol: ClientProtocol,
+    /// Thread pool serving the connections, each one holds a worker while open.
+    #[arg(long, value_enum, default_value_t = Pool::Naive)]
+    pool: Pool,
+    /// Workers of the pool, the most started by `elastic`, unused by `naive`.
+    /// Defaults to the number of cpus.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Most connections waiting for a worker of the `shared` or `elastic` pool.
+    #[arg(long)]
+    queue_size: Option<usize>,
+    /// What happens to a connection arriving while the queue is full.
+    #[arg(long, value_enum, default_value_t = WhenFull::Reject, requires = "queue_size")]
+    when_full: WhenFull,
+    /// Maximum number of keys kept by the memory engine, least recently used keys are evicted.
+    #[arg(long)]
+    capacity: Option<NonZeroUsize>,
+    /// Serve Prometheus metrics over HTTP at this address.
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Also serve the store over HTTP with json bodies at this address.
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+    /// Seconds before closing a connection which stays idle, or is slow to send a request.
+    #[arg(long, default_value_t = 60)]
+    read_timeout: u64,
+    /// Seconds to let open connections finish on shutdown, before closing them.
+    #[arg(long, default_value_t = 5)]
+    drain_timeout: u64,
+    /// Only accept TLS connections, with the certificate chain in this PEM file.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Private key of the TLS certificate, in a PEM file.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Require clients to authenticate with this token.
+    #[arg(long, env = "KVS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Run as a read-only replica of the primary at this address, until promoted by a client
+    /// with `--token`.
+    #[arg(long)]
+    replica_of: Option<SocketAddr>,
+    /// Connect to the primary with TLS, trusting the certificate authorities in this PEM file.
+    #[arg(long, requires = "replica_of")]
+    primary_tls_ca: Option<PathBuf>,
+    /// Authenticate to the primary with this token.
+    #[arg(long, env = "KVS_PRIMARY_TOKEN", hide_env_values = true)]
+    primary_token: Option<String>,
+    /// Run as this member of a Raft cluster, with `--raft-peer` for every other member.
+    /// Its term, vote and log are kept in the `raft` folder of the current directory.
+    /// Members authenticate to each other with `--token`, which they must share.
+    #[arg(
+        long,
+        requires = "raft_peer",
+        requires = "token",
+        conflicts_with = "replica_of"
+    )]
+    raft_id: Option<u64>,
+    /// Another member of the Raft cluster, as `id=address`. Repeat for each member.
+    #[arg(long, value_parser = parse_peer, requires = "raft_id")]
+    raft_peer: Vec<(u64, SocketAddr)>,
+    /// Connect to the other members with TLS, trusting the certificate authorities in this
+    /// PEM file.
+    #[arg(long, requires = "raft_id")]
+    raft_tls_ca: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone, PartialEq, Eq, ValueEnum)]
 enum Engine {
     Kvs,
     Sled,
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Pool {
+    /// Workers taking jobs from one shared queue.
+    Shared,
+    /// Workers with their own queues, stealing from each other.
+    Stealing,
+    /// Rayon's thread pool.
+    Rayon,
+    /// A new thread for every connection.
+    Naive,
+    /// A shared queue, with one worker kept and more started while connections wait.
+    Elastic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum WhenFull {
+    /// Answer its first request with a `Busy` error and close it.
+    Reject,
+    /// Wait for room in the queue, without accepting other connections meanwhile.
+    Block,
+}
+
+impl From<WhenFull> for QueuePolicy {
+    fn from(when_full: WhenFull) -> Self {
+        match when_full {
+            WhenFull::Reject => QueuePolicy::Reject,
+            WhenFull::Block => QueuePolicy::Block,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ClientProtocol {
+    Kvs,
+    Resp,
+}
+
+impl From<ClientProtocol> for Protocol {
+    fn from(protocol: ClientProtocol) -> Self {
+        match protocol {
+            ClientProtocol::Kvs => Protocol::Kvs,
+            ClientProtocol::Resp => Protocol::Resp,
+        }
+    }
+}
+
+fn parse_peer(peer: &str) -> std::result::Result<(u64, SocketAddr), String> {
+    let (id, address) = peer
+        .split_once('=')
+        .ok_or_else(|| "expected `id=address`".to_owned())?;
+    let id = id
+        .parse()
+        .map_err(|e| format!("invalid id `{}`: {}", id, e))?;
+    let address = address
+        .parse()
+        .map_err(|e| format!("invalid address `{}`: {}", address, e))?;
+    Ok((id, address))
 }
 
 fn main() -> Result<()> {
     tracing_subscriber::fmt().with_writer(io::stderr).init();
 
     let cli = Cli::parse();
+    if cli.capacity.is_some() && cli.engine != Engine::Memory {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "`--capacity` is only supported by the memory engine",
+            )
+            .exit();
+    }
+    if cli.queue_size.is_some() && !matches!(cli.pool, Pool::Shared | Pool::Elastic) {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "`--queue-size` is only supported by the shared and elastic pools",
+            )
+            .exit();
+    }
 
     let current_dir = env::current_dir().expect("get current working directory");
     let store = match cli.engine {
         Engine::Kvs => Store::open_with_kvs(&current_dir)?,
         Engine::Sled => Store::open_with_sled(&current_dir)?,
+        Engine::Memory => match cli.capacity {
+            Some(capacity) => Store::open_in_memory_with_capacity(capacity),
+            None => Store::open_in_memory(),
+        },
     };
 
-    let pool = thread_pool::NaiveThreadPool::new(1)?;
-    let server = KvsServer::open(cli.addr, store, pool)?;
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism()?.get() as u32,
+    };
+    let mut options = ThreadPoolOption::new();
+    options
+        .queue_size(cli.queue_size)
+        .when_full(cli.when_full.into());
+    match cli.pool {
+        Pool::Shared => {
+            let pool = SharedQueueThreadPool::with_options(threads, &options)?;
+            serve(cli, store, pool)
+        }
+        Pool::Stealing => serve(cli, store, WorkStealingThreadPool::new(threads)?),
+        Pool::Rayon => serve(cli, store, RayonThreadPool::new(threads)?),
+        Pool::Naive => serve(cli, store, NaiveThreadPool::new(threads)?),
+        Pool::Elastic => {
+            let pool = SharedQueueThreadPool::with_options(1, options.max_threads(threads))?;
+            serve(cli, store, pool)
+        }
+    }
+}
+
+/// Serve `store` with `pool` until interrupted.
+fn serve<P: ThreadPool>(cli: Cli, store: Store, pool: P) -> Result<()> {
+    let mut server = KvsServer::open(cli.addr, store, pool)?
+        .with_read_timeout(Duration::from_secs(cli.read_timeout))
+        .with_drain_timeout(Duration::from_secs(cli.drain_timeout))
+        .with_protocol(cli.protocol.into());
+    if let Some(metrics_addr) = cli.metrics_addr {
+        server = server.with_metrics(metrics_addr)?;
+    }
+    if let Some(http_addr) = cli.http_addr {
+        server = server.with_http(http_addr)?;
+    }
+    if let (Some(cert), Some(key)) = (cli.tls_cert, cli.tls_key) {
+        server = server.with_tls(cert, key)?;
+    }
+    if let Some(id) = cli.raft_id {
+        let mut options = ClientOption::new();
+        if let Some(ca) = cli.raft_tls_ca {
+            options.tls_ca(ca);
+        }
+        if let Some(token) = &cli.token {
+            options.token(token.clone());
+        }
+        let mut members = cli.raft_peer;
+        members.push((id, cli.addr));
+        let path = env::current_dir()?.join("raft");
+        server = server.with_raft(id, members, options, path)?;
+    }
+    if let Some(token) = cli.token {
+        server = server.with_token(token);
+    }
+    if let Some(primary) = cli.replica_of {
+        let mut options = ClientOption::new();
+        if let Some(ca) = cli.primary_tls_ca {
+            options.tls_ca(ca);
+        }
+        if let Some(token) = cli.primary_token {
+            options.token(token);
+        }
+        server = server.with_primary(primary, options);
+    }
 
-    server.serve();
+    let server = server.serve();
 
     // handling shutdown
     let (tx, rx) = mpsc::channel();
     ctrlc::set_handler(move || tx.send(()).expect("could not send signal on channel."))
         .expect("Error setting Ctrl-C handler");
     rx.recv().expect("Could not receive from channel.");
+    server.shutdown();
 
     Ok(())
 }
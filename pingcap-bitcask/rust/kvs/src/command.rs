@@ -1,6 +1,14 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, SystemTime},
+};
 
-use crate::{log::LogId, parser::ByteParser};
+use crate::{
+    log::{LogId, LogRead, LogReader},
+    parser::ByteParser,
+    Result,
+};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 
@@ -73,6 +81,7 @@ impl Command {
 pub(crate) struct CommandLocation {
     pub id: LogId,
     pub offset: usize,
+    pub size: usize,
     pub timestamp: Duration,
 }
 
@@ -86,6 +95,29 @@ impl CommandLocations {
         CommandLocations::default()
     }
 
+    /// Read all commands from the logs, keep the latest location of every live key.
+    pub fn load<P: AsRef<Path>>(folder: P, ids: &[LogId]) -> Result<CommandLocations> {
+        let locations = CommandLocations::new();
+
+        let mut tombstones = HashSet::new();
+        for id in ids {
+            let reader = LogReader::open(&folder, *id)?;
+            for (command, location) in reader.into_commands()? {
+                if matches!(command, Command::Remove { .. }) {
+                    tombstones.insert((location.id, location.offset));
+                }
+                locations.merge(command.key(), location);
+            }
+        }
+
+        // Keys whose latest command is a removal are not alive.
+        locations
+            .data
+            .retain(|_, location| !tombstones.contains(&(location.id, location.offset)));
+
+        Ok(locations)
+    }
+
     pub fn merge(&self, key: String, location: CommandLocation) {
         self.data
             .entry(key)
@@ -96,6 +128,16 @@ impl CommandLocations {
             })
             .or_insert(location);
     }
+
+    /// Move a key to its merged location, only if it still points into one of the merged logs.
+    /// Keys that were removed or rewritten since merging started are left untouched.
+    pub fn transfer(&self, key: String, location: CommandLocation, merged_ids: &[LogId]) {
+        if let Some(mut old_location) = self.data.get_mut(&key) {
+            if merged_ids.contains(&old_location.id) {
+                *old_location = location;
+            }
+        }
+    }
 }
 
 pub(crate) fn current_timestamp() -> Duration {
@@ -1,15 +1,15 @@
-use std::io;
+use std::{io, net::SocketAddr};
 
 use thiserror::Error;
 
 #[doc(hidden)]
 #[derive(Error, Debug)]
 pub enum KvError {
-    #[error("io error")]
+    #[error("io error: {0}")]
     Io(#[from] io::Error),
-    #[error("bson serialize error")]
+    #[error("bson serialize error: {0}")]
     BsonSer(#[from] bson::ser::Error),
-    #[error("bson deserialize error")]
+    #[error("bson deserialize error: {0}")]
     BsonDe(#[from] bson::de::Error),
     #[error("invalid pattern `{0}`")]
     GlobPattern(#[from] glob::PatternError),
@@ -17,6 +17,10 @@ pub enum KvError {
     Sled(#[from] sled::Error),
     #[error("rayon threadpool build `{0}`")]
     RayonThreadPoolBuild(#[from] rayon::ThreadPoolBuildError),
+    #[error("async task failed `{0}`")]
+    AsyncTask(#[from] tokio::task::JoinError),
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
 
     #[error("file id `{0}` does not exist")]
     FileIdDoesNotExist(u64),
@@ -32,11 +36,40 @@ pub enum KvError {
 
     #[error("mismatch engine")]
     MismatchEngine,
+    #[error("database is already opened by process `{0}`")]
+    AlreadyOpen(String),
+    #[error("migration failed: {0}")]
+    Migration(String),
+    #[error("corrupted data: {0}")]
+    Corrupted(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("server busy: {0}")]
+    Busy(String),
+    #[error("authentication failed: {0}")]
+    Unauthenticated(String),
+    #[error("read only: {0}")]
+    ReadOnly(String),
+    #[error("not the leader, leader is {}", leader.map_or("unknown".to_owned(), |leader| leader.to_string()))]
+    NotLeader { leader: Option<SocketAddr> },
+    #[error("subscription overflowed: {0}")]
+    Overflowed(String),
+    #[error("server error: {0}")]
+    Server(String),
+    #[error("protocol version `{version}` is not supported, server speaks {min} to {max}")]
+    UnsupportedVersion { version: u32, min: u32, max: u32 },
 
     #[error("cannot read shared data `{0}`")]
     SharedRead(String),
     #[error("cannot write shared data `{0}`")]
     SharedWrite(String),
+
+    #[error("thread pool is shut down")]
+    PoolShutdown,
+    #[error("job panicked")]
+    JobPanicked,
 }
 
 /// Alias result to avoid duplication
@@ -0,0 +1,283 @@
+//! Offline tooling for the kvs engine's data files.
+//!
+//! Every function takes the same directory as [`Store::open_with_kvs`][crate::Store::open_with_kvs].
+
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tracing::info;
+
+use super::{kv::KvStore, lock::DirLock, migrate, KvsEngine};
+use crate::{
+    command::{Command, CommandLocation, CommandLocations},
+    log::{finder, vfs::StdFs, LogId, LogRead, LogReader},
+    KvError, Result,
+};
+
+/// Summary of a log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogInfo {
+    /// Log id.
+    pub id: u64,
+    /// File size in bytes.
+    pub size: u64,
+    /// Number of readable records.
+    pub records: usize,
+    /// Number of records that are the latest value of a live key.
+    pub live_records: usize,
+    /// Size of the live records in bytes.
+    pub live_bytes: u64,
+}
+
+impl LogInfo {
+    /// Ratio of live bytes over the file size.
+    pub fn live_ratio(&self) -> f64 {
+        if self.size == 0 {
+            0.0
+        } else {
+            self.live_bytes as f64 / self.size as f64
+        }
+    }
+}
+
+/// A decoded record of a log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Offset of the record in the file.
+    pub offset: usize,
+    /// Size of the record in bytes.
+    pub size: usize,
+    /// Key of the record.
+    pub key: String,
+    /// Value of the record, `None` for removals.
+    pub value: Option<String>,
+    /// Time the record was written, since unix epoch.
+    pub timestamp: Duration,
+}
+
+/// A log file whose tail cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TornLog {
+    /// Log id.
+    pub id: u64,
+    /// Length of the readable prefix.
+    pub valid_len: u64,
+    /// File size in bytes.
+    pub size: u64,
+}
+
+/// Result of an integrity scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    /// Number of log files.
+    pub logs: usize,
+    /// Number of readable records.
+    pub records: usize,
+    /// Number of live keys.
+    pub keys: usize,
+    /// Logs with unreadable tails.
+    pub torn: Vec<TornLog>,
+}
+
+impl Verification {
+    /// Whether every log is fully readable.
+    pub fn is_ok(&self) -> bool {
+        self.torn.is_empty()
+    }
+}
+
+/// Result of a repair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// Logs truncated to their readable prefix.
+    pub truncated: Vec<TornLog>,
+    /// Number of live keys in the rebuilt index.
+    pub keys: usize,
+}
+
+struct LogScan {
+    id: LogId,
+    records: Vec<(Command, CommandLocation)>,
+    valid_len: u64,
+    size: u64,
+}
+
+impl LogScan {
+    fn read<P: AsRef<Path>>(dbpath: P, id: LogId) -> Result<LogScan> {
+        let size = fs::metadata(finder::log_path(&dbpath, &id))?.len();
+        let mut commands = LogReader::open(&dbpath, id)?.into_commands()?;
+        let records = commands.by_ref().collect();
+        let valid_len = commands.offset() as u64;
+        Ok(LogScan {
+            id,
+            records,
+            valid_len,
+            size,
+        })
+    }
+
+    fn torn(&self) -> Option<TornLog> {
+        (self.valid_len < self.size).then_some(TornLog {
+            id: self.id.0,
+            valid_len: self.valid_len,
+            size: self.size,
+        })
+    }
+}
+
+fn dbpath<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let dbpath = KvStore::dbpath(&path);
+    if !dbpath.is_dir() {
+        let message = format!("no kvs database at `{}`", path.as_ref().display());
+        return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+    }
+    Ok(dbpath)
+}
+
+fn sorted_log_ids<P: AsRef<Path>>(dbpath: P) -> Result<Vec<LogId>> {
+    let mut ids = finder::all_log_ids(dbpath)?;
+    ids.sort();
+    Ok(ids)
+}
+
+/// List all log files, ordered by id.
+pub fn list_logs<P: AsRef<Path>>(path: P) -> Result<Vec<LogInfo>> {
+    let dbpath = dbpath(path)?;
+    let ids = sorted_log_ids(&dbpath)?;
+    let locations = CommandLocations::load(&dbpath, &ids)?;
+
+    let mut logs = Vec::new();
+    for id in ids {
+        let scan = LogScan::read(&dbpath, id)?;
+        let mut info = LogInfo {
+            id: id.0,
+            size: scan.size,
+            records: scan.records.len(),
+            live_records: 0,
+            live_bytes: 0,
+        };
+        for (command, location) in &scan.records {
+            let live = locations
+                .data
+                .get(&command.key())
+                .is_some_and(|live| live.id == location.id && live.offset == location.offset);
+            if live {
+                info.live_records += 1;
+                info.live_bytes += location.size as u64;
+            }
+        }
+        logs.push(info);
+    }
+
+    Ok(logs)
+}
+
+/// Decode every record of a log file.
+pub fn dump<P: AsRef<Path>>(path: P, id: u64) -> Result<Vec<LogRecord>> {
+    let dbpath = dbpath(path)?;
+    let id = LogId(id);
+    if !finder::log_path(&dbpath, &id).exists() {
+        return Err(KvError::FileIdDoesNotExist(id.0));
+    }
+
+    let scan = LogScan::read(&dbpath, id)?;
+    let records = scan
+        .records
+        .iter()
+        .map(|(command, location)| LogRecord {
+            offset: location.offset,
+            size: location.size,
+            key: command.key(),
+            value: command.value(),
+            timestamp: command.timestamp(),
+        })
+        .collect();
+
+    Ok(records)
+}
+
+/// Decode every log file, and read back every live key from the index.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<Verification> {
+    let dbpath = dbpath(path)?;
+    let ids = sorted_log_ids(&dbpath)?;
+
+    let mut verification = Verification {
+        logs: ids.len(),
+        records: 0,
+        keys: 0,
+        torn: Vec::new(),
+    };
+    for id in &ids {
+        let scan = LogScan::read(&dbpath, *id)?;
+        verification.records += scan.records.len();
+        verification.torn.extend(scan.torn());
+    }
+
+    let locations = CommandLocations::load(&dbpath, &ids)?;
+    for location in locations.data.iter() {
+        let command = LogReader::open(&dbpath, location.id)?.read(&location)?;
+        if command.key() != *location.key() {
+            return Err(KvError::Corrupted(format!(
+                "key `{}` points to key `{}` in log `{}` at offset `{}`",
+                location.key(),
+                command.key(),
+                location.id.0,
+                location.offset
+            )));
+        }
+    }
+    verification.keys = locations.data.len();
+
+    Ok(verification)
+}
+
+/// Merge every log file into one, dropping stale records.
+///
+/// An interrupted migration of the folder is finished or undone first, like opening a
+/// [`Store`][crate::Store] does.
+pub fn compact<P: AsRef<Path>>(path: P) -> Result<()> {
+    let _lock = migrate::lock_folder(&StdFs, path.as_ref())?;
+    dbpath(&path)?;
+    let store = KvStore::open(&path)?;
+    store.compact()
+}
+
+/// Truncate unreadable tails of log files, then rebuild the index from what is left.
+///
+/// Records after an unreadable one are lost, as there is no way to find where they start.
+/// An interrupted migration of the folder is finished or undone first.
+pub fn repair<P: AsRef<Path>>(path: P) -> Result<Repair> {
+    let _lock = migrate::lock_folder(&StdFs, path.as_ref())?;
+    let dbpath = dbpath(&path)?;
+
+    let truncated = {
+        let _lock = DirLock::acquire(&dbpath)?;
+        let mut truncated = Vec::new();
+        for id in sorted_log_ids(&dbpath)? {
+            if let Some(torn) = LogScan::read(&dbpath, id)?.torn() {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(finder::log_path(&dbpath, &id))?;
+                file.set_len(torn.valid_len)?;
+                file.sync_all()?;
+                info!(
+                    id = torn.id,
+                    from = torn.size,
+                    to = torn.valid_len,
+                    "truncated log:"
+                );
+                truncated.push(torn);
+            }
+        }
+        truncated
+    };
+
+    let store = KvStore::open(&path)?;
+    let keys = store.keys()?.len();
+
+    Ok(Repair { truncated, keys })
+}
@@ -0,0 +1,78 @@
+use tokio::task;
+
+use super::KvsEngine;
+use crate::{Result, Stats};
+
+/// Async adapter over a [`KvsEngine`].
+///
+/// Engine calls block on disk, every call runs on tokio's blocking thread pool
+/// so it never stalls the async workers.
+///
+/// # Example
+/// ```rust
+/// # use kvs::{AsyncKvsEngine, Result, Store};
+/// # #[tokio::main]
+/// # async fn main() -> Result<()> {
+/// let engine = AsyncKvsEngine::new(Store::open_in_memory());
+///
+/// engine.set("key1".to_string(), "value1".to_string()).await?;
+/// assert_eq!(engine.get("key1".to_string()).await?, Some("value1".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncKvsEngine<E>
+where
+    E: KvsEngine,
+{
+    engine: E,
+}
+
+impl<E> AsyncKvsEngine<E>
+where
+    E: KvsEngine,
+{
+    /// Wrap a blocking engine.
+    pub fn new(engine: E) -> AsyncKvsEngine<E> {
+        AsyncKvsEngine { engine }
+    }
+
+    /// The wrapped engine.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        task::spawn_blocking(move || f(engine)).await?
+    }
+
+    /// See [`KvsEngine::set`].
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    /// See [`KvsEngine::get`].
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    /// See [`KvsEngine::remove`].
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(key)).await
+    }
+
+    /// See [`KvsEngine::keys`].
+    pub async fn keys(&self) -> Result<Vec<String>> {
+        self.run(|engine| engine.keys()).await
+    }
+
+    /// See [`KvsEngine::stats`].
+    pub async fn stats(&self) -> Result<Stats> {
+        self.run(|engine| engine.stats()).await
+    }
+}
@@ -1,4 +1,6 @@
-use crate::Result;
+use crate::{Result, Stats};
+
+use super::{ReplicationLog, Subscription};
 
 /// Trait for database engine.
 ///
@@ -10,4 +12,22 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     /// Remove a key from the store.
     fn remove(&self, key: String) -> Result<()>;
+    /// Get all live keys from the store, in ascending order.
+    fn keys(&self) -> Result<Vec<String>>;
+    /// Get counters and histograms describing the store.
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats::default())
+    }
+    /// Log of the writes, which replicas of a server stream from.
+    /// `None` for engines keeping no such log.
+    #[doc(hidden)]
+    fn replication_log(&self) -> Option<ReplicationLog> {
+        None
+    }
+    /// Changes to the keys starting with `prefix`, see [`Store::subscribe`][crate::Store::subscribe].
+    /// `None` for engines which cannot tell about their writes.
+    #[doc(hidden)]
+    fn subscribe(&self, _prefix: String, _capacity: usize) -> Option<Subscription> {
+        None
+    }
 }
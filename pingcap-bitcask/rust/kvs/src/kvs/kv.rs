@@ -4,17 +4,27 @@ use tracing::info;
 
 use crate::{
     command::{Command, CommandLocations},
-    log::{finder, LogId, LogRead, LogReader, LogWrite, LogWriter},
-    merger::Merger,
+    log::{finder, vfs::LogFile, LogId, LogRead, LogReader, LogWrite, LogWriter},
+    merger::{self, MergeInfo, Merger},
+    parser::ByteParser,
+    stats::{AtomicHistogram, Stats},
     KvError, KvOption, Result,
 };
 use std::{
     fs::{self, File},
+    io::{BufReader, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
 };
 
-use super::engine::KvsEngine;
+use super::{
+    engine::KvsEngine,
+    lock::DirLock,
+    replication::{LogChain, LogPosition},
+};
 
 const DATA_FOLDER: &str = "kvstore";
 
@@ -25,9 +35,11 @@ pub(crate) struct KvStore {
     path: PathBuf,
 
     /// Append writer recoding the incoming commands.
-    writer: SharedRw<LogWriter<File>>,
+    writer: SharedRw<LogWriter<Box<dyn LogFile>>>,
     /// Immutable readers.
     readers: Arc<DashSet<LogId>>,
+    /// Writer logs since the store was opened, read by replicas.
+    chain: SharedRw<LogChain>,
 
     /// In memory map pointing to located commands on disk.
     locations: Arc<CommandLocations>,
@@ -37,6 +49,18 @@ pub(crate) struct KvStore {
 
     /// Merger controls the merging process.
     merger: SharedRw<Merger>,
+
+    /// Exclusive lock on the database folder, held as long as the store is alive.
+    _lock: Arc<DirLock>,
+
+    /// Counters exposed through [`KvsEngine::stats`].
+    metrics: Arc<KvMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct KvMetrics {
+    bytes_written: AtomicU64,
+    merge: AtomicHistogram,
 }
 
 impl KvStore {
@@ -56,29 +80,32 @@ impl KvStore {
         info!(dbpath = %path.as_ref().display(), "open database:");
         let _ = fs::create_dir_all(&path);
 
-        let locations = CommandLocations::new();
+        let lock = DirLock::acquire(&path)?;
+
+        // Finish merges interrupted by a crash, before their logs are read twice.
+        merger::recover(&options.fs, &path)?;
 
         // Read all commands from previous log files.
-        let readers: DashSet<LogId> = finder::all_log_ids(&path)?.into_iter().collect();
-        for id in readers.iter() {
-            let reader = LogReader::open(&path, *id)?;
-            for (command, location) in reader.into_commands()? {
-                locations.merge(command.key(), location);
-            }
-        }
+        let ids = finder::all_log_ids(&path)?;
+        let locations = CommandLocations::load(&path, &ids)?;
+        let readers: DashSet<LogId> = ids.into_iter().collect();
 
         // Create new writer.
-        let writer = LogWriter::open(&path, finder::next_log_id(&path))?;
+        let writer = LogWriter::open(&options.fs, &path, finder::next_log_id(&options.fs, &path)?)?;
 
-        let merger = Merger::new(&path);
+        let chain = LogChain::new(writer.id);
+        let merger = Merger::new(options.fs.clone(), &path);
 
         let store = KvStore {
             path: path.as_ref().to_path_buf(),
             writer: SharedRw::new(writer),
             readers: Arc::new(readers),
+            chain: SharedRw::new(chain),
             locations: Arc::new(locations),
             merger: SharedRw::new(merger),
             options,
+            _lock: Arc::new(lock),
+            metrics: Arc::new(KvMetrics::default()),
         };
 
         info!(version = crate_version!(), database_path = %store.path.display(), "opened kvs database:");
@@ -94,13 +121,48 @@ impl KvStore {
         let should_merge = !merger.running() && self.readers.len() >= self.options.num_readers;
 
         if should_merge {
+            merger.finish_pending()?;
+            let readers = {
+                let writer = self.writer.rlock()?;
+                finder::all_log_ids(&self.path)?
+                    .into_iter()
+                    .filter(|id| id != &writer.id)
+                    .collect()
+            };
+            merger.merge(readers);
+
+            if !self.options.background_merge {
+                if let Some(merge_info) = merger.join() {
+                    self.transfer_merged_result(&mut merger, merge_info?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge all readonly logs, blocking until it is done.
+    pub fn compact(&self) -> Result<()> {
+        let mut merger = self.merger.wlock()?;
+
+        // Finish the running merge first, its logs must not be merged twice.
+        if let Some(merge_info) = merger.join() {
+            self.transfer_merged_result(&mut merger, merge_info?)?;
+        }
+        merger.finish_pending()?;
+
+        let readers = {
             let writer = self.writer.rlock()?;
-            let readers = finder::all_log_ids(&self.path)?
+            finder::all_log_ids(&self.path)?
                 .into_iter()
                 .filter(|id| id != &writer.id)
-                .collect();
-            merger.merge(readers);
+                .collect()
+        };
+        merger.merge(readers);
+
+        if let Some(merge_info) = merger.join() {
+            self.transfer_merged_result(&mut merger, merge_info?)?;
         }
+
         Ok(())
     }
 
@@ -109,33 +171,112 @@ impl KvStore {
         let mut merger = self.merger.wlock()?;
 
         if let Some(Ok(merge_info)) = merger.result() {
-            // transfer new key
-            {
-                for (key, location) in merge_info.locations.data {
-                    self.locations.merge(key, location)
-                }
-            }
+            self.transfer_merged_result(&mut merger, merge_info)?;
+        }
 
-            // remove old file ids
-            for id in &merge_info.reader_ids {
-                let reader_path = finder::log_path(&self.path, id);
-                fs::remove_file(reader_path)?;
-                self.readers.remove(id);
+        Ok(())
+    }
+
+    fn transfer_merged_result(&self, merger: &mut Merger, merge_info: MergeInfo) -> Result<()> {
+        self.metrics
+            .bytes_written
+            .fetch_add(merge_info.bytes_written as u64, Ordering::Relaxed);
+        self.metrics.merge.record(merge_info.duration);
+
+        // transfer new key
+        {
+            for (key, location) in merge_info.locations.data {
+                self.locations
+                    .transfer(key, location, &merge_info.reader_ids)
             }
         }
 
+        // Hold the writer, so no new log reuses a removed id before the manifest is gone.
+        let _writer = self.writer.wlock()?;
+        // Nothing is read from the merged logs anymore, even if removing them fails.
+        for id in &merge_info.reader_ids {
+            self.readers.remove(id);
+        }
+        self.chain.wlock()?.forget(&merge_info.reader_ids);
+        merger.finish(merge_info.id, merge_info.reader_ids)?;
+
         Ok(())
     }
 
     fn rollover(&self) -> Result<()> {
         let mut writer = self.writer.wlock()?;
         if writer.offset >= self.options.writer_size {
-            let new_writer_id = finder::next_log_id(&self.path);
-            *writer = LogWriter::open(&self.path, new_writer_id)?;
+            let new_writer_id = finder::next_log_id(&self.options.fs, &self.path)?;
+            let len = writer.offset as u64;
+            *writer = LogWriter::open(&self.options.fs, &self.path, new_writer_id)?;
             self.readers.insert(writer.id);
+            self.chain.wlock()?.roll(len, writer.id);
         }
         Ok(())
     }
+
+    pub(crate) fn log_position(&self) -> Result<LogPosition> {
+        let writer = self.writer.rlock()?;
+        let chain = self.chain.rlock()?;
+        Ok(chain.position(writer.offset as u64))
+    }
+
+    pub(crate) fn read_log(
+        &self,
+        position: &LogPosition,
+        max_bytes: usize,
+    ) -> Result<Option<(Vec<Command>, LogPosition)>> {
+        let mut position = *position;
+        let segment = loop {
+            let segment = {
+                let writer = self.writer.rlock()?;
+                let chain = self.chain.rlock()?;
+                chain.segment(&position, writer.offset as u64)
+            };
+            match segment {
+                None => return Ok(None),
+                // Go on with the next writer log once this one is read.
+                Some(segment) if segment.end == position.offset => {
+                    if !segment.closed {
+                        return Ok(Some((Vec::new(), position)));
+                    }
+                    match segment.next {
+                        Some(next) => position = next,
+                        None => return Ok(None),
+                    }
+                }
+                Some(segment) => break segment,
+            }
+        };
+
+        // Commands before `end` are flushed, and never rewritten in place.
+        let mut file = File::open(finder::log_path(&self.path, &segment.id))?;
+        file.seek(SeekFrom::Start(position.offset))?;
+        let mut reader = BufReader::new(file.take(segment.end - position.offset));
+        let mut commands = Vec::new();
+        let mut read = 0;
+        while position.offset < segment.end && read < max_bytes {
+            let command = Command::from_reader(&mut reader)?;
+            let size = command.to_bytes()?.len();
+            position.offset += size as u64;
+            read += size;
+            commands.push(command);
+        }
+
+        // The log may have been merged away, and its id reused, while reading it.
+        let writer = self.writer.rlock()?;
+        let chain = self.chain.rlock()?;
+        match chain.segment(&position, writer.offset as u64) {
+            Some(_) => Ok(Some((commands, position))),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn log_lag(&self, position: &LogPosition) -> Result<Option<u64>> {
+        let writer = self.writer.rlock()?;
+        let chain = self.chain.rlock()?;
+        Ok(chain.distance(position, writer.offset as u64))
+    }
 }
 
 impl KvsEngine for KvStore {
@@ -147,6 +288,9 @@ impl KvsEngine for KvStore {
 
         let command = Command::set(key.clone(), value);
         let location = writer.write(&command)?;
+        self.metrics
+            .bytes_written
+            .fetch_add(location.size as u64, Ordering::Relaxed);
         self.locations.data.insert(key, location);
 
         Ok(())
@@ -163,16 +307,55 @@ impl KvsEngine for KvStore {
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        if self.locations.data.remove(&key).is_none() {
-            return Err(KvError::KeyNotFound(key));
-        }
         self.rollover()?;
 
+        // Keys are only set and removed while holding the writer.
         let mut writer = self.writer.wlock()?;
-        writer.write(&Command::remove(key))?;
+        if !self.locations.data.contains_key(&key) {
+            return Err(KvError::KeyNotFound(key));
+        }
+        let location = writer.write(&Command::remove(key.clone()))?;
+        self.locations.data.remove(&key);
+        self.metrics
+            .bytes_written
+            .fetch_add(location.size as u64, Ordering::Relaxed);
 
         Ok(())
     }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .locations
+            .data
+            .iter()
+            .map(|e| e.key().clone())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let ids = finder::all_log_ids(&self.path)?;
+        let mut total_bytes = 0;
+        for id in &ids {
+            total_bytes += fs::metadata(finder::log_path(&self.path, id))?.len();
+        }
+        let live_bytes: u64 = self
+            .locations
+            .data
+            .iter()
+            .map(|location| location.size as u64)
+            .sum();
+
+        Ok(Stats {
+            bytes_written: self.metrics.bytes_written.load(Ordering::Relaxed),
+            live_bytes,
+            dead_bytes: total_bytes.saturating_sub(live_bytes),
+            log_files: ids.len() as u64,
+            merge: self.metrics.merge.snapshot(),
+            ..Stats::default()
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -193,13 +376,13 @@ where
         }
     }
 
-    fn rlock(&self) -> Result<RwLockReadGuard<T>> {
+    fn rlock(&self) -> Result<RwLockReadGuard<'_, T>> {
         self.inner
             .read()
             .map_err(|e| KvError::SharedRead(e.to_string()))
     }
 
-    fn wlock(&self) -> Result<RwLockWriteGuard<T>> {
+    fn wlock(&self) -> Result<RwLockWriteGuard<'_, T>> {
         self.inner
             .write()
             .map_err(|e| KvError::SharedWrite(e.to_string()))
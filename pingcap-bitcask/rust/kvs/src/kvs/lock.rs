@@ -0,0 +1,62 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::Path,
+    process,
+};
+
+use tracing::info;
+
+use crate::{KvError, Result};
+
+const LOCK_FILE: &str = "LOCK";
+
+/// Advisory lock on a database folder.
+///
+/// The lock is released once this is dropped, or when the owning process exits.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Take an exclusive lock on the folder, write the current pid to the lock file.
+    pub fn acquire<P: AsRef<Path>>(folder: P) -> Result<DirLock> {
+        let path = folder.as_ref().join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                let pid = match pid.trim() {
+                    "" => "unknown".to_string(),
+                    pid => pid.to_string(),
+                };
+                return Err(KvError::AlreadyOpen(pid));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", process::id())?;
+        file.flush()?;
+
+        info!(path = %path.display(), pid = process::id(), "acquired database lock:");
+
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use lru::LruCache;
+use tracing::info;
+
+use super::KvsEngine;
+use crate::{KvError, Result};
+
+/// Engine keeping every key in memory, nothing survives dropping it.
+///
+/// When bounded, the least recently used key is evicted to make room for a new one.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryKvsEngine {
+    data: Arc<Mutex<LruCache<String, String>>>,
+}
+
+impl MemoryKvsEngine {
+    /// Open an empty engine, holding at most `capacity` keys if provided.
+    pub fn open(capacity: Option<NonZeroUsize>) -> MemoryKvsEngine {
+        let data = match capacity {
+            Some(capacity) => LruCache::new(capacity),
+            None => LruCache::unbounded(),
+        };
+
+        info!(capacity = ?capacity, "opened memory database:");
+
+        MemoryKvsEngine {
+            data: Arc::new(Mutex::new(data)),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, LruCache<String, String>>> {
+        self.data
+            .lock()
+            .map_err(|e| KvError::SharedWrite(e.to_string()))
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock()?.put(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.lock()?.get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.lock()?.pop(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvError::KeyNotFound(key)),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self.lock()?.iter().map(|(key, _)| key.clone()).collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
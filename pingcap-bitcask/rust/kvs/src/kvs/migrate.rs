@@ -0,0 +1,233 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io::Write,
+    path::Path,
+};
+
+use tracing::info;
+
+use super::{lock::DirLock, EngineKind, KvsEngine, Store};
+use crate::{
+    log::vfs::{FaultyFs, FileSystem, StdFs},
+    KvError, Result,
+};
+
+const MIGRATE_FOLDER: &str = ".migrate";
+/// Written to the staging folder once it holds all the data, the swap is finished from then on.
+const SWAP_MARKER: &str = "SWAP";
+const TEMP_SWAP_MARKER: &str = "SWAP.tmp";
+
+/// Summary of the data copied by a migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// Number of live keys.
+    pub keys: usize,
+    /// Checksum over all key value pairs, in key order.
+    pub checksum: u64,
+}
+
+impl Migration {
+    /// Summarize all live keys of an engine.
+    fn of<E: KvsEngine>(engine: &E) -> Result<Migration> {
+        let mut hasher = DefaultHasher::new();
+        let keys = engine.keys()?;
+        for key in &keys {
+            key.hash(&mut hasher);
+            engine.get(key.clone())?.hash(&mut hasher);
+        }
+        Ok(Migration {
+            keys: keys.len(),
+            checksum: hasher.finish(),
+        })
+    }
+}
+
+/// Copy all live keys from `source` into `target`, one key at a time.
+///
+/// Both engines are summarized afterward, the migration fails if they do not hold the same data.
+///
+/// # Example
+/// ```rust
+/// # use kvs::{KvsEngine, Result, Store};
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<()> {
+/// # let kvs_directory = TempDir::new().expect("unable to create temporary working directory");
+/// # let sled_directory = TempDir::new().expect("unable to create temporary working directory");
+/// let source = Store::open_with_kvs(&kvs_directory)?;
+/// let target = Store::open_with_sled(&sled_directory)?;
+/// source.set("key1".to_string(), "value1".to_string())?;
+///
+/// let migration = kvs::migrate(&source, &target)?;
+/// assert_eq!(migration.keys, 1);
+/// assert_eq!(target.get("key1".to_string())?, Some("value1".to_string()));
+/// # Ok(())
+/// # }
+/// ```
+pub fn migrate<S, T>(source: &S, target: &T) -> Result<Migration>
+where
+    S: KvsEngine,
+    T: KvsEngine,
+{
+    for key in source.keys()? {
+        // The key might be removed concurrently, the verification below will catch it.
+        if let Some(value) = source.get(key.clone())? {
+            target.set(key, value)?;
+        }
+    }
+
+    let expected = Migration::of(source)?;
+    let actual = Migration::of(target)?;
+    if expected != actual {
+        return Err(KvError::Migration(format!(
+            "expected {} keys with checksum {:x}, found {} keys with checksum {:x}",
+            expected.keys, expected.checksum, actual.keys, actual.checksum
+        )));
+    }
+
+    Ok(actual)
+}
+
+impl Store {
+    /// Convert the database at `path` from one engine to another.
+    ///
+    /// The new engine is filled in a staging folder first, then swapped in with renames.
+    /// The old engine's data is removed only after the new data is in place.
+    /// The database folder stays locked meanwhile, a swap interrupted by a crash is finished
+    /// by the next open or migration.
+    pub fn migrate<P: AsRef<Path>>(path: P, from: EngineKind, to: EngineKind) -> Result<Migration> {
+        Store::migrate_with(&StdFs, path.as_ref(), from, to)
+    }
+
+    /// Like [`Store::migrate`], with the swap going through a file system which can crash.
+    #[doc(hidden)]
+    pub fn migrate_with_faulty_fs<P: AsRef<Path>>(
+        path: P,
+        from: EngineKind,
+        to: EngineKind,
+        fs: FaultyFs,
+    ) -> Result<Migration> {
+        Store::migrate_with(&fs, path.as_ref(), from, to)
+    }
+
+    fn migrate_with(
+        fs: &dyn FileSystem,
+        path: &Path,
+        from: EngineKind,
+        to: EngineKind,
+    ) -> Result<Migration> {
+        if from == to {
+            return Err(KvError::Migration(format!(
+                "database already uses {}",
+                from
+            )));
+        }
+
+        let (Some(source_path), Some(_)) = (from.dbpath(path), to.dbpath(path)) else {
+            return Err(KvError::Migration(
+                "only on-disk engines can be migrated".to_string(),
+            ));
+        };
+        if !path.is_dir() {
+            return Err(KvError::Migration(format!(
+                "no {} database at `{}`",
+                from,
+                path.display()
+            )));
+        }
+
+        let _lock = lock_folder(fs, path)?;
+        if !source_path.exists() {
+            return Err(KvError::Migration(format!(
+                "no {} database at `{}`",
+                from,
+                path.display()
+            )));
+        }
+
+        info!(from = %from, to = %to, path = %path.display(), "migrating database:");
+
+        let staging = path.join(MIGRATE_FOLDER);
+        let copied = Store::open_unlocked(from, path).and_then(|source| {
+            let target = Store::open_unlocked(to, &staging)?;
+            migrate(&source, &target)
+            // both stores are closed here
+        });
+        let migration = match copied {
+            Ok(migration) => migration,
+            Err(e) => {
+                // Without a marker, the staging folder would be removed by the next open anyway.
+                let _ = fs.remove_dir_all(&staging);
+                return Err(e);
+            }
+        };
+
+        // From here on, the swap is finished even if interrupted.
+        let temp_marker = staging.join(TEMP_SWAP_MARKER);
+        let mut marker = fs.create(&temp_marker)?;
+        marker.write_all(format!("{} {}", from, to).as_bytes())?;
+        marker.sync()?;
+        drop(marker);
+        fs.rename(&temp_marker, &staging.join(SWAP_MARKER))?;
+        swap(fs, path, from, to)?;
+
+        info!(keys = migration.keys, checksum = %format!("{:x}", migration.checksum), "migrated database:");
+
+        Ok(migration)
+    }
+}
+
+/// Lock the database folder, after finishing or undoing a migration interrupted by a crash.
+///
+/// Held while a store is opened or migrated, so that no store is opened in the middle of a swap.
+pub(super) fn lock_folder(fs: &dyn FileSystem, path: &Path) -> Result<DirLock> {
+    fs::create_dir_all(path)?;
+    let lock = DirLock::acquire(path)?;
+
+    let staging = path.join(MIGRATE_FOLDER);
+    let marker = staging.join(SWAP_MARKER);
+    if marker.exists() {
+        let content = fs::read_to_string(&marker)?;
+        let (from, to) = match content.split_once(' ') {
+            Some((from, to)) => (engine(from)?, engine(to)?),
+            None => return Err(KvError::Migration(format!("invalid marker `{}`", content))),
+        };
+        info!(from = %from, to = %to, path = %path.display(), "finishing interrupted migration:");
+        swap(fs, path, from, to)?;
+    } else if staging.exists() {
+        // The copy did not finish, the old engine is still in place.
+        info!(path = %staging.display(), "removing unfinished migration:");
+        fs.remove_dir_all(&staging)?;
+    }
+
+    Ok(lock)
+}
+
+/// Move the old engine's data to the staging folder, the new engine's data out of it,
+/// then remove the staging folder. Every step is skipped if done already.
+fn swap(fs: &dyn FileSystem, path: &Path, from: EngineKind, to: EngineKind) -> Result<()> {
+    let staging = path.join(MIGRATE_FOLDER);
+    let dbpath = |engine: EngineKind, path: &Path| engine.dbpath(path).expect("on-disk engine");
+
+    // Keep the old data next to the staged one until the new data is in place.
+    if dbpath(from, path).exists() {
+        fs.rename(&dbpath(from, path), &dbpath(from, &staging))?;
+    }
+    if dbpath(to, &staging).exists() {
+        fs.rename(&dbpath(to, &staging), &dbpath(to, path))?;
+    }
+    fs.remove_dir_all(&dbpath(from, &staging))?;
+    // The marker goes last, a staging folder without it only holds leftovers.
+    fs.remove_file(&staging.join(SWAP_MARKER))?;
+    fs.remove_dir_all(&staging)?;
+    Ok(())
+}
+
+fn engine(name: &str) -> Result<EngineKind> {
+    match name.trim() {
+        "kvs" => Ok(EngineKind::Kvs),
+        "sled" => Ok(EngineKind::Sled),
+        name => Err(KvError::Migration(format!("unknown engine `{}`", name))),
+    }
+}
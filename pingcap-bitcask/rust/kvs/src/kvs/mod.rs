@@ -1,8 +1,21 @@
+mod async_engine;
 mod engine;
+mod lock;
+mod memory;
+mod migrate;
+mod replication;
 mod sled;
 mod store;
+mod watch;
 
+pub mod admin;
 pub mod kv;
 
+pub(crate) use lock::DirLock;
+
+pub use async_engine::AsyncKvsEngine;
 pub use engine::KvsEngine;
-pub use store::Store;
+pub use migrate::{migrate, Migration};
+pub use replication::{LogPosition, ReplicationLog};
+pub use store::{EngineKind, Store};
+pub use watch::{KeyChange, Subscription};
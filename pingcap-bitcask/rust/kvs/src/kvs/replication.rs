@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{current_timestamp, Command},
+    log::LogId,
+    Result,
+};
+
+use super::kv::KvStore;
+
+/// Where a replica stands in the log of its primary.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// Identifies one opening of the primary store, positions of an earlier one are stale.
+    pub run: u64,
+    /// Writer log, counted from the opening of the store.
+    pub generation: u64,
+    /// Id of the writer log.
+    pub log_id: u64,
+    /// Bytes of the writer log already read.
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Generation {
+    id: LogId,
+    /// Bytes written to earlier generations.
+    start: u64,
+    /// Length of the log once it stopped being the writer.
+    len: Option<u64>,
+}
+
+/// Writer logs created since the store was opened, in order.
+///
+/// Log ids are reused once merged, generations tell apart logs sharing an id.
+#[derive(Debug)]
+pub(crate) struct LogChain {
+    run: u64,
+    generations: BTreeMap<u64, Generation>,
+}
+
+/// Part of a writer log a position can read.
+#[derive(Debug)]
+pub(crate) struct Segment {
+    pub id: LogId,
+    /// Offset of the last written byte in the log.
+    pub end: u64,
+    /// Whether the log stopped being the writer, it will not grow anymore.
+    pub closed: bool,
+    /// Start of the following log, if it is still in the chain.
+    pub next: Option<LogPosition>,
+}
+
+impl LogChain {
+    pub fn new(id: LogId) -> LogChain {
+        let mut generations = BTreeMap::new();
+        generations.insert(
+            0,
+            Generation {
+                id,
+                start: 0,
+                len: None,
+            },
+        );
+        LogChain {
+            run: current_timestamp().as_nanos() as u64,
+            generations,
+        }
+    }
+
+    fn current(&self) -> (u64, &Generation) {
+        let (generation, log) = self
+            .generations
+            .iter()
+            .next_back()
+            .expect("the writer log is always in the chain");
+        (*generation, log)
+    }
+
+    /// Close the current writer log at `len` bytes, and continue in log `id`.
+    pub fn roll(&mut self, len: u64, id: LogId) {
+        let (generation, current) = self.current();
+        let start = current.start + len;
+        let generation = generation + 1;
+        if let Some(current) = self.generations.get_mut(&(generation - 1)) {
+            current.len = Some(len);
+        }
+        self.generations.insert(
+            generation,
+            Generation {
+                id,
+                start,
+                len: None,
+            },
+        );
+    }
+
+    /// Forget merged logs, they cannot be read anymore.
+    pub fn forget(&mut self, ids: &[LogId]) {
+        self.generations
+            .retain(|_, log| log.len.is_none() || !ids.contains(&log.id));
+    }
+
+    /// Position right after `offset` bytes of the writer log.
+    pub fn position(&self, offset: u64) -> LogPosition {
+        let (generation, current) = self.current();
+        LogPosition {
+            run: self.run,
+            generation,
+            log_id: current.id.0,
+            offset,
+        }
+    }
+
+    fn find(&self, position: &LogPosition) -> Option<&Generation> {
+        if position.run != self.run {
+            return None;
+        }
+        self.generations
+            .get(&position.generation)
+            .filter(|log| log.id.0 == position.log_id)
+    }
+
+    /// The log `position` points into, `None` when it is not in the chain.
+    pub fn segment(&self, position: &LogPosition, writer_offset: u64) -> Option<Segment> {
+        let log = self.find(position)?;
+        let segment = match log.len {
+            Some(len) => {
+                let generation = position.generation + 1;
+                Segment {
+                    id: log.id,
+                    end: len,
+                    closed: true,
+                    next: self.generations.get(&generation).map(|next| LogPosition {
+                        run: self.run,
+                        generation,
+                        log_id: next.id.0,
+                        offset: 0,
+                    }),
+                }
+            }
+            None => Segment {
+                id: log.id,
+                end: writer_offset,
+                closed: false,
+                next: None,
+            },
+        };
+        Some(segment).filter(|segment| position.offset <= segment.end)
+    }
+
+    /// Bytes written after `position`, `None` when it is not in the chain.
+    pub fn distance(&self, position: &LogPosition, writer_offset: u64) -> Option<u64> {
+        let log = self.find(position)?;
+        let (_, current) = self.current();
+        (current.start + writer_offset).checked_sub(log.start + position.offset)
+    }
+}
+
+/// Log of the writes to a store, which replicas stream from.
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub struct ReplicationLog {
+    store: KvStore,
+}
+
+impl ReplicationLog {
+    pub(crate) fn new(store: KvStore) -> ReplicationLog {
+        ReplicationLog { store }
+    }
+
+    /// Position right after the last write.
+    pub(crate) fn position(&self) -> Result<LogPosition> {
+        self.store.log_position()
+    }
+
+    /// Commands written after `position`, about `max_bytes` of them at most,
+    /// and the position after them.
+    /// `None` when `position` is not in the log anymore, a snapshot is needed.
+    pub(crate) fn read(
+        &self,
+        position: &LogPosition,
+        max_bytes: usize,
+    ) -> Result<Option<(Vec<Command>, LogPosition)>> {
+        self.store.read_log(position, max_bytes)
+    }
+
+    /// Bytes written after `position`, `None` when it is not in the log anymore.
+    pub(crate) fn lag(&self, position: &LogPosition) -> Result<Option<u64>> {
+        self.store.log_lag(position)
+    }
+}
@@ -54,4 +54,12 @@ impl KvsEngine for SledKvsEngine {
         self.db.flush()?;
         Ok(())
     }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for key in self.db.iter().keys() {
+            keys.push(String::from_utf8_lossy(&key?).into_owned());
+        }
+        Ok(keys)
+    }
 }
@@ -1,19 +1,92 @@
-use crate::{KvError, Result};
-use std::path::Path;
+use crate::{
+    log::vfs::StdFs,
+    stats::{AtomicHistogram, Stats},
+    KvError, KvOption, Result,
+};
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
-use super::{kv::KvStore, sled::SledKvsEngine, KvsEngine};
+use super::{
+    kv::KvStore,
+    memory::MemoryKvsEngine,
+    migrate,
+    sled::SledKvsEngine,
+    watch::{Subscription, Watchers},
+    KvsEngine, ReplicationLog,
+};
 
 /// General store engine.
 #[derive(Debug, Clone)]
-pub struct Store(StoreInner);
+pub struct Store {
+    inner: StoreInner,
+    metrics: Arc<StoreMetrics>,
+    watchers: Arc<Watchers>,
+}
 
 #[derive(Debug, Clone)]
 enum StoreInner {
     Kvs(KvStore),
     Sled(SledKvsEngine),
+    Memory(MemoryKvsEngine),
+}
+
+/// Latency of every operation, regardless of the engine.
+#[derive(Debug, Default)]
+struct StoreMetrics {
+    get: AtomicHistogram,
+    set: AtomicHistogram,
+    remove: AtomicHistogram,
+}
+
+/// Internal engines which can back a [`Store`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    /// The bitcask engine.
+    Kvs,
+    /// The sled engine.
+    Sled,
+    /// The in-memory engine, without a capacity bound.
+    Memory,
+}
+
+impl EngineKind {
+    /// Path to the engine's data folder inside the database directory,
+    /// `None` for engines which keep nothing on disk.
+    pub fn dbpath<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        match self {
+            EngineKind::Kvs => Some(KvStore::dbpath(path)),
+            EngineKind::Sled => Some(SledKvsEngine::dbpath(path)),
+            EngineKind::Memory => None,
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => f.write_str("kvs"),
+            EngineKind::Sled => f.write_str("sled"),
+            EngineKind::Memory => f.write_str("memory"),
+        }
+    }
 }
 
 impl Store {
+    /// Open database with the provided engine.
+    /// The path is ignored by the in-memory engine.
+    pub fn open_with<P: AsRef<Path>>(engine: EngineKind, path: P) -> Result<Store> {
+        match engine {
+            EngineKind::Kvs => Store::open_with_kvs(path),
+            EngineKind::Sled => Store::open_with_sled(path),
+            EngineKind::Memory => Ok(Store::open_in_memory()),
+        }
+    }
+
     /// Open database with kvs as internal engine.
     /// This function calls [`Store::open_with_kvs`] internally.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Store> {
@@ -22,20 +95,154 @@ impl Store {
 
     /// Open database with kvs as internal engine.
     pub fn open_with_kvs<P: AsRef<Path>>(path: P) -> Result<Store> {
+        Store::open_with_options(path, KvOption::default())
+    }
+
+    /// Open database with kvs as internal engine, configured by `options`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvs::{KvOption, KvsEngine, Result, Store};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let directory = TempDir::new().expect("unable to create temporary working directory");
+    /// let mut options = KvOption::new();
+    /// options.writer_size(64 * 1024).num_log_readers(4);
+    /// let store = Store::open_with_options(&directory, options)?;
+    ///
+    /// store.set("key1".to_string(), "value1".to_string())?;
+    /// assert_eq!(store.get("key1".to_string())?, Some("value1".to_string()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: KvOption) -> Result<Store> {
+        let _lock = migrate::lock_folder(&*options.fs, path.as_ref())?;
+        Store::open_kvs(path, options)
+    }
+
+    /// Open database with sled as internal engine.
+    pub fn open_with_sled<P: AsRef<Path>>(path: P) -> Result<Store> {
+        let _lock = migrate::lock_folder(&StdFs, path.as_ref())?;
+        Store::open_sled(path)
+    }
+
+    /// Open an on-disk engine without locking the database folder, which the caller holds.
+    pub(super) fn open_unlocked<P: AsRef<Path>>(engine: EngineKind, path: P) -> Result<Store> {
+        match engine {
+            EngineKind::Kvs => Store::open_kvs(path, KvOption::default()),
+            EngineKind::Sled => Store::open_sled(path),
+            EngineKind::Memory => Ok(Store::open_in_memory()),
+        }
+    }
+
+    fn open_kvs<P: AsRef<Path>>(path: P, options: KvOption) -> Result<Store> {
         if SledKvsEngine::dbpath(&path).exists() {
             return Err(KvError::MismatchEngine);
         }
-        let inner = KvStore::open(&path)?;
-        Ok(Store(StoreInner::Kvs(inner)))
+        let inner = KvStore::open_with_options(KvStore::dbpath(&path), options)?;
+        Ok(Store::new(StoreInner::Kvs(inner)))
     }
 
-    /// Open database with sled as internal engine.
-    pub fn open_with_sled<P: AsRef<Path>>(path: P) -> Result<Store> {
+    fn open_sled<P: AsRef<Path>>(path: P) -> Result<Store> {
         if KvStore::dbpath(&path).exists() {
             return Err(KvError::MismatchEngine);
         }
         let inner = SledKvsEngine::open(&path)?;
-        Ok(Store(StoreInner::Sled(inner)))
+        Ok(Store::new(StoreInner::Sled(inner)))
+    }
+
+    /// Open an empty database kept in memory, nothing is written to disk.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvs::{KvsEngine, Result, Store};
+    /// # fn main() -> Result<()> {
+    /// let store = Store::open_in_memory();
+    ///
+    /// store.set("key1".to_string(), "value1".to_string())?;
+    /// assert_eq!(store.get("key1".to_string())?, Some("value1".to_string()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_in_memory() -> Store {
+        Store::new(StoreInner::Memory(MemoryKvsEngine::open(None)))
+    }
+
+    /// Open an empty database kept in memory, holding at most `capacity` keys.
+    /// Setting a new key on a full store evicts the least recently used one.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::num::NonZeroUsize;
+    /// # use kvs::{KvsEngine, Result, Store};
+    /// # fn main() -> Result<()> {
+    /// let store = Store::open_in_memory_with_capacity(NonZeroUsize::new(2).unwrap());
+    ///
+    /// store.set("key1".to_string(), "value1".to_string())?;
+    /// store.set("key2".to_string(), "value2".to_string())?;
+    /// store.get("key1".to_string())?;
+    /// store.set("key3".to_string(), "value3".to_string())?;
+    /// assert_eq!(store.keys()?, vec!["key1".to_string(), "key3".to_string()]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_in_memory_with_capacity(capacity: NonZeroUsize) -> Store {
+        Store::new(StoreInner::Memory(MemoryKvsEngine::open(Some(capacity))))
+    }
+
+    /// Get every change made by `set` and `remove` to the keys starting with `prefix`
+    /// from now on. The changes to a key come in the order they were made, concurrent
+    /// writes to different keys may come in either order.
+    ///
+    /// At most `capacity` changes wait to be received. Once another one is made, the
+    /// subscription is dropped: receiving fails with [`KvError::Overflowed`] after the
+    /// changes already waiting. Keys evicted by a full in-memory store are not reported.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvs::{KeyChange, KvsEngine, Result, Store};
+    /// # fn main() -> Result<()> {
+    /// let store = Store::open_in_memory();
+    /// let subscription = store.subscribe("config.".to_owned(), 16);
+    ///
+    /// store.set("config.port".to_owned(), "4000".to_owned())?;
+    /// store.set("other".to_owned(), "value".to_owned())?;
+    /// store.remove("config.port".to_owned())?;
+    ///
+    /// let change = subscription.recv()?;
+    /// assert_eq!(change.key, "config.port");
+    /// assert_eq!(change.value, Some("4000".to_owned()));
+    /// assert_eq!(subscription.recv()?.value, None);
+    /// assert_eq!(subscription.try_recv()?, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn subscribe(&self, prefix: String, capacity: usize) -> Subscription {
+        self.watchers.subscribe(prefix, capacity)
+    }
+
+    fn new(inner: StoreInner) -> Store {
+        Store {
+            inner,
+            metrics: Arc::new(StoreMetrics::default()),
+            watchers: Arc::new(Watchers::default()),
+        }
+    }
+
+    fn set_inner(&self, key: String, value: String) -> Result<()> {
+        match &self.inner {
+            StoreInner::Kvs(store) => store.set(key, value),
+            StoreInner::Sled(store) => store.set(key, value),
+            StoreInner::Memory(store) => store.set(key, value),
+        }
+    }
+
+    fn remove_inner(&self, key: String) -> Result<()> {
+        match &self.inner {
+            StoreInner::Kvs(store) => store.remove(key),
+            StoreInner::Sled(store) => store.remove(key),
+            StoreInner::Memory(store) => store.remove(key),
+        }
     }
 }
 
@@ -76,10 +283,19 @@ impl KvsEngine for Store {
     /// # }
     /// ```
     fn set(&self, key: String, value: String) -> Result<()> {
-        match &self.0 {
-            StoreInner::Kvs(store) => store.set(key, value),
-            StoreInner::Sled(store) => store.set(key, value),
-        }
+        let start = Instant::now();
+        let result = match self.watchers.lock_writes(&key) {
+            Some(watchers) => {
+                let result = self.set_inner(key.clone(), value.clone());
+                if result.is_ok() {
+                    watchers.notify(key, Some(value));
+                }
+                result
+            }
+            None => self.set_inner(key, value),
+        };
+        self.metrics.set.record(start.elapsed());
+        result
     }
 
     /// Get value of a key from the store.
@@ -122,10 +338,14 @@ impl KvsEngine for Store {
     /// # }
     /// ```
     fn get(&self, key: String) -> Result<Option<String>> {
-        match &self.0 {
+        let start = Instant::now();
+        let result = match &self.inner {
             StoreInner::Kvs(store) => store.get(key),
             StoreInner::Sled(store) => store.get(key),
-        }
+            StoreInner::Memory(store) => store.get(key),
+        };
+        self.metrics.get.record(start.elapsed());
+        result
     }
 
     /// Remove a key from the store.
@@ -171,9 +391,97 @@ impl KvsEngine for Store {
     /// # }
     /// ```
     fn remove(&self, key: String) -> Result<()> {
-        match &self.0 {
-            StoreInner::Kvs(store) => store.remove(key),
-            StoreInner::Sled(store) => store.remove(key),
+        let start = Instant::now();
+        let result = match self.watchers.lock_writes(&key) {
+            Some(watchers) => {
+                let result = self.remove_inner(key.clone());
+                if result.is_ok() {
+                    watchers.notify(key, None);
+                }
+                result
+            }
+            None => self.remove_inner(key),
+        };
+        self.metrics.remove.record(start.elapsed());
+        result
+    }
+
+    /// Get all live keys from the store, in ascending order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsEngine;
+    /// # use kvs::Store;
+    /// # use kvs::Result;
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let directory = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = Store::open(&directory)?;
+    ///
+    /// store.set("key2".to_string(), "value2".to_string())?;
+    /// store.set("key1".to_string(), "value1".to_string())?;
+    /// assert_eq!(store.keys()?, vec!["key1".to_string(), "key2".to_string()]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn keys(&self) -> Result<Vec<String>> {
+        match &self.inner {
+            StoreInner::Kvs(store) => store.keys(),
+            StoreInner::Sled(store) => store.keys(),
+            StoreInner::Memory(store) => store.keys(),
+        }
+    }
+
+    /// Get counters and histograms describing the store.
+    ///
+    /// Operation latencies are tracked for every engine,
+    /// disk usage and merges are only tracked by the kvs engine.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsEngine;
+    /// # use kvs::Store;
+    /// # use kvs::Result;
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let directory = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = Store::open(&directory)?;
+    ///
+    /// store.set("key1".to_string(), "value1".to_string())?;
+    /// store.get("key1".to_string())?;
+    ///
+    /// let stats = store.stats()?;
+    /// assert_eq!(stats.set.count, 1);
+    /// assert_eq!(stats.get.count, 1);
+    /// assert!(stats.live_bytes > 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn stats(&self) -> Result<Stats> {
+        let stats = match &self.inner {
+            StoreInner::Kvs(store) => store.stats()?,
+            StoreInner::Sled(store) => store.stats()?,
+            StoreInner::Memory(store) => store.stats()?,
+        };
+        Ok(Stats {
+            get: self.metrics.get.snapshot(),
+            set: self.metrics.set.snapshot(),
+            remove: self.metrics.remove.snapshot(),
+            ..stats
+        })
+    }
+
+    fn subscribe(&self, prefix: String, capacity: usize) -> Option<Subscription> {
+        Some(Store::subscribe(self, prefix, capacity))
+    }
+
+    /// Only the kvs engine keeps a log replicas can read.
+    fn replication_log(&self) -> Option<ReplicationLog> {
+        match &self.inner {
+            StoreInner::Kvs(store) => Some(ReplicationLog::new(store.clone())),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,162 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use crate::{KvError, Result};
+
+/// A write to a key, see [`Store::subscribe`][crate::Store::subscribe].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    /// Key written.
+    pub key: String,
+    /// New value of the key, `None` once it is removed.
+    pub value: Option<String>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    prefix: String,
+    sender: SyncSender<KeyChange>,
+    /// Shared with the [`Subscription`], set once a change did not fit its buffer.
+    overflowed: Arc<AtomicBool>,
+}
+
+/// Locks ordering the writes to the keys hashed to each, see [`Watchers::lock_writes`].
+const STRIPES: usize = 32;
+
+/// Subscribers to the writes of a store.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Number of subscribers, writes do not wait for the lock while there are none.
+    count: AtomicUsize,
+    stripes: [Mutex<()>; STRIPES],
+}
+
+impl Watchers {
+    pub fn subscribe(&self, prefix: String, capacity: usize) -> Subscription {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let mut subscribers = self.lock();
+        subscribers.push(Subscriber {
+            prefix,
+            sender,
+            overflowed: overflowed.clone(),
+        });
+        self.count.store(subscribers.len(), Ordering::SeqCst);
+        Subscription {
+            receiver,
+            overflowed,
+            capacity,
+        }
+    }
+
+    /// Hold back other writes to `key` until the change is notified, so subscribers get
+    /// the changes to a key in the order they were made. Writes to keys of other stripes
+    /// go on, the subscribers are only locked to notify. `None` while nobody subscribes.
+    pub fn lock_writes(&self, key: &str) -> Option<WriteGuard<'_>> {
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = &self.stripes[hasher.finish() as usize % STRIPES];
+        Some(WriteGuard {
+            watchers: self,
+            _stripe: stripe.lock().unwrap_or_else(|e| e.into_inner()),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Writes to a key held back while it is changed, see [`Watchers::lock_writes`].
+pub(crate) struct WriteGuard<'a> {
+    watchers: &'a Watchers,
+    _stripe: MutexGuard<'a, ()>,
+}
+
+impl WriteGuard<'_> {
+    /// Send the change to the subscribers of its key.
+    ///
+    /// A subscriber whose buffer is full is dropped, it gets
+    /// [`KvError::Overflowed`] after the changes already buffered.
+    pub fn notify(self, key: String, value: Option<String>) {
+        let change = KeyChange { key, value };
+        let mut subscribers = self.watchers.lock();
+        subscribers.retain(|subscriber| {
+            // The subscription was dropped.
+            if Arc::strong_count(&subscriber.overflowed) == 1 {
+                return false;
+            }
+            if !change.key.starts_with(&subscriber.prefix) {
+                return true;
+            }
+            match subscriber.sender.try_send(change.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.overflowed.store(true, Ordering::SeqCst);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        self.watchers
+            .count
+            .store(subscribers.len(), Ordering::SeqCst);
+    }
+}
+
+/// Writes to the keys starting with a prefix, see [`Store::subscribe`][crate::Store::subscribe].
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: Receiver<KeyChange>,
+    overflowed: Arc<AtomicBool>,
+    capacity: usize,
+}
+
+impl Subscription {
+    /// Wait for the next change.
+    pub fn recv(&self) -> Result<KeyChange> {
+        self.receiver.recv().map_err(|_| self.closed())
+    }
+
+    /// Wait at most `timeout` for the next change, `None` if none was made.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<KeyChange>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(change) => Ok(Some(change)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(self.closed()),
+        }
+    }
+
+    /// The next change if one is buffered, without waiting.
+    pub fn try_recv(&self) -> Result<Option<KeyChange>> {
+        match self.receiver.try_recv() {
+            Ok(change) => Ok(Some(change)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(self.closed()),
+        }
+    }
+
+    fn closed(&self) -> KvError {
+        if self.overflowed.load(Ordering::SeqCst) {
+            KvError::Overflowed(format!(
+                "more than {} changes were not received",
+                self.capacity
+            ))
+        } else {
+            io::Error::new(io::ErrorKind::BrokenPipe, "the store was closed").into()
+        }
+    }
+}
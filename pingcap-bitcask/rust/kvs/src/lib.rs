@@ -10,22 +10,40 @@ mod merger;
 mod net;
 mod options;
 mod parser;
+mod stats;
 
+pub mod raft;
 pub mod thread_pool;
 
-pub use kvs::Store;
+pub use kvs::admin;
+
 #[doc(hidden)]
 pub use kvs::Store as KvStore;
+pub use kvs::{migrate, EngineKind, KeyChange, Migration, Store, Subscription};
+#[doc(hidden)]
+pub use kvs::{LogPosition, ReplicationLog};
+
+pub use kvs::{AsyncKvsEngine, KvsEngine};
 
-pub use kvs::KvsEngine;
+pub use options::{ClientOption, KvOption, PoolOption, ThreadPoolOption};
 
-pub use options::KvOption;
+#[doc(hidden)]
+pub use log::vfs::FaultyFs;
+
+pub use stats::{Histogram, ReplicaStats, Stats, ThreadPoolStats};
 
 #[doc(hidden)]
 pub use error::{KvError, Result};
 
-pub use net::client::KvsClient;
-pub use net::server::KvsServer;
+pub use net::async_client::AsyncKvsClient;
+pub use net::async_server::AsyncKvsServer;
+pub use net::client::{KvsClient, Pipeline};
+pub use net::pool::{KvsClientPool, PooledClient};
+pub use net::server::{KvsServer, Protocol, RunningServer};
+pub use net::sharded::ShardedKvsClient;
+pub use net::watch::Watcher;
+
+pub use net::protocol::{ErrorCode, Feature, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
 #[doc(hidden)]
 pub use net::protocol::{KvsRequest, KvsResponse};
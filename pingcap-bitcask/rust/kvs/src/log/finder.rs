@@ -1,14 +1,17 @@
 use std::{
-    fs,
+    io,
     path::{Path, PathBuf},
 };
 
 use crate::Result;
 
-use super::LogId;
+use super::{vfs::Fs, LogId};
 
 const LOG_PREFIX: &str = "KVLOG";
 const LOG_EXT: &str = "wal";
+const MANIFEST_PREFIX: &str = "MERGE";
+const MANIFEST_EXT: &str = "manifest";
+const TEMP_EXT: &str = "tmp";
 
 /// Path for log reading.
 pub(crate) fn log_path<P: AsRef<Path>>(folder: P, id: &LogId) -> PathBuf {
@@ -17,28 +20,59 @@ pub(crate) fn log_path<P: AsRef<Path>>(folder: P, id: &LogId) -> PathBuf {
         .join(format!("{}_{:0>10}.{}", LOG_PREFIX, id.0, LOG_EXT))
 }
 
-/// Iterate over all existing log ids in the folder, attempt to create a new log reader file,
-/// if it succeeds, then the log file should be usable.
-pub(crate) fn next_log_id<P: AsRef<Path>>(folder: P) -> LogId {
-    let mut id = LogId(0);
+/// Attempt to create a new log file after all existing logs and manifests in the folder,
+/// if it succeeds, then the log id is reserved.
+///
+/// Ids are never reused: a manifest left by a merge which failed to remove its logs
+/// still names them, they are removed again once the store is reopened.
+pub(crate) fn next_log_id<P: AsRef<Path>>(fs: &Fs, folder: P) -> Result<LogId> {
+    let used = all_log_ids(&folder)?
+        .into_iter()
+        .chain(all_manifest_ids(&folder)?)
+        .chain(all_temp_manifest_ids(&folder)?);
+    let mut id = LogId(used.map(|id| id.0 + 1).max().unwrap_or(0));
     loop {
         let path = log_path(&folder, &id);
-        if fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path)
-            .is_ok()
-        {
-            break;
+        match fs.create_new(&path) {
+            Ok(()) => return Ok(id),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => id.0 += 1,
+            Err(e) => return Err(e.into()),
         }
-        id.0 += 1;
     }
-    id
+}
+
+/// Path of the manifest recording which logs were merged into log `id`.
+pub(crate) fn manifest_path<P: AsRef<Path>>(folder: P, id: &LogId) -> PathBuf {
+    folder.as_ref().join(format!(
+        "{}_{:0>10}.{}",
+        MANIFEST_PREFIX, id.0, MANIFEST_EXT
+    ))
+}
+
+/// Path a manifest is written to before being renamed into place.
+pub(crate) fn temp_manifest_path<P: AsRef<Path>>(folder: P, id: &LogId) -> PathBuf {
+    folder
+        .as_ref()
+        .join(format!("{}_{:0>10}.{}", MANIFEST_PREFIX, id.0, TEMP_EXT))
+}
+
+/// Get the ids of all merged logs which have a manifest.
+pub(crate) fn all_manifest_ids<P: AsRef<Path>>(folder: P) -> Result<Vec<LogId>> {
+    ids_with(folder, MANIFEST_PREFIX, MANIFEST_EXT)
+}
+
+/// Get the ids of all manifests which were not renamed into place.
+pub(crate) fn all_temp_manifest_ids<P: AsRef<Path>>(folder: P) -> Result<Vec<LogId>> {
+    ids_with(folder, MANIFEST_PREFIX, TEMP_EXT)
 }
 
 /// Get all logs ids.
 pub(crate) fn all_log_ids<P: AsRef<Path>>(folder: P) -> Result<Vec<LogId>> {
-    let pattern = format!("{}/{}_*.{}", folder.as_ref().display(), LOG_PREFIX, LOG_EXT);
+    ids_with(folder, LOG_PREFIX, LOG_EXT)
+}
+
+fn ids_with<P: AsRef<Path>>(folder: P, prefix: &str, ext: &str) -> Result<Vec<LogId>> {
+    let pattern = format!("{}/{}_*.{}", folder.as_ref().display(), prefix, ext);
 
     let path_to_file_stem = |path: PathBuf| -> Option<String> {
         path.file_stem()
@@ -8,6 +8,7 @@ use crate::{
 
 pub(crate) mod finder;
 mod reader;
+pub(crate) mod vfs;
 mod writer;
 
 pub(crate) use reader::LogReader;
@@ -52,6 +53,11 @@ where
             offset: 0,
         }
     }
+
+    /// Offset right after the last command read.
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
 }
 
 impl<R> Iterator for IntoCommands<R>
@@ -67,6 +73,7 @@ where
         let location = CommandLocation {
             id: self.id,
             offset: self.offset,
+            size: bytes.len(),
             timestamp: command.timestamp(),
         };
 
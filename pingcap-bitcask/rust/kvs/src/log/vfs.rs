@@ -0,0 +1,255 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Mutating file system operations used by the log layer.
+///
+/// Reads go straight to [`std::fs`], only operations which can leave the disk half-done go
+/// through here.
+pub(crate) trait FileSystem: fmt::Debug + Send + Sync {
+    /// Create an empty file, fail if it exists.
+    fn create_new(&self, path: &Path) -> io::Result<()>;
+
+    /// Create or truncate a file for writing.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>>;
+
+    /// Open a file for appending, creating it if needed.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn LogFile>>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a folder and everything inside it, if it exists.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+}
+
+/// A file opened for writing.
+pub(crate) trait LogFile: Write + Seek + fmt::Debug + Send + Sync {
+    /// Flush file content to disk.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// Shared handle to a file system.
+pub(crate) type Fs = Arc<dyn FileSystem>;
+
+/// The real file system.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StdFs;
+
+impl FileSystem for StdFs {
+    fn create_new(&self, path: &Path) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map(|_| ())
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        match fs::remove_dir_all(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl LogFile for File {
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    /// Number of operations seen so far.
+    operations: u64,
+    /// Operation which crashes the file system.
+    crash_at: Option<u64>,
+    crashed: bool,
+    /// Operation which fails alone.
+    fail_at: Option<u64>,
+}
+
+enum Step {
+    Run,
+    Crash,
+    Fail,
+}
+
+impl FaultState {
+    fn step(&mut self) -> io::Result<Step> {
+        if self.crashed {
+            return Err(crashed());
+        }
+        let operation = self.operations;
+        self.operations += 1;
+        if self.crash_at == Some(operation) {
+            self.crashed = true;
+            Ok(Step::Crash)
+        } else if self.fail_at == Some(operation) {
+            Ok(Step::Fail)
+        } else {
+            Ok(Step::Run)
+        }
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("injected crash")
+}
+
+fn failed() -> io::Error {
+    io::Error::other("injected failure")
+}
+
+/// A file system which crashes, or fails once, at a chosen operation.
+///
+/// Every call to [`FileSystem`] and every write and sync of its files counts as one operation.
+/// The crashing operation is cut short: a write only stores the first half of its buffer,
+/// other operations do nothing. Every operation after the crash fails.
+/// A failing operation does nothing, and the following ones run as usual.
+///
+/// Data is durable as soon as it is written, losing unsynced data is not simulated.
+#[doc(hidden)]
+#[derive(Debug, Clone, Default)]
+pub struct FaultyFs {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultyFs {
+    /// A file system which never crashes until told to.
+    pub fn new() -> FaultyFs {
+        FaultyFs::default()
+    }
+
+    /// Crash on the `n`th operation from now, starting at zero.
+    pub fn crash_after(&self, n: u64) {
+        let mut state = self.lock();
+        state.crash_at = Some(state.operations + n);
+    }
+
+    /// Fail the `n`th operation from now, starting at zero, and only that one.
+    pub fn fail_after(&self, n: u64) {
+        let mut state = self.lock();
+        state.fail_at = Some(state.operations + n);
+    }
+
+    /// Number of operations seen so far.
+    pub fn operations(&self) -> u64 {
+        self.lock().operations
+    }
+
+    /// Whether the file system has crashed.
+    pub fn crashed(&self) -> bool {
+        self.lock().crashed
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FaultState> {
+        // A test panicking while holding the lock must not hide the state from others.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count one operation, and run it unless it crashes or fails.
+    fn run<T>(&self, operation: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        match self.lock().step()? {
+            Step::Run => operation(),
+            Step::Crash => Err(crashed()),
+            Step::Fail => Err(failed()),
+        }
+    }
+
+    fn wrap(&self, file: Box<dyn LogFile>) -> Box<dyn LogFile> {
+        Box::new(FaultyFile {
+            fs: self.clone(),
+            file,
+        })
+    }
+}
+
+impl FileSystem for FaultyFs {
+    fn create_new(&self, path: &Path) -> io::Result<()> {
+        self.run(|| StdFs.create_new(path))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        self.run(|| StdFs.create(path)).map(|file| self.wrap(file))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        self.run(|| StdFs.open_append(path))
+            .map(|file| self.wrap(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.run(|| StdFs.rename(from, to))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.run(|| StdFs.remove_file(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.run(|| StdFs.remove_dir_all(path))
+    }
+}
+
+#[derive(Debug)]
+struct FaultyFile {
+    fs: FaultyFs,
+    file: Box<dyn LogFile>,
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let step = self.fs.lock().step()?;
+        match step {
+            Step::Run => self.file.write(buf),
+            Step::Crash => {
+                self.file.write_all(&buf[..buf.len() / 2])?;
+                Err(crashed())
+            }
+            Step::Fail => Err(failed()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.fs.crashed() {
+            return Err(crashed());
+        }
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl LogFile for FaultyFile {
+    fn sync(&self) -> io::Result<()> {
+        self.fs.run(|| self.file.sync())
+    }
+}
@@ -1,6 +1,5 @@
 use std::{
-    fs::{self, File},
-    io::{self, BufWriter, Seek, Write},
+    io::{self, Seek, Write},
     path::Path,
 };
 
@@ -10,7 +9,11 @@ use crate::{
     KvError, Result,
 };
 
-use super::{finder, LogId, LogWrite};
+use super::{
+    finder,
+    vfs::{Fs, LogFile},
+    LogId, LogWrite,
+};
 
 #[derive(Debug)]
 pub(crate) struct LogWriter<W>
@@ -22,7 +25,8 @@ where
     // Current writing offset.
     pub offset: usize,
 
-    writer: BufWriter<W>,
+    /// Written to without a buffer, so that a failed command is not written later on.
+    writer: W,
 }
 
 impl<R> LogWrite for LogWriter<R>
@@ -31,41 +35,51 @@ where
 {
     fn write(&mut self, command: &Command) -> Result<CommandLocation> {
         let bytes = command.to_bytes()?;
-        let n = self.writer.write(&bytes)?;
-        if n != bytes.len() {
-            // fallback to the previous location
-            self.writer.seek(io::SeekFrom::Current(-(n as i64)))?;
-            return Err(KvError::CannotWriteLen(bytes.len()));
+        let written = self.writer.write(&bytes);
+        if !matches!(written, Ok(n) if n == bytes.len()) {
+            // Go on after whatever part of the command reached the file.
+            self.offset = self.writer.seek(io::SeekFrom::End(0))? as usize;
+            return Err(match written {
+                Err(e) => e.into(),
+                Ok(_) => KvError::CannotWriteLen(bytes.len()),
+            });
         }
+        let n = bytes.len();
 
         let location = CommandLocation {
             id: self.id,
             offset: self.offset,
+            size: n,
             timestamp: command.timestamp(),
         };
 
         self.offset += n;
-        self.writer.flush()?;
 
         Ok(location)
     }
 }
 
-impl LogWriter<File> {
-    pub(crate) fn open<P>(folder: P, id: LogId) -> Result<LogWriter<File>>
+impl LogWriter<Box<dyn LogFile>> {
+    pub(crate) fn open<P>(fs: &Fs, folder: P, id: LogId) -> Result<LogWriter<Box<dyn LogFile>>>
     where
         P: AsRef<Path>,
     {
         let path = finder::log_path(&folder, &id);
-        let mut file = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&path)?;
+        let mut file = fs.open_append(&path)?;
 
         let offset = file.seek(std::io::SeekFrom::End(0))? as usize;
 
-        let writer = BufWriter::new(file);
+        Ok(LogWriter {
+            id,
+            writer: file,
+            offset,
+        })
+    }
 
-        Ok(LogWriter { id, writer, offset })
+    /// Flush written commands to disk.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.sync()?;
+        Ok(())
     }
 }
@@ -1,18 +1,28 @@
 use std::{
+    fs,
+    io::Write,
     path::{Path, PathBuf},
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+use tracing::info;
+
 use crate::{
-    command::CommandLocations,
-    log::{finder, LogId, LogRead, LogReader, LogWrite, LogWriter},
-    Result,
+    command::{Command, CommandLocations},
+    log::{finder, vfs::Fs, LogId, LogRead, LogReader, LogWrite, LogWriter},
+    KvError, Result,
 };
 
 #[derive(Debug)]
 pub(crate) struct MergeInfo {
+    /// Log holding the merged commands.
+    pub id: LogId,
     pub reader_ids: Vec<LogId>,
     pub locations: CommandLocations,
+    /// Bytes written to the merged log.
+    pub bytes_written: usize,
+    pub duration: Duration,
 }
 
 type MergeResult = Result<MergeInfo>;
@@ -20,13 +30,21 @@ type MergeResult = Result<MergeInfo>;
 #[derive(Debug)]
 pub(crate) struct Merger {
     path: PathBuf,
+    fs: Fs,
     job: Option<JoinHandle<MergeResult>>,
+    /// Committed merges whose logs could not all be removed, see [`Merger::finish`].
+    unfinished: Vec<(LogId, Vec<LogId>)>,
 }
 
 impl Merger {
-    pub fn new<P: AsRef<Path>>(path: P) -> Merger {
+    pub fn new<P: AsRef<Path>>(fs: Fs, path: P) -> Merger {
         let path = path.as_ref().to_path_buf();
-        Merger { path, job: None }
+        Merger {
+            path,
+            fs,
+            job: None,
+            unfinished: Vec::new(),
+        }
     }
 
     pub fn running(&self) -> bool {
@@ -36,10 +54,35 @@ impl Merger {
     pub fn merge(&mut self, reader_ids: Vec<LogId>) {
         if !self.running() {
             let path = self.path.clone();
-            self.job = Some(thread::spawn(move || merge(&path, reader_ids)));
+            let fs = self.fs.clone();
+            self.job = Some(thread::spawn(move || merge(&fs, &path, reader_ids)));
         }
     }
 
+    /// Block until the running merge is done, and take its result.
+    pub fn join(&mut self) -> Option<MergeResult> {
+        let job = std::mem::take(&mut self.job);
+        job.and_then(|j| j.join().ok())
+    }
+
+    /// Remove the merged readers of a committed merge, then its manifest.
+    ///
+    /// If that fails, it is done again by [`Merger::finish_pending`]: the merged log may have
+    /// dropped removals, a following merge must not see the readers holding the removed keys.
+    pub fn finish(&mut self, id: LogId, reader_ids: Vec<LogId>) -> Result<()> {
+        self.unfinished.push((id, reader_ids));
+        self.finish_pending()
+    }
+
+    /// Finish the merges which failed to, before the logs of the next merge are listed.
+    pub fn finish_pending(&mut self) -> Result<()> {
+        while let Some((id, reader_ids)) = self.unfinished.first() {
+            finish(&self.fs, &self.path, *id, reader_ids)?;
+            self.unfinished.remove(0);
+        }
+        Ok(())
+    }
+
     pub fn result(&mut self) -> Option<MergeResult> {
         let finished = self.job.as_ref().is_some_and(|j| j.is_finished());
 
@@ -52,10 +95,17 @@ impl Merger {
     }
 }
 
-fn merge<P: AsRef<Path>>(path: P, reader_ids: Vec<LogId>) -> MergeResult {
+/// Merge the readers into a new log.
+///
+/// The merge is committed once its manifest is in place, until then the merged log only holds
+/// copies of commands which are still in the readers. The caller must remove the readers, then
+/// the manifest, see [`finish`].
+fn merge<P: AsRef<Path>>(fs: &Fs, path: P, reader_ids: Vec<LogId>) -> MergeResult {
+    let start = Instant::now();
     let locations = CommandLocations::new();
 
-    let mut writer = LogWriter::open(&path, finder::next_log_id(&path))?;
+    let id = finder::next_log_id(fs, &path)?;
+    let mut writer = LogWriter::open(fs, &path, id)?;
 
     for id in &reader_ids {
         let reader = LogReader::open(&path, *id)?;
@@ -68,12 +118,83 @@ fn merge<P: AsRef<Path>>(path: P, reader_ids: Vec<LogId>) -> MergeResult {
 
     for (key, location) in locations.data {
         let command = LogReader::open(&path, location.id)?.read(&location)?;
+        // Every older command of this key is merged away, the removal is not needed anymore.
+        if matches!(command, Command::Remove { .. }) {
+            continue;
+        }
         let new_location = writer.write(&command)?;
         new_locations.data.insert(key, new_location);
     }
 
+    writer.sync()?;
+    write_manifest(fs, &path, id, &reader_ids)?;
+
     Ok(MergeInfo {
+        id,
         reader_ids,
         locations: new_locations,
+        bytes_written: writer.offset,
+        duration: start.elapsed(),
     })
 }
+
+fn write_manifest<P: AsRef<Path>>(fs: &Fs, path: P, id: LogId, reader_ids: &[LogId]) -> Result<()> {
+    let temp_path = finder::temp_manifest_path(&path, &id);
+    let mut file = fs.create(&temp_path)?;
+    for reader_id in reader_ids {
+        writeln!(file, "{}", reader_id.0)?;
+    }
+    file.flush()?;
+    file.sync()?;
+    fs.rename(&temp_path, &finder::manifest_path(&path, &id))?;
+    Ok(())
+}
+
+fn read_manifest<P: AsRef<Path>>(path: P, id: LogId) -> Result<Vec<LogId>> {
+    let manifest_path = finder::manifest_path(&path, &id);
+    fs::read_to_string(&manifest_path)?
+        .lines()
+        .map(|line| {
+            line.parse().map(LogId).map_err(|_| {
+                KvError::Corrupted(format!(
+                    "invalid log id `{}` in `{}`",
+                    line,
+                    manifest_path.display()
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Remove the merged readers of a committed merge, then its manifest.
+pub(crate) fn finish<P: AsRef<Path>>(
+    fs: &Fs,
+    path: P,
+    id: LogId,
+    reader_ids: &[LogId],
+) -> Result<()> {
+    for reader_id in reader_ids {
+        let reader_path = finder::log_path(&path, reader_id);
+        if reader_path.exists() {
+            fs.remove_file(&reader_path)?;
+        }
+    }
+    fs.remove_file(&finder::manifest_path(&path, &id))?;
+    Ok(())
+}
+
+/// Finish merges which were committed before the store was closed,
+/// and drop manifests which were never committed.
+pub(crate) fn recover<P: AsRef<Path>>(fs: &Fs, path: P) -> Result<()> {
+    for id in finder::all_temp_manifest_ids(&path)? {
+        fs.remove_file(&finder::temp_manifest_path(&path, &id))?;
+    }
+
+    for id in finder::all_manifest_ids(&path)? {
+        let reader_ids = read_manifest(&path, id)?;
+        info!(id = id.0, readers = ?reader_ids, "finishing interrupted merge:");
+        finish(fs, &path, id, &reader_ids)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+use tracing::info;
+
+use crate::{KvError, Result, Stats};
+use std::{collections::VecDeque, io, net::SocketAddr};
+
+use super::{
+    client::unexpected,
+    codec::{self, Message},
+    protocol::{self, Feature, KvsRequest, KvsResponse, PROTOCOL_VERSION},
+};
+
+/// Async counterpart of [`KvsClient`][crate::KvsClient], speaking the same protocol.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    next_id: u64,
+    /// Ids of the requests sent and not answered yet, oldest first.
+    in_flight: VecDeque<u64>,
+    version: u32,
+    features: Vec<Feature>,
+}
+
+impl AsyncKvsClient {
+    /// Connect to server at specific address.
+    ///
+    /// A server refusing the current protocol version is connected to again with the newest
+    /// version it speaks, if this crate speaks it too.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvs::Result;
+    /// # use kvs::{AsyncKvsClient, AsyncKvsServer, KvsRequest, KvsResponse, Store};
+    /// # use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<()> {
+    /// # let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+    /// let server = AsyncKvsServer::open(address, Store::open_in_memory()).await?;
+    /// let address = server.address;
+    /// tokio::spawn(server.serve());
+    ///
+    /// let mut client = AsyncKvsClient::connect(address).await?;
+    /// client.send(KvsRequest::Get { key: "key1".to_string() }).await?;
+    /// assert!(matches!(client.recv().await?, KvsResponse::Ok(None)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect(address: SocketAddr) -> Result<AsyncKvsClient> {
+        match AsyncKvsClient::connect_as(address, PROTOCOL_VERSION).await {
+            Err(e) => match protocol::fallback_version(&e) {
+                Some(version) => AsyncKvsClient::connect_as(address, version).await,
+                None => Err(e),
+            },
+            connected => connected,
+        }
+    }
+
+    /// Connect announcing protocol `version`.
+    async fn connect_as(address: SocketAddr, version: u32) -> Result<AsyncKvsClient> {
+        let stream = TcpStream::connect(address).await?;
+        let (reader, writer) = stream.into_split();
+
+        let mut client = AsyncKvsClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            next_id: 0,
+            in_flight: VecDeque::new(),
+            version,
+            features: Vec::new(),
+        };
+
+        client
+            .send(KvsRequest::Hello {
+                version,
+                features: vec![Feature::Batching],
+                token: None,
+            })
+            .await?;
+        let (version, features) = protocol::accept_hello(client.recv().await?)?;
+        client.version = version;
+        client.features = features;
+        info!(server_address = %address, version, features = ?client.features, "client connected");
+
+        Ok(client)
+    }
+
+    /// Protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Features agreed on with the server.
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    /// Set the value of a key.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(KvsRequest::Set { key, value })
+            .await
+            .map(|_| ())
+    }
+
+    /// Get the value of a key, `None` if it does not exist.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(KvsRequest::Get { key }).await? {
+            KvsResponse::Ok(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Remove a key, failing with [`KvError::KeyNotFound`] if it does not exist.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.request(KvsRequest::Remove { key }).await.map(|_| ())
+    }
+
+    /// Get the server's stats.
+    pub async fn stats(&mut self) -> Result<Stats> {
+        match self.request(KvsRequest::Stats {}).await? {
+            KvsResponse::Stats(stats) => Ok(*stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Send a request and wait for its response, failures are turned back into errors.
+    async fn request(&mut self, request: KvsRequest) -> Result<KvsResponse> {
+        self.send(request).await?;
+        self.recv().await?.into_result()
+    }
+
+    /// Send a request to server.
+    pub async fn send(&mut self, request: KvsRequest) -> Result<()> {
+        let id = self.next_id;
+        info!(id, request = ?request, "sent request");
+
+        let bytes = Message::encode(Some(id), &request)?;
+        self.writer.write_all(&bytes).await?;
+        self.writer.flush().await?;
+
+        self.next_id += 1;
+        self.in_flight.push_back(id);
+
+        Ok(())
+    }
+
+    /// Get a response from server, responses come in the order requests were sent.
+    pub async fn recv(&mut self) -> Result<KvsResponse> {
+        let message = codec::read_message_async(&mut self.reader)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        let expected = self.in_flight.pop_front();
+        if message.id().is_none() || message.id() != expected {
+            return Err(KvError::Protocol(format!(
+                "expected response to request {:?}, got {:?}",
+                expected,
+                message.id()
+            )));
+        }
+
+        let response = message.parse();
+        info!(id = ?message.id(), response = ?response, "received response");
+
+        response
+    }
+}
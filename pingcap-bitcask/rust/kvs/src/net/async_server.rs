@@ -0,0 +1,233 @@
+use std::{
+    future::{self, Future},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tracing::{info, warn};
+
+use crate::{AsyncKvsEngine, KvError, KvsEngine, Result};
+
+use super::{
+    codec,
+    protocol::{self, KvsRequest, KvsResponse, Session},
+    server::{encode_response, ServerMetrics},
+};
+
+/// Server running every connection as a tokio task, instead of holding a pool thread for it.
+///
+/// Engine calls go through [`AsyncKvsEngine`], so slow disk access does not block other
+/// connections. The engine is shared by reference between connection tasks, so it must be `Sync`.
+pub struct AsyncKvsServer<E>
+where
+    E: KvsEngine + Sync,
+{
+    /// The address at which the server is opened.
+    pub address: SocketAddr,
+    listener: TcpListener,
+    engine: AsyncKvsEngine<E>,
+}
+
+impl<E> AsyncKvsServer<E>
+where
+    E: KvsEngine + Sync,
+{
+    /// Open server at provided address.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvs::Result;
+    /// # use kvs::{AsyncKvsServer, Store};
+    /// # use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<()> {
+    /// let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+    /// let server = AsyncKvsServer::open(address, Store::open_in_memory()).await?;
+    ///
+    /// let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+    /// let running = tokio::spawn(server.serve_with_shutdown(async {
+    ///     let _ = signal.await;
+    /// }));
+    ///
+    /// shutdown.send(()).unwrap();
+    /// running.await.unwrap()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn open(address: SocketAddr, store: E) -> Result<AsyncKvsServer<E>> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+
+        let server = AsyncKvsServer {
+            address,
+            listener,
+            engine: AsyncKvsEngine::new(store),
+        };
+        info!(addr = %address, "async server started");
+
+        Ok(server)
+    }
+
+    /// Serve clients until the task is dropped.
+    pub async fn serve(self) -> Result<()> {
+        self.serve_with_shutdown(future::pending()).await
+    }
+
+    /// Serve clients until `shutdown` completes.
+    /// Connections still open at that point are closed.
+    pub async fn serve_with_shutdown<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        info!("serving");
+
+        let AsyncKvsServer {
+            address,
+            listener,
+            engine,
+        } = self;
+        let metrics = Arc::new(ServerMetrics::default());
+        let mut connections = JoinSet::new();
+
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+
+                // Reap finished connections.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+
+                accepted = listener.accept() => {
+                    let stream = match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!(error = %e, "cannot accept connection:");
+                            continue;
+                        }
+                    };
+
+                    let engine = engine.clone();
+                    let metrics = metrics.clone();
+                    connections.spawn(async move {
+                        metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+                        metrics.total_connections.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = handle_connection(&engine, stream, &metrics).await {
+                            warn!(error = %e, "connection closed:");
+                        }
+                        metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+            }
+        }
+
+        info!(address = %address, "shutdown async server");
+        connections.shutdown().await;
+
+        Ok(())
+    }
+}
+
+async fn handle_connection<E: KvsEngine + Sync>(
+    engine: &AsyncKvsEngine<E>,
+    stream: TcpStream,
+    metrics: &ServerMetrics,
+) -> Result<()> {
+    info!(peer = %stream.peer_addr()?, "connection accepted:");
+
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::new(None);
+
+    while let Some(message) = codec::read_message_async(&mut reader).await? {
+        let request = message.parse::<KvsRequest>();
+        info!(id = ?message.id(), request = ?request, "request:");
+
+        let response = match request {
+            Ok(request) => handle_request(engine, request, &mut session, metrics).await,
+            Err(e) => KvsResponse::InvalidCommand(e.to_string()),
+        };
+
+        writer
+            .write_all(&encode_response(message.id(), &response))
+            .await?;
+        if session.is_closing() {
+            break;
+        }
+        // Pipelined requests are answered together, once every request received is handled.
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+async fn handle_request<E: KvsEngine + Sync>(
+    engine: &AsyncKvsEngine<E>,
+    request: KvsRequest,
+    session: &mut Session,
+    metrics: &ServerMetrics,
+) -> KvsResponse {
+    if let Err(response) = session.authorize(&request) {
+        return session.downgrade(response);
+    }
+
+    let res = match request {
+        KvsRequest::Hello {
+            version,
+            features,
+            token,
+        } => Ok(session.handshake(version, features, token)),
+
+        KvsRequest::Get { key } => engine.get(key).await.map(KvsResponse::Ok),
+
+        KvsRequest::Set { key, value } => {
+            engine.set(key, value).await.map(|_| KvsResponse::Ok(None))
+        }
+
+        KvsRequest::Remove { key } => engine.remove(key).await.map(|_| KvsResponse::Ok(None)),
+
+        KvsRequest::Stats {} => engine
+            .stats()
+            .await
+            .map(|stats| KvsResponse::Stats(Box::new(metrics.with_connections(stats)))),
+
+        KvsRequest::Scan {
+            prefix,
+            cursor,
+            limit,
+        } => match protocol::scan_limit(limit) {
+            Ok(limit) => engine.keys().await.map(|keys| {
+                let (keys, cursor) = protocol::scan(&keys, &prefix, cursor.as_deref(), limit);
+                KvsResponse::Keys { keys, cursor }
+            }),
+            Err(e) => Err(e),
+        },
+
+        KvsRequest::Sync { .. }
+        | KvsRequest::Ack { .. }
+        | KvsRequest::Promote {}
+        | KvsRequest::Raft { .. }
+        | KvsRequest::Member { .. } => Err(KvError::InvalidArgument(
+            "replication is not supported by this server".to_owned(),
+        )),
+
+        KvsRequest::Watch { .. } => Err(KvError::InvalidArgument(
+            "watching keys is not supported by this server".to_owned(),
+        )),
+    };
+
+    session.started();
+
+    let res = session.downgrade(res.unwrap_or_else(KvsResponse::from));
+    info!(res = ?res, "response:");
+
+    res
+}
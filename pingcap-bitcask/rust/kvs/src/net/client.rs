@@ -1,17 +1,52 @@
 use tracing::info;
 
-use crate::{parser::ByteParser, Result};
+use rustls::pki_types::ServerName;
+
+use crate::{
+    raft::{Message as RaftMessage, NodeId},
+    ClientOption, KvError, Result, Stats,
+};
 use std::{
-    io::{BufReader, BufWriter, Write},
+    collections::VecDeque,
+    convert::TryFrom,
+    io::{self, Write},
     net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use super::{
+    codec::{self, Message},
+    protocol::{self, Feature, KvsRequest, KvsResponse, PROTOCOL_VERSION},
+    tls::TlsConnector,
+    transport::{BufStream, Transport},
+    watch::Watcher,
 };
 
-use super::protocol::{KvsRequest, KvsResponse};
+/// Most requests a pipeline sends before reading their responses.
+///
+/// The server does not read while it writes, so a client which never reads
+/// could fill both ends' buffers and wait forever.
+const MAX_IN_FLIGHT: usize = 1024;
+/// Most times a request follows `NotLeader` answers to another server.
+const MAX_REDIRECTS: usize = 3;
 
 /// Client that can talk with sever through internal network protocol.
+///
+/// Every request carries an id, the server answers them in order. Several requests can be
+/// sent before reading their responses, see [`KvsClient::pipeline`].
+///
+/// The protocol version and features are agreed on with a handshake when connecting.
 pub struct KvsClient {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    stream: BufStream,
+    /// The connection under `stream`, which may be wrapped in TLS.
+    socket: TcpStream,
+    next_id: u64,
+    /// Ids of the requests sent and not answered yet, oldest first.
+    in_flight: VecDeque<u64>,
+    version: u32,
+    features: Vec<Feature>,
+    /// Options to connect with, again when redirected to a leader.
+    options: ClientOption,
 }
 
 impl KvsClient {
@@ -35,32 +70,370 @@ impl KvsClient {
     /// # Ok(())
     /// # }
     pub fn connect(address: SocketAddr) -> Result<KvsClient> {
-        let stream = TcpStream::connect(address)?;
-        let reader = BufReader::new(stream.try_clone()?);
-        let writer = BufWriter::new(stream);
+        KvsClient::connect_with_options(address, &ClientOption::new())
+    }
+
+    /// Connect to server at specific address, over TLS or with a token if configured.
+    ///
+    /// A server refusing the current protocol version is connected to again with the newest
+    /// version it speaks, if this crate speaks it too.
+    pub fn connect_with_options(address: SocketAddr, options: &ClientOption) -> Result<KvsClient> {
+        match KvsClient::connect_as(address, options, PROTOCOL_VERSION) {
+            Err(e) => match protocol::fallback_version(&e) {
+                Some(version) => KvsClient::connect_as(address, options, version),
+                None => Err(e),
+            },
+            connected => connected,
+        }
+    }
+
+    /// Connect announcing protocol `version`.
+    fn connect_as(address: SocketAddr, options: &ClientOption, version: u32) -> Result<KvsClient> {
+        let socket = TcpStream::connect(address)?;
+        let connection = socket.try_clone()?;
+        let transport: Box<dyn Transport> = match &options.tls_ca {
+            Some(ca) => {
+                let server_name = match &options.server_name {
+                    Some(name) => ServerName::try_from(name.clone()).map_err(|e| {
+                        KvError::InvalidArgument(format!("server name `{}`: {}", name, e))
+                    })?,
+                    None => ServerName::from(address.ip()),
+                };
+                Box::new(TlsConnector::from_ca_file(ca)?.connect(server_name, socket)?)
+            }
+            None => Box::new(socket),
+        };
 
-        let client = KvsClient { reader, writer };
-        info!(server_address = %address, "client connected");
+        let mut features = vec![Feature::Batching];
+        if options.token.is_some() {
+            features.push(Feature::Auth);
+        }
+
+        let mut client = KvsClient {
+            stream: BufStream::new(transport),
+            socket: connection,
+            next_id: 0,
+            in_flight: VecDeque::new(),
+            version,
+            features: Vec::new(),
+            options: options.clone(),
+        };
+
+        client.send(KvsRequest::Hello {
+            version,
+            features,
+            token: options.token.clone(),
+        })?;
+        let (version, features) = protocol::accept_hello(client.recv()?)?;
+        client.version = version;
+        client.features = features;
+        info!(
+            server_address = %address,
+            tls = options.tls_ca.is_some(),
+            version,
+            features = ?client.features,
+            "client connected"
+        );
 
         Ok(client)
     }
 
+    /// Protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Features agreed on with the server.
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
     /// Send a request to server.
     pub fn send(&mut self, request: KvsRequest) -> Result<()> {
-        info!(request = ?request, "sent request");
-
-        let bytes = request.to_bytes()?;
-        self.writer.write_all(&bytes)?;
-        self.writer.flush()?;
+        self.write(&request)?;
+        self.stream.flush()?;
 
         Ok(())
     }
 
-    /// Get a response from server.
+    /// Get a response from server, responses come in the order requests were sent.
     pub fn recv(&mut self) -> Result<KvsResponse> {
-        let response = KvsResponse::from_reader(&mut self.reader);
-        info!(response = ?response, "received response");
+        let message = codec::read_message(&mut self.stream)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        let expected = self.in_flight.pop_front();
+        if message.id().is_none() || message.id() != expected {
+            return Err(KvError::Protocol(format!(
+                "expected response to request {:?}, got {:?}",
+                expected,
+                message.id()
+            )));
+        }
+
+        let response = message.parse();
+        info!(id = ?message.id(), response = ?response, "received response");
 
         response
     }
+
+    /// Set the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(KvsRequest::Set { key, value }).map(|_| ())
+    }
+
+    /// Get the value of a key, `None` if it does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(KvsRequest::Get { key })? {
+            KvsResponse::Ok(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Remove a key, failing with [`KvError::KeyNotFound`] if it does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(KvsRequest::Remove { key }).map(|_| ())
+    }
+
+    /// List at most `limit` keys starting with `prefix` in order, after `cursor`.
+    /// Returns them with the cursor of the next page, `None` once every key is listed.
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        match self.request(KvsRequest::Scan {
+            prefix,
+            cursor,
+            limit,
+        })? {
+            KvsResponse::Keys { keys, cursor } => Ok((keys, cursor)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Get the server's stats.
+    pub fn stats(&mut self) -> Result<Stats> {
+        match self.request(KvsRequest::Stats {})? {
+            KvsResponse::Stats(stats) => Ok(*stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Turn a replica back into a primary, accepting writes.
+    /// The connection must have presented the server's token, and the server be a replica.
+    pub fn promote(&mut self) -> Result<()> {
+        self.request(KvsRequest::Promote {}).map(|_| ())
+    }
+
+    /// Turn the connection into a stream of the changes to keys starting with `prefix`,
+    /// pushed by the server as they are made.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    /// # fn main() -> Result<()> {
+    /// # let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+    /// let watcher = KvsClient::connect(address)?.watch("config.".to_owned())?;
+    /// for change in watcher {
+    ///     let change = change?;
+    ///     println!("{} is now {:?}", change.key, change.value);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn watch(mut self, prefix: String) -> Result<Watcher> {
+        if self.version < 7 {
+            return Err(KvError::InvalidArgument(format!(
+                "watching keys needs protocol version 7, the server speaks {}",
+                self.version
+            )));
+        }
+        match self.request(KvsRequest::Watch { prefix })? {
+            KvsResponse::Watching {} => {
+                let id = self.next_id - 1;
+                Ok(Watcher::new(self.stream, self.socket, Some(id)))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The connection's stream and socket, to speak something else than requests and
+    /// responses on it.
+    pub(crate) fn into_parts(self) -> (BufStream, TcpStream) {
+        (self.stream, self.socket)
+    }
+
+    /// Name this connection as coming from member `id` of the Raft cluster at the other end,
+    /// with the cluster's `token`, before sending it messages.
+    pub(crate) fn member(&mut self, id: NodeId, token: &str) -> Result<()> {
+        if self.version < 8 {
+            return Err(KvError::InvalidArgument(format!(
+                "raft members need protocol version 8, the server speaks {}",
+                self.version
+            )));
+        }
+        self.request(KvsRequest::Member {
+            id,
+            token: token.to_owned(),
+        })
+        .map(|_| ())
+    }
+
+    /// Deliver a message to the member of a Raft cluster at the other end.
+    pub(crate) fn raft(&mut self, message: RaftMessage) -> Result<()> {
+        self.request(KvsRequest::Raft { message }).map(|_| ())
+    }
+
+    /// Whether the connection can take a new request: no response is pending,
+    /// and the server has not closed it.
+    pub(crate) fn is_healthy(&self) -> bool {
+        if !self.in_flight.is_empty() || self.socket.set_nonblocking(true).is_err() {
+            return false;
+        }
+        // Pending bytes may be TLS records sent by the server after the handshake.
+        let closed = match self.socket.peek(&mut [0]) {
+            Ok(read) => read == 0,
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        };
+        self.socket.set_nonblocking(false).is_ok() && !closed
+    }
+
+    /// Fail reads and writes taking longer than `timeout`.
+    pub(crate) fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(timeout)?;
+        self.socket.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Send a request and wait for its response, failures are turned back into errors.
+    ///
+    /// A follower of a Raft cluster names its leader, the client then reconnects to it
+    /// and sends the request again.
+    fn request(&mut self, request: KvsRequest) -> Result<KvsResponse> {
+        let mut redirects = 0;
+        loop {
+            self.write(&request)?;
+            self.stream.flush()?;
+            match self.recv()?.into_result() {
+                Err(KvError::NotLeader {
+                    leader: Some(leader),
+                }) if redirects < MAX_REDIRECTS => {
+                    info!(leader = %leader, "redirected to leader");
+                    *self = KvsClient::connect_with_options(leader, &self.options)?;
+                    redirects += 1;
+                }
+                response => return response,
+            }
+        }
+    }
+
+    /// Start a batch of requests, sent together and answered in one round-trip.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use kvs::{KvsClient, KvsResponse, Result};
+    /// # use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    /// # fn main() -> Result<()> {
+    /// # let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+    /// let mut client = KvsClient::connect(address)?;
+    /// let responses = client
+    ///     .pipeline()
+    ///     .set("key1".to_owned(), "value1".to_owned())
+    ///     .get("key1".to_owned())
+    ///     .execute()?;
+    /// assert!(matches!(&responses[1], KvsResponse::Ok(Some(value)) if value == "value1"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Buffer a request with a new id, without flushing it.
+    fn write(&mut self, request: &KvsRequest) -> Result<()> {
+        let id = self.next_id;
+        let bytes = Message::encode(Some(id), request)?;
+        self.stream.write_all(&bytes)?;
+
+        self.next_id += 1;
+        self.in_flight.push_back(id);
+        info!(id, request = ?request, "sent request");
+
+        Ok(())
+    }
+}
+
+pub(crate) fn unexpected(response: KvsResponse) -> KvError {
+    KvError::Protocol(format!("unexpected response {:?}", response))
+}
+
+/// Requests queued on a [`KvsClient`], see [`KvsClient::pipeline`].
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<KvsRequest>,
+}
+
+impl Pipeline<'_> {
+    /// Queue any request.
+    pub fn request(&mut self, request: KvsRequest) -> &mut Self {
+        self.requests.push(request);
+        self
+    }
+
+    /// Queue a set.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.request(KvsRequest::Set { key, value })
+    }
+
+    /// Queue a get.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.request(KvsRequest::Get { key })
+    }
+
+    /// Queue a remove.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.request(KvsRequest::Remove { key })
+    }
+
+    /// Number of requests queued.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send every queued request and return their responses, in the same order.
+    ///
+    /// Requests are applied one after the other, a failed request does not stop the next ones.
+    /// Without [`Feature::Batching`], requests are sent one at a time.
+    pub fn execute(&mut self) -> Result<Vec<KvsResponse>> {
+        let requests = std::mem::take(&mut self.requests);
+        let mut responses = Vec::with_capacity(requests.len());
+
+        let in_flight = if self.client.features.contains(&Feature::Batching) {
+            MAX_IN_FLIGHT
+        } else {
+            1
+        };
+        for batch in requests.chunks(in_flight) {
+            for request in batch {
+                self.client.write(request)?;
+            }
+            self.client.stream.flush()?;
+
+            for _ in batch {
+                responses.push(self.client.recv()?);
+            }
+        }
+
+        Ok(responses)
+    }
 }
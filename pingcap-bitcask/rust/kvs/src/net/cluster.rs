@@ -0,0 +1,306 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use super::{protocol::same_token, server::spawn_named};
+use crate::{
+    raft::{Applied, Message, NodeId, RaftCommand, RaftNode, ReadReady, Role},
+    ClientOption, KvError, KvsClient, KvsEngine, Result,
+};
+
+/// Time between two ticks of the Raft node, elections start after 10 to 20 ticks of silence.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a request waits for its entry to be committed and applied.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peer takes to answer a message before the connection is dropped.
+const PEER_TIMEOUT: Duration = Duration::from_secs(1);
+/// Delay before reconnecting to a peer which cannot be reached.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+/// How often idle peer senders check whether to stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+struct State<E: KvsEngine> {
+    node: RaftNode<E>,
+    /// Entries requests wait for, by index and term.
+    waiting: HashSet<(u64, u64)>,
+    applied: HashMap<(u64, u64), Result<()>>,
+    /// Reads requests wait for, by id.
+    reading: HashSet<u64>,
+    read: HashMap<u64, Result<()>>,
+}
+
+/// Member of a Raft cluster, driving a [`RaftNode`] over the network.
+///
+/// Messages to each peer go through a thread of their own, connected as a client.
+/// Messages which cannot be delivered are dropped, Raft sends them again.
+pub(crate) struct Cluster<E: KvsEngine> {
+    id: NodeId,
+    state: Mutex<State<E>>,
+    applied: Condvar,
+    /// Client address of every member, to redirect to the leader.
+    members: HashMap<NodeId, SocketAddr>,
+    /// What members prove who they are with.
+    token: String,
+    peers: HashMap<NodeId, Sender<Message>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<E: KvsEngine> Cluster<E> {
+    /// Join the cluster of `members` with `node`, members proving who they are with `token`.
+    /// Returns the threads to join once `active` is cleared.
+    pub fn start(
+        node: RaftNode<E>,
+        members: HashMap<NodeId, SocketAddr>,
+        token: String,
+        options: ClientOption,
+        active: Arc<AtomicBool>,
+    ) -> (Arc<Cluster<E>>, Vec<JoinHandle<()>>) {
+        let id = node.id();
+        let mut jobs = Vec::new();
+        let mut peers = HashMap::new();
+        for (&peer, &address) in members.iter().filter(|(peer, _)| **peer != id) {
+            let (sender, receiver) = mpsc::channel();
+            peers.insert(peer, sender);
+            let token = token.clone();
+            let options = options.clone();
+            let active = active.clone();
+            jobs.push(spawn_named("kvs-raft-peer", move || {
+                send_to_peer(id, &token, address, options, receiver, active)
+            }));
+        }
+
+        let cluster = Arc::new(Cluster {
+            id,
+            state: Mutex::new(State {
+                node,
+                waiting: HashSet::new(),
+                applied: HashMap::new(),
+                reading: HashSet::new(),
+                read: HashMap::new(),
+            }),
+            applied: Condvar::new(),
+            members,
+            token,
+            peers,
+        });
+        info!(id, members = ?cluster.members, "joined raft cluster");
+
+        let ticking = cluster.clone();
+        jobs.push(spawn_named("kvs-raft", move || {
+            while active.load(Ordering::SeqCst) {
+                thread::sleep(TICK_INTERVAL);
+                let mut state = lock(&ticking.state);
+                state.node.tick();
+                ticking.dispatch(&mut state);
+            }
+        }));
+
+        (cluster, jobs)
+    }
+
+    /// Let a connection naming itself member `id` send Raft messages, if it has the token
+    /// of the cluster and comes from the address configured for that member.
+    pub fn admit(&self, id: NodeId, token: &str, peer: Option<IpAddr>) -> Result<()> {
+        if !same_token(token.as_bytes(), self.token.as_bytes()) {
+            return Err(KvError::Unauthenticated("invalid member token".to_owned()));
+        }
+        let address = match self.members.get(&id) {
+            Some(address) if id != self.id => address,
+            _ => {
+                return Err(KvError::Unauthenticated(format!(
+                    "{} is not another member of the cluster",
+                    id
+                )))
+            }
+        };
+        if peer != Some(address.ip()) {
+            return Err(KvError::Unauthenticated(format!(
+                "member {} connects from {}",
+                id,
+                address.ip()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Handle a message from another member.
+    pub fn step(&self, message: Message) {
+        if message.to != self.id {
+            warn!(
+                id = self.id,
+                to = message.to,
+                "dropping message for another member"
+            );
+            return;
+        }
+        let mut state = lock(&self.state);
+        state.node.step(message);
+        self.dispatch(&mut state);
+    }
+
+    /// Replicate a command and wait until it is applied, on the leader only.
+    pub fn propose(&self, command: RaftCommand) -> Result<()> {
+        let mut state = lock(&self.state);
+        let entry = match state.node.propose(command) {
+            Some(entry) => entry,
+            None => return Err(self.not_leader(&state.node)),
+        };
+        state.waiting.insert(entry);
+        self.dispatch(&mut state);
+
+        let (mut state, result) = self.wait(state, |state| state.applied.remove(&entry));
+        result.unwrap_or_else(|| {
+            state.waiting.remove(&entry);
+            Err(KvError::Busy(
+                "the cluster did not commit the write in time".to_owned(),
+            ))
+        })
+    }
+
+    /// Wait until every write committed before the call is applied here, on the leader only.
+    ///
+    /// A majority confirms the leader with a round of heartbeats, shared by concurrent
+    /// calls, so a leader cut from the others cannot serve stale reads. A new leader goes
+    /// through the log instead, until it committed an entry of its term.
+    pub fn barrier(&self) -> Result<()> {
+        let mut state = lock(&self.state);
+        let id = match state.node.read() {
+            Some(id) => id,
+            None if state.node.role() == Role::Leader => {
+                drop(state);
+                return self.propose(RaftCommand::Noop {});
+            }
+            None => return Err(self.not_leader(&state.node)),
+        };
+        state.reading.insert(id);
+        self.dispatch(&mut state);
+
+        let (mut state, result) = self.wait(state, |state| state.read.remove(&id));
+        result.unwrap_or_else(|| {
+            state.reading.remove(&id);
+            Err(KvError::Busy(
+                "the cluster did not confirm the leader in time".to_owned(),
+            ))
+        })
+    }
+
+    /// Wait until `done` hands out a result, `None` after `COMMIT_TIMEOUT`.
+    fn wait<'a, F>(
+        &self,
+        mut state: MutexGuard<'a, State<E>>,
+        mut done: F,
+    ) -> (MutexGuard<'a, State<E>>, Option<Result<()>>)
+    where
+        F: FnMut(&mut State<E>) -> Option<Result<()>>,
+    {
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if let Some(result) = done(&mut state) {
+                return (state, Some(result));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return (state, None);
+            }
+            state = self
+                .applied
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    fn not_leader(&self, node: &RaftNode<E>) -> KvError {
+        KvError::NotLeader {
+            leader: node
+                .leader()
+                .and_then(|leader| self.members.get(&leader).copied()),
+        }
+    }
+
+    /// Send the node's messages and hand out its results, after each call to the node.
+    fn dispatch(&self, state: &mut State<E>) {
+        for message in state.node.take_messages() {
+            if let Some(peer) = self.peers.get(&message.to) {
+                let _ = peer.send(message);
+            }
+        }
+
+        let mut notify = false;
+        for Applied {
+            index,
+            term,
+            result,
+        } in state.node.take_applied()
+        {
+            if state.waiting.remove(&(index, term)) {
+                state.applied.insert((index, term), result);
+                notify = true;
+            }
+        }
+        for ReadReady { id, result } in state.node.take_reads() {
+            if state.reading.remove(&id) {
+                state.read.insert(id, result);
+                notify = true;
+            }
+        }
+        if notify {
+            self.applied.notify_all();
+        }
+    }
+}
+
+/// Deliver the messages of `receiver` to the peer at `address` as member `id` with `token`,
+/// until `active` is cleared.
+fn send_to_peer(
+    id: NodeId,
+    token: &str,
+    address: SocketAddr,
+    options: ClientOption,
+    receiver: Receiver<Message>,
+    active: Arc<AtomicBool>,
+) {
+    let mut client: Option<KvsClient> = None;
+    let mut retry_at = Instant::now();
+    while active.load(Ordering::SeqCst) {
+        let message = match receiver.recv_timeout(STOP_CHECK_INTERVAL) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // Drop messages while the peer is unreachable rather than queue them.
+        let connected = match client.take() {
+            Some(client) => Ok(client),
+            None if Instant::now() < retry_at => continue,
+            None => KvsClient::connect_with_options(address, &options).and_then(|mut client| {
+                client.set_timeout(Some(PEER_TIMEOUT))?;
+                client.member(id, token)?;
+                info!(peer = %address, "connected to raft peer");
+                Ok(client)
+            }),
+        };
+        let result = connected.and_then(|mut connected| {
+            connected.raft(message)?;
+            client = Some(connected);
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!(peer = %address, error = %e, "cannot reach raft peer:");
+            retry_at = Instant::now() + RECONNECT_INTERVAL;
+        }
+    }
+}
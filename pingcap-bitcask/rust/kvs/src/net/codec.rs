@@ -0,0 +1,171 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{self, Read},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{parser::ByteParser, KvError, Result};
+
+/// Largest document accepted, same as the bson specification.
+const MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Start of every frame.
+///
+/// Read as the little endian length of a bson document, it is far above
+/// [`MAX_DOCUMENT_SIZE`], so frames and bare documents from older clients never get mixed up.
+pub(crate) const MAGIC: [u8; 4] = *b"KVSF";
+/// Version of the frame layout.
+pub(crate) const VERSION: u8 = 1;
+/// Magic, version, request id and body length.
+const HEADER_SIZE: usize = 4 + 1 + 8 + 4;
+
+/// One message on the wire.
+///
+/// A frame is a header followed by a bson document:
+///
+/// | magic `KVSF` | version `u8` | request id `u64` | body length `u32` | body |
+///
+/// Integers are little endian. Older clients send bare documents, they are answered the same way.
+#[derive(Debug)]
+pub(crate) enum Message {
+    Framed { id: u64, body: Vec<u8> },
+    Document(Vec<u8>),
+}
+
+impl Message {
+    /// Request id, if the peer sent one.
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            Message::Framed { id, .. } => Some(*id),
+            Message::Document(_) => None,
+        }
+    }
+
+    pub fn parse<T: ByteParser>(&self) -> Result<T> {
+        match self {
+            Message::Framed { body, .. } | Message::Document(body) => {
+                T::from_reader(&mut body.as_slice())
+            }
+        }
+    }
+
+    /// Encode `value` the same way as a message with request id `id`.
+    pub fn encode<T: ByteParser>(id: Option<u64>, value: &T) -> Result<Vec<u8>> {
+        let body = value.to_bytes()?;
+        Ok(match id {
+            Some(id) => frame(id, &body),
+            None => body,
+        })
+    }
+}
+
+fn frame(id: u64, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&id.to_le_bytes());
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// What the first four bytes of a message announce.
+enum Prefix {
+    Frame,
+    Document(usize),
+}
+
+fn invalid_data(message: String) -> KvError {
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+fn prefix(bytes: [u8; 4]) -> Result<Prefix> {
+    if bytes == MAGIC {
+        return Ok(Prefix::Frame);
+    }
+    let size = i32::from_le_bytes(bytes);
+    match usize::try_from(size) {
+        Ok(size) if (bytes.len()..=MAX_DOCUMENT_SIZE).contains(&size) => Ok(Prefix::Document(size)),
+        _ => Err(invalid_data(format!("invalid document size `{}`", size))),
+    }
+}
+
+/// Request id and body length of a frame header, without the magic.
+fn frame_header(bytes: [u8; HEADER_SIZE - 4]) -> Result<(u64, usize)> {
+    let version = bytes[0];
+    if version != VERSION {
+        return Err(KvError::Protocol(format!(
+            "unsupported frame version `{}`",
+            version
+        )));
+    }
+    let id = u64::from_le_bytes(bytes[1..9].try_into().expect("8 bytes"));
+    let size = u32::from_le_bytes(bytes[9..].try_into().expect("4 bytes"));
+    match usize::try_from(size) {
+        Ok(size) if size <= MAX_DOCUMENT_SIZE => Ok((id, size)),
+        _ => Err(invalid_data(format!("invalid frame size `{}`", size))),
+    }
+}
+
+/// Buffer for a document of `size` bytes, holding the `start` already read.
+fn new_document(start: [u8; 4], size: usize) -> Vec<u8> {
+    let mut document = vec![0; size];
+    document[..start.len()].copy_from_slice(&start);
+    document
+}
+
+/// Read one message, `None` if the stream ends before it starts.
+pub(crate) fn read_message<R: Read>(reader: &mut R) -> Result<Option<Message>> {
+    let mut start = [0; 4];
+    match reader.read_exact(&mut start) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    match prefix(start)? {
+        Prefix::Frame => {
+            let mut header = [0; HEADER_SIZE - 4];
+            reader.read_exact(&mut header)?;
+            let (id, size) = frame_header(header)?;
+            let mut body = vec![0; size];
+            reader.read_exact(&mut body)?;
+            Ok(Some(Message::Framed { id, body }))
+        }
+        Prefix::Document(size) => {
+            let mut document = new_document(start, size);
+            reader.read_exact(&mut document[start.len()..])?;
+            Ok(Some(Message::Document(document)))
+        }
+    }
+}
+
+/// Async version of [`read_message`].
+pub(crate) async fn read_message_async<R>(reader: &mut R) -> Result<Option<Message>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut start = [0; 4];
+    match reader.read_exact(&mut start).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    match prefix(start)? {
+        Prefix::Frame => {
+            let mut header = [0; HEADER_SIZE - 4];
+            reader.read_exact(&mut header).await?;
+            let (id, size) = frame_header(header)?;
+            let mut body = vec![0; size];
+            reader.read_exact(&mut body).await?;
+            Ok(Some(Message::Framed { id, body }))
+        }
+        Prefix::Document(size) => {
+            let mut document = new_document(start, size);
+            reader.read_exact(&mut document[start.len()..]).await?;
+            Ok(Some(Message::Document(document)))
+        }
+    }
+}
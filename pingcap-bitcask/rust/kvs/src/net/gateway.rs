@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    http::{percent_decode, HttpRequest},
+    protocol::{self, same_token, ErrorCode, MAX_SCAN_LIMIT},
+    server::ServerMetrics,
+};
+use crate::{KvError, KvsEngine, Result};
+
+/// Keys listed by one scan without a `limit`.
+const DEFAULT_SCAN_LIMIT: usize = 100;
+
+/// Status and json body answered by the gateway.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, value: &T) -> Response {
+        Response {
+            status,
+            body: serde_json::to_vec(value).expect("serialize json response"),
+        }
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            body: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    /// [`ErrorCode`] of the failure, or `KeyNotFound`.
+    code: String,
+    message: String,
+    /// Operations of a batch applied before it failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied: Option<usize>,
+}
+
+fn status(error: &KvError) -> u16 {
+    if let KvError::KeyNotFound(_) = error {
+        return 404;
+    }
+    match ErrorCode::from(error) {
+        ErrorCode::InvalidArgument => 400,
+        ErrorCode::Unauthenticated => 401,
+        ErrorCode::ReadOnly => 403,
+        ErrorCode::NotLeader => 421,
+        ErrorCode::Busy => 503,
+        _ => 500,
+    }
+}
+
+pub(crate) fn error_response(error: &KvError, applied: Option<usize>) -> Response {
+    let code = match error {
+        KvError::KeyNotFound(_) => "KeyNotFound".to_owned(),
+        error => format!("{:?}", ErrorCode::from(error)),
+    };
+    let body = ErrorBody {
+        code,
+        message: error.to_string(),
+        applied,
+    };
+    Response::json(status(error), &body)
+}
+
+fn invalid(message: String) -> KvError {
+    KvError::InvalidArgument(message)
+}
+
+fn parse_json<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|e| invalid(format!("invalid json body: {}", e)))
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanBody {
+    keys: Vec<String>,
+    /// Pass as `cursor` to list the next keys, `null` once every key is listed.
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchBody {
+    operations: Vec<Operation>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    applied: usize,
+}
+
+/// Answer one request of the HTTP gateway.
+///
+/// - `GET /keys/{key}`: `{"key": .., "value": ..}`, or 404.
+/// - `PUT /keys/{key}` with `{"value": ..}`, `DELETE /keys/{key}`: 204, or 404 when deleting.
+/// - `GET /keys?prefix=..&cursor=..&limit=..`: `{"keys": [..], "cursor": ..}`, in key order.
+/// - `POST /batch` with `{"operations": [{"op": "set", "key": .., "value": ..},
+///   {"op": "delete", "key": ..}]}`: `{"applied": n}`.
+///   Operations run in order and are not atomic, a failure stops the batch.
+/// - `GET /stats`: engine and server [`Stats`](crate::Stats).
+///
+/// Failures answer `{"code": .., "message": ..}`. When `token` is set, requests must carry
+/// an `Authorization: Bearer <token>` header.
+pub(crate) fn handle<E: KvsEngine>(
+    store: &E,
+    request: &HttpRequest,
+    token: Option<&str>,
+    metrics: &ServerMetrics,
+) -> Response {
+    if let Some(token) = token {
+        let authorized = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|presented| same_token(presented.as_bytes(), token.as_bytes()));
+        if !authorized {
+            let error = KvError::Unauthenticated("missing or invalid bearer token".to_owned());
+            return error_response(&error, None);
+        }
+    }
+
+    let method = request.method.as_str();
+    let result = match request.path.as_str() {
+        "/keys" => match method {
+            "GET" => scan(store, request),
+            _ => return method_not_allowed(),
+        },
+        "/batch" => match method {
+            "POST" => return batch(store, request),
+            _ => return method_not_allowed(),
+        },
+        "/stats" => match method {
+            "GET" => metrics
+                .stats(store)
+                .map(|stats| Response::json(200, &stats)),
+            _ => return method_not_allowed(),
+        },
+        path => match path.strip_prefix("/keys/") {
+            Some(key) => match percent_decode(key) {
+                Some(key) => single_key(store, method, key, &request.body),
+                None => Err(invalid(format!("invalid key `{}`", key))),
+            },
+            None => {
+                let error = ErrorBody {
+                    code: "NotFound".to_owned(),
+                    message: format!("no route for `{}`", path),
+                    applied: None,
+                };
+                return Response::json(404, &error);
+            }
+        },
+    };
+
+    result.unwrap_or_else(|error| error_response(&error, None))
+}
+
+fn method_not_allowed() -> Response {
+    let error = ErrorBody {
+        code: "MethodNotAllowed".to_owned(),
+        message: "method not allowed".to_owned(),
+        applied: None,
+    };
+    Response::json(405, &error)
+}
+
+fn single_key<E: KvsEngine>(store: &E, method: &str, key: String, body: &[u8]) -> Result<Response> {
+    match method {
+        "GET" => match store.get(key.clone())? {
+            Some(value) => Ok(Response::json(200, &Entry { key, value })),
+            None => Err(KvError::KeyNotFound(key)),
+        },
+        "PUT" => {
+            let PutBody { value } = parse_json(body)?;
+            store.set(key, value)?;
+            Ok(Response::no_content())
+        }
+        "DELETE" => {
+            store.remove(key)?;
+            Ok(Response::no_content())
+        }
+        _ => Ok(method_not_allowed()),
+    }
+}
+
+/// List keys in order, starting after `cursor`.
+fn scan<E: KvsEngine>(store: &E, request: &HttpRequest) -> Result<Response> {
+    let mut prefix = String::new();
+    let mut cursor = None;
+    let mut limit = DEFAULT_SCAN_LIMIT;
+    for pair in request.query_pairs() {
+        let (name, value) = pair.ok_or_else(|| invalid("invalid query".to_owned()))?;
+        match name.as_str() {
+            "prefix" => prefix = value,
+            "cursor" => cursor = Some(value),
+            "limit" => {
+                limit = match value.parse() {
+                    Ok(limit) if (1..=MAX_SCAN_LIMIT).contains(&limit) => limit,
+                    _ => {
+                        return Err(invalid(format!(
+                            "limit must be between 1 and {}",
+                            MAX_SCAN_LIMIT
+                        )))
+                    }
+                }
+            }
+            _ => return Err(invalid(format!("unknown parameter `{}`", name))),
+        }
+    }
+
+    let (keys, cursor) = protocol::scan(&store.keys()?, &prefix, cursor.as_deref(), limit);
+    Ok(Response::json(200, &ScanBody { keys, cursor }))
+}
+
+fn batch<E: KvsEngine>(store: &E, request: &HttpRequest) -> Response {
+    let BatchBody { operations } = match parse_json(&request.body) {
+        Ok(body) => body,
+        Err(error) => return error_response(&error, Some(0)),
+    };
+
+    let count = operations.len();
+    for (applied, operation) in operations.into_iter().enumerate() {
+        let result = match operation {
+            Operation::Set { key, value } => store.set(key, value),
+            // Deleting is idempotent within a batch.
+            Operation::Delete { key } => match store.remove(key) {
+                Err(KvError::KeyNotFound(_)) => Ok(()),
+                result => result,
+            },
+        };
+        if let Err(error) = result {
+            return error_response(&error, Some(applied));
+        }
+    }
+
+    Response::json(200, &BatchResult { applied: count })
+}
@@ -0,0 +1,228 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::{KvError, Result};
+
+/// Largest request body accepted.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// Largest request line and headers accepted, together.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Minimal HTTP/1.1 request, enough for serving simple endpoints.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+    pub method: String,
+    /// Such as `HTTP/1.1`.
+    pub version: String,
+    /// Path of the target, without its query.
+    pub path: String,
+    /// Query of the target, without the `?`.
+    pub query: String,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Why a request could not be read.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum RequestError {
+    #[error("request headers are larger than {} bytes", MAX_HEAD_SIZE)]
+    HeadTooLarge,
+    #[error("request body is larger than {} bytes", MAX_BODY_SIZE)]
+    BodyTooLarge,
+    #[error(transparent)]
+    Invalid(#[from] KvError),
+}
+
+impl RequestError {
+    /// Status answering the request.
+    pub fn status(&self) -> u16 {
+        match self {
+            RequestError::HeadTooLarge => 431,
+            RequestError::BodyTooLarge => 413,
+            RequestError::Invalid(_) => 400,
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> RequestError {
+        RequestError::Invalid(e.into())
+    }
+}
+
+impl From<RequestError> for KvError {
+    fn from(e: RequestError) -> KvError {
+        match e {
+            RequestError::Invalid(e) => e,
+            e => KvError::InvalidArgument(e.to_string()),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read one line of the request head, at most `remaining` bytes of it.
+fn read_head_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    remaining: &mut usize,
+) -> std::result::Result<(), RequestError> {
+    line.clear();
+    let read = reader.by_ref().take(*remaining as u64).read_line(line)?;
+    *remaining -= read;
+    if line.ends_with('\n') {
+        Ok(())
+    } else if *remaining == 0 {
+        Err(RequestError::HeadTooLarge)
+    } else {
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+impl HttpRequest {
+    /// Read a request line, its headers and its body.
+    pub fn from_reader<R: BufRead>(
+        reader: &mut R,
+    ) -> std::result::Result<HttpRequest, RequestError> {
+        let mut remaining = MAX_HEAD_SIZE;
+        let mut line = String::new();
+        read_head_line(reader, &mut line, &mut remaining)?;
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().ok_or_else(|| invalid("missing method"))?;
+        let target = parts.next().ok_or_else(|| invalid("missing path"))?;
+        let version = parts.next().unwrap_or("HTTP/1.0");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut request = HttpRequest {
+            method: method.to_string(),
+            version: version.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        let mut content_length = 0;
+        loop {
+            read_head_line(reader, &mut line, &mut remaining)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                let name = name.trim().to_ascii_lowercase();
+                let value = value.trim().to_string();
+                if name == "content-length" {
+                    content_length = value
+                        .parse()
+                        .map_err(|_| invalid("invalid content length"))?;
+                    if content_length > MAX_BODY_SIZE {
+                        return Err(RequestError::BodyTooLarge);
+                    }
+                }
+                request.headers.push((name, value));
+            }
+        }
+
+        // Grown as the body arrives, rather than trusting the announced length.
+        reader
+            .take(content_length as u64)
+            .read_to_end(&mut request.body)?;
+        if request.body.len() < content_length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(request)
+    }
+
+    /// Value of the first header named `name`, which must be lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client keeps the connection open for another request, by default since
+    /// HTTP/1.1.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").unwrap_or_default();
+        let has = |option: &str| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(option))
+        };
+        if has("close") {
+            return false;
+        }
+        has("keep-alive") || self.version == "HTTP/1.1"
+    }
+
+    /// Decoded `name=value` pairs of the query.
+    pub fn query_pairs(&self) -> impl Iterator<Item = Option<(String, String)>> + '_ {
+        self.query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let pair = pair.replace('+', " ");
+                let (name, value) = pair.split_once('=').unwrap_or((&pair, ""));
+                Some((percent_decode(name)?, percent_decode(value)?))
+            })
+    }
+}
+
+/// Decode `%XX` escapes, `None` if they are invalid or the result is not utf-8.
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Write a full response, telling whether the connection stays open for another request.
+pub(crate) fn write_response<W: Write>(
+    writer: &mut W,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+    keep_alive: bool,
+) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        421 => "Misdirected Request",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" }
+    )?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,3 +1,16 @@
+pub mod async_client;
+pub mod async_server;
 pub mod client;
+mod cluster;
+mod codec;
+mod gateway;
+mod http;
+pub mod pool;
 pub mod protocol;
+mod replication;
+mod resp;
 pub mod server;
+pub mod sharded;
+mod tls;
+mod transport;
+pub mod watch;
@@ -0,0 +1,277 @@
+use std::{
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, MutexGuard},
+    thread,
+    time::Instant,
+};
+
+use tracing::{info, warn};
+
+use crate::{ClientOption, KvError, KvsClient, PoolOption, Result, Stats};
+
+#[derive(Default)]
+struct PoolState {
+    /// Connections not in use, with when they were last returned, most recent last.
+    idle: Vec<(KvsClient, Instant)>,
+    /// Connections open, in use or idle.
+    open: usize,
+}
+
+/// Connections to one server shared between threads.
+///
+/// Connections are opened when needed, up to a maximum, and reused. Requests which fail
+/// because of the connection are sent again on a new one, if doing so is safe:
+/// `Get`, `Stats` and scans always, `Set` if enabled with [`PoolOption::retry_sets`].
+///
+/// # Example
+/// ```no_run
+/// # use kvs::{ClientOption, KvsClientPool, PoolOption, Result};
+/// # use std::{net::SocketAddr, sync::Arc, thread};
+/// # fn main() -> Result<()> {
+/// # let address: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+/// let mut options = PoolOption::new();
+/// options.max_size(4).retry_sets(true);
+/// let pool = Arc::new(KvsClientPool::new(address, ClientOption::new(), options));
+///
+/// let writer = pool.clone();
+/// thread::spawn(move || writer.set("key1".to_owned(), "value1".to_owned()));
+/// pool.get("key1".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClientPool {
+    address: SocketAddr,
+    client_options: ClientOption,
+    options: PoolOption,
+    state: Mutex<PoolState>,
+    /// Signaled when a connection is returned or closed.
+    released: Condvar,
+}
+
+impl KvsClientPool {
+    /// Create a pool of connections to the server at `address`, opened with `client_options`.
+    /// No connection is opened until the first request.
+    pub fn new(
+        address: SocketAddr,
+        client_options: ClientOption,
+        options: PoolOption,
+    ) -> KvsClientPool {
+        KvsClientPool {
+            address,
+            client_options,
+            options,
+            state: Mutex::new(PoolState::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Connections open, in use or idle.
+    pub fn open(&self) -> usize {
+        self.lock().open
+    }
+
+    /// Connections open and not in use, once those idle for too long are closed.
+    pub fn idle(&self) -> usize {
+        let mut state = self.lock();
+        self.close_expired(&mut state);
+        state.idle.len()
+    }
+
+    /// Set the value of a key.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(self.options.retry_sets, |client| {
+            client.set(key.clone(), value.clone())
+        })
+    }
+
+    /// Get the value of a key, `None` if it does not exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(true, |client| client.get(key.clone()))
+    }
+
+    /// Remove a key, failing with [`KvError::KeyNotFound`] if it does not exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.run(false, |client| client.remove(key.clone()))
+    }
+
+    /// List at most `limit` keys starting with `prefix` in order, after `cursor`,
+    /// see [`KvsClient::scan`].
+    pub fn scan(
+        &self,
+        prefix: String,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.run(true, |client| {
+            client.scan(prefix.clone(), cursor.clone(), limit)
+        })
+    }
+
+    /// Get the server's stats.
+    pub fn stats(&self) -> Result<Stats> {
+        self.run(true, KvsClient::stats)
+    }
+
+    /// Take a connection for several requests, for instance a pipeline.
+    /// It goes back to the pool once dropped, unless a response is still pending.
+    pub fn connection(&self) -> Result<PooledClient<'_>> {
+        let deadline = self
+            .options
+            .request_timeout
+            .map(|timeout| Instant::now() + timeout);
+
+        let mut state = self.lock();
+        loop {
+            self.close_expired(&mut state);
+            while let Some((client, _)) = state.idle.pop() {
+                if client.is_healthy() {
+                    return Ok(self.pooled(client));
+                }
+                state.open -= 1;
+                info!(server = %self.address, "closing broken connection");
+            }
+
+            if state.open < self.options.max_size {
+                state.open += 1;
+                drop(state);
+                return match self.connect() {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        self.release(None);
+                        Err(e)
+                    }
+                };
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(KvError::Busy(format!(
+                            "all {} connections are in use",
+                            self.options.max_size
+                        )));
+                    }
+                    self.released
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.released.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn connect(&self) -> Result<KvsClient> {
+        let client = KvsClient::connect_with_options(self.address, &self.client_options)?;
+        client.set_timeout(self.options.request_timeout)?;
+        Ok(client)
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    fn close_expired(&self, state: &mut PoolState) {
+        let idle_timeout = self.options.idle_timeout;
+        let before = state.idle.len();
+        state
+            .idle
+            .retain(|(_, since)| since.elapsed() < idle_timeout);
+        state.open -= before - state.idle.len();
+    }
+
+    /// Take back a connection, `None` if it was closed.
+    fn release(&self, client: Option<KvsClient>) {
+        let mut state = self.lock();
+        match client {
+            Some(client) => state.idle.push((client, Instant::now())),
+            None => state.open -= 1,
+        }
+        self.released.notify_one();
+    }
+
+    /// Run `request` on a connection, again on a new one after a failure if it is `idempotent`.
+    ///
+    /// Failing to connect is always retried, the request was not sent yet.
+    fn run<T, F>(&self, idempotent: bool, mut request: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let mut backoff = self.options.min_backoff;
+        let mut attempt = 0;
+        loop {
+            let (error, sent) = match self.connection() {
+                Ok(mut client) => match request(&mut client) {
+                    Ok(value) => return Ok(value),
+                    Err(e) => {
+                        if is_retryable(&e) {
+                            client.discard();
+                        }
+                        (e, true)
+                    }
+                },
+                Err(e) => (e, false),
+            };
+
+            if attempt >= self.options.max_retries || !is_retryable(&error) || (sent && !idempotent)
+            {
+                return Err(error);
+            }
+            warn!(server = %self.address, error = %error, attempt, "retrying request:");
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(self.options.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+/// Failures which another attempt, on a new connection, may not hit.
+fn is_retryable(error: &KvError) -> bool {
+    matches!(
+        error,
+        KvError::Io(_) | KvError::Protocol(_) | KvError::Tls(_) | KvError::Busy(_)
+    )
+}
+
+/// A connection taken from a [`KvsClientPool`], given back once dropped.
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl PooledClient<'_> {
+    /// Close the connection instead of giving it back.
+    pub fn discard(mut self) {
+        self.client.take();
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("connection taken")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("connection taken")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        let client = self.client.take().filter(KvsClient::is_healthy);
+        self.pool.release(client);
+    }
+}
@@ -1,14 +1,176 @@
-use serde::{Deserialize, Serialize};
+use std::{io, net::IpAddr, sync::Arc};
+
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::parser::ByteParser;
-use crate::KvError;
+use crate::raft::{Message, NodeId};
+use crate::{KvError, LogPosition, Result, Stats};
+
+/// Version of the requests and responses spoken by this crate.
+///
+/// Clients announce theirs in the handshake, which the server refuses outside of
+/// [`MIN_PROTOCOL_VERSION`] and this one. Connections without a handshake use version 1.
+///
+/// - 1: requests and responses as first released.
+/// - 2: failures are answered with an [`ErrorCode`] and a message, instead of `ServerError`.
+/// - 3: the handshake carries an auth token, [`ErrorCode::Unauthenticated`].
+/// - 4: replication requests, [`ErrorCode::ReadOnly`].
+/// - 5: Raft messages between the members of a cluster, `NotLeader` responses.
+/// - 6: key scans.
+/// - 7: watching keys for changes, [`ErrorCode::Overflowed`].
+/// - 8: members of a Raft cluster name themselves, with their shared token, before sending
+///   Raft messages.
+pub const PROTOCOL_VERSION: u32 = 8;
+/// Oldest version a handshake may announce.
+///
+/// Version 1 answers every failure with a bare `ServerError`, it is only kept for the
+/// clients which predate the handshake.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// Version of connections without a handshake.
+const UNVERSIONED: u32 = 1;
+
+/// Optional protocol features, agreed on during the handshake.
+///
+/// Features this crate does not know are ignored when announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Feature {
+    /// Requests must be authenticated.
+    Auth,
+    /// Several requests in flight on one connection.
+    Batching,
+}
+
+/// Kind of failure reported by the server, so clients can tell what is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Reading or writing the disk failed.
+    Io,
+    /// Stored data cannot be read back.
+    Corrupted,
+    /// The server cannot take the request right now, it may succeed later.
+    Busy,
+    /// The request itself is wrong, sending it again will fail again.
+    InvalidArgument,
+    /// The database was created by another engine.
+    MismatchEngine,
+    /// The connection did not authenticate, the server closes it.
+    Unauthenticated,
+    /// The server is a replica, writes go to its primary.
+    ReadOnly,
+    /// The server is not the leader of its cluster, requests go to the leader.
+    NotLeader,
+    /// The client did not read the changes it watches fast enough, some were dropped.
+    Overflowed,
+    /// Anything else.
+    Internal,
+}
+
+impl ErrorCode {
+    /// Error matching this code, as seen by a client.
+    pub(crate) fn into_error(self, message: String) -> KvError {
+        match self {
+            ErrorCode::Io => io::Error::other(message).into(),
+            ErrorCode::Corrupted => KvError::Corrupted(message),
+            ErrorCode::Busy => KvError::Busy(message),
+            ErrorCode::InvalidArgument => KvError::InvalidArgument(message),
+            ErrorCode::MismatchEngine => KvError::MismatchEngine,
+            ErrorCode::Unauthenticated => KvError::Unauthenticated(message),
+            ErrorCode::ReadOnly => KvError::ReadOnly(message),
+            ErrorCode::NotLeader => KvError::NotLeader { leader: None },
+            ErrorCode::Overflowed => KvError::Overflowed(message),
+            ErrorCode::Internal => KvError::Server(message),
+        }
+    }
+}
+
+impl From<&KvError> for ErrorCode {
+    fn from(error: &KvError) -> Self {
+        match error {
+            KvError::Io(_) => ErrorCode::Io,
+            KvError::Sled(sled::Error::Io(_)) => ErrorCode::Io,
+            KvError::Sled(sled::Error::Corruption { .. })
+            | KvError::BsonDe(_)
+            | KvError::FileIdDoesNotExist(_)
+            | KvError::Corrupted(_) => ErrorCode::Corrupted,
+            KvError::Busy(_) | KvError::AlreadyOpen(_) => ErrorCode::Busy,
+            KvError::GlobPattern(_)
+            | KvError::CannotWriteLen(_)
+            | KvError::InvalidArgument(_)
+            | KvError::Protocol(_)
+            | KvError::UnsupportedVersion { .. } => ErrorCode::InvalidArgument,
+            KvError::MismatchEngine => ErrorCode::MismatchEngine,
+            KvError::Unauthenticated(_) => ErrorCode::Unauthenticated,
+            KvError::ReadOnly(_) => ErrorCode::ReadOnly,
+            KvError::NotLeader { .. } => ErrorCode::NotLeader,
+            KvError::Overflowed(_) => ErrorCode::Overflowed,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Most keys listed by one scan.
+pub(crate) const MAX_SCAN_LIMIT: usize = 1000;
+
+/// Features every server enables, [`Feature::Auth`] depends on its settings.
+const SUPPORTED_FEATURES: &[Feature] = &[Feature::Batching];
 
 #[doc(hidden)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KvsRequest {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    Stats {},
+    /// Handshake, only valid as the first request of a connection.
+    Hello {
+        version: u32,
+        #[serde(deserialize_with = "known_features")]
+        features: Vec<Feature>,
+        /// Since version 3.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Turn the connection into a replication stream, since version 4.
+    /// Without a position, or with one the primary cannot resume from, it starts with a
+    /// snapshot.
+    Sync {
+        position: Option<LogPosition>,
+    },
+    /// Sent by a replica on a replication stream, once it applied the writes up to `position`.
+    Ack {
+        position: LogPosition,
+    },
+    /// Stop replicating and accept writes, since version 4.
+    Promote {},
+    /// Message from another member of the Raft cluster, since version 5.
+    /// Only accepted after `Member` since version 8.
+    Raft {
+        message: Message,
+    },
+    /// The connection comes from member `id` of the Raft cluster, proven with the token
+    /// members share, since version 8.
+    Member {
+        id: NodeId,
+        token: String,
+    },
+    /// List keys starting with `prefix` in order, after `cursor`, since version 6.
+    Scan {
+        prefix: String,
+        cursor: Option<String>,
+        limit: u32,
+    },
+    /// Turn the connection into a stream of the changes to keys starting with `prefix`,
+    /// since version 7.
+    Watch {
+        prefix: String,
+    },
 }
 
 #[doc(hidden)]
@@ -17,17 +179,365 @@ pub enum KvsResponse {
     Ok(Option<String>),
     KeyNotFound(String),
     InvalidCommand(String),
-    ServerError,
+    /// Failure reported to clients before protocol version 2.
+    ///
+    /// A struct variant, bson cannot encode a unit variant as a whole document.
+    ServerError {},
+    Stats(Box<Stats>),
+    /// Handshake accepted, with the version and features the connection uses.
+    Hello {
+        version: u32,
+        features: Vec<Feature>,
+    },
+    /// Handshake rejected, the server closes the connection.
+    UnsupportedVersion {
+        version: u32,
+        min: u32,
+        max: u32,
+    },
+    /// Failure, since protocol version 2.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Part of the store, as `Set` requests, answering a `Sync`.
+    /// The replica drops its keys missing from the snapshot once it gets the `last` part.
+    Snapshot {
+        entries: Vec<KvsRequest>,
+        last: bool,
+        /// Writes after this position are streamed next.
+        position: LogPosition,
+    },
+    /// Writes streamed to a replica, as `Set` and `Remove` requests, leading to `position`.
+    /// Sent empty as a heartbeat when nothing is written.
+    Commands {
+        commands: Vec<KvsRequest>,
+        position: LogPosition,
+    },
+    /// The server is a follower of a Raft cluster, since version 5.
+    /// Earlier versions get a `Busy` error.
+    NotLeader {
+        /// Client address of the leader, if known.
+        leader: Option<String>,
+    },
+    /// Page of keys answering a `Scan`, with the cursor of the next page if any.
+    Keys {
+        keys: Vec<String>,
+        cursor: Option<String>,
+    },
+    /// Answers a `Watch`, changes follow as `KeyChanged` with the id of the request.
+    /// The stream ends with an `Overflowed` error if the client falls behind.
+    Watching {},
+    /// A key was set, or removed if `value` is `None`.
+    KeyChanged {
+        key: String,
+        value: Option<String>,
+    },
 }
 
 impl ByteParser for KvsRequest {}
 impl ByteParser for KvsResponse {}
 
+impl KvsRequest {
+    /// Oldest protocol version with this request.
+    pub(crate) fn since(&self) -> u32 {
+        match self {
+            KvsRequest::Set { .. }
+            | KvsRequest::Get { .. }
+            | KvsRequest::Remove { .. }
+            | KvsRequest::Stats {}
+            | KvsRequest::Hello { .. } => UNVERSIONED,
+            KvsRequest::Sync { .. } | KvsRequest::Ack { .. } | KvsRequest::Promote {} => 4,
+            KvsRequest::Raft { .. } => 5,
+            KvsRequest::Scan { .. } => 6,
+            KvsRequest::Watch { .. } => 7,
+            KvsRequest::Member { .. } => 8,
+        }
+    }
+}
+
+/// Features announced in a handshake, without those this crate does not know.
+fn known_features<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<Feature>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    Ok(names
+        .iter()
+        .filter_map(|name| match name.as_str() {
+            "Auth" => Some(Feature::Auth),
+            "Batching" => Some(Feature::Batching),
+            _ => None,
+        })
+        .collect())
+}
+
+impl KvsResponse {
+    /// Turn failures back into errors.
+    pub fn into_result(self) -> Result<KvsResponse> {
+        match self {
+            KvsResponse::KeyNotFound(key) => Err(KvError::KeyNotFound(key)),
+            KvsResponse::InvalidCommand(message) => Err(KvError::InvalidArgument(message)),
+            KvsResponse::ServerError {} => Err(KvError::Server("unknown error".to_owned())),
+            KvsResponse::UnsupportedVersion { version, min, max } => {
+                Err(KvError::UnsupportedVersion { version, min, max })
+            }
+            KvsResponse::Error { code, message } => Err(code.into_error(message)),
+            KvsResponse::NotLeader { leader } => Err(KvError::NotLeader {
+                leader: leader.and_then(|leader| leader.parse().ok()),
+            }),
+            response => Ok(response),
+        }
+    }
+}
+
 impl From<KvError> for KvsResponse {
     fn from(value: KvError) -> Self {
         match value {
             KvError::KeyNotFound(key) => KvsResponse::KeyNotFound(key),
-            _ => KvsResponse::ServerError,
+            KvError::NotLeader { leader } => KvsResponse::NotLeader {
+                leader: leader.map(|leader| leader.to_string()),
+            },
+            error => KvsResponse::Error {
+                code: ErrorCode::from(&error),
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+/// What a connection agreed on during the handshake.
+#[derive(Debug)]
+pub(crate) struct Session {
+    started: bool,
+    /// Token the client must present, if the server requires one.
+    token: Option<Arc<str>>,
+    authenticated: bool,
+    closing: bool,
+    pub version: u32,
+    pub features: Vec<Feature>,
+    /// Address the connection comes from, if known.
+    pub peer: Option<IpAddr>,
+    /// Raft member the connection comes from, once admitted.
+    pub member: Option<NodeId>,
+}
+
+impl Session {
+    /// Session of a new connection, which must authenticate with `token` if provided.
+    pub fn new(token: Option<Arc<str>>) -> Session {
+        Session {
+            started: false,
+            token,
+            authenticated: false,
+            closing: false,
+            version: UNVERSIONED,
+            features: Vec::new(),
+            peer: None,
+            member: None,
+        }
+    }
+
+    /// Answer a handshake, keeping the features both sides support.
+    pub fn handshake(
+        &mut self,
+        version: u32,
+        features: Vec<Feature>,
+        token: Option<String>,
+    ) -> KvsResponse {
+        if self.started {
+            return KvsResponse::InvalidCommand("handshake must be the first request".to_owned());
+        }
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            self.closing = true;
+            return KvsResponse::UnsupportedVersion {
+                version,
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            };
+        }
+        self.version = version;
+
+        if let Some(expected) = &self.token {
+            match token {
+                Some(token) if same_token(token.as_bytes(), expected.as_bytes()) => {
+                    self.authenticated = true;
+                }
+                _ => {
+                    self.closing = true;
+                    return KvError::Unauthenticated("invalid token".to_owned()).into();
+                }
+            }
+        }
+
+        let auth = self.token.is_some();
+        self.features = features
+            .into_iter()
+            .filter(|feature| {
+                SUPPORTED_FEATURES.contains(feature) || (auth && *feature == Feature::Auth)
+            })
+            .collect();
+
+        KvsResponse::Hello {
+            version: self.version,
+            features: self.features.clone(),
+        }
+    }
+
+    /// Let the request through if the connection may send it, in its protocol version.
+    /// Otherwise return the response rejecting it, the connection is closed afterwards when
+    /// it did not authenticate.
+    pub fn authorize(&mut self, request: &KvsRequest) -> std::result::Result<(), KvsResponse> {
+        let hello = matches!(request, KvsRequest::Hello { .. });
+        if self.token.is_some() && !self.authenticated && !hello {
+            self.closing = true;
+            return Err(KvError::Unauthenticated("authentication required".to_owned()).into());
+        }
+        if request.since() > self.version {
+            return Err(KvError::InvalidArgument(format!(
+                "request needs protocol version {}, the connection uses {}",
+                request.since(),
+                self.version
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Whether the connection presented the token of a server requiring one.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Whether the connection may turn into a replication stream.
+    pub fn may_replicate(&self) -> bool {
+        (self.token.is_none() || self.authenticated) && self.version >= 4
+    }
+
+    /// Whether the connection may turn into a stream of key changes.
+    pub fn may_watch(&self) -> bool {
+        (self.token.is_none() || self.authenticated) && self.version >= 7
+    }
+
+    /// Mark a request as handled, no handshake can happen afterwards.
+    pub fn started(&mut self) {
+        self.started = true;
+    }
+
+    /// Close the connection after the next response.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    /// Whether the server must close the connection, after sending the last response.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Rewrite a response into one the client's protocol version knows.
+    pub fn downgrade(&self, response: KvsResponse) -> KvsResponse {
+        match response {
+            KvsResponse::NotLeader { leader } if self.version < 5 => {
+                self.downgrade(KvsResponse::Error {
+                    code: ErrorCode::Busy,
+                    message: format!(
+                        "not the leader, leader is {}",
+                        leader.as_deref().unwrap_or("unknown")
+                    ),
+                })
+            }
+            KvsResponse::Error { .. } if self.version < 2 => KvsResponse::ServerError {},
+            KvsResponse::Error {
+                code: ErrorCode::Unauthenticated,
+                message,
+            } if self.version < 3 => KvsResponse::Error {
+                code: ErrorCode::InvalidArgument,
+                message,
+            },
+            KvsResponse::Error {
+                code: ErrorCode::ReadOnly,
+                message,
+            } if self.version < 4 => KvsResponse::Error {
+                code: ErrorCode::InvalidArgument,
+                message,
+            },
+            KvsResponse::Error {
+                code: ErrorCode::Overflowed,
+                message,
+            } if self.version < 7 => KvsResponse::Error {
+                code: ErrorCode::Busy,
+                message,
+            },
+            response => response,
+        }
+    }
+}
+
+/// Page of at most `limit` of the sorted `keys` starting with `prefix`, after `cursor`.
+/// Returns the cursor of the next page, `None` once every key is listed.
+pub(crate) fn scan(
+    keys: &[String],
+    prefix: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> (Vec<String>, Option<String>) {
+    let mut start = keys.partition_point(|key| key.as_str() < prefix);
+    if let Some(cursor) = cursor {
+        start = start.max(keys.partition_point(|key| key.as_str() <= cursor));
+    }
+    let mut page: Vec<String> = keys[start..]
+        .iter()
+        .take_while(|key| key.starts_with(prefix))
+        .take(limit + 1)
+        .cloned()
+        .collect();
+    let cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().cloned()
+    } else {
+        None
+    };
+    (page, cursor)
+}
+
+/// Check the limit of a `Scan` request.
+pub(crate) fn scan_limit(limit: u32) -> Result<usize> {
+    match limit as usize {
+        limit @ 1..=MAX_SCAN_LIMIT => Ok(limit),
+        _ => Err(KvError::InvalidArgument(format!(
+            "limit must be between 1 and {}",
+            MAX_SCAN_LIMIT
+        ))),
+    }
+}
+
+/// Compare tokens in a time which does not depend on where they differ.
+pub(crate) fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Version to announce again once a server refused ours with `error`, the newest both
+/// speak, if any.
+pub(crate) fn fallback_version(error: &KvError) -> Option<u32> {
+    match error {
+        KvError::UnsupportedVersion { version, min, max }
+            if *version == PROTOCOL_VERSION && *min <= *max && *max < PROTOCOL_VERSION =>
+        {
+            Some(*max).filter(|max| *max >= MIN_PROTOCOL_VERSION)
+        }
+        _ => None,
+    }
+}
+
+/// Check the server's answer to a handshake, returning the agreed version and features.
+pub(crate) fn accept_hello(response: KvsResponse) -> Result<(u32, Vec<Feature>)> {
+    match response.into_result()? {
+        KvsResponse::Hello { version, features }
+            if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+        {
+            Ok((version, features))
         }
+        response => Err(KvError::Protocol(format!(
+            "unexpected handshake response {:?}",
+            response
+        ))),
     }
 }
@@ -0,0 +1,524 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use super::{
+    cluster::Cluster,
+    codec::{self, Message},
+    protocol::{KvsRequest, KvsResponse},
+    server::encode_response,
+    transport::BufStream,
+};
+use crate::{
+    command::Command, raft::RaftCommand, ClientOption, KvError, KvsClient, KvsEngine, LogPosition,
+    ReplicaStats, ReplicationLog, Result, Stats, Subscription,
+};
+
+/// Most bytes of keys and values sent in one replication message.
+const MAX_BATCH_SIZE: usize = 1024 * 1024;
+/// How often the primary looks for new writes when a replica is up to date.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Longest a replication stream stays silent, replicas take silence for a dead primary.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a replica waits for the primary before reconnecting.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an acknowledgement takes to arrive once started.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before reconnecting to the primary, doubled after each failure.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a waiting replica checks whether to stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Role of a server, and the replicas streaming from it.
+#[derive(Debug, Default)]
+pub(crate) struct Replication {
+    /// The primary followed, while the server is a replica.
+    primary: Mutex<Option<SocketAddr>>,
+    /// Connection to the primary, shut down to stop following it.
+    upstream: Mutex<Option<TcpStream>>,
+    next_replica: AtomicU64,
+    replicas: Mutex<HashMap<u64, Replica>>,
+}
+
+#[derive(Debug)]
+struct Replica {
+    address: SocketAddr,
+    /// Last position the replica applied.
+    acked: Option<LogPosition>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Replication {
+    /// Role of a server following `primary`, or a primary if `None`.
+    pub fn new(primary: Option<SocketAddr>) -> Replication {
+        Replication {
+            primary: Mutex::new(primary),
+            ..Replication::default()
+        }
+    }
+
+    pub fn primary(&self) -> Option<SocketAddr> {
+        *lock(&self.primary)
+    }
+
+    /// Stop following the primary, return whether the server was a replica.
+    pub fn promote(&self) -> bool {
+        let primary = lock(&self.primary).take();
+        if let Some(primary) = primary {
+            info!(primary = %primary, "promoted to primary");
+            self.disconnect();
+        }
+        primary.is_some()
+    }
+
+    /// Close the connection to the primary, the replica reconnects unless it stops.
+    pub fn disconnect(&self) {
+        if let Some(upstream) = lock(&self.upstream).take() {
+            let _ = upstream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn register(&self, address: SocketAddr) -> ReplicaHandle<'_> {
+        let id = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        lock(&self.replicas).insert(
+            id,
+            Replica {
+                address,
+                acked: None,
+            },
+        );
+        ReplicaHandle {
+            replication: self,
+            id,
+        }
+    }
+}
+
+/// A replica streaming from this server, forgotten once dropped.
+struct ReplicaHandle<'a> {
+    replication: &'a Replication,
+    id: u64,
+}
+
+impl ReplicaHandle<'_> {
+    fn acknowledge(&self, position: LogPosition) {
+        if let Some(replica) = lock(&self.replication.replicas).get_mut(&self.id) {
+            replica.acked = Some(position);
+        }
+    }
+}
+
+impl Drop for ReplicaHandle<'_> {
+    fn drop(&mut self) {
+        lock(&self.replication.replicas).remove(&self.id);
+    }
+}
+
+/// The engine served, refusing writes while the server is a replica.
+///
+/// In a Raft cluster, writes go through the cluster's log and reads wait for the writes
+/// committed before them.
+#[derive(Clone)]
+pub(crate) struct Replicated<E: KvsEngine> {
+    engine: E,
+    pub replication: Arc<Replication>,
+    pub cluster: Option<Arc<Cluster<E>>>,
+}
+
+impl<E: KvsEngine> Replicated<E> {
+    pub fn new(engine: E, replication: Arc<Replication>) -> Replicated<E> {
+        Replicated {
+            engine,
+            replication,
+            cluster: None,
+        }
+    }
+
+    fn barrier(&self) -> Result<()> {
+        match &self.cluster {
+            Some(cluster) => cluster.barrier(),
+            None => Ok(()),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.replication.primary() {
+            Some(primary) => Err(KvError::ReadOnly(format!(
+                "replica of {}, write to the primary",
+                primary
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Replicated<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_writable()?;
+        match &self.cluster {
+            Some(cluster) => cluster.propose(RaftCommand::Set { key, value }),
+            None => self.engine.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.barrier()?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.check_writable()?;
+        match &self.cluster {
+            Some(cluster) => cluster.propose(RaftCommand::Remove { key }),
+            None => self.engine.remove(key),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.barrier()?;
+        self.engine.keys()
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let log = self.engine.replication_log();
+        let replicas: Vec<(SocketAddr, Option<LogPosition>)> = lock(&self.replication.replicas)
+            .values()
+            .map(|replica| (replica.address, replica.acked))
+            .collect();
+
+        let mut stats = Vec::with_capacity(replicas.len());
+        for (address, acked) in replicas {
+            let lag_bytes = match (&log, acked) {
+                (Some(log), Some(acked)) => log.lag(&acked)?,
+                _ => None,
+            };
+            stats.push(ReplicaStats {
+                address: address.to_string(),
+                lag_bytes,
+            });
+        }
+
+        Ok(Stats {
+            replicas: stats,
+            primary: self
+                .replication
+                .primary()
+                .map(|primary| primary.to_string()),
+            ..self.engine.stats()?
+        })
+    }
+
+    fn replication_log(&self) -> Option<ReplicationLog> {
+        self.engine.replication_log()
+    }
+
+    fn subscribe(&self, prefix: String, capacity: usize) -> Option<Subscription> {
+        self.engine.subscribe(prefix, capacity)
+    }
+}
+
+fn into_request(command: Command) -> KvsRequest {
+    match command {
+        Command::Set { key, value, .. } => KvsRequest::Set { key, value },
+        Command::Remove { key, .. } => KvsRequest::Remove { key },
+    }
+}
+
+pub(crate) fn send(stream: &mut BufStream, id: Option<u64>, response: &KvsResponse) -> Result<()> {
+    stream.write_all(&encode_response(id, response))?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Stream the writes of `store` to the replica which sent `Sync` as request `id`,
+/// until it disconnects or the server stops.
+pub(crate) fn serve_replica<E: KvsEngine>(
+    store: &Replicated<E>,
+    stream: &mut BufStream,
+    socket: &TcpStream,
+    id: Option<u64>,
+    position: Option<LogPosition>,
+    active: &AtomicBool,
+) -> Result<()> {
+    let log = match store.replication_log() {
+        Some(log) if store.replication.primary().is_none() => log,
+        log => {
+            let message = match log {
+                Some(_) => "a replica cannot be replicated from",
+                None => "only the kvs engine can be replicated from",
+            };
+            let error = KvError::InvalidArgument(message.to_owned());
+            return send(stream, id, &error.into());
+        }
+    };
+
+    let address = socket.peer_addr()?;
+    let replica = store.replication.register(address);
+    info!(replica = %address, position = ?position, "replica connected:");
+
+    let mut position = position;
+    let mut last_sent = Instant::now();
+    while active.load(Ordering::SeqCst) {
+        let batch = match &position {
+            Some(position) => log.read(position, MAX_BATCH_SIZE)?,
+            None => None,
+        };
+        let idle = match batch {
+            None => {
+                info!(replica = %address, "sending snapshot");
+                position = Some(send_snapshot(store, &log, stream, id)?);
+                last_sent = Instant::now();
+                false
+            }
+            Some((commands, next)) => {
+                let idle = commands.is_empty();
+                if !idle || last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                    let commands = commands.into_iter().map(into_request).collect();
+                    let response = KvsResponse::Commands {
+                        commands,
+                        position: next,
+                    };
+                    send(stream, id, &response)?;
+                    last_sent = Instant::now();
+                }
+                position = Some(next);
+                idle
+            }
+        };
+
+        // Waiting for acknowledgements paces the polling of the log.
+        let wait = if idle {
+            POLL_INTERVAL
+        } else {
+            Duration::from_millis(1)
+        };
+        if !read_ack(stream, socket, &replica, wait)? {
+            info!(replica = %address, "replica disconnected");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Send every key of the store, return the position to stream from afterwards.
+fn send_snapshot<E: KvsEngine>(
+    store: &Replicated<E>,
+    log: &ReplicationLog,
+    stream: &mut BufStream,
+    id: Option<u64>,
+) -> Result<LogPosition> {
+    // Writes racing with the snapshot are streamed again afterwards, replaying them converges.
+    let position = log.position()?;
+
+    let mut entries = Vec::new();
+    let mut size = 0;
+    for key in store.keys()? {
+        let value = match store.get(key.clone())? {
+            Some(value) => value,
+            None => continue,
+        };
+        size += key.len() + value.len();
+        entries.push(KvsRequest::Set { key, value });
+        if size >= MAX_BATCH_SIZE {
+            let response = KvsResponse::Snapshot {
+                entries: std::mem::take(&mut entries),
+                last: false,
+                position,
+            };
+            send(stream, id, &response)?;
+            size = 0;
+        }
+    }
+    let response = KvsResponse::Snapshot {
+        entries,
+        last: true,
+        position,
+    };
+    send(stream, id, &response)?;
+
+    Ok(position)
+}
+
+/// Read an acknowledgement if one arrives within `wait`.
+/// Returns `false` once the replica disconnects.
+fn read_ack(
+    stream: &mut BufStream,
+    socket: &TcpStream,
+    replica: &ReplicaHandle<'_>,
+    wait: Duration,
+) -> Result<bool> {
+    socket.set_read_timeout(Some(wait))?;
+    match stream.fill_buf() {
+        Ok([]) => return Ok(false),
+        Ok(_) => {}
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+            ) =>
+        {
+            return Ok(true)
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    socket.set_read_timeout(Some(ACK_TIMEOUT))?;
+    let message = match codec::read_message(stream)? {
+        Some(message) => message,
+        None => return Ok(false),
+    };
+    match message.parse::<KvsRequest>()? {
+        KvsRequest::Ack { position } => replica.acknowledge(position),
+        request => {
+            return Err(KvError::Protocol(format!(
+                "unexpected request {:?} on a replication stream",
+                request
+            )))
+        }
+    }
+    Ok(true)
+}
+
+/// Apply the writes of the primary at `address` to `store`, until promoted or the server
+/// stops. Reconnects when the connection fails, resuming from the last position applied.
+pub(crate) fn follow<E: KvsEngine>(
+    store: Replicated<E>,
+    address: SocketAddr,
+    options: ClientOption,
+    active: Arc<AtomicBool>,
+) {
+    let following = || active.load(Ordering::SeqCst) && store.replication.primary().is_some();
+
+    let mut position = None;
+    let mut retry = MIN_RETRY_INTERVAL;
+    while following() {
+        let before = position;
+        match follow_once(&store, address, &options, &following, &mut position) {
+            Ok(()) => info!(primary = %address, "replication stream closed"),
+            Err(e) => warn!(primary = %address, error = %e, "replication failed:"),
+        }
+        lock(&store.replication.upstream).take();
+
+        // Back off while the primary cannot be reached, not after a working stream broke.
+        if position != before {
+            retry = MIN_RETRY_INTERVAL;
+        }
+        let deadline = Instant::now() + retry;
+        while following() && Instant::now() < deadline {
+            thread::sleep(STOP_CHECK_INTERVAL);
+        }
+        retry = (retry * 2).min(MAX_RETRY_INTERVAL);
+    }
+    info!(primary = %address, "stopped following primary");
+}
+
+fn follow_once<E: KvsEngine>(
+    store: &Replicated<E>,
+    address: SocketAddr,
+    options: &ClientOption,
+    following: &impl Fn() -> bool,
+    position: &mut Option<LogPosition>,
+) -> Result<()> {
+    let (mut stream, socket) = KvsClient::connect_with_options(address, options)?.into_parts();
+    socket.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    *lock(&store.replication.upstream) = Some(socket.try_clone()?);
+    // Promoted or stopped before the connection could be shut down.
+    if !following() {
+        return Ok(());
+    }
+
+    let request = KvsRequest::Sync {
+        position: *position,
+    };
+    stream.write_all(&Message::encode(None, &request)?)?;
+    stream.flush()?;
+    info!(primary = %address, position = ?position, "following primary");
+
+    let engine = &store.engine;
+    // Keys received by the snapshot in progress.
+    let mut snapshot: Option<HashSet<String>> = None;
+    while let Some(message) = codec::read_message(&mut stream)? {
+        if !following() {
+            break;
+        }
+        let applied = match message.parse::<KvsResponse>()?.into_result()? {
+            KvsResponse::Snapshot {
+                entries,
+                last,
+                position,
+            } => {
+                let received = snapshot.get_or_insert_with(HashSet::new);
+                for entry in entries {
+                    if let KvsRequest::Set { key, value } = entry {
+                        received.insert(key.clone());
+                        engine.set(key, value)?;
+                    }
+                }
+                if last {
+                    for key in engine.keys()? {
+                        if !received.contains(&key) {
+                            remove(engine, key)?;
+                        }
+                    }
+                    snapshot = None;
+                    Some(position)
+                } else {
+                    None
+                }
+            }
+            KvsResponse::Commands { commands, position } => {
+                for command in commands {
+                    match command {
+                        KvsRequest::Set { key, value } => engine.set(key, value)?,
+                        KvsRequest::Remove { key } => remove(engine, key)?,
+                        command => {
+                            return Err(KvError::Protocol(format!(
+                                "unexpected replicated command {:?}",
+                                command
+                            )))
+                        }
+                    }
+                }
+                Some(position)
+            }
+            response => {
+                return Err(KvError::Protocol(format!(
+                    "unexpected replication response {:?}",
+                    response
+                )))
+            }
+        };
+
+        if let Some(applied) = applied {
+            *position = Some(applied);
+            let ack = KvsRequest::Ack { position: applied };
+            stream.write_all(&Message::encode(None, &ack)?)?;
+            stream.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a key, which may already be gone when a write is replayed.
+fn remove<E: KvsEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key) {
+        Err(KvError::KeyNotFound(_)) => Ok(()),
+        result => result,
+    }
+}
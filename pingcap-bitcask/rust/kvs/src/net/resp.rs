@@ -0,0 +1,436 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Read, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use glob::Pattern;
+
+use super::protocol::{self, ErrorCode};
+use crate::{KvError, KvsEngine, Result};
+
+/// Longest line accepted, for inline commands and the headers of multi bulk commands.
+const MAX_LINE_SIZE: usize = 64 * 1024;
+/// Largest bulk string accepted, same as the frame bodies of the kvs protocol.
+const MAX_BULK_SIZE: usize = 16 * 1024 * 1024;
+/// Most arguments accepted in one command.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+/// Keys looked at by one `SCAN` without a `COUNT`, same as Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 reply.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            // Line breaks would end the error early.
+            Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(writer))
+            }
+        }
+    }
+}
+
+impl From<KvError> for Reply {
+    fn from(error: KvError) -> Self {
+        let prefix = match ErrorCode::from(&error) {
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::Unauthenticated => "NOAUTH",
+            ErrorCode::ReadOnly => "READONLY",
+            ErrorCode::NotLeader => "NOTLEADER",
+            _ => "ERR",
+        };
+        Reply::Error(format!("{} {}", prefix, error))
+    }
+}
+
+fn protocol_error(message: &str) -> KvError {
+    KvError::Protocol(message.to_owned())
+}
+
+/// Read one line, without its line ending. `None` if the stream ends before it starts.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() >= MAX_LINE_SIZE {
+            protocol_error("too big line")
+        } else {
+            io::Error::from(io::ErrorKind::UnexpectedEof).into()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parse the number following the type byte of a header line.
+fn header_length(line: &[u8], kind: u8, max: usize, name: &str) -> Result<i64> {
+    let invalid = || protocol_error(&format!("invalid {} length", name));
+    match line.split_first() {
+        Some((first, length)) if *first == kind => {
+            let length: i64 = std::str::from_utf8(length)
+                .ok()
+                .and_then(|length| length.parse().ok())
+                .ok_or_else(invalid)?;
+            if length > max as i64 {
+                return Err(invalid());
+            }
+            Ok(length)
+        }
+        _ => Err(protocol_error(&format!(
+            "expected '{}', got '{}'",
+            kind as char,
+            String::from_utf8_lossy(line)
+        ))),
+    }
+}
+
+fn read_bulk<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
+    let line = read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    let length = header_length(&line, b'$', MAX_BULK_SIZE, "bulk")?;
+    if length < 0 {
+        return Err(protocol_error("invalid bulk length"));
+    }
+
+    let mut bulk = vec![0; length as usize + 2];
+    reader.read_exact(&mut bulk)?;
+    if !bulk.ends_with(b"\r\n") {
+        return Err(protocol_error("bulk not terminated by CRLF"));
+    }
+    bulk.truncate(length as usize);
+    Ok(bulk)
+}
+
+/// Read the arguments of the next command, either a multi bulk array as sent by client
+/// libraries, or an inline command as typed in telnet.
+/// `None` if the stream ends before a command starts.
+pub(crate) fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let arguments = if line.first() == Some(&b'*') {
+            let count = header_length(&line, b'*', MAX_ARGUMENTS, "multibulk")?;
+            (0..count)
+                .map(|_| read_bulk(reader))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            line.split(u8::is_ascii_whitespace)
+                .filter(|argument| !argument.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect()
+        };
+
+        // Empty commands are ignored, like Redis does.
+        if !arguments.is_empty() {
+            return Ok(Some(arguments));
+        }
+    }
+}
+
+/// Deadlines set by `EXPIRE`, which [`KvsEngine`] has no notion of.
+///
+/// Kept in memory and shared by every connection of a server, so they are lost on restart.
+/// Expired keys are removed from the engine the next time a command reads them.
+#[derive(Debug, Default)]
+pub(crate) struct Expirations {
+    deadlines: Mutex<HashMap<String, Instant>>,
+}
+
+impl Expirations {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.deadlines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set(&self, key: String, deadline: Instant) {
+        self.lock().insert(key, deadline);
+    }
+
+    fn clear(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    /// Whether the key has expired, forgetting its deadline if so.
+    fn take_expired(&self, key: &str) -> bool {
+        let mut deadlines = self.lock();
+        match deadlines.get(key) {
+            Some(deadline) if *deadline <= Instant::now() => {
+                deadlines.remove(key);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The engine seen through Redis commands, with expired keys hidden.
+struct Keyspace<'a, E> {
+    store: &'a E,
+    expirations: &'a Expirations,
+}
+
+impl<E: KvsEngine> Keyspace<'_, E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if self.expirations.take_expired(key) {
+            self.remove(key)?;
+            return Ok(None);
+        }
+        self.store.get(key.to_owned())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    fn set(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        self.store.set(key.clone(), value)?;
+        match ttl {
+            Some(ttl) => self.expirations.set(key, Instant::now() + ttl),
+            None => self.expirations.clear(&key),
+        }
+        Ok(())
+    }
+
+    /// Remove a key, returning whether it existed and had not expired.
+    fn remove(&self, key: &str) -> Result<bool> {
+        let expired = self.expirations.take_expired(key);
+        self.expirations.clear(key);
+        match self.store.remove(key.to_owned()) {
+            Ok(()) => Ok(!expired),
+            Err(KvError::KeyNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn parse_integer<T: std::str::FromStr>(argument: &str) -> std::result::Result<T, Reply> {
+    argument.parse().map_err(|_| not_an_integer())
+}
+
+/// What one RESP connection knows about its client.
+#[derive(Debug)]
+pub(crate) struct RespSession {
+    /// Token the client must present with `AUTH`, if the server requires one.
+    token: Option<Arc<str>>,
+    authenticated: bool,
+}
+
+impl RespSession {
+    pub fn new(token: Option<Arc<str>>) -> RespSession {
+        RespSession {
+            token,
+            authenticated: false,
+        }
+    }
+
+    /// Run one command against `store`.
+    pub fn execute<E: KvsEngine>(
+        &mut self,
+        store: &E,
+        expirations: &Expirations,
+        arguments: Vec<Vec<u8>>,
+    ) -> Reply {
+        let arguments = match arguments
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+        {
+            Ok(arguments) => arguments,
+            Err(_) => return Reply::Error("ERR arguments must be valid utf-8".to_owned()),
+        };
+        let keyspace = Keyspace { store, expirations };
+
+        match self.run(&keyspace, &arguments) {
+            Ok(reply) => reply,
+            Err(reply) => reply,
+        }
+    }
+
+    fn run<E: KvsEngine>(
+        &mut self,
+        keyspace: &Keyspace<'_, E>,
+        arguments: &[String],
+    ) -> std::result::Result<Reply, Reply> {
+        let name = arguments[0].to_ascii_lowercase();
+        let arguments = &arguments[1..];
+
+        if name == "auth" {
+            return self.auth(arguments);
+        }
+        if self.token.is_some() && !self.authenticated {
+            return Err(Reply::Error("NOAUTH Authentication required.".to_owned()));
+        }
+
+        let wrong_arity = || {
+            Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ))
+        };
+        let reply = match (name.as_str(), arguments) {
+            ("ping", []) => Reply::Simple("PONG"),
+            ("ping", [message]) => Reply::Bulk(Some(message.clone())),
+            ("get", [key]) => Reply::Bulk(keyspace.get(key)?),
+            ("set", [key, value, options @ ..]) => {
+                let ttl = match options {
+                    [] => None,
+                    [unit, amount] => {
+                        let amount: u64 = parse_integer(amount)?;
+                        if amount == 0 {
+                            return Err(Reply::Error(
+                                "ERR invalid expire time in 'set' command".to_owned(),
+                            ));
+                        }
+                        match unit.to_ascii_lowercase().as_str() {
+                            "ex" => Some(Duration::from_secs(amount)),
+                            "px" => Some(Duration::from_millis(amount)),
+                            _ => return Err(syntax_error()),
+                        }
+                    }
+                    _ => return Err(syntax_error()),
+                };
+                keyspace.set(key.clone(), value.clone(), ttl)?;
+                Reply::Simple("OK")
+            }
+            ("del", keys) if !keys.is_empty() => {
+                let mut removed = 0;
+                for key in keys {
+                    removed += keyspace.remove(key)? as i64;
+                }
+                Reply::Integer(removed)
+            }
+            ("exists", keys) if !keys.is_empty() => {
+                let mut existing = 0;
+                for key in keys {
+                    existing += keyspace.exists(key)? as i64;
+                }
+                Reply::Integer(existing)
+            }
+            ("expire", [key, seconds]) => {
+                let seconds: i64 = parse_integer(seconds)?;
+                if !keyspace.exists(key)? {
+                    Reply::Integer(0)
+                } else if seconds <= 0 {
+                    keyspace.remove(key)?;
+                    Reply::Integer(1)
+                } else {
+                    let deadline = Instant::now() + Duration::from_secs(seconds as u64);
+                    keyspace.expirations.set(key.clone(), deadline);
+                    Reply::Integer(1)
+                }
+            }
+            ("scan", [cursor, options @ ..]) => scan(keyspace, cursor, options)?,
+            ("ping" | "get" | "set" | "del" | "exists" | "expire" | "scan", _) => {
+                return Err(wrong_arity())
+            }
+            _ => return Err(Reply::Error(format!("ERR unknown command '{}'", name))),
+        };
+        Ok(reply)
+    }
+
+    /// `AUTH [username] password`, the username is ignored.
+    fn auth(&mut self, arguments: &[String]) -> std::result::Result<Reply, Reply> {
+        let password = match arguments {
+            [password] | [_, password] => password,
+            _ => {
+                return Err(Reply::Error(
+                    "ERR wrong number of arguments for 'auth' command".to_owned(),
+                ))
+            }
+        };
+        match &self.token {
+            None => Err(Reply::Error(
+                "ERR AUTH called without any password configured".to_owned(),
+            )),
+            Some(token) if protocol::same_token(password.as_bytes(), token.as_bytes()) => {
+                self.authenticated = true;
+                Ok(Reply::Simple("OK"))
+            }
+            Some(_) => {
+                self.authenticated = false;
+                Err(Reply::Error(
+                    "WRONGPASS invalid username-password pair".to_owned(),
+                ))
+            }
+        }
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`.
+///
+/// The cursor is the position in the engine's sorted keys, keys set or removed between calls
+/// may shift it, so a scan may miss or repeat them.
+fn scan<E: KvsEngine>(
+    keyspace: &Keyspace<'_, E>,
+    cursor: &str,
+    options: &[String],
+) -> std::result::Result<Reply, Reply> {
+    let cursor: usize = cursor
+        .parse()
+        .map_err(|_| Reply::Error("ERR invalid cursor".to_owned()))?;
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("match") => {
+                pattern = Some(Pattern::new(value).map_err(|_| syntax_error())?);
+            }
+            [name, value] if name.eq_ignore_ascii_case("count") => {
+                count = parse_integer(value)?;
+                if count == 0 {
+                    return Err(syntax_error());
+                }
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let keys = keyspace.store.keys()?;
+    let end = cursor.saturating_add(count).min(keys.len());
+    let mut found = Vec::new();
+    for key in keys.get(cursor..end).unwrap_or_default() {
+        if pattern.as_ref().is_none_or(|pattern| pattern.matches(key)) && keyspace.exists(key)? {
+            found.push(Reply::Bulk(Some(key.clone())));
+        }
+    }
+    let next = if end < keys.len() { end } else { 0 };
+
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string())),
+        Reply::Array(found),
+    ]))
+}
@@ -1,22 +1,200 @@
-use tracing::info;
+use mio::{Events, Interest, Poll, Token, Waker};
+use tracing::{info, warn};
 
-use crate::{parser::ByteParser, thread_pool::ThreadPool, KvsEngine, Result};
+use crate::{
+    raft::{NodeId, RaftNode, RaftOption},
+    thread_pool::{PoolMetrics, QueuePolicy, ThreadPool},
+    ClientOption, KvError, KvsEngine, Result, Stats,
+};
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::Path,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{
+    cluster::Cluster,
+    codec::{self, Message},
+    gateway,
+    http::{self, HttpRequest},
+    protocol::{self, KvsRequest, KvsResponse, Session},
+    replication::{self, Replicated, Replication},
+    resp::{self, Expirations, Reply, RespSession},
+    tls::TlsAcceptor,
+    transport::{BufStream, Transport},
+    watch,
 };
 
-use super::protocol::{KvsRequest, KvsResponse};
+/// Close connections which stay idle, or take longer than this to send a request.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long shutdown waits for open connections before closing them.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often idle connections check whether the server is shutting down.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+/// Longest wait for the first request of a connection rejected while the pool is busy,
+/// it holds up accepting other connections.
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Listeners use the token of their index.
+const WAKER: Token = Token(usize::MAX);
+
+/// Blocking accept on several listeners, which another thread can interrupt through a [`Waker`].
+///
+/// Connections come with the tag of the listener which accepted them.
+struct Acceptor<T> {
+    poll: Poll,
+    events: Events,
+    listeners: Vec<(mio::net::TcpListener, T)>,
+}
+
+impl<T: Copy> Acceptor<T> {
+    fn new(listener: TcpListener, tag: T) -> Result<(Acceptor<T>, Arc<Waker>)> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+        let mut acceptor = Acceptor {
+            poll,
+            events: Events::with_capacity(16),
+            listeners: Vec::new(),
+        };
+        acceptor.add(listener, tag)?;
+        Ok((acceptor, waker))
+    }
+
+    /// Also accept connections from `listener`.
+    fn add(&mut self, listener: TcpListener, tag: T) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let mut listener = mio::net::TcpListener::from_std(listener);
+        self.poll.registry().register(
+            &mut listener,
+            Token(self.listeners.len()),
+            Interest::READABLE,
+        )?;
+        self.listeners.push((listener, tag));
+        Ok(())
+    }
+
+    /// Wait for the next connection, `None` once woken up.
+    fn accept(&mut self) -> Option<io::Result<(T, TcpStream)>> {
+        loop {
+            for (listener, tag) in &self.listeners {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let stream = TcpStream::from(stream);
+                        return Some(stream.set_nonblocking(false).map(|_| (*tag, stream)));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            // Readiness is edge triggered, only wait once every pending connection is accepted.
+            match self.poll.poll(&mut self.events, None) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            }
+            if self.events.iter().any(|event| event.token() == WAKER) {
+                return None;
+            }
+        }
+    }
+}
+
+/// Connections accepted and not closed yet, so shutdown can wait for them or close them.
+#[derive(Debug, Default)]
+struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, TcpStream>>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, TcpStream>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn register(&self, stream: &TcpStream) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(id, stream.try_clone()?);
+        Ok(id)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.lock().remove(&id);
+        self.closed.notify_all();
+    }
+
+    /// Wait until every connection is closed, return the number still open at the deadline.
+    fn drain(&self, timeout: Duration) -> usize {
+        let open = self.lock();
+        let (open, _) = self
+            .closed
+            .wait_timeout_while(open, timeout, |open| !open.is_empty())
+            .unwrap_or_else(|e| e.into_inner());
+        open.len()
+    }
+
+    fn close_all(&self) {
+        for stream in self.lock().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics {
+    pub active_connections: AtomicU64,
+    pub total_connections: AtomicU64,
+    /// Pool running the connections, tokio's for async servers is not tracked.
+    pub pool: Option<Arc<PoolMetrics>>,
+}
+
+impl ServerMetrics {
+    /// Engine stats, with the server's connection counts and pool.
+    pub fn stats<E: KvsEngine>(&self, store: &E) -> Result<Stats> {
+        Ok(self.with_connections(store.stats()?))
+    }
 
-#[derive(Debug)]
-enum ServerMessage {
-    Shutdown,
+    /// Fill in the server's connection counts and pool.
+    pub fn with_connections(&self, stats: Stats) -> Stats {
+        Stats {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            pool: self.pool.as_ref().map_or(stats.pool, |pool| pool.stats()),
+            ..stats
+        }
+    }
+}
+
+/// Protocol a server speaks with its clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Framed bson requests, as sent by [`KvsClient`](crate::KvsClient).
+    #[default]
+    Kvs,
+    /// RESP2, the Redis protocol, so `redis-cli` and Redis client libraries can be used.
+    ///
+    /// Supports `GET`, `SET` (with `EX` or `PX`), `DEL`, `EXISTS`, `PING`, `SCAN`, `EXPIRE`
+    /// and `AUTH` when a token is required.
+    /// Expirations are kept in memory by the server, they do not survive a restart.
+    Resp,
+}
+
+/// What the connections of a listener expect.
+#[derive(Debug, Clone, Copy)]
+enum Endpoint {
+    /// Clients of the server's [`Protocol`].
+    Clients,
+    /// The HTTP gateway.
+    Http,
 }
 
 /// Server directly interacts with on-disk database to serve clients' requests.
@@ -29,9 +207,34 @@ where
 {
     /// The address at which the server is opened.
     pub address: SocketAddr,
-    listener: TcpListener,
+    listener: (Acceptor<Endpoint>, Arc<Waker>),
     store: E,
     pool: P,
+    metrics_listener: Option<(SocketAddr, Acceptor<()>, Arc<Waker>)>,
+    http_address: Option<SocketAddr>,
+    primary: Option<(SocketAddr, ClientOption)>,
+    raft: Option<RaftSettings<E>>,
+    settings: ConnectionSettings,
+    drain_timeout: Duration,
+}
+
+/// Membership of a Raft cluster.
+struct RaftSettings<E: KvsEngine> {
+    node: RaftNode<E>,
+    members: HashMap<NodeId, SocketAddr>,
+    /// What members prove who they are with.
+    token: String,
+    /// How to connect to the other members.
+    options: ClientOption,
+}
+
+/// What every connection of a server shares.
+#[derive(Debug, Clone)]
+struct ConnectionSettings {
+    read_timeout: Duration,
+    tls: Option<TlsAcceptor>,
+    token: Option<Arc<str>>,
+    protocol: Protocol,
 }
 
 impl<E, P> KvsServer<E, P>
@@ -41,6 +244,9 @@ where
 {
     /// Open server at provided address.
     ///
+    /// Fails with [`KvError::InvalidArgument`] for a pool running jobs on the spawning thread
+    /// when full: connections would then be served on the thread accepting them.
+    ///
     /// # Example
     /// ```rust
     /// # use kvs::Result;
@@ -57,129 +263,661 @@ where
     /// # Ok(())
     /// # }
     pub fn open(address: SocketAddr, store: E, pool: P) -> Result<KvsServer<E, P>> {
+        if pool.when_full() == QueuePolicy::CallerRuns {
+            return Err(KvError::InvalidArgument(
+                "a server cannot run connections on its accept thread, reject them instead"
+                    .to_owned(),
+            ));
+        }
         let listener = TcpListener::bind(address)?;
         let address = listener.local_addr()?;
 
         let server = KvsServer {
             address,
-            listener,
+            listener: Acceptor::new(listener, Endpoint::Clients)?,
             store,
             pool,
+            metrics_listener: None,
+            http_address: None,
+            primary: None,
+            raft: None,
+            settings: ConnectionSettings {
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                tls: None,
+                token: None,
+                protocol: Protocol::Kvs,
+            },
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
         };
         info!(addr = %address,  "server started");
 
         Ok(server)
     }
 
+    /// Also serve stats at `GET /metrics` over HTTP, in Prometheus text format.
+    pub fn with_metrics(mut self, address: SocketAddr) -> Result<KvsServer<E, P>> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let (acceptor, waker) = Acceptor::new(listener, ())?;
+        info!(addr = %address, "metrics endpoint started");
+        self.metrics_listener = Some((address, acceptor, waker));
+        Ok(self)
+    }
+
+    /// Also serve the store over HTTP with json bodies at this address, on the same pool.
+    ///
+    /// - `GET`, `PUT` and `DELETE /keys/{key}`,
+    /// - `GET /keys?prefix=..&cursor=..&limit=..` to list keys,
+    /// - `POST /batch` for several writes,
+    /// - `GET /stats`.
+    ///
+    /// TLS and the token apply too, the token as an `Authorization: Bearer` header.
+    pub fn with_http(mut self, address: SocketAddr) -> Result<KvsServer<E, P>> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        self.listener.0.add(listener, Endpoint::Http)?;
+        info!(addr = %address, "http gateway started");
+        self.http_address = Some(address);
+        Ok(self)
+    }
+
+    /// Close connections which stay idle, or take longer than `timeout` to send a request.
+    /// Defaults to 60 seconds.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.settings.read_timeout = timeout;
+        self
+    }
+
+    /// Only accept TLS connections, presenting the PEM encoded certificate chain and
+    /// private key found in these files.
+    pub fn with_tls<C, K>(mut self, certificate: C, key: K) -> Result<KvsServer<E, P>>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let acceptor = TlsAcceptor::from_pem_files(certificate.as_ref(), key.as_ref())?;
+        self.settings.tls = Some(acceptor);
+        Ok(self)
+    }
+
+    /// Require clients to present `token` in the handshake, before any other request.
+    /// Without TLS, the token travels in clear.
+    pub fn with_token(mut self, token: String) -> KvsServer<E, P> {
+        self.settings.token = Some(token.into());
+        self
+    }
+
+    /// Speak `protocol` with clients instead of the kvs protocol.
+    pub fn with_protocol(mut self, protocol: Protocol) -> KvsServer<E, P> {
+        self.settings.protocol = protocol;
+        self
+    }
+
+    /// Run as a replica of the primary at `address`, connecting to it with `options`.
+    ///
+    /// The replica applies the primary's writes to its store, serves reads and refuses
+    /// writes, until promoted by a client presenting the token of [`KvsServer::with_token`].
+    /// The primary must use the kvs engine, the replica any engine.
+    /// A replica starting afresh, or whose primary restarted, first receives a snapshot.
+    pub fn with_primary(mut self, address: SocketAddr, options: ClientOption) -> KvsServer<E, P> {
+        self.primary = Some((address, options));
+        self.raft = None;
+        self
+    }
+
+    /// Run as member `id` of a Raft cluster, made of `members` with their client address,
+    /// this server included. Other members are connected to with `options`, whose token
+    /// members share and prove who they are with.
+    ///
+    /// Writes are accepted by the leader once a majority of members stores them, and reads
+    /// are served by the leader too. Other members answer with the leader's address, which
+    /// [`KvsClient`](crate::KvsClient) follows.
+    ///
+    /// The term, vote and log are saved in the folder at `path`, which a restarted member
+    /// starts again from. Raft messages are only accepted from connections which presented
+    /// the token as the member sending them, from the address of that member.
+    pub fn with_raft<Q: AsRef<Path>>(
+        mut self,
+        id: u64,
+        members: Vec<(u64, SocketAddr)>,
+        options: ClientOption,
+        path: Q,
+    ) -> Result<KvsServer<E, P>> {
+        let count = members.len();
+        let members: HashMap<NodeId, SocketAddr> = members.into_iter().collect();
+        if members.len() != count {
+            return Err(KvError::InvalidArgument(
+                "raft member ids must be unique".to_owned(),
+            ));
+        }
+        if !members.contains_key(&id) {
+            return Err(KvError::InvalidArgument(format!(
+                "raft member {} is not in the cluster",
+                id
+            )));
+        }
+        let token = match &options.token {
+            Some(token) if !token.is_empty() => token.clone(),
+            _ => {
+                return Err(KvError::InvalidArgument(
+                    "raft members need a token to authenticate each other".to_owned(),
+                ))
+            }
+        };
+        let node = RaftNode::open(
+            id,
+            members.keys().copied().collect(),
+            self.store.clone(),
+            RaftOption::new(),
+            path,
+        )?;
+        self.raft = Some(RaftSettings {
+            node,
+            members,
+            token,
+            options,
+        });
+        self.primary = None;
+        Ok(self)
+    }
+
+    /// How long [`RunningServer::shutdown`] waits for open connections to finish
+    /// before closing them. Defaults to 5 seconds.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.drain_timeout = timeout;
+        self
+    }
+
     /// Start listening for incoming requests.
     pub fn serve(self) -> RunningServer {
         info!("serving");
 
-        let (sender, receiver) = mpsc::channel();
-
         let KvsServer {
             address,
             listener,
             store,
             pool,
+            metrics_listener,
+            http_address,
+            primary,
+            raft,
+            settings,
+            drain_timeout,
         } = self;
 
         let active = Arc::new(AtomicBool::new(true));
+        let replication = Arc::new(Replication::new(
+            primary.as_ref().map(|(address, _)| *address),
+        ));
+        let mut wakers = Vec::new();
+        let mut jobs = Vec::new();
 
-        let waiting_server = RunningServer {
-            address,
-            active: active.clone(),
-            receiver,
-        };
+        let mut store = Replicated::new(store, replication.clone());
+        if let Some(RaftSettings {
+            node,
+            members,
+            token,
+            options,
+        }) = raft
+        {
+            let (cluster, threads) = Cluster::start(node, members, token, options, active.clone());
+            store.cluster = Some(cluster);
+            jobs.extend(threads);
+        }
+        let metrics = Arc::new(ServerMetrics {
+            pool: Some(pool.metrics()),
+            ..ServerMetrics::default()
+        });
+        let connections = Arc::new(Connections::default());
+        let expirations = Arc::new(Expirations::default());
 
-        thread::spawn(move || loop {
-            if !active.load(Ordering::SeqCst) {
-                sender
-                    .send(ServerMessage::Shutdown)
-                    .expect("cannot notify shutdown message");
-                break;
+        let metrics_address = match metrics_listener {
+            Some((metrics_address, acceptor, waker)) => {
+                let store = store.clone();
+                let metrics = metrics.clone();
+                wakers.push(waker);
+                jobs.push(spawn_named("kvs-metrics", move || {
+                    serve_metrics(acceptor, store, metrics)
+                }));
+                Some(metrics_address)
             }
+            None => None,
+        };
 
-            listener
-                .set_nonblocking(true)
-                .expect("cannot set listener as unblocking");
+        if let Some((address, options)) = primary {
+            let store = store.clone();
+            let active = active.clone();
+            jobs.push(spawn_named("kvs-replica", move || {
+                replication::follow(store, address, options, active)
+            }));
+        }
 
-            if let Ok((stream, _)) = listener.accept() {
-                let store = store.clone();
+        let (mut acceptor, waker) = listener;
+        wakers.push(waker);
+        {
+            let active = active.clone();
+            let connections = connections.clone();
+            jobs.push(spawn_named("kvs-accept", move || {
+                while let Some(accepted) = acceptor.accept() {
+                    let (endpoint, stream) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!(error = %e, "cannot accept connection:");
+                            continue;
+                        }
+                    };
+                    let id = match connections.register(&stream) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            warn!(error = %e, "cannot track connection:");
+                            continue;
+                        }
+                    };
 
-                let active = active.clone();
+                    // Kept to answer the connection if no worker can take it.
+                    let socket = match stream.try_clone() {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            warn!(error = %e, "cannot serve connection:");
+                            connections.unregister(id);
+                            continue;
+                        }
+                    };
 
-                pool.spawn(move || {
-                    let _ = handle_connection(store, stream, active);
-                })
-            }
-        });
+                    let job = {
+                        let store = store.clone();
+                        let active = active.clone();
+                        let metrics = metrics.clone();
+                        let connections = connections.clone();
+                        let settings = settings.clone();
+                        let expirations = expirations.clone();
 
-        waiting_server
+                        move || {
+                            metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+                            metrics.total_connections.fetch_add(1, Ordering::Relaxed);
+                            let result = match (endpoint, settings.protocol) {
+                                (Endpoint::Http, _) => handle_http_connection(
+                                    store, stream, &active, &settings, &metrics,
+                                ),
+                                (Endpoint::Clients, Protocol::Kvs) => {
+                                    handle_connection(store, stream, &active, &settings, &metrics)
+                                }
+                                (Endpoint::Clients, Protocol::Resp) => handle_resp_connection(
+                                    store,
+                                    stream,
+                                    &active,
+                                    &settings,
+                                    &expirations,
+                                ),
+                            };
+                            if let Err(e) = result {
+                                warn!(error = %e, "connection closed:");
+                            }
+                            metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+                            connections.unregister(id);
+                        }
+                    };
+
+                    match pool.try_spawn(job) {
+                        Ok(()) => {}
+                        Err(e @ KvError::Busy(_)) => {
+                            if let Err(e) = reject(endpoint, socket, &settings, e) {
+                                warn!(error = %e, "cannot reject connection:");
+                            }
+                            connections.unregister(id);
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "cannot serve connection:");
+                            connections.unregister(id);
+                        }
+                    }
+                }
+                info!("stopped accepting connections");
+            }));
+        }
+
+        RunningServer {
+            address,
+            metrics_address,
+            http_address,
+            active,
+            replication,
+            wakers,
+            jobs,
+            connections,
+            drain_timeout,
+        }
     }
 }
 
 /// Controller returned when serving, this helps shutting down server programmatically.
 pub struct RunningServer {
+    /// The address at which the server is opened.
     pub address: SocketAddr,
+    /// The address of the metrics endpoint, if enabled.
+    pub metrics_address: Option<SocketAddr>,
+    /// The address of the HTTP gateway, if enabled.
+    pub http_address: Option<SocketAddr>,
     active: Arc<AtomicBool>,
-    receiver: Receiver<ServerMessage>,
+    replication: Arc<Replication>,
+    wakers: Vec<Arc<Waker>>,
+    jobs: Vec<JoinHandle<()>>,
+    connections: Arc<Connections>,
+    drain_timeout: Duration,
 }
 
 impl RunningServer {
+    /// Stop accepting connections, let open connections finish their current request,
+    /// then close whatever is still open once the drain timeout passes.
     pub fn shutdown(self) {
         info!(address = %self.address, "shutdown server");
 
         // Tell everyone to stop
         self.active.store(false, Ordering::SeqCst);
+        for waker in &self.wakers {
+            if let Err(e) = waker.wake() {
+                warn!(error = %e, "cannot wake listener:");
+            }
+        }
+        self.replication.disconnect();
+        for job in self.jobs {
+            let _ = job.join();
+        }
 
         // Waiting everyone to stop
-        while let Ok(msg) = self.receiver.recv() {
-            if matches!(msg, ServerMessage::Shutdown) {
-                break;
+        let remaining = self.connections.drain(self.drain_timeout);
+        if remaining > 0 {
+            warn!(
+                connections = remaining,
+                "drain timed out, closing connections:"
+            );
+            self.connections.close_all();
+        }
+    }
+}
+
+pub(crate) fn spawn_named<F>(name: &str, f: F) -> JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .expect("cannot spawn server thread")
+}
+
+/// Wait until the next request starts arriving.
+/// Returns `false` once the client disconnects, stays idle for too long, or the server stops.
+fn wait_for_request(
+    reader: &mut BufStream,
+    socket: &TcpStream,
+    active: &AtomicBool,
+    read_timeout: Duration,
+) -> Result<bool> {
+    socket.set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL.min(read_timeout)))?;
+
+    let idle_since = Instant::now();
+    while active.load(Ordering::SeqCst) {
+        match reader.fill_buf() {
+            Ok(buf) => return Ok(!buf.is_empty()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if idle_since.elapsed() >= read_timeout {
+                    info!(peer = ?socket.peer_addr().ok(), "closing idle connection:");
+                    return Ok(false);
+                }
             }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
         }
     }
+
+    Ok(false)
+}
+
+/// Accept a connection, with TLS if enabled.
+fn accept_stream(socket: &TcpStream, settings: &ConnectionSettings) -> Result<BufStream> {
+    info!(
+        peer = %socket.peer_addr()?,
+        tls = settings.tls.is_some(),
+        protocol = ?settings.protocol,
+        "connection accepted:"
+    );
+
+    let transport: Box<dyn Transport> = match &settings.tls {
+        Some(tls) => Box::new(tls.accept(socket.try_clone()?)?),
+        None => Box::new(socket.try_clone()?),
+    };
+    Ok(BufStream::new(transport))
+}
+
+/// Answer the first request of a connection no worker can take with `error`, then close it.
+/// TLS connections are closed right away, their handshake would hold up accepting others.
+fn reject(
+    endpoint: Endpoint,
+    socket: TcpStream,
+    settings: &ConnectionSettings,
+    error: KvError,
+) -> Result<()> {
+    info!(peer = ?socket.peer_addr().ok(), error = %error, "rejecting connection:");
+    if settings.tls.is_some() {
+        return Ok(());
+    }
+    socket.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    socket.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    let mut stream = BufStream::new(Box::new(socket));
+
+    // Requests are read so that closing does not reset the connection before the answer arrives.
+    match (endpoint, settings.protocol) {
+        (Endpoint::Http, _) => {
+            HttpRequest::from_reader(&mut stream)?;
+            let response = gateway::error_response(&error, None);
+            http::write_response(
+                &mut stream,
+                response.status,
+                "application/json",
+                &response.body,
+                false,
+            )?;
+        }
+        (Endpoint::Clients, Protocol::Kvs) => {
+            let id = codec::read_message(&mut stream)?.and_then(|message| message.id());
+            stream.write_all(&encode_response(id, &error.into()))?;
+        }
+        (Endpoint::Clients, Protocol::Resp) => {
+            resp::read_command(&mut stream)?;
+            Reply::from(error).write_to(&mut stream)?;
+        }
+    }
+    stream.flush()?;
+    Ok(())
 }
 
 fn handle_connection<E: KvsEngine>(
-    store: E,
-    stream: TcpStream,
-    active: Arc<AtomicBool>,
+    store: Replicated<E>,
+    socket: TcpStream,
+    active: &AtomicBool,
+    settings: &ConnectionSettings,
+    metrics: &ServerMetrics,
 ) -> Result<()> {
-    let mut reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
+    let mut stream = accept_stream(&socket, settings)?;
+    let mut session = Session::new(settings.token.clone());
+    session.peer = socket.peer_addr().ok().map(|address| address.ip());
+    let read_timeout = settings.read_timeout;
 
-    info!(peer = %stream.peer_addr().unwrap(), "connection accepted:");
+    while wait_for_request(&mut stream, &socket, active, read_timeout)? {
+        // The request has started, the rest of it must arrive in time.
+        socket.set_read_timeout(Some(read_timeout))?;
 
-    while active.load(Ordering::SeqCst) {
-        let request = KvsRequest::from_reader(&mut reader);
-        info!(request = ?request, "request:");
+        let (id, response) = match codec::read_message(&mut stream) {
+            Ok(Some(message)) => {
+                let request = message.parse::<KvsRequest>();
+                info!(id = ?message.id(), request = ?request, "request:");
+                let response = match request {
+                    Ok(KvsRequest::Sync { position }) if session.may_replicate() => {
+                        stream.flush()?;
+                        return replication::serve_replica(
+                            &store,
+                            &mut stream,
+                            &socket,
+                            message.id(),
+                            position,
+                            active,
+                        );
+                    }
+                    Ok(KvsRequest::Watch { prefix }) if session.may_watch() => {
+                        stream.flush()?;
+                        return watch::serve_watcher(
+                            &store,
+                            &mut stream,
+                            &socket,
+                            message.id(),
+                            prefix,
+                            active,
+                        );
+                    }
+                    Ok(request) => handle_request(&store, request, &mut session, metrics),
+                    Err(e) => KvsResponse::InvalidCommand(e.to_string()),
+                };
+                (message.id(), response)
+            }
+            Ok(None) => break,
+            Err(e) => {
+                // The stream cannot be trusted to be at a request boundary anymore.
+                let response = KvsResponse::InvalidCommand(e.to_string());
+                stream.write_all(&encode_response(None, &response))?;
+                break;
+            }
+        };
 
-        let response = match request {
-            Ok(request) => handle_request(&store, request),
-            Err(e) => KvsResponse::InvalidCommand(e.to_string()),
+        stream.write_all(&encode_response(id, &response))?;
+        if session.is_closing() {
+            break;
+        }
+        // Pipelined requests are answered together, once every request received is handled.
+        if stream.buffer().is_empty() {
+            stream.flush()?;
+        }
+    }
+    stream.flush()?;
+
+    Ok(())
+}
+
+fn handle_resp_connection<E: KvsEngine>(
+    store: E,
+    socket: TcpStream,
+    active: &AtomicBool,
+    settings: &ConnectionSettings,
+    expirations: &Expirations,
+) -> Result<()> {
+    let mut stream = accept_stream(&socket, settings)?;
+    let mut session = RespSession::new(settings.token.clone());
+    let read_timeout = settings.read_timeout;
+
+    while wait_for_request(&mut stream, &socket, active, read_timeout)? {
+        socket.set_read_timeout(Some(read_timeout))?;
+
+        let reply = match resp::read_command(&mut stream) {
+            Ok(Some(command)) => {
+                info!(command = %String::from_utf8_lossy(&command[0]), "request:");
+                session.execute(&store, expirations, command)
+            }
+            Ok(None) => break,
+            Err(e) => {
+                // Like Redis, protocol errors close the connection.
+                Reply::Error(format!("ERR Protocol error: {}", e)).write_to(&mut stream)?;
+                break;
+            }
         };
+        info!(reply = ?reply, "response:");
 
-        let bytes = response.to_bytes().unwrap_or_else(|_| {
-            KvsResponse::ServerError
-                .to_bytes()
-                .expect("parser simple response")
-        });
+        reply.write_to(&mut stream)?;
+        if stream.buffer().is_empty() {
+            stream.flush()?;
+        }
+    }
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Answer one request of the HTTP gateway, then close the connection.
+fn handle_http_connection<E: KvsEngine>(
+    store: E,
+    socket: TcpStream,
+    active: &AtomicBool,
+    settings: &ConnectionSettings,
+    metrics: &ServerMetrics,
+) -> Result<()> {
+    let mut stream = accept_stream(&socket, settings)?;
+
+    while wait_for_request(&mut stream, &socket, active, settings.read_timeout)? {
+        // The request has started, the rest of it must arrive in time.
+        socket.set_read_timeout(Some(settings.read_timeout))?;
+
+        let (response, keep_alive) = match HttpRequest::from_reader(&mut stream) {
+            Ok(request) => {
+                info!(method = %request.method, path = %request.path, "http request:");
+                let response =
+                    gateway::handle(&store, &request, settings.token.as_deref(), metrics);
+                (response, request.keep_alive())
+            }
+            // The stream cannot be trusted to be at a request boundary anymore.
+            Err(e) => {
+                let response = gateway::Response {
+                    status: e.status(),
+                    ..gateway::error_response(&KvError::InvalidArgument(e.to_string()), None)
+                };
+                (response, false)
+            }
+        };
+        info!(status = response.status, "http response:");
 
-        // check connection before writing
-        if stream.peer_addr().is_err() {
+        // Once the server stops, the connection is closed after its current request.
+        let keep_alive = keep_alive && active.load(Ordering::SeqCst);
+        http::write_response(
+            &mut stream,
+            response.status,
+            "application/json",
+            &response.body,
+            keep_alive,
+        )?;
+        if !keep_alive {
             break;
         }
-        writer.write_all(&bytes)?;
-        writer.flush()?;
     }
-
     Ok(())
 }
 
-fn handle_request<E: KvsEngine>(store: &E, request: KvsRequest) -> KvsResponse {
+/// Encode a response to the request `id`, falling back to `ServerError` if it cannot be encoded.
+pub(crate) fn encode_response(id: Option<u64>, response: &KvsResponse) -> Vec<u8> {
+    Message::encode(id, response).unwrap_or_else(|_| {
+        Message::encode(id, &KvsResponse::ServerError {}).expect("parser simple response")
+    })
+}
+
+fn handle_request<E: KvsEngine>(
+    store: &Replicated<E>,
+    request: KvsRequest,
+    session: &mut Session,
+    metrics: &ServerMetrics,
+) -> KvsResponse {
+    if let Err(response) = session.authorize(&request) {
+        return session.downgrade(response);
+    }
+
     let res = match request {
+        KvsRequest::Hello {
+            version,
+            features,
+            token,
+        } => Ok(session.handshake(version, features, token)),
+
         KvsRequest::Get { key } => store
             .get(key.clone())
             .map(KvsResponse::Ok)
@@ -194,13 +932,141 @@ fn handle_request<E: KvsEngine>(store: &E, request: KvsRequest) -> KvsResponse {
             .remove(key)
             .map(|_| KvsResponse::Ok(None))
             .map_err(KvsResponse::from),
+
+        KvsRequest::Stats {} => metrics
+            .stats(store)
+            .map(|stats| KvsResponse::Stats(Box::new(stats)))
+            .map_err(KvsResponse::from),
+
+        // Only with the token, anyone reaching the server could otherwise take writes over.
+        KvsRequest::Promote {} if !session.is_authenticated() => {
+            session.close();
+            Err(KvError::Unauthenticated("promoting needs the server's token".to_owned()).into())
+        }
+        KvsRequest::Promote {} => match store.replication.promote() {
+            true => Ok(KvsResponse::Ok(None)),
+            false => {
+                Err(KvError::InvalidArgument("the server is already a primary".to_owned()).into())
+            }
+        },
+
+        // Only valid as the first request of a connection on protocol version 4.
+        KvsRequest::Sync { .. } => Err(KvsResponse::InvalidCommand(
+            "replication needs protocol version 4".to_owned(),
+        )),
+
+        // Only valid on protocol version 7.
+        KvsRequest::Watch { .. } => Err(KvsResponse::InvalidCommand(
+            "watching keys needs protocol version 7".to_owned(),
+        )),
+
+        KvsRequest::Ack { .. } => Err(KvsResponse::InvalidCommand(
+            "acknowledgement outside of a replication stream".to_owned(),
+        )),
+
+        KvsRequest::Scan {
+            prefix,
+            cursor,
+            limit,
+        } => protocol::scan_limit(limit)
+            .and_then(|limit| {
+                let keys = store.keys()?;
+                Ok(protocol::scan(&keys, &prefix, cursor.as_deref(), limit))
+            })
+            .map(|(keys, cursor)| KvsResponse::Keys { keys, cursor })
+            .map_err(KvsResponse::from),
+
+        KvsRequest::Member { id, token } => match &store.cluster {
+            Some(cluster) => match cluster.admit(id, &token, session.peer) {
+                Ok(()) => {
+                    session.member = Some(id);
+                    Ok(KvsResponse::Ok(None))
+                }
+                Err(e) => {
+                    session.close();
+                    Err(e.into())
+                }
+            },
+            None => Err(KvsResponse::InvalidCommand(
+                "not a member of a raft cluster".to_owned(),
+            )),
+        },
+
+        KvsRequest::Raft { message } => match &store.cluster {
+            Some(cluster) if session.member == Some(message.from) => {
+                cluster.step(message);
+                Ok(KvsResponse::Ok(None))
+            }
+            Some(_) => {
+                session.close();
+                Err(KvError::Unauthenticated(
+                    "raft messages are only accepted from the member sending them".to_owned(),
+                )
+                .into())
+            }
+            None => Err(KvsResponse::InvalidCommand(
+                "not a member of a raft cluster".to_owned(),
+            )),
+        },
     };
+    session.started();
 
     let res = match res {
         Ok(res) => res,
         Err(res) => res,
     };
+    let res = session.downgrade(res);
     info!(res = ?res, "response:");
 
     res
 }
+
+fn serve_metrics<E: KvsEngine>(mut acceptor: Acceptor<()>, store: E, metrics: Arc<ServerMetrics>) {
+    while let Some(accepted) = acceptor.accept() {
+        let result = accepted
+            .map(|((), stream)| stream)
+            .map_err(Into::into)
+            .and_then(|stream| handle_metrics(&store, stream, &metrics));
+        if let Err(e) = result {
+            warn!(error = %e, "cannot serve metrics:");
+        }
+    }
+}
+
+fn handle_metrics<E: KvsEngine>(
+    store: &E,
+    stream: TcpStream,
+    metrics: &ServerMetrics,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let request = HttpRequest::from_reader(&mut BufReader::new(&stream));
+    let mut writer = BufWriter::new(&stream);
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            let body = e.to_string();
+            return http::write_response(
+                &mut writer,
+                e.status(),
+                "text/plain",
+                body.as_bytes(),
+                false,
+            );
+        }
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = metrics.stats(store)?.to_prometheus();
+            http::write_response(
+                &mut writer,
+                200,
+                "text/plain; version=0.0.4",
+                body.as_bytes(),
+                false,
+            )
+        }
+        _ => http::write_response(&mut writer, 404, "text/plain", b"not found", false),
+    }
+}
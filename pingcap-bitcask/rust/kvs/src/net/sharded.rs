@@ -0,0 +1,279 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use tracing::info;
+
+use super::protocol::MAX_SCAN_LIMIT;
+use crate::{ClientOption, KvError, KvsClient, Result};
+
+/// Points each server gets on the ring, more spread keys more evenly.
+const VIRTUAL_NODES: usize = 128;
+
+/// Servers placed on a ring of hashes, each key belongs to the first server point at or
+/// after its hash.
+#[derive(Debug, Clone, Default)]
+struct HashRing {
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+impl HashRing {
+    fn add(&mut self, server: SocketAddr) {
+        for i in 0..VIRTUAL_NODES {
+            self.points
+                .insert(hash(format!("{}#{}", server, i).as_bytes()), server);
+        }
+    }
+
+    fn remove(&mut self, server: SocketAddr) {
+        self.points.retain(|_, point| *point != server);
+    }
+
+    fn owner(&self, key: &str) -> Option<SocketAddr> {
+        let hash = hash(key.as_bytes());
+        self.points
+            .range(hash..)
+            .chain(self.points.iter())
+            .next()
+            .map(|(_, server)| *server)
+    }
+}
+
+/// FNV-1a, with a final mix so close inputs land far apart.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Client spreading keys over several servers by consistent hashing.
+///
+/// Each key lives on one server, picked from its hash. Adding or removing a server only
+/// moves the keys between it and its neighbours on the ring, see
+/// [`ShardedKvsClient::add_server`].
+///
+/// Every client of the same servers routes keys the same way, whatever the order servers
+/// are listed in.
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    /// One connection per server, opened again on the next request once it fails.
+    connections: BTreeMap<SocketAddr, Option<KvsClient>>,
+    options: ClientOption,
+}
+
+impl ShardedKvsClient {
+    /// Connect to every server.
+    pub fn connect(servers: &[SocketAddr]) -> Result<ShardedKvsClient> {
+        ShardedKvsClient::connect_with_options(servers, &ClientOption::new())
+    }
+
+    /// Connect to every server, over TLS or with a token if configured.
+    pub fn connect_with_options(
+        servers: &[SocketAddr],
+        options: &ClientOption,
+    ) -> Result<ShardedKvsClient> {
+        if servers.is_empty() {
+            return Err(KvError::InvalidArgument(
+                "at least one server is needed".to_owned(),
+            ));
+        }
+        let mut client = ShardedKvsClient {
+            ring: HashRing::default(),
+            connections: BTreeMap::new(),
+            options: options.clone(),
+        };
+        for server in servers {
+            client.connections.insert(*server, None);
+            client.ring.add(*server);
+            client.connection(*server)?;
+        }
+        Ok(client)
+    }
+
+    /// Servers keys are spread over.
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        self.connections.keys().copied().collect()
+    }
+
+    /// Server holding `key`.
+    pub fn server_for(&self, key: &str) -> SocketAddr {
+        self.ring
+            .owner(key)
+            .expect("a sharded client has at least one server")
+    }
+
+    /// Set the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let server = self.server_for(&key);
+        self.on(server, |client| client.set(key, value))
+    }
+
+    /// Get the value of a key, `None` if it does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let server = self.server_for(&key);
+        self.on(server, |client| client.get(key))
+    }
+
+    /// Remove a key, failing with [`KvError::KeyNotFound`] if it does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let server = self.server_for(&key);
+        self.on(server, |client| client.remove(key))
+    }
+
+    /// List at most `limit` keys starting with `prefix` in order, after `cursor`, from every
+    /// server. Returns them with the cursor of the next page, `None` once every key is listed.
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let mut keys = Vec::new();
+        let mut more = false;
+        for server in self.servers() {
+            let (page, next) = self.on(server, |client| {
+                client.scan(prefix.clone(), cursor.clone(), limit)
+            })?;
+            keys.extend(page);
+            more |= next.is_some();
+        }
+        keys.sort_unstable();
+
+        // Keys left on any server come after the last of the first `limit` keys merged.
+        let limit = limit as usize;
+        more |= keys.len() > limit;
+        keys.truncate(limit);
+        let cursor = if more { keys.last().cloned() } else { None };
+        Ok((keys, cursor))
+    }
+
+    /// Every key starting with `prefix`, in order.
+    pub fn keys(&mut self, prefix: String) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for server in self.servers() {
+            keys.extend(self.server_keys(server, &prefix)?);
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Spread keys over one more server, moving the keys it now holds from the other servers.
+    /// Returns the number of keys moved.
+    ///
+    /// Other clients must not write while keys move, and must then add the server too.
+    /// If moving fails, calling it again moves the remaining keys.
+    pub fn add_server(&mut self, server: SocketAddr) -> Result<usize> {
+        if !self.connections.contains_key(&server) {
+            let client = KvsClient::connect_with_options(server, &self.options)?;
+            self.connections.insert(server, Some(client));
+        }
+        let sources: Vec<SocketAddr> = self
+            .servers()
+            .into_iter()
+            .filter(|source| *source != server)
+            .collect();
+
+        let mut ring = self.ring.clone();
+        ring.remove(server);
+        ring.add(server);
+        let moved = self.migrate(&ring, &sources)?;
+        self.ring = ring;
+        info!(server = %server, moved, "server added");
+        Ok(moved)
+    }
+
+    /// Stop using a server, moving its keys to the servers now holding them.
+    /// Returns the number of keys moved.
+    ///
+    /// Other clients must not write while keys move, and must then remove the server too.
+    pub fn remove_server(&mut self, server: SocketAddr) -> Result<usize> {
+        if !self.connections.contains_key(&server) {
+            return Ok(0);
+        }
+        if self.connections.len() == 1 {
+            return Err(KvError::InvalidArgument(
+                "cannot remove the last server".to_owned(),
+            ));
+        }
+
+        let mut ring = self.ring.clone();
+        ring.remove(server);
+        let moved = self.migrate(&ring, &[server])?;
+        self.ring = ring;
+        self.connections.remove(&server);
+        info!(server = %server, moved, "server removed");
+        Ok(moved)
+    }
+
+    /// Move the keys of `sources` which `ring` places on another server.
+    fn migrate(&mut self, ring: &HashRing, sources: &[SocketAddr]) -> Result<usize> {
+        let mut moved = 0;
+        for source in sources {
+            for key in self.server_keys(*source, "")? {
+                let owner = ring.owner(&key).expect("the ring has servers");
+                if owner == *source {
+                    continue;
+                }
+                let value = match self.on(*source, |client| client.get(key.clone()))? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                self.on(owner, |client| client.set(key.clone(), value))?;
+                match self.on(*source, |client| client.remove(key)) {
+                    Ok(()) | Err(KvError::KeyNotFound(_)) => {}
+                    Err(e) => return Err(e),
+                }
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Every key of one server starting with `prefix`.
+    fn server_keys(&mut self, server: SocketAddr, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = self.on(server, |client| {
+                client.scan(prefix.to_owned(), cursor.take(), MAX_SCAN_LIMIT as u32)
+            })?;
+            keys.extend(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(keys),
+            }
+        }
+    }
+
+    /// Connection to `server`, opened if needed.
+    fn connection(&mut self, server: SocketAddr) -> Result<&mut KvsClient> {
+        let options = &self.options;
+        let connection = self
+            .connections
+            .get_mut(&server)
+            .ok_or_else(|| KvError::InvalidArgument(format!("unknown server {}", server)))?;
+        if connection.is_none() {
+            *connection = Some(KvsClient::connect_with_options(server, options)?);
+        }
+        Ok(connection.as_mut().expect("connection just opened"))
+    }
+
+    /// Run `f` on the connection to `server`, dropping the connection if it breaks.
+    fn on<T, F>(&mut self, server: SocketAddr, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvsClient) -> Result<T>,
+    {
+        let result = f(self.connection(server)?);
+        if let Err(KvError::Io(_)) | Err(KvError::Protocol(_)) = result {
+            if let Some(connection) = self.connections.get_mut(&server) {
+                *connection = None;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::Result;
+
+fn invalid_pem(path: &Path, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} in `{}`", message, path.display()),
+    )
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certificates.is_empty() {
+        return Err(invalid_pem(path, "no certificate").into());
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?;
+    Ok(key.ok_or_else(|| invalid_pem(path, "no private key"))?)
+}
+
+/// Server side of TLS connections.
+#[derive(Debug, Clone)]
+pub(crate) struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Present the PEM encoded certificate chain and private key found in these files.
+    pub fn from_pem_files(certificate: &Path, key: &Path) -> Result<TlsAcceptor> {
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(read_certificates(certificate)?, read_private_key(key)?)?;
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Wrap an accepted connection, the TLS handshake happens on first use.
+    pub fn accept(&self, stream: TcpStream) -> Result<StreamOwned<ServerConnection, TcpStream>> {
+        let connection = ServerConnection::new(self.config.clone())?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// Client side of TLS connections.
+#[derive(Debug, Clone)]
+pub(crate) struct TlsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    /// Trust servers whose certificate is signed by one of the PEM encoded certificates in `ca`.
+    pub fn from_ca_file(ca: &Path) -> Result<TlsConnector> {
+        let mut roots = RootCertStore::empty();
+        for certificate in read_certificates(ca)? {
+            roots.add(certificate)?;
+        }
+
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(TlsConnector {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Wrap a connection to `server_name`, the TLS handshake happens on first use.
+    pub fn connect(
+        &self,
+        server_name: ServerName<'static>,
+        stream: TcpStream,
+    ) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+        let connection = ClientConnection::new(self.config.clone(), server_name)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// A connection to a peer, plain or encrypted.
+pub(crate) trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+/// Buffered reads and writes over one transport.
+///
+/// An encrypted stream cannot be split into a reader and a writer, so both buffers live here.
+/// Writes are kept until [`flush`][Write::flush], which sends them all at once.
+pub(crate) struct BufStream {
+    reader: BufReader<Box<dyn Transport>>,
+    writes: Vec<u8>,
+}
+
+impl BufStream {
+    pub fn new(transport: Box<dyn Transport>) -> BufStream {
+        BufStream {
+            reader: BufReader::new(transport),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Bytes received and not read yet.
+    pub fn buffer(&self) -> &[u8] {
+        self.reader.buffer()
+    }
+}
+
+impl Read for BufStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl BufRead for BufStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+impl Write for BufStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let transport = self.reader.get_mut();
+        transport.write_all(&self.writes)?;
+        self.writes.clear();
+        transport.flush()
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::TcpStream,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use tracing::info;
+
+use super::{
+    client::unexpected,
+    codec,
+    protocol::KvsResponse,
+    replication::{send, Replicated},
+    server::encode_response,
+    transport::BufStream,
+};
+use crate::{KeyChange, KvError, KvsEngine, Result, Subscription};
+
+/// Changes waiting to be sent to a watching connection before it falls behind.
+pub(crate) const WATCH_BUFFER: usize = 1024;
+/// How often an idle watch checks whether the client left or the server stops.
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+/// Most changes sent between checks of the server stopping.
+const MAX_BATCH: usize = 128;
+
+/// Stream the changes to the keys starting with `prefix` to the client which sent `Watch`
+/// as request `id`, until it disconnects, falls behind or the server stops.
+pub(crate) fn serve_watcher<E: KvsEngine>(
+    store: &Replicated<E>,
+    stream: &mut BufStream,
+    socket: &TcpStream,
+    id: Option<u64>,
+    prefix: String,
+    active: &AtomicBool,
+) -> Result<()> {
+    let subscription = match store.subscribe(prefix.clone(), WATCH_BUFFER) {
+        Some(subscription) => subscription,
+        None => {
+            let error = KvError::InvalidArgument("this engine cannot be watched".to_owned());
+            return send(stream, id, &error.into());
+        }
+    };
+    send(stream, id, &KvsResponse::Watching {})?;
+
+    let peer = socket.peer_addr()?;
+    info!(peer = %peer, prefix = %prefix, "watching keys:");
+    while active.load(Ordering::SeqCst) {
+        let first = match subscription.recv_timeout(CHECK_INTERVAL) {
+            Ok(Some(change)) => change,
+            Ok(None) => {
+                if !connected(stream, socket, id)? {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => {
+                info!(peer = %peer, error = %e, "watch closed:");
+                return send(stream, id, &e.into());
+            }
+        };
+
+        send_changes(stream, id, &subscription, Some(first), MAX_BATCH)?;
+    }
+    // Changes made before the server stopped.
+    if !active.load(Ordering::SeqCst) {
+        send_changes(stream, id, &subscription, None, WATCH_BUFFER)?;
+    }
+
+    info!(peer = %peer, "watcher disconnected");
+    Ok(())
+}
+
+/// Send `first` and the changes buffered after it, `limit` of them at most.
+fn send_changes(
+    stream: &mut BufStream,
+    id: Option<u64>,
+    subscription: &Subscription,
+    first: Option<KeyChange>,
+    limit: usize,
+) -> Result<()> {
+    let mut change = first.or_else(|| subscription.try_recv().unwrap_or(None));
+    let mut sent = 0;
+    while let Some(KeyChange { key, value }) = change {
+        let response = KvsResponse::KeyChanged { key, value };
+        stream.write_all(&encode_response(id, &response))?;
+        sent += 1;
+        // An overflow is reported by the next wait.
+        change = if sent < limit {
+            subscription.try_recv().unwrap_or(None)
+        } else {
+            None
+        };
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Whether the client is still there. Clients send nothing on a watch stream,
+/// a request is answered with an error and ends the stream.
+fn connected(stream: &mut BufStream, socket: &TcpStream, id: Option<u64>) -> Result<bool> {
+    socket.set_read_timeout(Some(Duration::from_millis(1)))?;
+    match stream.fill_buf() {
+        Ok([]) => Ok(false),
+        Ok(_) => {
+            let error =
+                KvError::InvalidArgument("no request can be sent while watching".to_owned());
+            send(stream, id, &error.into())?;
+            Ok(false)
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(true)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Changes to keys pushed by a server, see [`KvsClient::watch`][crate::KvsClient::watch].
+pub struct Watcher {
+    stream: BufStream,
+    socket: TcpStream,
+    /// Id of the `Watch` request, carried by every change.
+    id: Option<u64>,
+}
+
+impl Watcher {
+    pub(crate) fn new(stream: BufStream, socket: TcpStream, id: Option<u64>) -> Watcher {
+        Watcher { stream, socket, id }
+    }
+
+    /// Wait for the next change.
+    ///
+    /// Fails with [`KvError::Overflowed`] once the server dropped changes this watcher did
+    /// not read in time, the watch is over then.
+    pub fn recv(&mut self) -> Result<KeyChange> {
+        self.socket.set_read_timeout(None)?;
+        self.read()
+    }
+
+    /// Wait at most `timeout` for the next change, `None` if none was made.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<KeyChange>> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        match self.stream.fill_buf() {
+            Ok([]) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // The change started arriving, wait for the rest of it.
+        self.socket.set_read_timeout(None)?;
+        self.read().map(Some)
+    }
+
+    fn read(&mut self) -> Result<KeyChange> {
+        let message = codec::read_message(&mut self.stream)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if message.id() != self.id {
+            return Err(KvError::Protocol(format!(
+                "expected a change to watch {:?}, got {:?}",
+                self.id,
+                message.id()
+            )));
+        }
+        match message.parse::<KvsResponse>()?.into_result()? {
+            KvsResponse::KeyChanged { key, value } => Ok(KeyChange { key, value }),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<KeyChange>;
+
+    /// The next change, `None` once the server closed the watch.
+    fn next(&mut self) -> Option<Result<KeyChange>> {
+        match self.recv() {
+            Err(KvError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            result => Some(result),
+        }
+    }
+}
@@ -1,3 +1,10 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    log::vfs::{FaultyFs, Fs, StdFs},
+    thread_pool::QueuePolicy,
+};
+
 /// Provide database configuration.
 #[derive(Debug, Clone)]
 pub struct KvOption {
@@ -6,6 +13,12 @@ pub struct KvOption {
 
     /// Maximum writer size in bytes.
     pub(crate) writer_size: usize,
+
+    /// Whether merges run on a background thread.
+    pub(crate) background_merge: bool,
+
+    /// File system used for writing logs.
+    pub(crate) fs: Fs,
 }
 
 impl Default for KvOption {
@@ -13,6 +26,8 @@ impl Default for KvOption {
         KvOption {
             num_readers: 10,
             writer_size: 1024 * 1024, // 1 Mb
+            background_merge: true,
+            fs: Arc::new(StdFs),
         }
     }
 }
@@ -34,4 +49,198 @@ impl KvOption {
         self.writer_size = active_datafile_size;
         self
     }
+
+    /// Run merges on a background thread, or block the write which triggers them.
+    pub fn background_merge(&mut self, background_merge: bool) -> &mut KvOption {
+        self.background_merge = background_merge;
+        self
+    }
+
+    /// Write logs through a fault injecting file system.
+    #[doc(hidden)]
+    pub fn faulty_fs(&mut self, fs: FaultyFs) -> &mut KvOption {
+        self.fs = Arc::new(fs);
+        self
+    }
+}
+
+/// Provide client connection configuration.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOption {
+    /// PEM file of the certificates trusted to sign the server's, connect with TLS if set.
+    pub(crate) tls_ca: Option<PathBuf>,
+
+    /// Name the server's certificate must be valid for, defaults to its ip address.
+    pub(crate) server_name: Option<String>,
+
+    /// Token presented in the handshake.
+    pub(crate) token: Option<String>,
+}
+
+impl ClientOption {
+    /// Construct a new option with default value, a plain connection without token.
+    pub fn new() -> ClientOption {
+        ClientOption::default()
+    }
+
+    /// Connect with TLS, trusting servers whose certificate is signed by one in this PEM file.
+    pub fn tls_ca<P: Into<PathBuf>>(&mut self, path: P) -> &mut ClientOption {
+        self.tls_ca = Some(path.into());
+        self
+    }
+
+    /// Check the server's certificate against this name instead of its ip address.
+    pub fn server_name(&mut self, name: String) -> &mut ClientOption {
+        self.server_name = Some(name);
+        self
+    }
+
+    /// Authenticate with this token.
+    pub fn token(&mut self, token: String) -> &mut ClientOption {
+        self.token = Some(token);
+        self
+    }
+}
+
+/// Provide connection pool configuration, see [`KvsClientPool`](crate::KvsClientPool).
+#[derive(Debug, Clone)]
+pub struct PoolOption {
+    /// Most connections open at once.
+    pub(crate) max_size: usize,
+
+    /// Close connections left unused for this long.
+    pub(crate) idle_timeout: Duration,
+
+    /// Longest wait for a response, or for a connection once `max_size` are in use.
+    pub(crate) request_timeout: Option<Duration>,
+
+    /// Attempts after the first one, for requests which can be sent again safely.
+    pub(crate) max_retries: usize,
+
+    /// Delay before the first retry, doubled after each failure up to `max_backoff`.
+    pub(crate) min_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+
+    /// Whether a `Set` may be sent again after a failure.
+    pub(crate) retry_sets: bool,
+}
+
+impl Default for PoolOption {
+    fn default() -> PoolOption {
+        PoolOption {
+            max_size: 8,
+            idle_timeout: Duration::from_secs(60),
+            request_timeout: Some(Duration::from_secs(5)),
+            max_retries: 3,
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            retry_sets: false,
+        }
+    }
+}
+
+impl PoolOption {
+    /// Construct a new option with default value.
+    pub fn new() -> PoolOption {
+        PoolOption::default()
+    }
+
+    /// Set the most connections open at once.
+    pub fn max_size(&mut self, max_size: usize) -> &mut PoolOption {
+        self.max_size = max_size.max(1);
+        self
+    }
+
+    /// Close connections left unused for this long.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut PoolOption {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Fail requests whose response takes longer than this, `None` to wait forever.
+    /// Also bounds the wait for a connection when all are in use.
+    pub fn request_timeout(&mut self, timeout: Option<Duration>) -> &mut PoolOption {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Set the attempts after the first one, for requests which can be sent again safely.
+    pub fn max_retries(&mut self, retries: usize) -> &mut PoolOption {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Wait `min` before the first retry, doubling after each failure up to `max`.
+    pub fn backoff(&mut self, min: Duration, max: Duration) -> &mut PoolOption {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// Also retry `Set`, which is safe as long as no other client writes the same key.
+    /// `Remove` is never retried once sent, a second attempt would fail with `KeyNotFound`.
+    pub fn retry_sets(&mut self, retry: bool) -> &mut PoolOption {
+        self.retry_sets = retry;
+        self
+    }
+}
+
+/// Provide thread pool configuration, see
+/// [`SharedQueueThreadPool`](crate::thread_pool::SharedQueueThreadPool).
+#[derive(Debug, Clone)]
+pub struct ThreadPoolOption {
+    /// Most jobs waiting for a worker, unbounded if `None`.
+    pub(crate) queue_size: Option<usize>,
+
+    /// What happens to a job spawned while the queue is full.
+    pub(crate) when_full: QueuePolicy,
+
+    /// Most workers started when jobs wait, the pool does not grow if `None`.
+    pub(crate) max_threads: Option<u32>,
+
+    /// Workers started when jobs waited stop once idle for this long.
+    pub(crate) idle_timeout: Duration,
+}
+
+impl Default for ThreadPoolOption {
+    fn default() -> ThreadPoolOption {
+        ThreadPoolOption {
+            queue_size: None,
+            when_full: QueuePolicy::Block,
+            max_threads: None,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ThreadPoolOption {
+    /// Construct a new option with default value, an unbounded queue and a fixed number of workers.
+    pub fn new() -> ThreadPoolOption {
+        ThreadPoolOption::default()
+    }
+
+    /// Set the most jobs waiting for a worker, `None` for no limit.
+    /// With `0`, jobs are only handed to idle workers.
+    pub fn queue_size(&mut self, queue_size: Option<usize>) -> &mut ThreadPoolOption {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Choose what happens to a job spawned while the queue is full.
+    pub fn when_full(&mut self, policy: QueuePolicy) -> &mut ThreadPoolOption {
+        self.when_full = policy;
+        self
+    }
+
+    /// Start more workers while jobs wait for one, up to `max_threads` in total.
+    pub fn max_threads(&mut self, max_threads: u32) -> &mut ThreadPoolOption {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Stop the workers started when jobs waited once they stay idle for this long.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut ThreadPoolOption {
+        self.idle_timeout = timeout;
+        self
+    }
 }
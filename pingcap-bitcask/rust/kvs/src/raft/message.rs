@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+/// Identifies a node of a cluster.
+pub type NodeId = u64;
+
+/// Write applied to the state machine of every node, once committed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RaftCommand {
+    /// Set a key.
+    Set {
+        /// Key to set.
+        key: String,
+        /// Its new value.
+        value: String,
+    },
+    /// Remove a key.
+    Remove {
+        /// Key to remove.
+        key: String,
+    },
+    /// Changes nothing, appended by new leaders and to order reads after every write.
+    Noop {},
+}
+
+/// Entry of the replicated log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Position in the log, starting at 1.
+    pub index: u64,
+    /// Term of the leader which appended it.
+    pub term: u64,
+    /// Write to apply.
+    pub command: RaftCommand,
+}
+
+/// Every key and value of a state machine, once the entries up to `index` are applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Last entry included.
+    pub index: u64,
+    /// Term of the last entry included.
+    pub term: u64,
+    /// Keys and their values.
+    pub entries: Vec<(String, String)>,
+}
+
+/// Message between two nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    /// Sender.
+    pub from: NodeId,
+    /// Recipient.
+    pub to: NodeId,
+    /// Term of the sender.
+    pub term: u64,
+    /// What the message is about.
+    pub body: MessageBody,
+}
+
+/// Kinds of messages, as described in the Raft paper.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageBody {
+    /// A candidate asks for a vote.
+    RequestVote {
+        /// Index of the candidate's last entry.
+        last_index: u64,
+        /// Term of the candidate's last entry.
+        last_term: u64,
+    },
+    /// Answer to [`MessageBody::RequestVote`].
+    Vote {
+        /// Whether the vote is granted.
+        granted: bool,
+    },
+    /// The leader replicates entries following `prev_index`, empty as a heartbeat.
+    Append {
+        /// Index of the entry preceding `entries`.
+        prev_index: u64,
+        /// Term of the entry preceding `entries`.
+        prev_term: u64,
+        /// Entries to append.
+        entries: Vec<Entry>,
+        /// Index of the leader's last committed entry.
+        commit: u64,
+        /// Last round of reads of the leader, echoed back to confirm it still leads.
+        #[serde(default)]
+        read: u64,
+    },
+    /// Answer to [`MessageBody::Append`] and [`MessageBody::InstallSnapshot`].
+    AppendResult {
+        /// Whether the entries were appended.
+        success: bool,
+        /// Last entry known to match the leader's log when successful,
+        /// otherwise the last entry the leader should try next.
+        match_index: u64,
+        /// Round of reads of the [`MessageBody::Append`] answered.
+        #[serde(default)]
+        read: u64,
+    },
+    /// The leader replaces the state of a follower which is too far behind.
+    InstallSnapshot {
+        /// State to install.
+        snapshot: Snapshot,
+    },
+}
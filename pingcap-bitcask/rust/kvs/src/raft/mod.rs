@@ -0,0 +1,45 @@
+//! Raft consensus, replicating writes to a [`KvsEngine`](crate::KvsEngine) on every node of a cluster.
+//!
+//! [`RaftNode`] implements leader election, log replication and snapshots. Its only I/O is
+//! saving its state, when opened on a folder. `kvs-server` drives it over TCP, and
+//! [`SimNetwork`] drives several nodes in one process over a simulated network which can
+//! partition, delay and drop messages.
+
+mod message;
+mod node;
+mod sim;
+mod storage;
+
+pub use message::{Entry, Message, MessageBody, NodeId, RaftCommand, Snapshot};
+pub use node::{Applied, RaftNode, RaftOption, ReadReady, Role};
+pub use sim::SimNetwork;
+
+/// Small xorshift generator, for election timeouts and the simulated network.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Xorshift never leaves zero, and close seeds give close first values.
+        let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1);
+        rng.next();
+        rng
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Whether an event with `probability` happens.
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    /// Uniform in `[low, high]`.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+}
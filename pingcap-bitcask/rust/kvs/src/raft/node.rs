@@ -0,0 +1,1064 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use tracing::{error, info, warn};
+
+use super::{
+    message::{Entry, Message, MessageBody, NodeId, RaftCommand, Snapshot},
+    storage::{HardState, RaftStorage},
+    Rng,
+};
+use crate::{KvError, KvsEngine, Result};
+
+/// Timing and sizes of a [`RaftNode`], counted in ticks of its driver.
+#[derive(Debug, Clone)]
+pub struct RaftOption {
+    /// Ticks without hearing from a leader before starting an election,
+    /// randomized up to twice as many.
+    pub(crate) election_ticks: u64,
+
+    /// Ticks between two heartbeats of a leader.
+    pub(crate) heartbeat_ticks: u64,
+
+    /// Most entries sent in one message.
+    pub(crate) max_append_entries: usize,
+
+    /// Entries kept in the log before dropping the applied ones.
+    pub(crate) max_log_entries: usize,
+}
+
+impl Default for RaftOption {
+    fn default() -> RaftOption {
+        RaftOption {
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            max_append_entries: 64,
+            max_log_entries: 1024,
+        }
+    }
+}
+
+impl RaftOption {
+    /// Construct a new option with default value.
+    pub fn new() -> RaftOption {
+        RaftOption::default()
+    }
+
+    /// Set the ticks without hearing from a leader before starting an election.
+    pub fn election_ticks(&mut self, ticks: u64) -> &mut RaftOption {
+        self.election_ticks = ticks.max(1);
+        self
+    }
+
+    /// Set the ticks between two heartbeats of a leader, less than the election ticks.
+    pub fn heartbeat_ticks(&mut self, ticks: u64) -> &mut RaftOption {
+        self.heartbeat_ticks = ticks.max(1);
+        self
+    }
+
+    /// Set the most entries sent in one message.
+    pub fn max_append_entries(&mut self, entries: usize) -> &mut RaftOption {
+        self.max_append_entries = entries.max(1);
+        self
+    }
+
+    /// Set the entries kept in the log. Followers missing dropped entries get a snapshot.
+    pub fn max_log_entries(&mut self, entries: usize) -> &mut RaftOption {
+        self.max_log_entries = entries;
+        self
+    }
+}
+
+/// Role of a node in its current term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Follows the leader's log.
+    Follower,
+    /// Asks for votes to become the leader.
+    Candidate,
+    /// Accepts writes and replicates them.
+    Leader,
+}
+
+/// Outcome of an entry proposed by this node.
+#[derive(Debug)]
+pub struct Applied {
+    /// Index of the entry.
+    pub index: u64,
+    /// Term the entry was proposed in.
+    pub term: u64,
+    /// Result of applying it to the state machine.
+    /// [`KvError::NotLeader`] if another leader replaced the entry.
+    pub result: Result<()>,
+}
+
+/// Outcome of a read asked of this node.
+#[derive(Debug)]
+pub struct ReadReady {
+    /// Id returned by [`RaftNode::read`].
+    pub id: u64,
+    /// Ok once the state machine holds every entry committed before the read was asked,
+    /// [`KvError::NotLeader`] if the node stopped leading first.
+    pub result: Result<()>,
+}
+
+/// Read waiting for a majority to confirm the leader.
+#[derive(Debug, Clone, Copy)]
+struct PendingRead {
+    id: u64,
+    /// Round of heartbeats confirming it, 0 until one is sent.
+    round: u64,
+}
+
+/// Entries following a compacted prefix.
+#[derive(Debug, Default)]
+struct RaftLog {
+    /// Last entry dropped, its state is in the state machine.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    entries: Vec<Entry>,
+}
+
+impl RaftLog {
+    fn first_index(&self) -> u64 {
+        self.snapshot_index + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// Term of the entry at `index`, `None` if it is not in the log anymore or yet.
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let position = index.checked_sub(self.first_index())?;
+        self.entries.get(position as usize)
+    }
+
+    fn slice(&self, from: u64, max: usize) -> Vec<Entry> {
+        let start = (from - self.first_index()) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// Drop the entries from `index` on.
+    fn truncate(&mut self, index: u64) {
+        let len = (index - self.first_index()) as usize;
+        self.entries.truncate(len);
+    }
+
+    /// Drop the entries up to `index`, which is applied.
+    fn compact(&mut self, index: u64, term: u64) {
+        let dropped = (index - self.snapshot_index) as usize;
+        self.entries.drain(..dropped.min(self.entries.len()));
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+}
+
+/// What a leader knows of a follower's log.
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    /// Next entry to send.
+    next: u64,
+    /// Last entry known to match.
+    matched: u64,
+    /// Last round of reads answered.
+    read: u64,
+}
+
+/// A member of a Raft cluster, applying committed entries to its [`KvsEngine`].
+///
+/// The node keeps no clock and does no network I/O: its driver calls [`RaftNode::tick`] at a regular
+/// interval, passes it the messages of other nodes with [`RaftNode::step`], and delivers the
+/// messages returned by [`RaftNode::take_messages`].
+///
+/// A node made with [`RaftNode::new`] keeps its state in memory. One made with
+/// [`RaftNode::open`] saves its term, vote and log before returning from each call, so the
+/// messages it hands out never promise something a restart would forget.
+pub struct RaftNode<E: KvsEngine> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    options: RaftOption,
+    engine: E,
+
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+
+    log: RaftLog,
+    commit: u64,
+    applied: u64,
+
+    /// Ticks since the last heartbeat sent, or heard from the leader.
+    elapsed: u64,
+    election_timeout: u64,
+    rng: Rng,
+
+    votes: HashSet<NodeId>,
+    progress: HashMap<NodeId, Progress>,
+    /// Peers heard from since the leader last checked it still has a quorum.
+    active: HashSet<NodeId>,
+    quorum_elapsed: u64,
+
+    /// Entries proposed by this node and not applied yet, with their term.
+    proposed: HashMap<u64, u64>,
+    applied_entries: Vec<Applied>,
+    outbox: Vec<Message>,
+
+    /// Last round of heartbeats sent to confirm reads, one at a time.
+    read_round: u64,
+    last_read: u64,
+    reads: Vec<PendingRead>,
+    ready_reads: Vec<ReadReady>,
+
+    storage: Option<RaftStorage>,
+    /// Term and vote last saved.
+    saved: HardState,
+    /// Last entry saved, and the first one changed since.
+    saved_last: u64,
+    unsaved_from: Option<u64>,
+    unsaved_snapshot: Option<Snapshot>,
+    /// Set once saving failed, the node then stops like a crashed one.
+    halted: bool,
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    /// Create node `id` of a cluster made of itself and `peers`, starting as a follower.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, engine: E, options: RaftOption) -> RaftNode<E> {
+        let peers = peers.into_iter().filter(|peer| *peer != id).collect();
+        let mut node = RaftNode {
+            id,
+            peers,
+            options,
+            engine,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: RaftLog::default(),
+            commit: 0,
+            applied: 0,
+            elapsed: 0,
+            election_timeout: 0,
+            rng: Rng::new(id),
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            active: HashSet::new(),
+            quorum_elapsed: 0,
+            proposed: HashMap::new(),
+            applied_entries: Vec::new(),
+            outbox: Vec::new(),
+            read_round: 0,
+            last_read: 0,
+            reads: Vec::new(),
+            ready_reads: Vec::new(),
+            storage: None,
+            saved: HardState::default(),
+            saved_last: 0,
+            unsaved_from: None,
+            unsaved_snapshot: None,
+            halted: false,
+        };
+        node.reset_election_timeout();
+        node
+    }
+
+    /// Create node `id` like [`RaftNode::new`], saving its state in the folder at `path`.
+    ///
+    /// A node saved there before starts again from its term, vote and log. Its snapshot, if
+    /// any, replaces the contents of `engine`.
+    pub fn open<P: AsRef<Path>>(
+        id: NodeId,
+        peers: Vec<NodeId>,
+        engine: E,
+        options: RaftOption,
+        path: P,
+    ) -> Result<RaftNode<E>> {
+        let (storage, restored) = RaftStorage::open(path.as_ref())?;
+        let mut node = RaftNode::new(id, peers, engine, options);
+        node.term = restored.hard_state.term;
+        node.voted_for = restored.hard_state.voted_for;
+        node.saved = restored.hard_state;
+        if let Some(snapshot) = restored.snapshot {
+            node.install(snapshot)?;
+        }
+        node.log.entries = restored.entries;
+        node.saved_last = node.log.last_index();
+        node.storage = Some(storage);
+        Ok(node)
+    }
+
+    /// Id of the node.
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Role in the current term.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Current term.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Leader of the current term, if known.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// Index of the last committed entry.
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    /// Index of the last entry applied to the state machine.
+    pub fn applied_index(&self) -> u64 {
+        self.applied
+    }
+
+    /// Index of the last entry in the log.
+    pub fn last_index(&self) -> u64 {
+        self.log.last_index()
+    }
+
+    /// The state machine.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Append a command to the log, if this node is the leader.
+    /// Returns its index and term, its outcome comes from [`RaftNode::take_applied`].
+    pub fn propose(&mut self, command: RaftCommand) -> Option<(u64, u64)> {
+        if self.role != Role::Leader || self.halted {
+            return None;
+        }
+        let index = self.append(command);
+        // An earlier proposal at this index was dropped by another leader.
+        if let Some(term) = self.proposed.insert(index, self.term) {
+            self.applied_entries.push(Applied {
+                index,
+                term,
+                result: Err(KvError::NotLeader { leader: None }),
+            });
+        }
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+        self.maybe_commit();
+        if !self.save() {
+            return None;
+        }
+        Some((index, self.term))
+    }
+
+    /// Ask to read the state machine, if this node is the leader and committed an entry of
+    /// its term. Returns the id of the read, which [`RaftNode::take_reads`] hands out once the
+    /// state machine holds every entry committed before the call.
+    ///
+    /// A round of heartbeats confirms to the leader that no other replaced it, without
+    /// going through the log. Reads asked while a round is out share the next one.
+    pub fn read(&mut self) -> Option<u64> {
+        if self.role != Role::Leader || self.halted || self.log.term(self.commit) != Some(self.term)
+        {
+            return None;
+        }
+        self.last_read += 1;
+        self.reads.push(PendingRead {
+            id: self.last_read,
+            round: 0,
+        });
+        self.confirm_reads();
+        Some(self.last_read)
+    }
+
+    /// Reads asked of this node which completed since the last call.
+    pub fn take_reads(&mut self) -> Vec<ReadReady> {
+        std::mem::take(&mut self.ready_reads)
+    }
+
+    /// Messages to deliver to other nodes.
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Entries proposed by this node which were applied since the last call.
+    pub fn take_applied(&mut self) -> Vec<Applied> {
+        std::mem::take(&mut self.applied_entries)
+    }
+
+    /// Let one unit of time pass.
+    pub fn tick(&mut self) {
+        if self.halted {
+            return;
+        }
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader => {
+                if self.elapsed >= self.options.heartbeat_ticks {
+                    self.elapsed = 0;
+                    for peer in self.peers.clone() {
+                        self.send_append(peer);
+                    }
+                }
+                // Step down once cut from the majority, so clients look for the new leader.
+                self.quorum_elapsed += 1;
+                if self.quorum_elapsed >= self.options.election_ticks * 2 {
+                    self.quorum_elapsed = 0;
+                    if self.active.len() + 1 < self.quorum() {
+                        info!(id = self.id, term = self.term, "leader lost its quorum");
+                        self.become_follower(self.term, None);
+                    }
+                    self.active.clear();
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if self.elapsed >= self.election_timeout {
+                    self.campaign();
+                }
+            }
+        }
+        self.save();
+    }
+
+    /// Handle a message from another node.
+    pub fn step(&mut self, message: Message) {
+        if self.halted {
+            return;
+        }
+        self.handle(message);
+        self.save();
+    }
+
+    fn handle(&mut self, message: Message) {
+        let Message {
+            from, term, body, ..
+        } = message;
+
+        if term > self.term {
+            let leader = match body {
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(term, leader);
+        }
+        if term < self.term {
+            // Let the stale node learn about the current term.
+            match body {
+                MessageBody::RequestVote { .. } => {
+                    self.send(from, MessageBody::Vote { granted: false })
+                }
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => self.send(
+                    from,
+                    MessageBody::AppendResult {
+                        success: false,
+                        match_index: self.log.last_index(),
+                        read: 0,
+                    },
+                ),
+                _ => {}
+            }
+            return;
+        }
+
+        match body {
+            MessageBody::RequestVote {
+                last_index,
+                last_term,
+            } => self.handle_request_vote(from, last_index, last_term),
+            MessageBody::Vote { granted } => self.handle_vote(from, granted),
+            MessageBody::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read,
+            } => self.handle_append(from, prev_index, prev_term, entries, commit, read),
+            MessageBody::AppendResult {
+                success,
+                match_index,
+                read,
+            } => self.handle_append_result(from, success, match_index, read),
+            MessageBody::InstallSnapshot { snapshot } => self.handle_snapshot(from, snapshot),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn send(&mut self, to: NodeId, body: MessageBody) {
+        self.outbox.push(Message {
+            from: self.id,
+            to,
+            term: self.term,
+            body,
+        });
+    }
+
+    fn reset_election_timeout(&mut self) {
+        let ticks = self.options.election_ticks;
+        self.election_timeout = ticks + self.rng.next() % ticks;
+    }
+
+    fn append(&mut self, command: RaftCommand) -> u64 {
+        let index = self.log.last_index() + 1;
+        self.log.entries.push(Entry {
+            index,
+            term: self.term,
+            command,
+        });
+        self.changed(index);
+        index
+    }
+
+    /// Note that the entries from `index` on must be saved.
+    fn changed(&mut self, index: u64) {
+        self.unsaved_from = Some(self.unsaved_from.map_or(index, |from| from.min(index)));
+    }
+
+    /// Save what changed since the last call, before any message leaves.
+    /// Returns whether the node can go on.
+    fn save(&mut self) -> bool {
+        if self.halted {
+            return false;
+        }
+        if let Err(e) = self.try_save() {
+            // Its messages may promise what is not on disk, drop them and stop.
+            error!(id = self.id, error = %e, "cannot save raft state, stopping:");
+            self.halted = true;
+            self.outbox.clear();
+            self.fail_reads();
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+        !self.halted
+    }
+
+    fn try_save(&mut self) -> Result<()> {
+        let storage = match self.storage.as_mut() {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+
+        let mut rewrite = false;
+        if let Some(snapshot) = &self.unsaved_snapshot {
+            storage.save_snapshot(snapshot)?;
+            self.unsaved_snapshot = None;
+            rewrite = true;
+        }
+        if let Some(from) = self.unsaved_from {
+            // Saved entries were replaced.
+            rewrite |= from <= self.saved_last;
+        }
+        if rewrite {
+            storage.rewrite_log(&self.log.entries)?;
+        } else if let Some(from) = self.unsaved_from {
+            let start = (from - self.log.first_index()) as usize;
+            storage.append(&self.log.entries[start..])?;
+        }
+        self.saved_last = self.log.last_index();
+        self.unsaved_from = None;
+
+        let hard_state = HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        };
+        if hard_state != self.saved {
+            storage.save_hard_state(&hard_state)?;
+            self.saved = hard_state;
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if self.role == Role::Leader {
+            self.fail_reads();
+        }
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+        self.votes.clear();
+        self.reset_election_timeout();
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.elapsed = 0;
+        self.reset_election_timeout();
+        self.votes = HashSet::from([self.id]);
+        info!(id = self.id, term = self.term, "starting election");
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let last_index = self.log.last_index();
+        let last_term = self.log.last_term();
+        for peer in self.peers.clone() {
+            self.send(
+                peer,
+                MessageBody::RequestVote {
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+    }
+
+    fn become_leader(&mut self) {
+        info!(id = self.id, term = self.term, "elected leader");
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.quorum_elapsed = 0;
+        self.active.clear();
+        let next = self.log.last_index() + 1;
+        let read = self.read_round;
+        self.progress = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let progress = Progress {
+                    next,
+                    matched: 0,
+                    read,
+                };
+                (*peer, progress)
+            })
+            .collect();
+
+        // Entries of earlier terms only commit along with one of the current term.
+        self.append(RaftCommand::Noop {});
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+        self.maybe_commit();
+    }
+
+    fn handle_request_vote(&mut self, from: NodeId, last_index: u64, last_term: u64) {
+        let up_to_date = (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+        let granted = up_to_date && self.voted_for.is_none_or(|voted| voted == from);
+        if granted {
+            self.voted_for = Some(from);
+            self.elapsed = 0;
+        }
+        self.send(from, MessageBody::Vote { granted });
+    }
+
+    fn handle_vote(&mut self, from: NodeId, granted: bool) {
+        if self.role != Role::Candidate || !granted {
+            return;
+        }
+        self.votes.insert(from);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+        }
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        read: u64,
+    ) {
+        if self.role != Role::Follower || self.leader != Some(from) {
+            self.become_follower(self.term, Some(from));
+        }
+        self.elapsed = 0;
+
+        let reject = |node: &mut RaftNode<E>, match_index| {
+            node.send(
+                from,
+                MessageBody::AppendResult {
+                    success: false,
+                    match_index,
+                    read,
+                },
+            )
+        };
+        if prev_index > self.log.last_index() {
+            return reject(self, self.log.last_index());
+        }
+        // Compacted entries are committed, they match the leader's.
+        if prev_index < self.log.snapshot_index {
+            let match_index = self.log.snapshot_index;
+            return self.send(
+                from,
+                MessageBody::AppendResult {
+                    success: true,
+                    match_index,
+                    read,
+                },
+            );
+        }
+        if self.log.term(prev_index) != Some(prev_term) {
+            // Committed entries match, start again after them.
+            return reject(self, self.commit);
+        }
+
+        let match_index = prev_index + entries.len() as u64;
+        for entry in entries {
+            match self.log.term(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(entry.index),
+                None => {}
+            }
+            self.changed(entry.index);
+            self.log.entries.push(entry);
+        }
+
+        let commit = commit.min(match_index);
+        if commit > self.commit {
+            self.commit = commit;
+            self.apply();
+        }
+        self.send(
+            from,
+            MessageBody::AppendResult {
+                success: true,
+                match_index,
+                read,
+            },
+        );
+    }
+
+    fn handle_append_result(&mut self, from: NodeId, success: bool, match_index: u64, read: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+        self.active.insert(from);
+        let last_index = self.log.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if read > progress.read {
+            progress.read = read;
+            self.confirm_reads();
+        }
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+
+        if success {
+            progress.matched = progress.matched.max(match_index);
+            progress.next = progress.next.max(match_index + 1);
+            let next = progress.next;
+            self.maybe_commit();
+            if next <= last_index {
+                self.send_append(from);
+            }
+        } else {
+            progress.next = (match_index + 1).max(progress.matched + 1);
+            self.send_append(from);
+        }
+    }
+
+    fn handle_snapshot(&mut self, from: NodeId, snapshot: Snapshot) {
+        if self.role != Role::Follower || self.leader != Some(from) {
+            self.become_follower(self.term, Some(from));
+        }
+        self.elapsed = 0;
+
+        let index = snapshot.index;
+        if index > self.commit {
+            if let Err(e) = self.install(snapshot) {
+                warn!(id = self.id, error = %e, "cannot install snapshot:");
+                return;
+            }
+        }
+        self.send(
+            from,
+            MessageBody::AppendResult {
+                success: true,
+                match_index: index,
+                read: 0,
+            },
+        );
+    }
+
+    /// Replace the state machine and the log with a snapshot.
+    fn install(&mut self, snapshot: Snapshot) -> Result<()> {
+        info!(id = self.id, index = snapshot.index, "installing snapshot");
+        if self.storage.is_some() {
+            self.unsaved_snapshot = Some(snapshot.clone());
+        }
+        let keys: HashSet<&String> = snapshot.entries.iter().map(|(key, _)| key).collect();
+        for key in self.engine.keys()? {
+            if !keys.contains(&key) {
+                remove(&self.engine, key)?;
+            }
+        }
+        for (key, value) in &snapshot.entries {
+            self.engine.set(key.clone(), value.clone())?;
+        }
+
+        self.log = RaftLog {
+            snapshot_index: snapshot.index,
+            snapshot_term: snapshot.term,
+            entries: Vec::new(),
+        };
+        self.commit = snapshot.index;
+        self.applied = snapshot.index;
+
+        // Whether our proposals made it in is unknown, let their clients retry on the leader.
+        let mut covered: Vec<(u64, u64)> = self
+            .proposed
+            .iter()
+            .filter(|(index, _)| **index <= snapshot.index)
+            .map(|(index, term)| (*index, *term))
+            .collect();
+        covered.sort_unstable();
+        for (index, term) in covered {
+            self.proposed.remove(&index);
+            self.applied_entries.push(Applied {
+                index,
+                term,
+                result: Err(KvError::NotLeader { leader: None }),
+            });
+        }
+        Ok(())
+    }
+
+    /// Send the entries a follower misses, or a snapshot once they are dropped.
+    fn send_append(&mut self, peer: NodeId) {
+        let progress = match self.progress.get(&peer) {
+            Some(progress) => *progress,
+            None => return,
+        };
+
+        if progress.next < self.log.first_index() {
+            let snapshot = match self.snapshot() {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!(id = self.id, error = %e, "cannot build snapshot:");
+                    return;
+                }
+            };
+            self.set_next(peer, snapshot.index + 1);
+            return self.send(peer, MessageBody::InstallSnapshot { snapshot });
+        }
+
+        let prev_index = progress.next - 1;
+        let prev_term = self
+            .log
+            .term(prev_index)
+            .expect("entries after the snapshot are in the log");
+        let entries = self
+            .log
+            .slice(progress.next, self.options.max_append_entries);
+        // Send the following entries right away, without waiting for the answer.
+        if let Some(last) = entries.last() {
+            self.set_next(peer, last.index + 1);
+        }
+        self.send(
+            peer,
+            MessageBody::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit: self.commit,
+                read: self.read_round,
+            },
+        );
+    }
+
+    /// Hand out the reads a majority confirmed, and start a round for the others if none is
+    /// out.
+    fn confirm_reads(&mut self) {
+        let mut answered: Vec<u64> = self
+            .progress
+            .values()
+            .map(|progress| progress.read)
+            .collect();
+        answered.push(self.read_round);
+        answered.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed = answered[self.quorum() - 1];
+
+        // The leader applies entries as it commits them, so confirmed reads are ready.
+        let mut waiting = false;
+        let mut ready = Vec::new();
+        self.reads.retain(|read| {
+            if read.round == 0 || read.round > confirmed {
+                waiting |= read.round > confirmed;
+                return true;
+            }
+            ready.push(ReadReady {
+                id: read.id,
+                result: Ok(()),
+            });
+            false
+        });
+        self.ready_reads.extend(ready);
+
+        if !waiting && self.reads.iter().any(|read| read.round == 0) {
+            self.read_round += 1;
+            let round = self.read_round;
+            for read in self.reads.iter_mut() {
+                read.round = round;
+            }
+            if self.peers.is_empty() {
+                return self.confirm_reads();
+            }
+            for peer in self.peers.clone() {
+                self.send_append(peer);
+            }
+        }
+    }
+
+    /// Fail the reads waiting for confirmation, once the node stops leading.
+    fn fail_reads(&mut self) {
+        for read in self.reads.drain(..) {
+            self.ready_reads.push(ReadReady {
+                id: read.id,
+                result: Err(KvError::NotLeader { leader: None }),
+            });
+        }
+    }
+
+    fn set_next(&mut self, peer: NodeId, next: u64) {
+        if let Some(progress) = self.progress.get_mut(&peer) {
+            progress.next = next;
+        }
+    }
+
+    /// State of the state machine at the last applied entry.
+    ///
+    /// The node is the only writer of its engine, so reading it between two applied entries
+    /// sees exactly the state at `applied`. The engine is read rather than its own log: the
+    /// engine may be any [`KvsEngine`], and the kvs store only reads back writer logs created
+    /// since it was opened, merged ones being rewritten, so no log position covers it all.
+    fn snapshot(&self) -> Result<Snapshot> {
+        let mut entries = Vec::new();
+        for key in self.engine.keys()? {
+            if let Some(value) = self.engine.get(key.clone())? {
+                entries.push((key, value));
+            }
+        }
+        Ok(Snapshot {
+            index: self.applied,
+            term: self
+                .log
+                .term(self.applied)
+                .expect("applied entries are not compacted past"),
+            entries,
+        })
+    }
+
+    /// Commit the entries a majority stores.
+    fn maybe_commit(&mut self) {
+        // The leader counts its own entries, once they are saved.
+        if !self.save() {
+            return;
+        }
+        let mut matched: Vec<u64> = self
+            .progress
+            .values()
+            .map(|progress| progress.matched)
+            .collect();
+        matched.push(self.log.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.log.term(index) == Some(self.term) {
+            self.commit = index;
+            self.apply();
+        }
+    }
+
+    fn apply(&mut self) {
+        while self.applied < self.commit {
+            let index = self.applied + 1;
+            let entry = self
+                .log
+                .entry(index)
+                .expect("committed entries are in the log")
+                .clone();
+            let result = match entry.command {
+                RaftCommand::Set { key, value } => self.engine.set(key, value),
+                RaftCommand::Remove { key } => self.engine.remove(key),
+                RaftCommand::Noop {} => Ok(()),
+            };
+            self.applied = index;
+
+            match self.proposed.remove(&index) {
+                Some(term) => {
+                    let result = if term == entry.term {
+                        result
+                    } else {
+                        Err(KvError::NotLeader { leader: None })
+                    };
+                    self.applied_entries.push(Applied {
+                        index,
+                        term,
+                        result,
+                    });
+                }
+                None => {
+                    if let Err(e) = result {
+                        if !matches!(e, KvError::KeyNotFound(_)) {
+                            warn!(id = self.id, index, error = %e, "cannot apply entry:");
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.log.entries.len() > self.options.max_log_entries {
+            // Saved entries are only dropped along with a snapshot replacing them.
+            if self.storage.is_some() {
+                match self.snapshot() {
+                    Ok(snapshot) => self.unsaved_snapshot = Some(snapshot),
+                    Err(e) => {
+                        warn!(id = self.id, error = %e, "cannot build snapshot:");
+                        return;
+                    }
+                }
+            }
+            let term = self
+                .log
+                .term(self.applied)
+                .expect("applied entries are not compacted past");
+            self.log.compact(self.applied, term);
+        }
+    }
+}
+
+/// Remove a key, which may already be gone.
+fn remove<E: KvsEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key) {
+        Err(KvError::KeyNotFound(_)) => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{BTreeMap, HashSet};
+
+use super::{
+    message::{Message, NodeId, RaftCommand},
+    node::{RaftNode, Role},
+    Rng,
+};
+use crate::KvsEngine;
+
+/// Nodes of a cluster exchanging messages in memory, one tick at a time.
+///
+/// Everything is driven by the caller and decided by a seeded generator, so a run can be
+/// replayed exactly.
+pub struct SimNetwork<E: KvsEngine> {
+    nodes: BTreeMap<NodeId, RaftNode<E>>,
+    /// Messages on their way, with the tick they arrive at.
+    in_flight: Vec<(u64, Message)>,
+    now: u64,
+    rng: Rng,
+    drop_rate: f64,
+    min_delay: u64,
+    max_delay: u64,
+    /// Group of each node, nodes of different groups cannot talk.
+    groups: BTreeMap<NodeId, usize>,
+    stopped: HashSet<NodeId>,
+}
+
+impl<E: KvsEngine> SimNetwork<E> {
+    /// Connect `nodes` through a network which delivers every message on the next tick.
+    pub fn new(nodes: Vec<RaftNode<E>>, seed: u64) -> SimNetwork<E> {
+        SimNetwork {
+            nodes: nodes.into_iter().map(|node| (node.id(), node)).collect(),
+            in_flight: Vec::new(),
+            now: 0,
+            rng: Rng::new(seed),
+            drop_rate: 0.0,
+            min_delay: 1,
+            max_delay: 1,
+            groups: BTreeMap::new(),
+            stopped: HashSet::new(),
+        }
+    }
+
+    /// Ids of the nodes.
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    /// A node of the network.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no node `id`.
+    pub fn node(&self, id: NodeId) -> &RaftNode<E> {
+        &self.nodes[&id]
+    }
+
+    /// A node of the network, to take its applied entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no node `id`.
+    pub fn node_mut(&mut self, id: NodeId) -> &mut RaftNode<E> {
+        self.nodes.get_mut(&id).expect("no such node")
+    }
+
+    /// Ticks elapsed.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Drop each message with `probability`.
+    pub fn set_drop_rate(&mut self, probability: f64) {
+        self.drop_rate = probability;
+    }
+
+    /// Deliver each message after between `min` and `max` ticks, at least one.
+    pub fn set_delay(&mut self, min: u64, max: u64) {
+        self.min_delay = min.max(1);
+        self.max_delay = max.max(self.min_delay);
+    }
+
+    /// Split the network: nodes only reach the nodes of their group.
+    /// Nodes left out of every group are cut from all others.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        let isolated = groups.len();
+        self.groups = self
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(i, id)| (*id, isolated + i))
+            .collect();
+        for (group, ids) in groups.iter().enumerate() {
+            for id in ids.iter() {
+                self.groups.insert(*id, group);
+            }
+        }
+    }
+
+    /// Let every node reach every other again.
+    pub fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    /// Stop a node: it neither ticks nor receives messages, and keeps its state for a restart.
+    pub fn stop(&mut self, id: NodeId) {
+        self.stopped.insert(id);
+    }
+
+    /// Restart a stopped node.
+    pub fn start(&mut self, id: NodeId) {
+        self.stopped.remove(&id);
+    }
+
+    /// The leader of the highest term among running nodes.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .filter(|node| node.role() == Role::Leader && !self.stopped.contains(&node.id()))
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// Propose a command on node `id`, see [`RaftNode::propose`].
+    pub fn propose(&mut self, id: NodeId, command: RaftCommand) -> Option<(u64, u64)> {
+        if self.stopped.contains(&id) {
+            return None;
+        }
+        let proposed = self.nodes.get_mut(&id)?.propose(command);
+        self.collect();
+        proposed
+    }
+
+    /// Advance by one tick: deliver the messages due, then tick every running node.
+    pub fn tick(&mut self) {
+        self.now += 1;
+        let now = self.now;
+        let (due, later) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.in_flight = later;
+
+        for (_, message) in due {
+            if !self.reachable(message.from, message.to) {
+                continue;
+            }
+            if let Some(node) = self.nodes.get_mut(&message.to) {
+                node.step(message);
+            }
+        }
+        for (id, node) in self.nodes.iter_mut() {
+            if !self.stopped.contains(id) {
+                node.tick();
+            }
+        }
+        self.collect();
+    }
+
+    /// Advance by `ticks`.
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Advance until `condition` holds, at most `ticks`. Returns whether it holds.
+    pub fn run_until<F: FnMut(&SimNetwork<E>) -> bool>(
+        &mut self,
+        ticks: u64,
+        mut condition: F,
+    ) -> bool {
+        for _ in 0..ticks {
+            if condition(self) {
+                return true;
+            }
+            self.tick();
+        }
+        condition(self)
+    }
+
+    fn reachable(&self, from: NodeId, to: NodeId) -> bool {
+        !self.stopped.contains(&from)
+            && !self.stopped.contains(&to)
+            && self.groups.get(&from) == self.groups.get(&to)
+    }
+
+    /// Put the messages sent by the nodes on the network.
+    fn collect(&mut self) {
+        let messages: Vec<Message> = self
+            .nodes
+            .values_mut()
+            .flat_map(|node| node.take_messages())
+            .collect();
+        for message in messages {
+            if self.rng.chance(self.drop_rate) {
+                continue;
+            }
+            let at = self.now + self.rng.between(self.min_delay, self.max_delay);
+            self.in_flight.push((at, message));
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::message::{Entry, NodeId, Snapshot};
+use crate::{kvs::DirLock, KvError, Result};
+
+const STATE_FILE: &str = "state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+
+/// Term and vote of a node, it must never vote twice in a term.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+/// What a node saved before it stopped.
+#[derive(Debug, Default)]
+pub(super) struct Restored {
+    pub hard_state: HardState,
+    pub snapshot: Option<Snapshot>,
+    /// Entries following the snapshot.
+    pub entries: Vec<Entry>,
+}
+
+/// Files of a node in a folder, which it locks.
+///
+/// Every write is synced before returning. The log is appended to, and rewritten whole when
+/// entries are replaced or dropped. The term, vote and snapshot are replaced with a rename.
+#[derive(Debug)]
+pub(super) struct RaftStorage {
+    path: PathBuf,
+    log: File,
+    _lock: DirLock,
+}
+
+impl RaftStorage {
+    /// Open the folder at `path`, creating it if needed, and read what it holds.
+    pub fn open(path: &Path) -> Result<(RaftStorage, Restored)> {
+        fs::create_dir_all(path)?;
+        let lock = DirLock::acquire(path)?;
+
+        let hard_state = match read_file(&path.join(STATE_FILE))? {
+            Some(bytes) => bson::from_slice(&bytes)?,
+            None => HardState::default(),
+        };
+        let snapshot: Option<Snapshot> = match read_file(&path.join(SNAPSHOT_FILE))? {
+            Some(bytes) => Some(bson::from_slice(&bytes)?),
+            None => None,
+        };
+
+        let log_path = path.join(LOG_FILE);
+        let bytes = read_file(&log_path)?.unwrap_or_default();
+        let (entries, valid_len) = read_entries(&bytes)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        // The tail of an append which did not complete, never acknowledged.
+        if valid_len < bytes.len() {
+            warn!(from = bytes.len(), to = valid_len, "truncated raft log:");
+            log.set_len(valid_len as u64)?;
+            log.sync_all()?;
+        }
+
+        // The log is rewritten after the snapshot, a crash in between leaves covered entries.
+        let snapshot_index = snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+        let entries: Vec<Entry> = entries
+            .into_iter()
+            .filter(|entry| entry.index > snapshot_index)
+            .collect();
+        for (expected, entry) in (snapshot_index + 1..).zip(&entries) {
+            if entry.index != expected {
+                return Err(KvError::Corrupted(format!(
+                    "raft log misses entry {}",
+                    expected
+                )));
+            }
+        }
+
+        info!(
+            path = %path.display(),
+            term = hard_state.term,
+            snapshot = snapshot_index,
+            entries = entries.len(),
+            "opened raft storage:"
+        );
+        let storage = RaftStorage {
+            path: path.to_owned(),
+            log,
+            _lock: lock,
+        };
+        let restored = Restored {
+            hard_state,
+            snapshot,
+            entries,
+        };
+        Ok((storage, restored))
+    }
+
+    pub fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()> {
+        self.replace(STATE_FILE, &bson::to_vec(hard_state)?)
+    }
+
+    pub fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.replace(SNAPSHOT_FILE, &bson::to_vec(snapshot)?)
+    }
+
+    /// Add entries at the end of the log.
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        self.log.write_all(&encode_entries(entries)?)?;
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the whole log with `entries`.
+    pub fn rewrite_log(&mut self, entries: &[Entry]) -> Result<()> {
+        self.replace(LOG_FILE, &encode_entries(entries)?)?;
+        self.log = OpenOptions::new()
+            .append(true)
+            .open(self.path.join(LOG_FILE))?;
+        Ok(())
+    }
+
+    /// Write a file next to `name`, then rename it over.
+    fn replace(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let temp = self.path.join(format!("{}.tmp", name));
+        let mut file = File::create(&temp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.path.join(name))?;
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn encode_entries(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        bytes.extend(bson::to_vec(entry)?);
+    }
+    Ok(bytes)
+}
+
+/// Entries of a log, with the length of its complete documents.
+fn read_entries(bytes: &[u8]) -> Result<(Vec<Entry>, usize)> {
+    let mut entries = Vec::new();
+    let mut position = 0;
+    while let Some(header) = bytes.get(position..position + 4) {
+        // Documents start with their length, header included.
+        let len = u32::from_le_bytes(header.try_into().expect("4 bytes")) as usize;
+        let document = match bytes.get(position..position + len) {
+            Some(document) if len > 4 => document,
+            _ => break,
+        };
+        entries.push(bson::from_slice(document)?);
+        position += len;
+    }
+    Ok((entries, position))
+}
@@ -0,0 +1,222 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds of histogram buckets, in microseconds.
+const BUCKETS: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 100_000, 1_000_000,
+];
+
+/// Snapshot of a duration histogram.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Number of samples falling in each bucket of [`Histogram::bounds`],
+    /// the last bucket holds samples above every bound.
+    pub buckets: Vec<u64>,
+    /// Number of samples.
+    pub count: u64,
+    /// Sum of all samples, in microseconds.
+    pub sum_micros: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; BUCKETS.len() + 1],
+            count: 0,
+            sum_micros: 0,
+        }
+    }
+}
+
+impl Histogram {
+    /// Upper bounds of the buckets, in microseconds.
+    pub fn bounds() -> &'static [u64] {
+        &BUCKETS
+    }
+}
+
+/// Counters and histograms describing a store and the server in front of it.
+///
+/// Engine specific values are left at zero by engines that do not track them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Latency of `get` operations, its count is the number of operations.
+    pub get: Histogram,
+    /// Latency of `set` operations, its count is the number of operations.
+    pub set: Histogram,
+    /// Latency of `remove` operations, its count is the number of operations.
+    pub remove: Histogram,
+
+    /// Bytes appended to log files, including merges.
+    pub bytes_written: u64,
+    /// Bytes in log files holding the latest value of a live key.
+    pub live_bytes: u64,
+    /// Bytes in log files that can be reclaimed by merging.
+    pub dead_bytes: u64,
+    /// Number of log files.
+    pub log_files: u64,
+    /// Duration of finished merges, its count is the number of merges.
+    pub merge: Histogram,
+
+    /// Number of currently opened connections.
+    pub active_connections: u64,
+    /// Number of connections accepted since the server started.
+    pub total_connections: u64,
+
+    /// Replicas streaming from this server.
+    #[serde(default)]
+    pub replicas: Vec<ReplicaStats>,
+    /// Address of the primary, while this server is a replica.
+    #[serde(default)]
+    pub primary: Option<String>,
+
+    /// Thread pool serving the connections.
+    #[serde(default)]
+    pub pool: ThreadPoolStats,
+}
+
+/// State of a [`ThreadPool`](crate::thread_pool::ThreadPool).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreadPoolStats {
+    /// Jobs waiting for a worker.
+    pub queued: u64,
+    /// Workers running a job.
+    pub active_workers: u64,
+    /// Workers alive, idle or not.
+    pub workers: u64,
+    /// Jobs finished, including those which panicked.
+    pub completed: u64,
+    /// Jobs which panicked, their worker was replaced.
+    pub panicked: u64,
+}
+
+/// A replica streaming from a server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaStats {
+    /// Address the replica connected from.
+    pub address: String,
+    /// Bytes written to the server the replica did not acknowledge yet,
+    /// `None` until it acknowledges a position of the current log.
+    pub lag_bytes: Option<u64>,
+}
+
+impl Stats {
+    /// Render the stats in Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# TYPE kvs_op_duration_seconds histogram\n");
+        for (op, histogram) in [
+            ("get", &self.get),
+            ("set", &self.set),
+            ("remove", &self.remove),
+        ] {
+            write_histogram(
+                &mut out,
+                "kvs_op_duration_seconds",
+                &format!("op=\"{}\",", op),
+                histogram,
+            );
+        }
+
+        out.push_str("# TYPE kvs_merge_duration_seconds histogram\n");
+        write_histogram(&mut out, "kvs_merge_duration_seconds", "", &self.merge);
+
+        let values = [
+            ("kvs_bytes_written_total", "counter", self.bytes_written),
+            ("kvs_live_bytes", "gauge", self.live_bytes),
+            ("kvs_dead_bytes", "gauge", self.dead_bytes),
+            ("kvs_log_files", "gauge", self.log_files),
+            ("kvs_active_connections", "gauge", self.active_connections),
+            ("kvs_connections_total", "counter", self.total_connections),
+            ("kvs_replicas", "gauge", self.replicas.len() as u64),
+            ("kvs_pool_queued_jobs", "gauge", self.pool.queued),
+            ("kvs_pool_active_workers", "gauge", self.pool.active_workers),
+            ("kvs_pool_workers", "gauge", self.pool.workers),
+            (
+                "kvs_pool_jobs_completed_total",
+                "counter",
+                self.pool.completed,
+            ),
+            ("kvs_pool_panics_total", "counter", self.pool.panicked),
+        ];
+        for (name, kind, value) in values {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out.push_str("# TYPE kvs_replica_lag_bytes gauge\n");
+        for replica in &self.replicas {
+            if let Some(lag) = replica.lag_bytes {
+                let _ = writeln!(
+                    out,
+                    "kvs_replica_lag_bytes{{replica=\"{}\"}} {}",
+                    replica.address, lag
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += count;
+        let le = *bound as f64 / 1e6;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name, labels, le, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{}le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+
+    let labels = labels.trim_end_matches(',');
+    let sum = histogram.sum_micros as f64 / 1e6;
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+/// Lock-free histogram recorder.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -3,14 +3,27 @@
 mod naive;
 mod rayon;
 mod shared;
+mod stealing;
 
-use crate::Result;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use crate::{KvError, Result, ThreadPoolStats};
 
 pub use naive::NaiveThreadPool;
 pub use rayon::RayonThreadPool;
 pub use shared::SharedQueueThreadPool;
+pub use stealing::WorkStealingThreadPool;
 
 /// Trait handles thread pool to avoiding re-creating threads.
+///
+/// Dropping a pool shuts it down without waiting, jobs already spawned still run.
 pub trait ThreadPool
 where
     Self: std::marker::Sized + Send + 'static,
@@ -19,7 +32,172 @@ where
     fn new(threads: u32) -> Result<Self>;
 
     /// Spawn a new function running in thread pool.
+    ///
+    /// # Panics
+    ///
+    /// If the pool is shut down, see [`try_spawn`](ThreadPool::try_spawn).
     fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn(job).expect("cannot spawn job")
+    }
+
+    /// Spawn a new function running in thread pool,
+    /// fails with [`KvError::PoolShutdown`] once the pool is shut down.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawn a function whose result is given back by the returned handle.
+    fn spawn_with_handle<F, T>(&self, job: F) -> Result<JobHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.try_spawn(move || {
+            // The handle may be gone already.
+            let _ = sender.send(job());
+        })?;
+        Ok(JobHandle { receiver })
+    }
+
+    /// Stop accepting jobs. Those already spawned still run, then the workers stop.
+    fn shutdown(&self);
+
+    /// Wait until every job spawned so far has finished.
+    fn join(&self);
+
+    /// Counters of the pool, which stay readable once the pool is moved away.
+    fn metrics(&self) -> Arc<PoolMetrics>;
+
+    /// What happens to a job spawned while the pool has no room for it.
+    fn when_full(&self) -> QueuePolicy {
+        QueuePolicy::Block
+    }
+}
+
+/// What [`SharedQueueThreadPool`] does with a job spawned while its queue is full,
+/// see [`ThreadPoolOption`](crate::ThreadPoolOption).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// Wait for room in the queue.
+    #[default]
+    Block,
+    /// Fail with [`KvError::Busy`].
+    Reject,
+    /// Run the job on the spawning thread, which stops spawning meanwhile.
+    CallerRuns,
+}
+
+/// Result of a job spawned with [`ThreadPool::spawn_with_handle`].
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> JobHandle<T> {
+    /// Wait for the job to finish and return its result,
+    /// fails with [`KvError::JobPanicked`] if it panicked.
+    pub fn join(self) -> Result<T> {
+        self.receiver.recv().map_err(|_| KvError::JobPanicked)
+    }
+}
+
+/// Live counters of a thread pool, shared with its workers, see [`ThreadPool::metrics`].
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    /// Jobs spawned which have not finished, for [`ThreadPool::join`].
+    pending: AtomicUsize,
+    running: AtomicUsize,
+    workers: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    lock: Mutex<()>,
+    finished: Condvar,
+}
+
+impl PoolMetrics {
+    /// Current state of the pool.
+    pub fn stats(&self) -> ThreadPoolStats {
+        let pending = self.pending.load(Ordering::SeqCst);
+        let running = self.running.load(Ordering::SeqCst);
+        ThreadPoolStats {
+            queued: pending.saturating_sub(running) as u64,
+            active_workers: running as u64,
+            workers: self.workers.load(Ordering::SeqCst) as u64,
+            completed: self.completed.load(Ordering::SeqCst),
+            panicked: self.panicked.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Count `job` as pending until it returns, panics or is dropped without running.
+    fn track<F>(self: &Arc<Self>, job: F) -> impl FnOnce() + Send + 'static
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let done = Done(self.clone());
+        move || {
+            let done = done;
+            let _running = Running::new(&done.0);
+            job()
+        }
+    }
+
+    /// Count a worker alive until the returned guard is dropped.
+    fn worker(self: &Arc<Self>) -> WorkerAlive {
+        self.workers.fetch_add(1, Ordering::SeqCst);
+        WorkerAlive(self.clone())
+    }
+
+    /// Wait until no job is pending.
+    fn wait(&self) {
+        let mut guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            guard = self.finished.wait(guard).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Marks its job done when dropped.
+struct Done(Arc<PoolMetrics>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _guard = self.0.lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.0.finished.notify_all();
+        }
+    }
+}
+
+/// Counts its job running, then completed or panicked once dropped.
+struct Running<'a>(&'a PoolMetrics);
+
+impl<'a> Running<'a> {
+    fn new(metrics: &'a PoolMetrics) -> Running<'a> {
+        metrics.running.fetch_add(1, Ordering::SeqCst);
+        Running(metrics)
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.panicked.fetch_add(1, Ordering::SeqCst);
+        }
+        self.0.completed.fetch_add(1, Ordering::SeqCst);
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts a worker alive until dropped.
+struct WorkerAlive(Arc<PoolMetrics>);
+
+impl Drop for WorkerAlive {
+    fn drop(&mut self) {
+        self.0.workers.fetch_sub(1, Ordering::SeqCst);
+    }
 }
@@ -1,20 +1,53 @@
-use std::thread;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
 
-use super::ThreadPool;
-use crate::Result;
+use super::{PoolMetrics, ThreadPool};
+use crate::{KvError, Result};
 
 /// Naive thread pool implementation without allocating threads ahead.
-pub struct NaiveThreadPool {}
+pub struct NaiveThreadPool {
+    closed: AtomicBool,
+    metrics: Arc<PoolMetrics>,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<NaiveThreadPool> {
-        Ok(NaiveThreadPool {})
+        Ok(NaiveThreadPool {
+            closed: AtomicBool::new(false),
+            metrics: Arc::default(),
+        })
     }
 
-    fn spawn<F>(&self, job: F)
+    fn try_spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvError::PoolShutdown);
+        }
+        let job = self.metrics.track(job);
+        let metrics = self.metrics.clone();
+        thread::Builder::new().spawn(move || {
+            let _alive = metrics.worker();
+            job()
+        })?;
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn join(&self) {
+        self.metrics.wait();
+    }
+
+    fn metrics(&self) -> Arc<PoolMetrics> {
+        self.metrics.clone()
     }
 }
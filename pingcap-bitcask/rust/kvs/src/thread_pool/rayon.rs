@@ -1,25 +1,63 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
 use rayon::ThreadPoolBuilder;
+use tracing::warn;
 
-use super::ThreadPool;
-use crate::Result;
+use super::{PoolMetrics, ThreadPool};
+use crate::{KvError, Result};
 
 /// Wrapper of [`rayon::ThreadPool`].
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
+    closed: AtomicBool,
+    metrics: Arc<PoolMetrics>,
 }
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<RayonThreadPool> {
+        let metrics = Arc::<PoolMetrics>::default();
+        let (started, exited) = (metrics.clone(), metrics.clone());
         let pool = ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            // Panicking jobs abort the process otherwise.
+            .panic_handler(|_| warn!("job panicked"))
+            .start_handler(move |_| {
+                started.workers.fetch_add(1, Ordering::SeqCst);
+            })
+            .exit_handler(move |_| {
+                exited.workers.fetch_sub(1, Ordering::SeqCst);
+            })
             .build()?;
-        Ok(RayonThreadPool { pool })
+        Ok(RayonThreadPool {
+            pool,
+            closed: AtomicBool::new(false),
+            metrics,
+        })
     }
 
-    fn spawn<F>(&self, job: F)
+    fn try_spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job)
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvError::PoolShutdown);
+        }
+        self.pool.spawn(self.metrics.track(job));
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn join(&self) {
+        self.metrics.wait();
+    }
+
+    fn metrics(&self) -> Arc<PoolMetrics> {
+        self.metrics.clone()
     }
 }
@@ -1,18 +1,24 @@
 use std::{
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+        Arc, Mutex, RwLock,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 use tracing::{info, warn};
 
-use super::ThreadPool;
-use crate::Result;
+use super::{PoolMetrics, QueuePolicy, ThreadPool};
+use crate::{KvError, Result, ThreadPoolOption};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Longest an idle worker of an elastic pool waits on the queue at once. Idle workers take
+/// turns holding the queue, and each one checks its own idle time in between.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct WorkerId(u32);
 
@@ -20,29 +26,120 @@ enum ThreadPoolMessage {
     Run(Job),
 }
 
+/// Sending end of the queue of jobs.
+enum Queue {
+    Unbounded(Sender<ThreadPoolMessage>),
+    Bounded(SyncSender<ThreadPoolMessage>, QueuePolicy),
+}
+
 #[derive(Debug)]
 struct SharedData {
     receiver: Mutex<Receiver<ThreadPoolMessage>>,
+    metrics: Arc<PoolMetrics>,
+    /// Workers kept even when idle.
+    min_threads: usize,
+    /// Most workers started while jobs wait.
+    max_threads: usize,
+    when_full: QueuePolicy,
+    /// Workers above `min_threads` stop once idle for this long.
+    idle_timeout: Duration,
+    /// Workers started and not stopped, a panicking one is replaced under the same id.
+    threads: AtomicUsize,
+    next_id: AtomicU32,
+}
+
+impl SharedData {
+    fn is_elastic(&self) -> bool {
+        self.max_threads > self.min_threads
+    }
+
+    /// Start a worker if more jobs wait than workers are idle, up to `max_threads`.
+    fn grow(self: &Arc<Self>) {
+        let stats = self.metrics.stats();
+        let idle = self
+            .threads
+            .load(Ordering::SeqCst)
+            .saturating_sub(stats.active_workers as usize);
+        if stats.queued as usize <= idle {
+            return;
+        }
+        let started = self
+            .threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |threads| {
+                (threads < self.max_threads).then_some(threads + 1)
+            });
+        if started.is_ok() {
+            let worker_id = WorkerId(self.next_id.fetch_add(1, Ordering::SeqCst));
+            spawn_worker(worker_id, self.clone());
+        }
+    }
+
+    /// How long a worker waits on the queue before checking whether it stays idle for too long.
+    fn poll_interval(&self) -> Duration {
+        if self.is_elastic() {
+            (self.idle_timeout / 10).clamp(Duration::from_millis(1), IDLE_POLL)
+        } else {
+            self.idle_timeout
+        }
+    }
+
+    /// Whether an idle worker stops, as long as `min_threads` are left.
+    fn retire(&self) -> bool {
+        self.threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |threads| {
+                (threads > self.min_threads).then_some(threads - 1)
+            })
+            .is_ok()
+    }
 }
 
 /// Custom thread pool implementation.
 ///
 /// The code is heavily based on [threadpool](https://docs.rs/threadpool/latest/threadpool/).
+/// Its queue is unbounded and its number of workers fixed,
+/// unless created [with options](SharedQueueThreadPool::with_options).
 pub struct SharedQueueThreadPool {
     /// Sender sends message to workers, for spawning workers.
-    sender: Sender<ThreadPoolMessage>,
+    /// Taken on shutdown, so that workers stop once the queue is empty.
+    sender: RwLock<Option<Queue>>,
+    shared_data: Arc<SharedData>,
+    metrics: Arc<PoolMetrics>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
-        let (sender, receiver) = mpsc::channel::<ThreadPoolMessage>();
-
-        info!(num_threads = threads, "starting thread pool");
+impl SharedQueueThreadPool {
+    /// Create a thread pool configured by `options`, starting `threads` workers.
+    pub fn with_options(threads: u32, options: &ThreadPoolOption) -> Result<SharedQueueThreadPool> {
+        let (sender, receiver) = match options.queue_size {
+            Some(size) => {
+                let (sender, receiver) = mpsc::sync_channel::<ThreadPoolMessage>(size);
+                (Queue::Bounded(sender, options.when_full), receiver)
+            }
+            None => {
+                let (sender, receiver) = mpsc::channel::<ThreadPoolMessage>();
+                (Queue::Unbounded(sender), receiver)
+            }
+        };
 
-        let pool = SharedQueueThreadPool { sender };
+        info!(
+            num_threads = threads,
+            max_threads = ?options.max_threads,
+            queue_size = ?options.queue_size,
+            "starting thread pool"
+        );
 
+        let metrics = Arc::<PoolMetrics>::default();
         let shared_data = Arc::new(SharedData {
             receiver: Mutex::new(receiver),
+            metrics: metrics.clone(),
+            min_threads: threads as usize,
+            max_threads: options.max_threads.unwrap_or(threads).max(threads) as usize,
+            when_full: match options.queue_size {
+                Some(_) => options.when_full,
+                None => QueuePolicy::Block,
+            },
+            idle_timeout: options.idle_timeout,
+            threads: AtomicUsize::new(threads as usize),
+            next_id: AtomicU32::new(threads),
         });
 
         for id in 0..threads {
@@ -50,16 +147,73 @@ impl ThreadPool for SharedQueueThreadPool {
             spawn_worker(worker_id, shared_data.clone());
         }
 
-        Ok(pool)
+        Ok(SharedQueueThreadPool {
+            sender: RwLock::new(Some(sender)),
+            shared_data,
+            metrics,
+        })
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        SharedQueueThreadPool::with_options(threads, &ThreadPoolOption::new())
     }
 
-    fn spawn<F>(&self, job: F)
+    fn try_spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
+        let message = ThreadPoolMessage::Run(Box::new(self.metrics.track(job)));
+        let (sender, policy) = {
+            let sender = self.sender.read().unwrap_or_else(|e| e.into_inner());
+            let queue = sender.as_ref().ok_or(KvError::PoolShutdown)?;
+            if self.shared_data.is_elastic() {
+                self.shared_data.grow();
+            }
+            match queue {
+                Queue::Unbounded(sender) => {
+                    return sender.send(message).map_err(|_| KvError::PoolShutdown)
+                }
+                Queue::Bounded(sender, policy) => (sender.clone(), *policy),
+            }
+        };
+
+        // Out of the lock, shutting down does not wait for a job blocked on a full queue,
+        // or run by the caller.
+        if policy == QueuePolicy::Block {
+            return sender.send(message).map_err(|_| KvError::PoolShutdown);
+        }
+        match sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => Err(KvError::PoolShutdown),
+            Err(TrySendError::Full(ThreadPoolMessage::Run(job))) => match policy {
+                QueuePolicy::CallerRuns => {
+                    job();
+                    Ok(())
+                }
+                _ => Err(KvError::Busy("thread pool queue is full".to_owned())),
+            },
+        }
+    }
+
+    fn shutdown(&self) {
         self.sender
-            .send(ThreadPoolMessage::Run(Box::new(job)))
-            .expect("cannot spawn job");
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+    }
+
+    fn join(&self) {
+        self.metrics.wait();
+    }
+
+    fn metrics(&self) -> Arc<PoolMetrics> {
+        self.metrics.clone()
+    }
+
+    fn when_full(&self) -> QueuePolicy {
+        self.shared_data.when_full
     }
 }
 
@@ -96,6 +250,9 @@ impl<'a> Drop for Sentinel<'a> {
 fn spawn_worker(worker_id: WorkerId, shared_data: Arc<SharedData>) {
     thread::spawn(move || {
         let sentinel = Sentinel::new(worker_id, &shared_data);
+        let _alive = shared_data.metrics.worker();
+        let poll_interval = shared_data.poll_interval();
+        let mut idle_since = Instant::now();
 
         loop {
             let msg = {
@@ -103,12 +260,21 @@ fn spawn_worker(worker_id: WorkerId, shared_data: Arc<SharedData>) {
                     .receiver
                     .lock()
                     .unwrap_or_else(|_| panic!("cannot acquire lock for worker {}", worker_id.0));
-                tx.recv()
+                tx.recv_timeout(poll_interval)
             };
 
             match msg {
-                Ok(ThreadPoolMessage::Run(job)) => job(),
-                Err(_) => break,
+                Ok(ThreadPoolMessage::Run(job)) => {
+                    job();
+                    idle_since = Instant::now();
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if idle_since.elapsed() >= shared_data.idle_timeout && shared_data.retire() {
+                        info!(worker_id = worker_id.0, "idle worker stopped:");
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
             }
         }
 
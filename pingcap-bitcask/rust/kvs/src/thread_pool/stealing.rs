@@ -0,0 +1,223 @@
+use std::{
+    iter,
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread,
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use tracing::{info, warn};
+
+use super::{PoolMetrics, ThreadPool};
+use crate::{KvError, Result};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct WorkerId(u32);
+
+struct SharedData {
+    /// Jobs spawned from outside, taken by workers in batches.
+    injector: Injector<Job>,
+    /// Ends of the workers' deques other workers steal from, indexed by worker id.
+    stealers: Vec<Stealer<Job>>,
+    /// Workers waiting for jobs.
+    sleeping: AtomicUsize,
+    /// Set once the pool is shut down, workers stop when no job is left.
+    closed: AtomicBool,
+    /// Held by spawners while pushing, so that no job comes after the pool is closed.
+    spawning: RwLock<()>,
+    lock: Mutex<()>,
+    wakeup: Condvar,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl SharedData {
+    /// Wake a sleeping worker, if any, for work which was just made available.
+    fn wake_one(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.wakeup.notify_one();
+        }
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+
+    /// Next job for `worker_id`: from its own deque, then the injector, then other workers.
+    fn find_job(&self, worker_id: WorkerId, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            // Start with the next worker, so that thieves spread over their victims.
+            let id = worker_id.0 as usize;
+            let others = self.stealers[id + 1..].iter().chain(&self.stealers[..id]);
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| others.clone().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    /// Wait until jobs are spawned or the pool is closed.
+    fn sleep(&self) {
+        let guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.sleeping.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if !self.has_jobs() && !self.closed.load(Ordering::SeqCst) {
+            drop(self.wakeup.wait(guard).unwrap_or_else(|e| e.into_inner()));
+        }
+        self.sleeping.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Thread pool where every worker has its own deque of jobs.
+///
+/// Spawned jobs go to a global queue, workers move them to their deques in batches and steal
+/// from each other once theirs is empty, instead of all waiting on a single locked queue.
+pub struct WorkStealingThreadPool {
+    shared_data: Arc<SharedData>,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<WorkStealingThreadPool> {
+        info!(num_threads = threads, "starting work-stealing thread pool");
+
+        let workers: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let metrics = Arc::<PoolMetrics>::default();
+        let shared_data = Arc::new(SharedData {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            sleeping: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            spawning: RwLock::new(()),
+            lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+            metrics: metrics.clone(),
+        });
+
+        for (id, local) in (0..threads).zip(workers) {
+            spawn_worker(WorkerId(id), shared_data.clone(), local);
+        }
+
+        Ok(WorkStealingThreadPool {
+            shared_data,
+            metrics,
+        })
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared_data = &self.shared_data;
+        let _spawning = shared_data
+            .spawning
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        if shared_data.closed.load(Ordering::SeqCst) {
+            return Err(KvError::PoolShutdown);
+        }
+        shared_data.injector.push(Box::new(self.metrics.track(job)));
+        shared_data.wake_one();
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let shared_data = &self.shared_data;
+        let _spawning = shared_data
+            .spawning
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        shared_data.closed.store(true, Ordering::SeqCst);
+        let _guard = shared_data.lock.lock().unwrap_or_else(|e| e.into_inner());
+        shared_data.wakeup.notify_all();
+    }
+
+    fn join(&self) {
+        self.metrics.wait();
+    }
+
+    fn metrics(&self) -> Arc<PoolMetrics> {
+        self.metrics.clone()
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Respawns its worker with the same deque when a job panics.
+struct Sentinel<'a> {
+    shared_data: &'a Arc<SharedData>,
+    worker_id: WorkerId,
+    local: Option<Worker<Job>>,
+}
+
+impl<'a> Sentinel<'a> {
+    fn new(
+        worker_id: WorkerId,
+        shared_data: &'a Arc<SharedData>,
+        local: Worker<Job>,
+    ) -> Sentinel<'a> {
+        Sentinel {
+            shared_data,
+            worker_id,
+            local: Some(local),
+        }
+    }
+
+    fn local(&self) -> &Worker<Job> {
+        self.local
+            .as_ref()
+            .expect("worker deque is kept until the worker stops")
+    }
+
+    fn cancel(mut self) {
+        self.local = None;
+    }
+}
+
+impl<'a> Drop for Sentinel<'a> {
+    fn drop(&mut self) {
+        if let Some(local) = self.local.take() {
+            if thread::panicking() {
+                warn!(worker_id = self.worker_id.0, "worker panicked:");
+            }
+            spawn_worker(self.worker_id, self.shared_data.clone(), local);
+        }
+    }
+}
+
+fn spawn_worker(worker_id: WorkerId, shared_data: Arc<SharedData>, local: Worker<Job>) {
+    thread::spawn(move || {
+        let sentinel = Sentinel::new(worker_id, &shared_data, local);
+        let _alive = shared_data.metrics.worker();
+
+        loop {
+            match shared_data.find_job(worker_id, sentinel.local()) {
+                Some(job) => {
+                    // Jobs left in the deque can be stolen while this one runs.
+                    if !sentinel.local().is_empty() {
+                        shared_data.wake_one();
+                    }
+                    job();
+                }
+                None if shared_data.closed.load(Ordering::SeqCst) => break,
+                None => shared_data.sleep(),
+            }
+        }
+
+        sentinel.cancel();
+    });
+
+    info!(worker_id = worker_id.0, "worker started:");
+}
@@ -0,0 +1,250 @@
+use assert_cmd::prelude::*;
+use kvs::{admin, EngineKind, FaultyFs, KvsEngine, Result, Store};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+fn fill(path: &Path) -> Result<()> {
+    let store = Store::open_with_kvs(path)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "value10".to_owned())?;
+    store.remove("key1".to_owned())?;
+    Ok(())
+}
+
+// Path to the only log file holding data
+fn data_log(path: &Path) -> Result<(u64, PathBuf)> {
+    let log = admin::list_logs(path)?
+        .into_iter()
+        .find(|log| log.records > 0)
+        .expect("a log with records");
+    let file = fs::read_dir(path.join("kvstore"))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|file| {
+            file.to_string_lossy()
+                .ends_with(&format!("{:0>10}.wal", log.id))
+        })
+        .expect("log file exists");
+    Ok((log.id, file))
+}
+
+fn tear(file: &Path) -> Result<()> {
+    let mut file = OpenOptions::new().append(true).open(file)?;
+    file.write_all(&[0x20, 0, 0, 0, 2, b'S'])?;
+    Ok(())
+}
+
+#[test]
+fn admin_list_logs_and_dump() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+
+    let logs = admin::list_logs(temp_dir.path())?;
+    let records: usize = logs.iter().map(|log| log.records).sum();
+    let live: usize = logs.iter().map(|log| log.live_records).sum();
+    assert_eq!(records, 12);
+    assert_eq!(live, 9);
+    assert!(logs.iter().all(|log| log.live_ratio() <= 1.0));
+
+    let (id, _) = data_log(temp_dir.path())?;
+    let records = admin::dump(temp_dir.path(), id)?;
+    assert_eq!(records.len(), 12);
+    assert_eq!(records[0].offset, 0);
+    assert_eq!(records[1].offset, records[0].size);
+    assert_eq!(records[10].key, "key0");
+    assert_eq!(records[10].value, Some("value10".to_owned()));
+    assert_eq!(records[11].key, "key1");
+    assert_eq!(records[11].value, None);
+
+    assert!(admin::dump(temp_dir.path(), 1000).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn admin_verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+
+    let verification = admin::verify(temp_dir.path())?;
+    assert!(verification.is_ok());
+    assert_eq!(verification.records, 12);
+    assert_eq!(verification.keys, 9);
+
+    let (id, file) = data_log(temp_dir.path())?;
+    let size = fs::metadata(&file)?.len();
+    tear(&file)?;
+
+    let verification = admin::verify(temp_dir.path())?;
+    assert!(!verification.is_ok());
+    assert_eq!(verification.torn.len(), 1);
+    assert_eq!(verification.torn[0].id, id);
+    assert_eq!(verification.torn[0].valid_len, size);
+
+    let repair = admin::repair(temp_dir.path())?;
+    assert_eq!(repair.truncated, verification.torn);
+    assert_eq!(repair.keys, 9);
+    assert_eq!(fs::metadata(&file)?.len(), size);
+    assert!(admin::verify(temp_dir.path())?.is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn admin_compact() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+
+    admin::compact(temp_dir.path())?;
+
+    let logs = admin::list_logs(temp_dir.path())?;
+    let records: usize = logs.iter().map(|log| log.records).sum();
+    let live: usize = logs.iter().map(|log| log.live_records).sum();
+    assert_eq!(records, 9);
+    assert_eq!(live, 9);
+
+    let store = Store::open_with_kvs(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value10".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.keys()?.len(), 9);
+
+    Ok(())
+}
+
+// Should finish an interrupted migration to kvs before touching the logs
+#[test]
+fn admin_after_interrupted_migration() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = Store::open_with_sled(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // Crash between the two renames: the sled data is moved away, the kvs data not yet in place.
+    let fs = FaultyFs::new();
+    fs.crash_after(5);
+    assert!(Store::migrate_with_faulty_fs(
+        temp_dir.path(),
+        EngineKind::Sled,
+        EngineKind::Kvs,
+        fs.clone()
+    )
+    .is_err());
+    assert!(fs.crashed());
+    assert!(!EngineKind::Kvs.dbpath(temp_dir.path()).unwrap().exists());
+
+    admin::compact(temp_dir.path())?;
+    assert!(!temp_dir.path().join(".migrate").exists());
+    assert_eq!(admin::repair(temp_dir.path())?.keys, 1);
+    let store = Store::open_with_kvs(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn admin_missing_database() {
+    let temp_dir = TempDir::new().unwrap();
+    assert!(admin::list_logs(temp_dir.path()).is_err());
+    assert!(admin::verify(temp_dir.path()).is_err());
+    assert!(admin::compact(temp_dir.path()).is_err());
+    assert!(admin::repair(temp_dir.path()).is_err());
+    assert!(!temp_dir.path().join("kvstore").exists());
+}
+
+#[test]
+fn admin_cli_inspect() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+    let (id, file) = data_log(temp_dir.path())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["ls-logs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("ratio"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", &id.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("set key0 value10"))
+        .stdout(contains("rm key1"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("9 keys"));
+
+    tear(&file)?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("is torn"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["repair"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("rebuilt index with 9 keys"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["compact"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("compacted"));
+
+    Ok(())
+}
+
+#[test]
+fn admin_cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = Store::open_with_kvs(&temp_dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("migrated 2 keys"));
+
+    let store = Store::open_with_sled(&temp_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // data is not in kvs format anymore
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::local_address;
+use kvs::{
+    AsyncKvsClient, AsyncKvsEngine, AsyncKvsServer, KvsClient, KvsRequest, KvsResponse, Result,
+    Store,
+};
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+struct Running {
+    address: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    job: JoinHandle<Result<()>>,
+}
+
+impl Running {
+    async fn stop(self) -> Result<()> {
+        let _ = self.shutdown.send(());
+        self.job.await.expect("server task panicked")
+    }
+}
+
+async fn serve(store: Store) -> Result<Running> {
+    let server = AsyncKvsServer::open(local_address(), store).await?;
+    let address = server.address;
+    let (shutdown, signal) = oneshot::channel();
+    let job = tokio::spawn(server.serve_with_shutdown(async {
+        let _ = signal.await;
+    }));
+    Ok(Running {
+        address,
+        shutdown,
+        job,
+    })
+}
+
+async fn request(client: &mut AsyncKvsClient, request: KvsRequest) -> Result<KvsResponse> {
+    client.send(request).await?;
+    client.recv().await
+}
+
+// Should serve every request kind
+#[tokio::test]
+async fn async_server_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = serve(Store::open_with_kvs(&temp_dir)?).await?;
+    let mut client = AsyncKvsClient::connect(server.address).await?;
+
+    let set = KvsRequest::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    assert!(matches!(
+        request(&mut client, set).await?,
+        KvsResponse::Ok(None)
+    ));
+
+    let get = KvsRequest::Get {
+        key: "key1".to_owned(),
+    };
+    match request(&mut client, get).await? {
+        KvsResponse::Ok(Some(value)) => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    let remove = KvsRequest::Remove {
+        key: "key2".to_owned(),
+    };
+    match request(&mut client, remove).await? {
+        KvsResponse::KeyNotFound(key) => assert_eq!(key, "key2"),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    match request(&mut client, KvsRequest::Stats {}).await? {
+        KvsResponse::Stats(stats) => {
+            assert_eq!(stats.active_connections, 1);
+            assert_eq!(stats.set.count, 1);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    server.stop().await
+}
+
+// Should keep many more connections open than there are worker threads
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_server_many_connections() -> Result<()> {
+    const CLIENTS: usize = 200;
+
+    let server = serve(Store::open_in_memory()).await?;
+
+    let mut clients = Vec::new();
+    for _ in 0..CLIENTS {
+        clients.push(AsyncKvsClient::connect(server.address).await?);
+    }
+
+    let jobs: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut client)| {
+            tokio::spawn(async move {
+                let set = KvsRequest::Set {
+                    key: format!("key{}", i),
+                    value: format!("value{}", i),
+                };
+                request(&mut client, set).await?;
+                let get = KvsRequest::Get {
+                    key: format!("key{}", i),
+                };
+                let response = request(&mut client, get).await?;
+                assert!(matches!(response, KvsResponse::Ok(Some(value)) if value == format!("value{}", i)));
+                Ok::<_, kvs::KvError>(client)
+            })
+        })
+        .collect();
+
+    // Every client is still connected while the others are served
+    let mut clients = Vec::new();
+    for job in jobs {
+        clients.push(job.await.expect("client task panicked")?);
+    }
+    match request(&mut clients[0], KvsRequest::Stats {}).await? {
+        KvsResponse::Stats(stats) => assert_eq!(stats.active_connections, CLIENTS as u64),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    server.stop().await
+}
+
+// Blocking clients speak the same protocol
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_server_blocking_client() -> Result<()> {
+    let server = serve(Store::open_in_memory()).await?;
+    let address = server.address;
+
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut client = KvsClient::connect(address)?;
+        client.send(KvsRequest::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })?;
+        assert!(matches!(client.recv()?, KvsResponse::Ok(None)));
+        client.send(KvsRequest::Get {
+            key: "key1".to_owned(),
+        })?;
+        assert!(matches!(client.recv()?, KvsResponse::Ok(Some(value)) if value == "value1"));
+        Ok(())
+    })
+    .await
+    .expect("client task panicked")?;
+
+    server.stop().await
+}
+
+// Shutting down closes open connections
+#[tokio::test]
+async fn async_server_shutdown() -> Result<()> {
+    let server = serve(Store::open_in_memory()).await?;
+    let address = server.address;
+    let mut client = AsyncKvsClient::connect(address).await?;
+    request(&mut client, KvsRequest::Stats {}).await?;
+
+    server.stop().await?;
+
+    let get = KvsRequest::Get {
+        key: "key1".to_owned(),
+    };
+    assert!(request(&mut client, get).await.is_err());
+    assert!(AsyncKvsClient::connect(address).await.is_err());
+
+    Ok(())
+}
+
+// Should run blocking engine calls off the async workers
+#[tokio::test]
+async fn async_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(Store::open_with_kvs(&temp_dir)?);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    engine.set("key2".to_owned(), "value2".to_owned()).await?;
+    engine.remove("key2".to_owned()).await?;
+    assert!(engine.remove("key2".to_owned()).await.is_err());
+
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.keys().await?, vec!["key1".to_owned()]);
+    assert_eq!(engine.stats().await?.set.count, 2);
+
+    Ok(())
+}
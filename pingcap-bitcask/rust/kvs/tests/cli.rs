@@ -1,6 +1,8 @@
 use assert_cmd::prelude::*;
+use kvs::{KvError, KvsClient};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -164,6 +166,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -184,6 +187,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +207,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,6 +217,29 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn cli_locked_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(child.id().to_string()));
+
+    child.kill().expect("server exited before killed");
+
+    child.wait().expect("failed to wait on server");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +252,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -307,6 +336,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -337,3 +367,283 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--capacity", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+
+    // Only one key fits
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_capacity_needs_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--capacity",
+            "1",
+            "--addr",
+            "127.0.0.1:4009",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--capacity"));
+}
+
+#[test]
+fn cli_token() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .env("KVS_TOKEN", "secret")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .env_remove("KVS_TOKEN")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("authentication failed"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .env("KVS_TOKEN", "secret")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_batch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let commands = temp_dir.path().join("commands");
+    fs::write(
+        &commands,
+        "# comment\nset key1 value1\n\nset \"key 2\" 'value 2'\nget key1\nget \"key 2\"\nget key3\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", commands.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nvalue 2\nKey not found\n");
+
+    // Failures do not stop the batch, but make it exit with a non-zero code
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "-", "--format", "json", "--addr", addr])
+        .write_stdin("rm key1\nrm key1\nunknown key1\nget key1\nget \"key 2\"\n")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(
+            "{\"line\":1,\"ok\":true}\n\
+             {\"line\":2,\"ok\":false,\"error\":\"Key not found `key1`\"}\n\
+             {\"line\":3,\"ok\":false,\"error\":\"invalid argument: unrecognized subcommand 'unknown'\"}\n\
+             {\"line\":4,\"ok\":true,\"value\":null}\n\
+             {\"line\":5,\"ok\":true,\"value\":\"value 2\"}\n",
+        )
+        .stderr(contains("2 command(s) failed"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_shell() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let history = temp_dir.path().join("history");
+    let history_arg = history.to_str().unwrap();
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--history", history_arg, "--addr", addr])
+        .write_stdin("set key1 value1\nrm key2\nget key1\nquit\nget key2\n")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"))
+        .stderr(contains("Key not found `key2`"));
+    assert!(fs::read_to_string(&history)
+        .unwrap()
+        .contains("set key1 value1"));
+
+    // Commands from the history are kept
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--history", history_arg, "--addr", addr])
+        .write_stdin("get key1\n")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    let history = fs::read_to_string(&history).unwrap();
+    assert!(history.contains("set key1 value1") && history.contains("get key1"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `--pool` and `--threads` choose the pool serving connections
+#[test]
+fn cli_pool() {
+    let addr = "127.0.0.1:4013";
+    for pool in ["shared", "stealing", "rayon", "elastic"] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", "memory", "--addr", addr])
+            .args(["--pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", pool, "--addr", addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["stats", "--addr", addr])
+            .assert()
+            .success()
+            .stdout(contains("kvs_pool_jobs_completed_total 1\n"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    }
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--threads", "0"])
+        .assert()
+        .failure();
+}
+
+// A server with a full queue answers new connections with `Busy`
+#[test]
+fn cli_busy_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr, "--pool", "shared"])
+        .args(["--threads", "1", "--queue-size", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // One connection holds the worker, the next one waits in the queue.
+    let address = addr.parse().unwrap();
+    let mut client = KvsClient::connect(address).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let queued = TcpStream::connect(address).unwrap();
+
+    let error = KvsClient::connect(address).err();
+    assert!(matches!(error, Some(KvError::Busy(_))), "{:?}", error);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("queue is full"));
+
+    drop(queued);
+    drop(client);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_queue_size_needs_shared_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4015"])
+        .args(["--pool", "naive", "--queue-size", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--queue-size"));
+}
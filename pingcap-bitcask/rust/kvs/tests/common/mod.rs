@@ -0,0 +1,32 @@
+//! Servers on local ports, shared by the network tests.
+// Every test file uses only some of them.
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvsEngine, KvsServer, Result, Store};
+
+/// Loopback address on a port picked by the OS.
+pub fn local_address() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
+}
+
+/// Server for `engine` on `address`, with 4 pool threads.
+pub fn open_at<E: KvsEngine>(
+    address: SocketAddr,
+    engine: E,
+) -> Result<KvsServer<E, SharedQueueThreadPool>> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    KvsServer::open(address, engine, pool)
+}
+
+/// Server for `engine` on a local address.
+pub fn open_with<E: KvsEngine>(engine: E) -> Result<KvsServer<E, SharedQueueThreadPool>> {
+    open_at(local_address(), engine)
+}
+
+/// Server for an in-memory store on a local address.
+pub fn open() -> Result<KvsServer<Store, SharedQueueThreadPool>> {
+    open_with(Store::open_in_memory())
+}
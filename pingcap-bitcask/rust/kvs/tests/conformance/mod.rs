@@ -0,0 +1,374 @@
+//! Behaviours every [`KvsEngine`] must have.
+//!
+//! A backend implements [`Backend`], then [`conformance_tests!`] generates one test per case.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use kvs::{KvError, KvsEngine, Result};
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestRunner};
+use tempfile::TempDir;
+
+/// How to open an engine under test.
+pub trait Backend {
+    type Engine: KvsEngine + Sync;
+
+    /// Whether data survives dropping and reopening the engine.
+    const PERSISTENT: bool = true;
+
+    fn open(path: &Path) -> Result<Self::Engine>;
+}
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+/// Drop the engine, then open it again from the same folder if the backend is persistent.
+fn reopen<B: Backend>(engine: B::Engine, path: &Path) -> Result<B::Engine> {
+    if B::PERSISTENT {
+        drop(engine);
+        B::open(path)
+    } else {
+        Ok(engine)
+    }
+}
+
+// Should get previously stored value
+pub fn get_stored_value<B: Backend>() -> Result<()> {
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+pub fn overwrite_value<B: Backend>() -> Result<()> {
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+pub fn get_non_existent_value<B: Backend>() -> Result<()> {
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should fail with `KeyNotFound` when removing a key that is not there
+pub fn remove_non_existent_key<B: Backend>() -> Result<()> {
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    match store.remove("key1".to_owned()) {
+        Err(KvError::KeyNotFound(key)) => assert_eq!(key, "key1"),
+        other => panic!("expected key not found, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Removed keys stay removed, also after reopening
+pub fn remove_key<B: Backend>() -> Result<()> {
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+
+    // The key can be set again
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should list live keys in order
+pub fn list_keys<B: Backend>() -> Result<()> {
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+    assert!(store.keys()?.is_empty());
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+
+    Ok(())
+}
+
+// Overwrite the same keys with large values, enough to trigger compaction of on-disk engines.
+// Test data correctness afterward.
+pub fn compaction<B: Backend>() -> Result<()> {
+    const KEYS: usize = 100;
+    const ITERATIONS: usize = 40;
+
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    let value = |iter: usize| format!("{:0>4096}", iter);
+    for iter in 0..ITERATIONS {
+        for key_id in 0..KEYS {
+            store.set(format!("key{}", key_id), value(iter))?;
+        }
+    }
+    for key_id in (0..KEYS).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let check = |store: &B::Engine| -> Result<()> {
+        for key_id in 0..KEYS {
+            let expected = (key_id % 2 == 1).then(|| value(ITERATIONS - 1));
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.keys()?.len(), KEYS / 2);
+        Ok(())
+    };
+
+    check(&store)?;
+    let store = reopen::<B>(store, temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Sets from many threads are all visible
+pub fn concurrent_set<B: Backend>() -> Result<()> {
+    const THREADS: usize = 100;
+
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..THREADS {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    for i in 0..THREADS {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Readers never see a key that was set before they started as missing
+pub fn concurrent_get_set<B: Backend>() -> Result<()> {
+    const THREADS: usize = 20;
+    const KEYS: usize = 50;
+
+    let temp_dir = temp_dir();
+    let store = B::open(temp_dir.path())?;
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "0".to_owned())?;
+    }
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..KEYS {
+                    let key = format!("key{}", (i + thread_id) % KEYS);
+                    if thread_id % 2 == 0 {
+                        store.set(key, thread_id.to_string()).unwrap();
+                    } else {
+                        let value = store.get(key).unwrap().expect("key is never removed");
+                        assert!(value.parse::<usize>().is_ok());
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let store = reopen::<B>(store, temp_dir.path())?;
+    assert_eq!(store.keys()?.len(), KEYS);
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Get(String),
+    Remove(String),
+    Reopen,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let key = "key[0-9]";
+    prop_oneof![
+        4 => (key, "[a-z]{0,8}").prop_map(|(key, value)| Op::Set(key, value)),
+        2 => key.prop_map(Op::Get),
+        2 => key.prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+// Random sequences of operations behave the same as on a `BTreeMap`
+pub fn model<B: Backend>() -> Result<()> {
+    let mut runner = TestRunner::new(Config {
+        cases: 32,
+        failure_persistence: None,
+        ..Config::default()
+    });
+
+    runner
+        .run(&prop::collection::vec(op(), 1..64), |ops| {
+            let temp_dir = temp_dir();
+            let mut store = Some(B::open(temp_dir.path()).unwrap());
+            let mut model = BTreeMap::new();
+
+            for op in ops {
+                let engine = store.as_ref().unwrap();
+                match op {
+                    Op::Set(key, value) => {
+                        engine.set(key.clone(), value.clone()).unwrap();
+                        model.insert(key, value);
+                    }
+                    Op::Get(key) => {
+                        prop_assert_eq!(engine.get(key.clone()).unwrap(), model.get(&key).cloned());
+                    }
+                    Op::Remove(key) => match engine.remove(key.clone()) {
+                        Ok(()) => prop_assert!(model.remove(&key).is_some()),
+                        Err(KvError::KeyNotFound(_)) => prop_assert!(!model.contains_key(&key)),
+                        Err(e) => panic!("unexpected error {}", e),
+                    },
+                    Op::Reopen => {
+                        let engine = store.take().unwrap();
+                        store = Some(reopen::<B>(engine, temp_dir.path()).unwrap());
+                    }
+                }
+            }
+
+            let keys: Vec<String> = model.keys().cloned().collect();
+            prop_assert_eq!(store.unwrap().keys().unwrap(), keys);
+            Ok(())
+        })
+        .unwrap();
+
+    Ok(())
+}
+
+/// Generate a test module running every case against a backend.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($name:ident, $backend:ty) => {
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn get_stored_value() -> kvs::Result<()> {
+                conformance::get_stored_value::<$backend>()
+            }
+
+            #[test]
+            fn overwrite_value() -> kvs::Result<()> {
+                conformance::overwrite_value::<$backend>()
+            }
+
+            #[test]
+            fn get_non_existent_value() -> kvs::Result<()> {
+                conformance::get_non_existent_value::<$backend>()
+            }
+
+            #[test]
+            fn remove_non_existent_key() -> kvs::Result<()> {
+                conformance::remove_non_existent_key::<$backend>()
+            }
+
+            #[test]
+            fn remove_key() -> kvs::Result<()> {
+                conformance::remove_key::<$backend>()
+            }
+
+            #[test]
+            fn list_keys() -> kvs::Result<()> {
+                conformance::list_keys::<$backend>()
+            }
+
+            #[test]
+            fn compaction() -> kvs::Result<()> {
+                conformance::compaction::<$backend>()
+            }
+
+            #[test]
+            fn concurrent_set() -> kvs::Result<()> {
+                conformance::concurrent_set::<$backend>()
+            }
+
+            #[test]
+            fn concurrent_get_set() -> kvs::Result<()> {
+                conformance::concurrent_get_set::<$backend>()
+            }
+
+            #[test]
+            fn model() -> kvs::Result<()> {
+                conformance::model::<$backend>()
+            }
+        }
+    };
+}
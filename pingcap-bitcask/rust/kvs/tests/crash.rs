@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use kvs::{FaultyFs, KvError, KvOption, KvsEngine, Result, Store};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tempfile::TempDir;
+
+const SEEDS: u64 = 8;
+const OPERATIONS: usize = 300;
+const CRASH_POINTS: u64 = 24;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+}
+
+impl Op {
+    fn key(&self) -> &str {
+        match self {
+            Op::Set(key, _) | Op::Remove(key) => key,
+        }
+    }
+}
+
+fn workload(seed: u64) -> Vec<Op> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    (0..OPERATIONS)
+        .map(|_| {
+            let key = format!("key{}", rng.gen_range(0..20));
+            if rng.gen_bool(0.8) {
+                let len = rng.gen_range(0..200);
+                Op::Set(key, "v".repeat(len))
+            } else {
+                Op::Remove(key)
+            }
+        })
+        .collect()
+}
+
+// Small logs and few readers, so that rollovers and merges happen all the time.
+fn options() -> KvOption {
+    let mut options = KvOption::new();
+    options
+        .writer_size(1024)
+        .num_log_readers(3)
+        .background_merge(false);
+    options
+}
+
+fn open(path: &Path, fs: &FaultyFs) -> Result<Store> {
+    let mut options = options();
+    options.faulty_fs(fs.clone());
+    Store::open_with_options(path, options)
+}
+
+/// Apply one operation to the store and the model.
+fn apply(store: &Store, model: &mut BTreeMap<String, String>, op: &Op) -> Result<()> {
+    match op {
+        Op::Set(key, value) => {
+            store.set(key.clone(), value.clone())?;
+            model.insert(key.clone(), value.clone());
+        }
+        Op::Remove(key) => match store.remove(key.clone()) {
+            Ok(()) => assert!(model.remove(key).is_some()),
+            Err(KvError::KeyNotFound(_)) => assert!(!model.contains_key(key)),
+            Err(e) => return Err(e),
+        },
+    }
+    Ok(())
+}
+
+/// Run the workload until the file system crashes.
+/// Returns the model of acknowledged operations, and the operation that was cut short.
+fn run_until_crash(
+    path: &Path,
+    fs: &FaultyFs,
+    ops: &[Op],
+) -> (BTreeMap<String, String>, Option<Op>) {
+    let store = match open(path, fs) {
+        Ok(store) => store,
+        Err(e) => {
+            assert!(fs.crashed(), "failed to open without crashing: {}", e);
+            return (BTreeMap::new(), None);
+        }
+    };
+    let mut model = BTreeMap::new();
+    for op in ops {
+        if apply(&store, &mut model, op).is_err() {
+            assert!(fs.crashed(), "failed without crashing on {:?}", op);
+            return (model, Some(op.clone()));
+        }
+    }
+    (model, None)
+}
+
+/// Every acknowledged operation survives, the interrupted one is either applied or not.
+fn check(store: &Store, model: &BTreeMap<String, String>, interrupted: Option<&Op>) -> Result<()> {
+    let mut expected_keys: Vec<String> = model.keys().cloned().collect();
+    if let Some(op) = interrupted {
+        let key = op.key().to_owned();
+        let before = model.get(&key).cloned();
+        let after = match op {
+            Op::Set(_, value) => Some(value.clone()),
+            Op::Remove(_) => None,
+        };
+        let actual = store.get(key.clone())?;
+        assert!(
+            actual == before || actual == after,
+            "key `{}` holds {:?}, expected {:?} or {:?}",
+            key,
+            actual,
+            before,
+            after
+        );
+        expected_keys.retain(|k| k != &key);
+        if actual.is_some() {
+            expected_keys.push(key);
+            expected_keys.sort();
+        }
+    }
+
+    for (key, value) in model {
+        if interrupted.is_some_and(|op| op.key() == key) {
+            continue;
+        }
+        assert_eq!(
+            store.get(key.clone())?,
+            Some(value.clone()),
+            "key `{}`",
+            key
+        );
+    }
+    assert_eq!(store.keys()?, expected_keys);
+
+    Ok(())
+}
+
+// Count the file system operations of a whole workload.
+fn count_operations(ops: &[Op]) -> u64 {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = FaultyFs::new();
+    let (_, interrupted) = run_until_crash(temp_dir.path(), &fs, ops);
+    assert!(interrupted.is_none());
+    fs.operations()
+}
+
+// Crash at evenly spread points of random workloads, reopen, and compare with a model.
+#[test]
+fn crash_and_recover() -> Result<()> {
+    for seed in 0..SEEDS {
+        let ops = workload(seed);
+        let total = count_operations(&ops);
+        assert!(total > CRASH_POINTS);
+
+        for point in 0..CRASH_POINTS {
+            let crash_at = point * total / CRASH_POINTS + seed % 7;
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+            let fs = FaultyFs::new();
+            fs.crash_after(crash_at);
+            let (model, interrupted) = run_until_crash(temp_dir.path(), &fs, &ops);
+            assert!(fs.crashed(), "seed {} did not crash at {}", seed, crash_at);
+
+            // Reopen twice, recovery itself must leave a consistent store behind.
+            for _ in 0..2 {
+                let store = Store::open_with_options(temp_dir.path(), options())?;
+                check(&store, &model, interrupted.as_ref())?;
+            }
+
+            // The store keeps working after recovery.
+            let store = Store::open_with_options(temp_dir.path(), options())?;
+            let mut model: BTreeMap<String, String> = store
+                .keys()?
+                .into_iter()
+                .map(|key| {
+                    let value = store.get(key.clone()).unwrap().unwrap();
+                    (key, value)
+                })
+                .collect();
+            for op in &ops {
+                apply(&store, &mut model, op)?;
+            }
+            check(&store, &model, None)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Crash on every single operation of a short workload.
+#[test]
+fn crash_everywhere() -> Result<()> {
+    let ops = &workload(42)[..60];
+    let total = count_operations(ops);
+
+    for crash_at in 0..total {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let fs = FaultyFs::new();
+        fs.crash_after(crash_at);
+        let (model, interrupted) = run_until_crash(temp_dir.path(), &fs, ops);
+
+        let store = Store::open_with_options(temp_dir.path(), options())?;
+        check(&store, &model, interrupted.as_ref())?;
+    }
+
+    Ok(())
+}
+
+// Fail every single operation of a workload once, merges included. The store goes on, the
+// failed operation is not applied, and the data on disk matches what the store read.
+#[test]
+fn fail_once_everywhere() -> Result<()> {
+    let ops = &workload(7)[..150];
+    let total = count_operations(ops);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), &FaultyFs::new())?;
+    let mut model = BTreeMap::new();
+    for op in ops {
+        apply(&store, &mut model, op)?;
+    }
+    assert!(store.stats()?.merge.count > 0, "the workload merges");
+    drop(store);
+
+    for fail_at in 0..total {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let fs = FaultyFs::new();
+        fs.fail_after(fail_at);
+        // The failure may hit opening, which works the second time.
+        let (store, mut failed) = match open(temp_dir.path(), &fs) {
+            Ok(store) => (store, false),
+            Err(_) => (open(temp_dir.path(), &fs)?, true),
+        };
+
+        let mut model = BTreeMap::new();
+        for op in ops {
+            if let Err(e) = apply(&store, &mut model, op) {
+                assert!(!failed, "failed again on {:?}: {}", op, e);
+                failed = true;
+            }
+        }
+        assert!(failed, "nothing failed at {}", fail_at);
+        check(&store, &model, None)?;
+        drop(store);
+
+        let store = Store::open_with_options(temp_dir.path(), options())?;
+        check(&store, &model, None)?;
+    }
+
+    Ok(())
+}
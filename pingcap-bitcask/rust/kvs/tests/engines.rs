@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use kvs::{Result, Store};
+
+mod conformance;
+
+use conformance::Backend;
+
+struct KvsBackend;
+
+impl Backend for KvsBackend {
+    type Engine = Store;
+
+    fn open(path: &Path) -> Result<Store> {
+        Store::open_with_kvs(path)
+    }
+}
+
+struct SledBackend;
+
+impl Backend for SledBackend {
+    type Engine = Store;
+
+    fn open(path: &Path) -> Result<Store> {
+        Store::open_with_sled(path)
+    }
+}
+
+struct MemoryBackend;
+
+impl Backend for MemoryBackend {
+    type Engine = Store;
+
+    const PERSISTENT: bool = false;
+
+    fn open(_path: &Path) -> Result<Store> {
+        Ok(Store::open_in_memory())
+    }
+}
+
+conformance_tests!(kvs_engine, KvsBackend);
+conformance_tests!(sled_engine, SledBackend);
+conformance_tests!(memory_engine, MemoryBackend);
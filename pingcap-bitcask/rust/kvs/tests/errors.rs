@@ -0,0 +1,185 @@
+mod common;
+mod wire;
+
+use std::io::{self, Write};
+use std::net::TcpStream;
+
+use common::{local_address, open_with};
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, ErrorCode, KvError, KvsClient, KvsEngine, KvsRequest,
+    KvsResponse, Result,
+};
+use wire::read_frame;
+
+/// Engine failing every request, with the error named by the key.
+#[derive(Debug, Clone)]
+struct FailingEngine;
+
+fn failure(key: &str) -> KvError {
+    match key {
+        "io" => io::Error::new(io::ErrorKind::PermissionDenied, "disk says no").into(),
+        "corrupted" => KvError::Corrupted("bad checksum".to_owned()),
+        "busy" => KvError::Busy("too many requests".to_owned()),
+        "mismatch" => KvError::MismatchEngine,
+        "invalid" => KvError::CannotWriteLen(usize::MAX),
+        "missing" => KvError::KeyNotFound(key.to_owned()),
+        _ => KvError::SharedRead("poisoned lock".to_owned()),
+    }
+}
+
+impl KvsEngine for FailingEngine {
+    fn set(&self, key: String, _value: String) -> Result<()> {
+        Err(failure(&key))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Err(failure(&key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        Err(failure(&key))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Err(failure(""))
+    }
+}
+
+fn check_errors(mut get: impl FnMut(&str) -> Result<Option<String>>) {
+    match get("io") {
+        Err(KvError::Io(e)) => assert!(e.to_string().contains("disk says no"), "{}", e),
+        other => panic!("unexpected {:?}", other),
+    }
+    match get("corrupted") {
+        Err(KvError::Corrupted(message)) => assert!(message.contains("bad checksum")),
+        other => panic!("unexpected {:?}", other),
+    }
+    match get("busy") {
+        Err(KvError::Busy(message)) => assert!(message.contains("too many requests")),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(get("mismatch"), Err(KvError::MismatchEngine)));
+    assert!(matches!(get("invalid"), Err(KvError::InvalidArgument(_))));
+    assert!(matches!(get("missing"), Err(KvError::KeyNotFound(key)) if key == "missing"));
+    match get("other") {
+        Err(KvError::Server(message)) => assert!(message.contains("poisoned lock")),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+// Server failures come back as the matching errors
+#[test]
+fn client_errors() -> Result<()> {
+    let server = open_with(FailingEngine)?.serve();
+    let address = server.address;
+    let mut client = KvsClient::connect(address)?;
+
+    check_errors(|key| client.get(key.to_owned()));
+    assert!(matches!(
+        client.set("busy".to_owned(), "value".to_owned()),
+        Err(KvError::Busy(_))
+    ));
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Same with the async client and server
+#[test]
+fn async_client_errors() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let mut client = runtime.block_on(async {
+        let server = AsyncKvsServer::open(local_address(), FailingEngine).await?;
+        let address = server.address;
+        tokio::spawn(server.serve());
+        AsyncKvsClient::connect(address).await
+    })?;
+
+    check_errors(|key| runtime.block_on(client.get(key.to_owned())));
+
+    Ok(())
+}
+
+// Errors carry a code on the wire
+#[test]
+fn error_codes() -> Result<()> {
+    let server = open_with(FailingEngine)?.serve();
+    let address = server.address;
+    let mut stream = TcpStream::connect(address)?;
+    let hello = KvsRequest::Hello {
+        version: kvs::PROTOCOL_VERSION,
+        features: vec![],
+        token: None,
+    };
+    stream.write_all(&wire::encode(0, &hello))?;
+    read_frame(&mut stream)?;
+
+    let cases = [
+        ("io", ErrorCode::Io),
+        ("corrupted", ErrorCode::Corrupted),
+        ("busy", ErrorCode::Busy),
+        ("invalid", ErrorCode::InvalidArgument),
+        ("mismatch", ErrorCode::MismatchEngine),
+        ("other", ErrorCode::Internal),
+    ];
+    for (id, (key, expected)) in cases.iter().enumerate() {
+        let request = KvsRequest::Get {
+            key: key.to_string(),
+        };
+        stream.write_all(&wire::encode(id as u64 + 1, &request))?;
+        match read_frame(&mut stream)? {
+            (_, KvsResponse::Error { code, message }) => {
+                assert_eq!(code, *expected);
+                assert!(!message.is_empty());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    server.shutdown();
+    Ok(())
+}
+
+// Clients which predate version 2 keep getting the old error
+#[test]
+fn version_one_errors() -> Result<()> {
+    let server = open_with(FailingEngine)?.serve();
+    let address = server.address;
+    let request = KvsRequest::Get {
+        key: "io".to_owned(),
+    };
+
+    // No handshake
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(&wire::encode(1, &request))?;
+    assert!(matches!(
+        read_frame(&mut stream)?.1,
+        KvsResponse::ServerError {}
+    ));
+
+    // A handshake cannot ask for version 1 anymore
+    let mut stream = TcpStream::connect(address)?;
+    let hello = KvsRequest::Hello {
+        version: 1,
+        features: vec![],
+        token: None,
+    };
+    stream.write_all(&wire::encode(1, &hello))?;
+    assert!(matches!(
+        read_frame(&mut stream)?.1,
+        KvsResponse::UnsupportedVersion { .. }
+    ));
+
+    // Bare documents
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(&bson::to_vec(&request).unwrap())?;
+    let response: KvsResponse = bson::from_reader(&mut stream)?;
+    assert!(matches!(response, KvsResponse::ServerError {}));
+
+    server.shutdown();
+    Ok(())
+}
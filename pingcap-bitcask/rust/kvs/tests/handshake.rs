@@ -0,0 +1,309 @@
+mod common;
+mod wire;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::{local_address, open};
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, ErrorCode, Feature, KvError, KvsClient, KvsRequest,
+    KvsResponse, Result, Store, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use wire::read_frame;
+
+fn hello(version: u32, features: Vec<Feature>) -> KvsRequest {
+    KvsRequest::Hello {
+        version,
+        features,
+        token: None,
+    }
+}
+
+fn get(key: &str) -> KvsRequest {
+    KvsRequest::Get {
+        key: key.to_owned(),
+    }
+}
+
+fn set(key: &str, value: &str) -> KvsRequest {
+    KvsRequest::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+// Clients agree on the current version when connecting
+#[test]
+fn handshake() -> Result<()> {
+    let server = open()?.serve();
+
+    let client = KvsClient::connect(server.address)?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    assert_eq!(client.features(), &[Feature::Batching]);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Same with the async client and server
+#[test]
+fn async_handshake() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let address = runtime.block_on(async {
+        let server = AsyncKvsServer::open(local_address(), Store::open_in_memory()).await?;
+        let address = server.address;
+        tokio::spawn(server.serve());
+
+        let client = AsyncKvsClient::connect(address).await?;
+        assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+        assert_eq!(client.features(), &[Feature::Batching]);
+        Ok::<_, KvError>(address)
+    })?;
+
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(&wire::encode(1, &hello(MIN_PROTOCOL_VERSION - 1, vec![])))?;
+    let (_, response) = read_frame(&mut stream)?;
+    assert!(matches!(response, KvsResponse::UnsupportedVersion { .. }));
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+
+    Ok(())
+}
+
+// Newer clients are refused with the versions the server speaks
+#[test]
+fn newer_client() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    stream.write_all(&wire::encode(1, &hello(PROTOCOL_VERSION + 1, vec![])))?;
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 1);
+    match response {
+        KvsResponse::UnsupportedVersion { version, min, max } => {
+            assert_eq!(version, PROTOCOL_VERSION + 1);
+            assert_eq!(min, MIN_PROTOCOL_VERSION);
+            assert_eq!(max, PROTOCOL_VERSION);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Features the server does not know or enable are left out
+#[test]
+fn unknown_features() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+
+    let hello = bson::doc! {
+        "Hello": {
+            "version": PROTOCOL_VERSION,
+            "features": ["Compression", "Batching", "Auth"],
+            "token": null,
+        }
+    };
+    stream.write_all(&wire::encode(1, &hello))?;
+    let (_, response) = read_frame(&mut stream)?;
+    match response {
+        KvsResponse::Hello { version, features } => {
+            assert_eq!(version, PROTOCOL_VERSION);
+            assert_eq!(features, vec![Feature::Batching]);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    stream.write_all(&wire::encode(2, &get("key1")))?;
+    let (_, response) = read_frame(&mut stream)?;
+    assert!(matches!(response, KvsResponse::Ok(None)));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Requests newer than the agreed version are refused, without closing the connection
+#[test]
+fn requests_need_version() -> Result<()> {
+    let server = open()?.serve();
+    let scan = KvsRequest::Scan {
+        prefix: String::new(),
+        cursor: None,
+        limit: 10,
+    };
+
+    let mut stream = TcpStream::connect(server.address)?;
+    stream.write_all(&wire::encode(1, &hello(MIN_PROTOCOL_VERSION, vec![])))?;
+    read_frame(&mut stream)?;
+    stream.write_all(&wire::encode(2, &scan))?;
+    let (_, response) = read_frame(&mut stream)?;
+    assert!(matches!(
+        response,
+        KvsResponse::Error {
+            code: ErrorCode::InvalidArgument,
+            ..
+        }
+    ));
+    stream.write_all(&wire::encode(3, &get("key1")))?;
+    let (_, response) = read_frame(&mut stream)?;
+    assert!(matches!(response, KvsResponse::Ok(None)));
+
+    // Without a handshake, version 1 reports failures without a code
+    let mut unversioned = TcpStream::connect(server.address)?;
+    unversioned.write_all(&wire::encode(1, &scan))?;
+    let (_, response) = read_frame(&mut unversioned)?;
+    assert!(matches!(response, KvsResponse::ServerError {}));
+
+    let mut client = KvsClient::connect(server.address)?;
+    client.send(scan)?;
+    assert!(matches!(client.recv()?, KvsResponse::Keys { .. }));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Versions older than the server serves are refused, and the connection closed
+#[test]
+fn unsupported_version() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut bytes = wire::encode(1, &hello(MIN_PROTOCOL_VERSION - 1, vec![]));
+    bytes.extend(wire::encode(2, &get("key1")));
+    stream.write_all(&bytes)?;
+
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 1);
+    match response {
+        KvsResponse::UnsupportedVersion { version, min, max } => {
+            assert_eq!(version, MIN_PROTOCOL_VERSION - 1);
+            assert_eq!(min, MIN_PROTOCOL_VERSION);
+            assert_eq!(max, PROTOCOL_VERSION);
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+
+    server.shutdown();
+    Ok(())
+}
+
+// The handshake cannot happen in the middle of a connection
+#[test]
+fn late_handshake() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+
+    stream.write_all(&wire::encode(1, &get("key1")))?;
+    read_frame(&mut stream)?;
+    stream.write_all(&wire::encode(2, &hello(PROTOCOL_VERSION, vec![])))?;
+    let (_, response) = read_frame(&mut stream)?;
+    assert!(matches!(response, KvsResponse::InvalidCommand(_)));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Clients from before framing, before the handshake, and current ones share one server
+#[test]
+fn old_and_new_clients() -> Result<()> {
+    let server = open()?.serve();
+
+    // Bare bson documents
+    let mut unframed = TcpStream::connect(server.address)?;
+    // Frames without a handshake
+    let mut unversioned = TcpStream::connect(server.address)?;
+    let mut client = KvsClient::connect(server.address)?;
+
+    unframed.write_all(&bson::to_vec(&set("key1", "unframed")).unwrap())?;
+    let response: KvsResponse = bson::from_reader(&mut unframed)?;
+    assert!(matches!(response, KvsResponse::Ok(None)));
+
+    unversioned.write_all(&wire::encode(1, &get("key1")))?;
+    let (_, response) = read_frame(&mut unversioned)?;
+    assert!(matches!(response, KvsResponse::Ok(Some(v)) if v == "unframed"));
+    unversioned.write_all(&wire::encode(2, &set("key2", "unversioned")))?;
+    read_frame(&mut unversioned)?;
+
+    client.send(get("key2"))?;
+    assert!(matches!(client.recv()?, KvsResponse::Ok(Some(v)) if v == "unversioned"));
+    client.send(set("key3", "current"))?;
+    client.recv()?;
+
+    unframed.write_all(&bson::to_vec(&get("key3")).unwrap())?;
+    let response: KvsResponse = bson::from_reader(&mut unframed)?;
+    assert!(matches!(response, KvsResponse::Ok(Some(v)) if v == "current"));
+
+    server.shutdown();
+    Ok(())
+}
+
+// A server refusing the client's version is reported as such
+#[test]
+fn client_unsupported_version() -> Result<()> {
+    let listener = TcpListener::bind(local_address())?;
+    let address = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let (id, request) = wire::read::<KvsRequest>(&mut stream)?;
+        assert!(matches!(request, KvsRequest::Hello { .. }));
+        let response = KvsResponse::UnsupportedVersion {
+            version: PROTOCOL_VERSION,
+            min: PROTOCOL_VERSION + 1,
+            max: PROTOCOL_VERSION + 2,
+        };
+        stream.write_all(&wire::encode(id, &response))?;
+        Ok(())
+    });
+
+    match KvsClient::connect(address) {
+        Err(KvError::UnsupportedVersion { version, min, max }) => {
+            assert_eq!(version, PROTOCOL_VERSION);
+            assert_eq!(min, PROTOCOL_VERSION + 1);
+            assert_eq!(max, PROTOCOL_VERSION + 2);
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("connected with an unsupported version"),
+    }
+
+    server.join().unwrap()
+}
+
+// Clients speak an older server's newest version, once it refused theirs
+#[test]
+fn client_falls_back_to_older_server() -> Result<()> {
+    let listener = TcpListener::bind(local_address())?;
+    let address = listener.local_addr()?;
+    let older = PROTOCOL_VERSION - 1;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let (id, _) = wire::read::<KvsRequest>(&mut stream)?;
+        let response = KvsResponse::UnsupportedVersion {
+            version: PROTOCOL_VERSION,
+            min: MIN_PROTOCOL_VERSION,
+            max: older,
+        };
+        stream.write_all(&wire::encode(id, &response))?;
+        drop(stream);
+
+        let (mut stream, _) = listener.accept()?;
+        let (id, request) = wire::read::<KvsRequest>(&mut stream)?;
+        assert!(matches!(request, KvsRequest::Hello { version, .. } if version == older));
+        let response = KvsResponse::Hello {
+            version: older,
+            features: vec![Feature::Batching],
+        };
+        stream.write_all(&wire::encode(id, &response))?;
+        Ok(())
+    });
+
+    let client = KvsClient::connect(address)?;
+    assert_eq!(client.protocol_version(), older);
+
+    server.join().unwrap()
+}
@@ -0,0 +1,352 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use common::local_address;
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KvsClient, KvsServer, Result, Store};
+use serde_json::{json, Value};
+
+fn open() -> Result<KvsServer<Store, SharedQueueThreadPool>> {
+    common::open()?.with_http(local_address())
+}
+
+/// Send one request on its own connection, return the status and the json body, `Null` if
+/// empty.
+fn request(
+    address: SocketAddr,
+    method: &str,
+    target: &str,
+    headers: &[(&str, &str)],
+    body: Option<&Value>,
+) -> Result<(u16, Value)> {
+    let body = body.map(Value::to_string).unwrap_or_default();
+    let mut stream = TcpStream::connect(address)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n",
+        method, target
+    )?;
+    for (name, value) in headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(
+        stream,
+        "Connection: close\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(body).unwrap()
+    };
+    Ok((status, body))
+}
+
+fn get(address: SocketAddr, target: &str) -> Result<(u16, Value)> {
+    request(address, "GET", target, &[], None)
+}
+
+fn put(address: SocketAddr, key: &str, value: &str) -> Result<u16> {
+    let body = json!({ "value": value });
+    Ok(request(address, "PUT", &format!("/keys/{}", key), &[], Some(&body))?.0)
+}
+
+// Keys can be read, written and removed
+#[test]
+fn keys() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+
+    assert_eq!(put(address, "key1", "value1")?, 204);
+    let (status, body) = get(address, "/keys/key1")?;
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "key": "key1", "value": "value1" }));
+
+    // Keys are percent decoded
+    assert_eq!(put(address, "a%20key%2Fwith%20slash", "value2")?, 204);
+    let (_, body) = get(address, "/keys/a%20key%2Fwith%20slash")?;
+    assert_eq!(body["key"], "a key/with slash");
+
+    let (status, body) = request(address, "DELETE", "/keys/key1", &[], None)?;
+    assert_eq!((status, body), (204, Value::Null));
+    let (status, body) = get(address, "/keys/key1")?;
+    assert_eq!(status, 404);
+    assert_eq!(body["code"], "KeyNotFound");
+    let (status, _) = request(address, "DELETE", "/keys/key1", &[], None)?;
+    assert_eq!(status, 404);
+
+    server.shutdown();
+    Ok(())
+}
+
+// The gateway and the TCP protocol share the store
+#[test]
+fn same_store() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+    let mut client = KvsClient::connect(server.address)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(get(address, "/keys/key1")?.1["value"], "value1");
+    put(address, "key2", "value2")?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Keys are listed in pages, following the cursor
+#[test]
+fn scan() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+    for i in 0..25 {
+        put(address, &format!("key{:02}", i), "value")?;
+    }
+    put(address, "other", "value")?;
+
+    let mut keys = Vec::new();
+    let mut target = "/keys?prefix=key&limit=10".to_owned();
+    loop {
+        let (status, body) = get(address, &target)?;
+        assert_eq!(status, 200);
+        let page = body["keys"].as_array().unwrap();
+        assert!(page.len() <= 10);
+        keys.extend(page.iter().map(|key| key.as_str().unwrap().to_owned()));
+        match body["cursor"].as_str() {
+            Some(cursor) => target = format!("/keys?prefix=key&limit=10&cursor={}", cursor),
+            None => break,
+        }
+    }
+    let expected: Vec<String> = (0..25).map(|i| format!("key{:02}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let (_, body) = get(address, "/keys")?;
+    assert_eq!(body["keys"].as_array().unwrap().len(), 26);
+    assert_eq!(body["cursor"], Value::Null);
+
+    for target in ["/keys?limit=0", "/keys?limit=many", "/keys?sort=desc"] {
+        let (status, body) = get(address, target)?;
+        assert_eq!(status, 400, "{}", target);
+        assert_eq!(body["code"], "InvalidArgument");
+    }
+
+    server.shutdown();
+    Ok(())
+}
+
+// Several writes in one request
+#[test]
+fn batch() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+    put(address, "key3", "value3")?;
+
+    let body = json!({ "operations": [
+        { "op": "set", "key": "key1", "value": "value1" },
+        { "op": "set", "key": "key2", "value": "value2" },
+        { "op": "delete", "key": "key3" },
+        { "op": "delete", "key": "missing" },
+    ]});
+    let (status, response) = request(address, "POST", "/batch", &[], Some(&body))?;
+    assert_eq!(status, 200);
+    assert_eq!(response, json!({ "applied": 4 }));
+    assert_eq!(get(address, "/keys/key2")?.1["value"], "value2");
+    assert_eq!(get(address, "/keys/key3")?.0, 404);
+
+    let body = json!({ "operations": [{ "op": "rename", "key": "key1" }] });
+    let (status, response) = request(address, "POST", "/batch", &[], Some(&body))?;
+    assert_eq!(status, 400);
+    assert_eq!(response["applied"], 0);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Stats as json
+#[test]
+fn stats() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+    put(address, "key1", "value1")?;
+    get(address, "/keys/key1")?;
+
+    let (status, body) = get(address, "/stats")?;
+    assert_eq!(status, 200);
+    assert_eq!(body["set"]["count"], 1);
+    assert_eq!(body["get"]["count"], 1);
+    assert!(body["total_connections"].as_u64().unwrap() >= 3);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Errors map to status codes
+#[test]
+fn errors() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+
+    let (status, body) = get(address, "/unknown")?;
+    assert_eq!(status, 404);
+    assert_eq!(body["code"], "NotFound");
+    assert_eq!(request(address, "POST", "/keys/key1", &[], None)?.0, 405);
+    assert_eq!(request(address, "DELETE", "/stats", &[], None)?.0, 405);
+
+    let (status, body) = request(
+        address,
+        "PUT",
+        "/keys/key1",
+        &[],
+        Some(&json!({ "data": 1 })),
+    )?;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "InvalidArgument");
+    assert_eq!(get(address, "/keys/%zz")?.0, 400);
+
+    // Not HTTP at all
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(b"\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 400"));
+
+    server.shutdown();
+    Ok(())
+}
+
+/// Send raw bytes, return the whole response.
+fn send_raw(address: SocketAddr, bytes: &[u8]) -> Result<String> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(bytes)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// Requests too large are refused before they are read
+#[test]
+fn too_large_requests() -> Result<()> {
+    let server = open()?.serve();
+    let address = server.http_address.unwrap();
+
+    // No memory is set aside for the announced body.
+    let response = send_raw(
+        address,
+        b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 1000000000000\r\n\r\n",
+    )?;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+    // 64 KiB of headers, without their end.
+    let mut head = b"GET /keys/key1 HTTP/1.1\r\nX-Padding: ".to_vec();
+    head.resize(64 * 1024, b'a');
+    let response = send_raw(address, &head)?;
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    // Just below the limits
+    let mut head = b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\nX-Padding: ".to_vec();
+    head.resize(64 * 1024 - 4, b'a');
+    head.extend_from_slice(b"\r\n\r\n");
+    assert!(send_raw(address, &head)?.starts_with("HTTP/1.1 404"));
+
+    server.shutdown();
+    Ok(())
+}
+
+// With a token, requests need a bearer token
+#[test]
+fn token() -> Result<()> {
+    let server = open()?.with_token("secret".to_owned()).serve();
+    let address = server.http_address.unwrap();
+
+    let (status, body) = get(address, "/keys/key1")?;
+    assert_eq!(status, 401);
+    assert_eq!(body["code"], "Unauthenticated");
+    let wrong = [("Authorization", "Bearer wrong")];
+    assert_eq!(request(address, "GET", "/stats", &wrong, None)?.0, 401);
+
+    let auth = [("Authorization", "Bearer secret")];
+    let body = json!({ "value": "value1" });
+    assert_eq!(
+        request(address, "PUT", "/keys/key1", &auth, Some(&body))?.0,
+        204
+    );
+    let (status, body) = request(address, "GET", "/keys/key1", &auth, None)?;
+    assert_eq!(status, 200);
+    assert_eq!(body["value"], "value1");
+
+    server.shutdown();
+    Ok(())
+}
+
+/// Read one response from a connection kept open, return its status and head.
+fn read_response(reader: &mut BufReader<TcpStream>) -> Result<(u16, String)> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        head.push_str(&line);
+    }
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    reader.read_exact(&mut vec![0; length])?;
+    Ok((status, head))
+}
+
+// Connections serve several requests, until the server stops
+#[test]
+fn keep_alive() -> Result<()> {
+    let server = open()?.with_drain_timeout(Duration::from_secs(10)).serve();
+    let address = server.http_address.unwrap();
+
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let body = json!({ "value": "value1" }).to_string();
+    write!(
+        stream,
+        "PUT /keys/key1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    let (status, head) = read_response(&mut reader)?;
+    assert_eq!(status, 204);
+    assert!(head.contains("Connection: keep-alive"), "{}", head);
+    write!(stream, "GET /keys/key1 HTTP/1.1\r\n\r\n")?;
+    assert_eq!(read_response(&mut reader)?.0, 200);
+
+    // HTTP/1.0 closes unless asked otherwise
+    write!(stream, "GET /keys/key1 HTTP/1.0\r\n\r\n")?;
+    let (_, head) = read_response(&mut reader)?;
+    assert!(head.contains("Connection: close"), "{}", head);
+    assert_eq!(reader.read(&mut [0; 16])?, 0);
+
+    // An idle connection does not hold up shutting down
+    let idle = TcpStream::connect(address)?;
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut idle_reader = BufReader::new(idle.try_clone()?);
+    write!(&idle, "GET /keys/key1 HTTP/1.1\r\n\r\n")?;
+    assert_eq!(read_response(&mut idle_reader)?.0, 200);
+    let started = Instant::now();
+    server.shutdown();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(idle_reader.read(&mut [0; 16])?, 0);
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvError, KvStore, KvsEngine, Result};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -132,15 +132,17 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
         });
+        handles.push(handle);
     }
     barrier.wait();
 
@@ -149,6 +151,9 @@ fn concurrent_set() -> Result<()> {
     }
 
     // Open from disk again and check persistent data
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -209,3 +214,62 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Only one store can be opened on a directory at the same time.
+#[test]
+fn open_locked_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::AlreadyOpen(pid)) => assert_eq!(pid, std::process::id().to_string()),
+        other => panic!("expected already open error, got {:?}", other),
+    }
+
+    // Clones share the same lock
+    let cloned = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    // The lock is released once every clone is dropped
+    drop(cloned);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+// Removed keys stay removed after reopening
+#[test]
+fn remove_key_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+    Ok(())
+}
+
+// Should list live keys in order
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::num::NonZeroUsize;
+
+use kvs::{EngineKind, KvsEngine, Result, Store};
+use tempfile::TempDir;
+
+fn bounded(capacity: usize) -> Store {
+    Store::open_in_memory_with_capacity(NonZeroUsize::new(capacity).unwrap())
+}
+
+// Should evict the least recently set key once full
+#[test]
+fn evict_oldest_key() -> Result<()> {
+    let store = bounded(3);
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    assert_eq!(store.keys()?, vec!["key2", "key3", "key4"]);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Reading or overwriting a key makes it recently used
+#[test]
+fn evict_least_recently_used_key() -> Result<()> {
+    let store = bounded(3);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    store.get("key1".to_owned())?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+
+    assert_eq!(store.keys()?, vec!["key1", "key2", "key5"]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Removing a key frees its slot
+#[test]
+fn remove_frees_capacity() -> Result<()> {
+    let store = bounded(2);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.keys()?, vec!["key2", "key3"]);
+
+    Ok(())
+}
+
+// Should not touch the disk, nor take part in migrations
+#[test]
+fn memory_engine_is_not_on_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open_with(EngineKind::Memory, &temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(EngineKind::Memory.dbpath(&temp_dir), None);
+    assert_eq!(std::fs::read_dir(&temp_dir)?.count(), 0);
+    assert!(Store::migrate(&temp_dir, EngineKind::Memory, EngineKind::Kvs).is_err());
+
+    Ok(())
+}
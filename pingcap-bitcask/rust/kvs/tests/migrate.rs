@@ -0,0 +1,178 @@
+use kvs::{migrate, EngineKind, FaultyFs, KvError, KvsEngine, Result, Store};
+use tempfile::TempDir;
+
+fn fill(store: &Store) -> Result<()> {
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
+    }
+    Ok(())
+}
+
+fn check(store: &Store) -> Result<()> {
+    assert_eq!(store.keys()?.len(), 90);
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 10..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should copy every live key between engines
+#[test]
+fn migrate_engines() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = Store::open_with_kvs(&source_dir)?;
+    let target = Store::open_with_sled(&target_dir)?;
+    fill(&source)?;
+
+    let migration = migrate(&source, &target)?;
+    assert_eq!(migration.keys, 90);
+    check(&target)?;
+
+    Ok(())
+}
+
+// Should fail verification if the target holds other data
+#[test]
+fn migrate_to_non_empty_engine() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = Store::open_with_kvs(&source_dir)?;
+    let target = Store::open_with_sled(&target_dir)?;
+    fill(&source)?;
+    target.set("other".to_owned(), "value".to_owned())?;
+
+    assert!(matches!(
+        migrate(&source, &target),
+        Err(KvError::Migration(_))
+    ));
+
+    Ok(())
+}
+
+// Should swap the database folder to the new engine, and back
+#[test]
+fn migrate_database_folder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(&Store::open_with_kvs(&temp_dir)?)?;
+
+    let migration = Store::migrate(&temp_dir, EngineKind::Kvs, EngineKind::Sled)?;
+    assert_eq!(migration.keys, 90);
+    assert!(!EngineKind::Kvs.dbpath(&temp_dir).unwrap().exists());
+    assert!(matches!(
+        Store::open_with_kvs(&temp_dir),
+        Err(KvError::MismatchEngine)
+    ));
+    check(&Store::open_with_sled(&temp_dir)?)?;
+
+    let back = Store::migrate(&temp_dir, EngineKind::Sled, EngineKind::Kvs)?;
+    assert_eq!(back, migration);
+    assert!(!EngineKind::Sled.dbpath(&temp_dir).unwrap().exists());
+    check(&Store::open_with_kvs(&temp_dir)?)?;
+
+    Ok(())
+}
+
+#[test]
+fn migrate_invalid_database_folder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(Store::migrate(&temp_dir, EngineKind::Kvs, EngineKind::Sled).is_err());
+
+    Store::open_with_kvs(&temp_dir)?;
+    assert!(Store::migrate(&temp_dir, EngineKind::Kvs, EngineKind::Kvs).is_err());
+    assert!(Store::migrate(&temp_dir, EngineKind::Sled, EngineKind::Kvs).is_err());
+
+    Ok(())
+}
+
+// Should leave one complete engine behind wherever the swap crashes, and migrate again afterward
+#[test]
+fn migrate_crash() -> Result<()> {
+    for crash_at in 0.. {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fill(&Store::open_with_kvs(&temp_dir)?)?;
+
+        let fs = FaultyFs::new();
+        fs.crash_after(crash_at);
+        let result =
+            Store::migrate_with_faulty_fs(&temp_dir, EngineKind::Kvs, EngineKind::Sled, fs.clone());
+        if !fs.crashed() {
+            assert_eq!(result?.keys, 90);
+            assert!(crash_at > 0);
+            break;
+        }
+        assert!(result.is_err());
+
+        // Opening finishes or undoes the swap.
+        let (store, engine) = match Store::open_with_kvs(&temp_dir) {
+            Ok(store) => (store, EngineKind::Kvs),
+            Err(KvError::MismatchEngine) => (Store::open_with_sled(&temp_dir)?, EngineKind::Sled),
+            Err(e) => return Err(e),
+        };
+        check(&store)?;
+        drop(store);
+        assert!(
+            !temp_dir.path().join(".migrate").exists(),
+            "crash at {}",
+            crash_at
+        );
+
+        let other = match engine {
+            EngineKind::Kvs => EngineKind::Sled,
+            _ => EngineKind::Kvs,
+        };
+        Store::migrate(&temp_dir, engine, other)?;
+        check(&Store::open_with(other, &temp_dir)?)?;
+    }
+
+    Ok(())
+}
+
+// Should finish an interrupted swap on the next migration too, without opening a store
+#[test]
+fn migrate_after_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(&Store::open_with_kvs(&temp_dir)?)?;
+
+    // Crash between the two renames: the marker is created, written, synced and renamed,
+    // the old data moved away.
+    let fs = FaultyFs::new();
+    fs.crash_after(5);
+    assert!(Store::migrate_with_faulty_fs(
+        &temp_dir,
+        EngineKind::Kvs,
+        EngineKind::Sled,
+        fs.clone()
+    )
+    .is_err());
+    assert!(fs.crashed());
+    assert!(!EngineKind::Kvs.dbpath(&temp_dir).unwrap().exists());
+    assert!(!EngineKind::Sled.dbpath(&temp_dir).unwrap().exists());
+
+    Store::migrate(&temp_dir, EngineKind::Sled, EngineKind::Kvs)?;
+    check(&Store::open_with_kvs(&temp_dir)?)?;
+
+    Ok(())
+}
+
+// Should not open a database while it is migrated
+#[test]
+fn open_while_migrating() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open_with_kvs(&temp_dir)?;
+    fill(&store)?;
+
+    assert!(matches!(
+        Store::migrate(&temp_dir, EngineKind::Kvs, EngineKind::Sled),
+        Err(KvError::AlreadyOpen(_))
+    ));
+    check(&store)?;
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+mod common;
+mod wire;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use common::{local_address, open};
+use kvs::{AsyncKvsServer, KvsClient, KvsRequest, KvsResponse, Result, Store};
+use wire::{frame, read_frame};
+
+fn get(key: &str) -> KvsRequest {
+    KvsRequest::Get {
+        key: key.to_owned(),
+    }
+}
+
+fn set(key: &str, value: &str) -> KvsRequest {
+    KvsRequest::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn check_pipeline(address: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::connect(address)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..100 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    assert_eq!(pipeline.len(), 100);
+    let responses = pipeline.execute()?;
+    assert_eq!(responses.len(), 100);
+    assert!(responses.iter().all(|r| matches!(r, KvsResponse::Ok(None))));
+
+    let responses = client
+        .pipeline()
+        .get("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key99".to_owned())
+        .execute()?;
+    assert!(matches!(&responses[0], KvsResponse::Ok(Some(v)) if v == "value1"));
+    assert!(matches!(&responses[1], KvsResponse::Ok(None)));
+    assert!(matches!(&responses[2], KvsResponse::Ok(None)));
+    assert!(matches!(&responses[3], KvsResponse::KeyNotFound(_)));
+    assert!(matches!(&responses[4], KvsResponse::Ok(Some(v)) if v == "value99"));
+
+    // Single requests still work on the same connection
+    client.send(get("key2"))?;
+    assert!(matches!(client.recv()?, KvsResponse::Ok(Some(v)) if v == "value2"));
+
+    Ok(())
+}
+
+// Batched requests are answered in order
+#[test]
+fn pipeline() -> Result<()> {
+    let server = open()?.serve();
+    check_pipeline(server.address)?;
+    server.shutdown();
+    Ok(())
+}
+
+// Same against the async server
+#[test]
+fn pipeline_async_server() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let server = runtime.block_on(AsyncKvsServer::open(
+        local_address(),
+        Store::open_in_memory(),
+    ))?;
+    let address = server.address;
+    runtime.spawn(server.serve());
+
+    check_pipeline(address)
+}
+
+// Pipelines larger than what the client keeps in flight
+#[test]
+fn large_pipeline() -> Result<()> {
+    let server = open()?.serve();
+    let mut client = KvsClient::connect(server.address)?;
+
+    let value = "v".repeat(1000);
+    let mut pipeline = client.pipeline();
+    for i in 0..5000 {
+        pipeline.set(format!("key{}", i), value.clone());
+    }
+    let responses = pipeline.execute()?;
+    assert_eq!(responses.len(), 5000);
+
+    let mut pipeline = client.pipeline();
+    for i in 0..5000 {
+        pipeline.get(format!("key{}", i));
+    }
+    let responses = pipeline.execute()?;
+    assert!(responses
+        .iter()
+        .all(|r| matches!(r, KvsResponse::Ok(Some(v)) if *v == value)));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Responses carry the id of their request
+#[test]
+fn request_ids() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+
+    let mut bytes = wire::encode(7, &set("key1", "value1"));
+    bytes.extend(wire::encode(3, &get("key1")));
+    bytes.extend(wire::encode(u64::MAX, &get("key2")));
+    stream.write_all(&bytes)?;
+
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 7);
+    assert!(matches!(response, KvsResponse::Ok(None)));
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 3);
+    assert!(matches!(response, KvsResponse::Ok(Some(v)) if v == "value1"));
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, u64::MAX);
+    assert!(matches!(response, KvsResponse::Ok(None)));
+
+    server.shutdown();
+    Ok(())
+}
+
+// A bad request in a frame does not end the connection
+#[test]
+fn invalid_frame_body() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+
+    let unknown = bson::to_vec(&bson::doc! { "Unknown": {} }).unwrap();
+    let mut bytes = frame(1, 1, &unknown);
+    bytes.extend(wire::encode(2, &get("key1")));
+    stream.write_all(&bytes)?;
+
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 1);
+    assert!(matches!(response, KvsResponse::InvalidCommand(_)));
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 2);
+    assert!(matches!(response, KvsResponse::Ok(None)));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Unknown frame versions are refused
+#[test]
+fn unsupported_frame_version() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    stream.write_all(&frame(9, 1, &bson::to_vec(&get("key1")).unwrap()))?;
+    let response: KvsResponse = bson::from_reader(&mut stream)?;
+    assert!(matches!(response, KvsResponse::InvalidCommand(_)));
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Clients sending bare documents are answered with bare documents
+#[test]
+fn unframed_client() -> Result<()> {
+    let server = open()?.serve();
+    let mut stream = TcpStream::connect(server.address)?;
+
+    for request in [set("key1", "value1"), get("key1")] {
+        stream.write_all(&bson::to_vec(&request).unwrap())?;
+    }
+    let response: KvsResponse = bson::from_reader(&mut stream)?;
+    assert!(matches!(response, KvsResponse::Ok(None)));
+    let response: KvsResponse = bson::from_reader(&mut stream)?;
+    assert!(matches!(response, KvsResponse::Ok(Some(v)) if v == "value1"));
+
+    // Both kinds can be mixed on one connection
+    stream.write_all(&wire::encode(5, &get("key1")))?;
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 5);
+    assert!(matches!(response, KvsResponse::Ok(Some(v)) if v == "value1"));
+
+    server.shutdown();
+    Ok(())
+}
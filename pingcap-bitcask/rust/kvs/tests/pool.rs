@@ -0,0 +1,201 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::local_address;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ClientOption, KvError, KvsClientPool, KvsEngine, KvsServer, PoolOption, Result, RunningServer,
+    Store,
+};
+use tempfile::TempDir;
+
+fn serve<E: KvsEngine>(address: SocketAddr, engine: E) -> Result<RunningServer> {
+    let pool = SharedQueueThreadPool::new(8)?;
+    Ok(KvsServer::open(address, engine, pool)?.serve())
+}
+
+/// Engine counting the requests it gets, which takes a while to answer them.
+#[derive(Clone)]
+struct SlowEngine {
+    store: Store,
+    delay: Duration,
+    calls: Arc<AtomicUsize>,
+}
+
+impl SlowEngine {
+    fn new(delay: Duration) -> SlowEngine {
+        SlowEngine {
+            store: Store::open_in_memory(),
+            delay,
+            calls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn wait(&self) {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(self.delay);
+    }
+}
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.wait();
+        self.store.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.wait();
+        self.store.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.wait();
+        self.store.remove(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.store.keys()
+    }
+}
+
+// Threads share connections, never more than the maximum
+#[test]
+fn shared_between_threads() -> Result<()> {
+    let server = serve(local_address(), Store::open_in_memory())?;
+    let mut options = PoolOption::new();
+    options.max_size(2);
+    let pool = Arc::new(KvsClientPool::new(
+        server.address,
+        ClientOption::new(),
+        options,
+    ));
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for j in 0..20 {
+                    let key = format!("key{}-{}", i, j);
+                    pool.set(key.clone(), "value".to_owned())?;
+                    assert_eq!(pool.get(key)?, Some("value".to_owned()));
+                    assert!(pool.open() <= 2);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(pool.open(), 2);
+    assert_eq!(pool.idle(), 2);
+    let (keys, _) = pool.scan("key".to_owned(), None, 1000)?;
+    assert_eq!(keys.len(), 160);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Connections broken by a server restart are replaced
+#[test]
+fn reconnect() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let server = serve(local_address(), Store::open(&dir)?)?;
+    let address = server.address;
+    let pool = KvsClientPool::new(address, ClientOption::new(), PoolOption::new());
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.idle(), 1);
+
+    server.shutdown();
+    assert!(matches!(pool.get("key1".to_owned()), Err(KvError::Io(_))));
+    assert_eq!(pool.open(), 0);
+
+    let server = serve(address, Store::open(&dir)?)?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    pool.remove("key1".to_owned())?;
+
+    server.shutdown();
+    Ok(())
+}
+
+// Only requests safe to send twice are retried after a timeout
+#[test]
+fn retries_idempotent_requests() -> Result<()> {
+    let engine = SlowEngine::new(Duration::from_millis(200));
+    let calls = engine.calls.clone();
+    let server = serve(local_address(), engine)?;
+
+    let mut options = PoolOption::new();
+    options
+        .request_timeout(Some(Duration::from_millis(50)))
+        .max_retries(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(20));
+    let pool = KvsClientPool::new(server.address, ClientOption::new(), options.clone());
+
+    assert!(matches!(
+        pool.set("key".to_owned(), "value".to_owned()),
+        Err(KvError::Io(_))
+    ));
+    assert!(pool.remove("key".to_owned()).is_err());
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(calls.swap(0, Ordering::SeqCst), 2);
+
+    assert!(pool.get("key".to_owned()).is_err());
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+    options.retry_sets(true);
+    let pool = KvsClientPool::new(server.address, ClientOption::new(), options);
+    assert!(pool.set("key".to_owned(), "value".to_owned()).is_err());
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Requests wait for a free connection, up to the request timeout
+#[test]
+fn exhausted() -> Result<()> {
+    let server = serve(local_address(), Store::open_in_memory())?;
+    let mut options = PoolOption::new();
+    options
+        .max_size(1)
+        .max_retries(0)
+        .request_timeout(Some(Duration::from_millis(100)));
+    let pool = KvsClientPool::new(server.address, ClientOption::new(), options);
+
+    let mut connection = pool.connection()?;
+    connection.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(pool.get("key1".to_owned()), Err(KvError::Busy(_))));
+    drop(connection);
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Connections left unused are closed
+#[test]
+fn idle_timeout() -> Result<()> {
+    let server = serve(local_address(), Store::open_in_memory())?;
+    let mut options = PoolOption::new();
+    options.idle_timeout(Duration::from_millis(100));
+    let pool = KvsClientPool::new(server.address, ClientOption::new(), options);
+
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.idle(), 1);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.idle(), 0);
+    assert_eq!(pool.open(), 0);
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    server.shutdown();
+    Ok(())
+}
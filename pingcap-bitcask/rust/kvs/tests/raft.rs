@@ -0,0 +1,629 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::local_address;
+use kvs::raft::{
+    Entry, Message, MessageBody, NodeId, RaftCommand, RaftNode, RaftOption, Role, SimNetwork,
+};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ClientOption, ErrorCode, KvError, KvsClient, KvsEngine, KvsRequest, KvsResponse, KvsServer,
+    Result, RunningServer, Store,
+};
+use tempfile::TempDir;
+
+fn cluster(size: u64, seed: u64, options: &RaftOption) -> SimNetwork<Store> {
+    let ids: Vec<NodeId> = (1..=size).collect();
+    let nodes = ids
+        .iter()
+        .map(|id| RaftNode::new(*id, ids.clone(), Store::open_in_memory(), options.clone()))
+        .collect();
+    SimNetwork::new(nodes, seed)
+}
+
+fn set(key: &str, value: &str) -> RaftCommand {
+    RaftCommand::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn elect(network: &mut SimNetwork<Store>) -> NodeId {
+    assert!(network.run_until(1000, |network| network.leader().is_some()));
+    network.leader().unwrap()
+}
+
+/// Keys and values of a node's state machine.
+fn contents(network: &SimNetwork<Store>, id: NodeId) -> BTreeMap<String, String> {
+    let engine = network.node(id).engine();
+    let mut contents = BTreeMap::new();
+    for key in engine.keys().unwrap() {
+        let value = engine.get(key.clone()).unwrap().unwrap();
+        contents.insert(key, value);
+    }
+    contents
+}
+
+/// Whether every node applied the leader's whole log.
+fn converged(network: &SimNetwork<Store>) -> bool {
+    let leader = match network.leader() {
+        Some(leader) => network.node(leader),
+        None => return false,
+    };
+    network.ids().iter().all(|id| {
+        let node = network.node(*id);
+        node.applied_index() == leader.last_index() && node.term() == leader.term()
+    })
+}
+
+fn assert_same_contents(network: &SimNetwork<Store>) {
+    let ids = network.ids();
+    let expected = contents(network, ids[0]);
+    for id in &ids[1..] {
+        assert_eq!(contents(network, *id), expected, "node {}", id);
+    }
+}
+
+// A single leader is elected, and every node learns about it
+#[test]
+fn election() {
+    for seed in 0..10 {
+        let mut network = cluster(5, seed, &RaftOption::new());
+        let leader = elect(&mut network);
+        network.run(50);
+
+        assert_eq!(network.leader(), Some(leader), "seed {}", seed);
+        let term = network.node(leader).term();
+        for id in network.ids() {
+            let node = network.node(id);
+            assert_eq!(node.leader(), Some(leader), "seed {}", seed);
+            assert_eq!(node.term(), term, "seed {}", seed);
+            if id != leader {
+                assert_eq!(node.role(), Role::Follower, "seed {}", seed);
+            }
+        }
+    }
+}
+
+// Committed entries are applied by every node, only the proposer gets their result
+#[test]
+fn replication() {
+    let mut network = cluster(3, 1, &RaftOption::new());
+    let leader = elect(&mut network);
+    let follower = network.ids().into_iter().find(|id| *id != leader).unwrap();
+    assert_eq!(network.propose(follower, set("key", "value")), None);
+
+    let mut proposed = Vec::new();
+    for i in 0..20 {
+        let entry = network.propose(leader, set(&format!("key{}", i), "value"));
+        proposed.push(entry.unwrap().0);
+    }
+    network.propose(
+        leader,
+        RaftCommand::Remove {
+            key: "key0".to_owned(),
+        },
+    );
+    let missing = network.propose(
+        leader,
+        RaftCommand::Remove {
+            key: "missing".to_owned(),
+        },
+    );
+    assert!(network.run_until(100, converged));
+
+    assert_same_contents(&network);
+    let contents = contents(&network, follower);
+    assert_eq!(contents.len(), 19);
+    assert_eq!(contents.get("key1"), Some(&"value".to_owned()));
+
+    let applied = network.node_mut(leader).take_applied();
+    assert_eq!(applied.len(), 22);
+    assert_eq!(applied[0].index, proposed[0]);
+    assert!(applied[..21].iter().all(|applied| applied.result.is_ok()));
+    assert_eq!(applied[21].index, missing.unwrap().0);
+    assert!(matches!(applied[21].result, Err(KvError::KeyNotFound(_))));
+    assert!(network.node_mut(follower).take_applied().is_empty());
+}
+
+// A leader cut from the majority commits nothing and steps down, its entries are
+// replaced by those of the new leader once the partition heals
+#[test]
+fn partition() {
+    let mut network = cluster(5, 2, &RaftOption::new());
+    let old = elect(&mut network);
+    network.propose(old, set("key", "before"));
+    assert!(network.run_until(100, converged));
+
+    let others: Vec<NodeId> = network.ids().into_iter().filter(|id| *id != old).collect();
+    network.partition(&[&[old, others[0]], &others[1..]]);
+    let (lost, _) = network.propose(old, set("key", "lost")).unwrap();
+
+    assert!(network.run_until(1000, |network| {
+        network.leader().is_some_and(|leader| leader != old)
+    }));
+    let new = network.leader().unwrap();
+    network.propose(new, set("key", "after"));
+    network.run(100);
+    assert_eq!(network.node(old).role(), Role::Follower);
+    assert!(network.node(old).commit_index() < lost);
+
+    network.heal();
+    assert!(network.run_until(1000, converged));
+    assert_same_contents(&network);
+    assert_eq!(contents(&network, old)["key"], "after");
+
+    let applied = network.node_mut(old).take_applied();
+    let lost = applied
+        .iter()
+        .find(|applied| applied.index == lost)
+        .unwrap();
+    assert!(matches!(lost.result, Err(KvError::NotLeader { .. })));
+}
+
+/// Ids of the reads node `id` completes within `ticks`, with whether they succeeded.
+fn reads(network: &mut SimNetwork<Store>, id: NodeId, ticks: u64) -> Vec<(u64, bool)> {
+    let mut reads = Vec::new();
+    for _ in 0..ticks {
+        network.tick();
+        reads.extend(
+            network
+                .node_mut(id)
+                .take_reads()
+                .into_iter()
+                .map(|read| (read.id, read.result.is_ok())),
+        );
+    }
+    reads
+}
+
+// Reads are confirmed by rounds of heartbeats, those asked during a round share the next
+// one, without log entries
+#[test]
+fn reads_confirmed_by_majority() {
+    let mut network = cluster(5, 6, &RaftOption::new());
+    let leader = elect(&mut network);
+    network.propose(leader, set("key", "value"));
+    assert!(network.run_until(100, converged));
+
+    let last_index = network.node(leader).last_index();
+    let asked: Vec<u64> = (0..3)
+        .map(|_| network.node_mut(leader).read().unwrap())
+        .collect();
+    let done = reads(&mut network, leader, 10);
+    assert_eq!(done, asked.iter().map(|id| (*id, true)).collect::<Vec<_>>());
+    assert_eq!(network.node(leader).last_index(), last_index);
+
+    let follower = network.ids().into_iter().find(|id| *id != leader).unwrap();
+    assert!(network.node_mut(follower).read().is_none());
+}
+
+// A leader cut from the majority confirms no read, and fails them once it steps down
+#[test]
+fn partitioned_leader_reads_fail() {
+    let mut network = cluster(5, 7, &RaftOption::new());
+    let old = elect(&mut network);
+    network.propose(old, set("key", "before"));
+    assert!(network.run_until(100, converged));
+
+    let others: Vec<NodeId> = network.ids().into_iter().filter(|id| *id != old).collect();
+    network.partition(&[&[old, others[0]], &others[1..]]);
+    let id = network.node_mut(old).read().unwrap();
+    assert_eq!(reads(&mut network, old, 100), vec![(id, false)]);
+    assert_eq!(network.node(old).role(), Role::Follower);
+}
+
+// Nodes converge on a network which drops and delays messages
+#[test]
+fn unreliable_network() {
+    let mut network = cluster(5, 3, &RaftOption::new());
+    network.set_drop_rate(0.2);
+    network.set_delay(1, 5);
+
+    let mut proposed = 0;
+    while proposed < 100 {
+        network.tick();
+        if let Some(leader) = network.leader() {
+            let key = format!("key{}", proposed % 10);
+            if network
+                .propose(leader, set(&key, &proposed.to_string()))
+                .is_some()
+            {
+                proposed += 1;
+            }
+        }
+        assert!(network.now() < 10_000, "no progress");
+    }
+
+    network.set_drop_rate(0.0);
+    assert!(network.run_until(2000, converged));
+    assert_same_contents(&network);
+}
+
+// A node missing entries dropped from the leader's log catches up from a snapshot
+#[test]
+fn snapshot() {
+    let mut options = RaftOption::new();
+    options.max_log_entries(10);
+    let mut network = cluster(3, 4, &options);
+    let leader = elect(&mut network);
+    network.propose(leader, set("removed", "value"));
+    assert!(network.run_until(100, converged));
+
+    let lagging = network.ids().into_iter().find(|id| *id != leader).unwrap();
+    network.stop(lagging);
+    network.propose(
+        leader,
+        RaftCommand::Remove {
+            key: "removed".to_owned(),
+        },
+    );
+    for i in 0..100 {
+        network.propose(leader, set(&format!("key{}", i), "value"));
+        network.tick();
+    }
+    network.run(10);
+    assert!(contents(&network, lagging).contains_key("removed"));
+
+    network.start(lagging);
+    assert!(network.run_until(1000, converged));
+    assert_same_contents(&network);
+    assert!(!contents(&network, lagging).contains_key("removed"));
+    assert_eq!(contents(&network, lagging).len(), 100);
+}
+
+fn open_node(path: &Path, options: &RaftOption) -> Result<RaftNode<Store>> {
+    RaftNode::open(
+        1,
+        vec![1, 2, 3],
+        Store::open_in_memory(),
+        options.clone(),
+        path,
+    )
+}
+
+/// Ask node 1 for its vote in `term`, return whether it is granted.
+fn request_vote(node: &mut RaftNode<Store>, candidate: NodeId, term: u64) -> bool {
+    node.step(Message {
+        from: candidate,
+        to: 1,
+        term,
+        body: MessageBody::RequestVote {
+            last_index: 10,
+            last_term: term,
+        },
+    });
+    match &node.take_messages()[..] {
+        [Message {
+            body: MessageBody::Vote { granted },
+            ..
+        }] => *granted,
+        messages => panic!("unexpected messages {:?}", messages),
+    }
+}
+
+/// Replicate `entries` to node 1 from leader 2.
+fn append(node: &mut RaftNode<Store>, term: u64, prev: (u64, u64), entries: &[(u64, &str)]) {
+    let entries = entries
+        .iter()
+        .enumerate()
+        .map(|(i, (term, key))| Entry {
+            index: prev.0 + 1 + i as u64,
+            term: *term,
+            command: set(key, "value"),
+        })
+        .collect();
+    node.step(Message {
+        from: 2,
+        to: 1,
+        term,
+        body: MessageBody::Append {
+            prev_index: prev.0,
+            prev_term: prev.1,
+            entries,
+            commit: 0,
+            read: 0,
+        },
+    });
+    node.take_messages();
+}
+
+/// Tell node 1 that the entries up to `commit` are committed.
+fn commit(node: &mut RaftNode<Store>, term: u64, last: (u64, u64), commit: u64) {
+    node.step(Message {
+        from: 2,
+        to: 1,
+        term,
+        body: MessageBody::Append {
+            prev_index: last.0,
+            prev_term: last.1,
+            entries: Vec::new(),
+            commit,
+            read: 0,
+        },
+    });
+    node.take_messages();
+}
+
+fn keys(node: &RaftNode<Store>) -> Vec<String> {
+    let mut keys = node.engine().keys().unwrap();
+    keys.sort();
+    keys
+}
+
+// A restarted node keeps its term and vote, it never votes twice in a term
+#[test]
+fn restart_keeps_vote() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut node = open_node(temp_dir.path(), &RaftOption::new())?;
+    assert!(request_vote(&mut node, 2, 1));
+    assert!(matches!(
+        open_node(temp_dir.path(), &RaftOption::new()),
+        Err(KvError::AlreadyOpen(_))
+    ));
+    drop(node);
+
+    let mut node = open_node(temp_dir.path(), &RaftOption::new())?;
+    assert_eq!(node.term(), 1);
+    assert!(!request_vote(&mut node, 3, 1));
+    assert!(request_vote(&mut node, 2, 1));
+    assert!(request_vote(&mut node, 3, 2));
+    drop(node);
+
+    let mut node = open_node(temp_dir.path(), &RaftOption::new())?;
+    assert_eq!(node.term(), 2);
+    assert!(!request_vote(&mut node, 2, 2));
+    Ok(())
+}
+
+// A restarted node keeps its log, with the entries replaced by a later leader
+#[test]
+fn restart_keeps_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut node = open_node(temp_dir.path(), &RaftOption::new())?;
+    append(
+        &mut node,
+        1,
+        (0, 0),
+        &[(1, "key1"), (1, "key2"), (1, "key3")],
+    );
+    append(&mut node, 2, (1, 1), &[(2, "other2")]);
+    assert_eq!(node.last_index(), 2);
+    drop(node);
+
+    let mut node = open_node(temp_dir.path(), &RaftOption::new())?;
+    assert_eq!(node.last_index(), 2);
+    assert!(keys(&node).is_empty());
+    commit(&mut node, 2, (2, 2), 2);
+    assert_eq!(keys(&node), vec!["key1".to_owned(), "other2".to_owned()]);
+    Ok(())
+}
+
+// A restarted node starts from its last snapshot, then its log
+#[test]
+fn restart_keeps_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = RaftOption::new();
+    options.max_log_entries(2);
+    let mut node = open_node(temp_dir.path(), &options)?;
+    let entries: Vec<(u64, String)> = (0..5).map(|i| (1, format!("key{}", i))).collect();
+    let entries: Vec<(u64, &str)> = entries.iter().map(|(t, k)| (*t, k.as_str())).collect();
+    append(&mut node, 1, (0, 0), &entries);
+    commit(&mut node, 1, (5, 1), 4);
+    drop(node);
+
+    let node = open_node(temp_dir.path(), &options)?;
+    assert_eq!(node.applied_index(), 4);
+    assert_eq!(node.last_index(), 5);
+    assert_eq!(keys(&node).len(), 4);
+    Ok(())
+}
+
+/// Serve a cluster of `size` members on localhost, with their Raft folders in `path`,
+/// returning their addresses.
+const TOKEN: &str = "members-only";
+
+fn serve_cluster(size: u64, path: &Path) -> Result<Vec<(SocketAddr, RunningServer)>> {
+    let mut servers = Vec::new();
+    for _ in 0..size {
+        let pool = SharedQueueThreadPool::new(4)?;
+        servers.push(KvsServer::open(
+            local_address(),
+            Store::open_in_memory(),
+            pool,
+        )?);
+    }
+    let members: Vec<(u64, SocketAddr)> = servers
+        .iter()
+        .enumerate()
+        .map(|(i, server)| (i as u64 + 1, server.address))
+        .collect();
+
+    let mut running = Vec::new();
+    for (i, server) in servers.into_iter().enumerate() {
+        let address = server.address;
+        let id = i as u64 + 1;
+        let mut options = ClientOption::new();
+        options.token(TOKEN.to_owned());
+        let server = server.with_raft(id, members.clone(), options, path.join(id.to_string()))?;
+        running.push((address, server.serve()));
+    }
+    Ok(running)
+}
+
+/// Retry `f` while the cluster has no leader, failing after a few seconds.
+fn retry<T, F: FnMut() -> Result<T>>(mut f: F) -> Result<T> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match f() {
+            Err(KvError::NotLeader { .. }) | Err(KvError::Busy(_)) | Err(KvError::Io(_))
+                if Instant::now() < deadline =>
+            {
+                thread::sleep(Duration::from_millis(50));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Address of the member answering reads, without following redirects.
+fn find_leader(addresses: &[SocketAddr]) -> Result<SocketAddr> {
+    retry(|| {
+        for address in addresses {
+            let mut client = KvsClient::connect(*address)?;
+            let responses = client.pipeline().get("key".to_owned()).execute()?;
+            if let KvsResponse::Ok(_) = responses[0] {
+                return Ok(*address);
+            }
+        }
+        Err(KvError::NotLeader { leader: None })
+    })
+}
+
+// Clients of any member reach the leader, followers name it
+#[test]
+fn cluster_redirects_to_leader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let servers = serve_cluster(3, temp_dir.path())?;
+    let addresses: Vec<SocketAddr> = servers.iter().map(|(address, _)| *address).collect();
+    let leader = find_leader(&addresses)?;
+
+    for (i, address) in addresses.iter().enumerate() {
+        let mut client = KvsClient::connect(*address)?;
+        let key = format!("key{}", i);
+        retry(|| client.set(key.clone(), "value".to_owned()))?;
+        assert_eq!(client.get(key)?, Some("value".to_owned()));
+    }
+
+    let follower = addresses
+        .iter()
+        .find(|address| **address != leader)
+        .unwrap();
+    let mut client = KvsClient::connect(*follower)?;
+    let responses = client.pipeline().get("key0".to_owned()).execute()?;
+    match &responses[0] {
+        KvsResponse::NotLeader {
+            leader: Some(named),
+        } => assert_eq!(*named, leader.to_string()),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    for (_, server) in servers {
+        server.shutdown();
+    }
+    Ok(())
+}
+
+// The remaining members elect a new leader, which kept every acknowledged write
+#[test]
+fn cluster_survives_leader_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let servers = serve_cluster(3, temp_dir.path())?;
+    let addresses: Vec<SocketAddr> = servers.iter().map(|(address, _)| *address).collect();
+    let leader = find_leader(&addresses)?;
+
+    let mut client = KvsClient::connect(leader)?;
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    drop(client);
+
+    let mut remaining = Vec::new();
+    for (address, server) in servers {
+        if address == leader {
+            server.shutdown();
+        } else {
+            remaining.push((address, server));
+        }
+    }
+
+    let addresses: Vec<SocketAddr> = remaining.iter().map(|(address, _)| *address).collect();
+    let new_leader = find_leader(&addresses)?;
+    let mut client = KvsClient::connect(new_leader)?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert_eq!(client.get("key19".to_owned())?, Some("value19".to_owned()));
+    client.set("key0".to_owned(), "again".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, Some("again".to_owned()));
+
+    for (_, server) in remaining {
+        server.shutdown();
+    }
+    Ok(())
+}
+
+/// Send `requests` on a new connection to `address`, return the response to the last one.
+fn send(address: SocketAddr, requests: Vec<KvsRequest>) -> Result<KvsResponse> {
+    let mut client = KvsClient::connect(address)?;
+    let mut response = None;
+    for request in requests {
+        client.send(request)?;
+        response = Some(client.recv()?);
+    }
+    Ok(response.expect("at least one request"))
+}
+
+// Raft messages are only accepted from connections which named the member sending them,
+// with the token of the cluster
+#[test]
+fn raft_messages_need_member() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let servers = serve_cluster(3, temp_dir.path())?;
+    let address = servers[0].0;
+    let vote = |from| KvsRequest::Raft {
+        message: Message {
+            from,
+            to: 1,
+            term: 100,
+            body: MessageBody::RequestVote {
+                last_index: 100,
+                last_term: 100,
+            },
+        },
+    };
+    let rejected = |response| match response {
+        KvsResponse::Error { code, .. } => code == ErrorCode::Unauthenticated,
+        _ => false,
+    };
+
+    let member = |id, token: &str| KvsRequest::Member {
+        id,
+        token: token.to_owned(),
+    };
+
+    assert!(rejected(send(address, vec![vote(2)])?));
+    assert!(rejected(send(address, vec![member(2, "guess")])?));
+    assert!(rejected(send(address, vec![member(2, "")])?));
+    assert!(rejected(send(address, vec![member(9, TOKEN)])?));
+    assert!(rejected(send(address, vec![member(1, TOKEN)])?));
+    assert!(rejected(send(address, vec![member(2, TOKEN), vote(3)])?));
+    assert!(matches!(
+        send(address, vec![member(2, TOKEN), vote(2)])?,
+        KvsResponse::Ok(None)
+    ));
+
+    for (_, server) in servers {
+        server.shutdown();
+    }
+    Ok(())
+}
+
+// Members cannot prove who they are without a token
+#[test]
+fn raft_needs_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::open(
+        local_address(),
+        Store::open_in_memory(),
+        SharedQueueThreadPool::new(1)?,
+    )?;
+    let members = vec![(1, server.address), (2, local_address())];
+    let result = server.with_raft(1, members, ClientOption::new(), temp_dir.path());
+    assert!(matches!(result, Err(KvError::InvalidArgument(_))));
+    Ok(())
+}
@@ -0,0 +1,253 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{open_at, open_with};
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{ClientOption, KvError, KvOption, KvsClient, KvsServer, Result, Store};
+use tempfile::TempDir;
+
+fn open_replica(primary: SocketAddr) -> Result<KvsServer<Store, SharedQueueThreadPool>> {
+    Ok(open_with(Store::open_in_memory())?.with_primary(primary, ClientOption::new()))
+}
+
+const TOKEN: &str = "replica-admin";
+
+fn with_token() -> ClientOption {
+    let mut options = ClientOption::new();
+    options.token(TOKEN.to_owned());
+    options
+}
+
+/// Poll `condition` until it holds, failing after a few seconds.
+fn wait_until<F: FnMut() -> Result<bool>>(mut condition: F) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition()? {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+fn has_value(client: &mut KvsClient, key: &str, value: Option<&str>) -> Result<bool> {
+    Ok(client.get(key.to_owned())? == value.map(str::to_owned))
+}
+
+// A replica starts from a snapshot, then applies the writes streamed after it
+#[test]
+fn snapshot_then_stream() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let primary = open_with(Store::open(&dir)?)?.serve();
+    let mut client = KvsClient::connect(primary.address)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+
+    let replica = open_replica(primary.address)?.serve();
+    let mut reader = KvsClient::connect(replica.address)?;
+    wait_until(|| has_value(&mut reader, "key99", Some("value99")))?;
+    assert_eq!(reader.get("key0".to_owned())?, None);
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    client.set("key1".to_owned(), "changed".to_owned())?;
+    client.remove("key2".to_owned())?;
+    client.set("new".to_owned(), "value".to_owned())?;
+    wait_until(|| has_value(&mut reader, "new", Some("value")))?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+// Replicas serve reads and refuse writes
+#[test]
+fn replica_is_read_only() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let primary = open_with(Store::open(&dir)?)?.serve();
+    KvsClient::connect(primary.address)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let replica = open_replica(primary.address)?.serve();
+    let mut client = KvsClient::connect(replica.address)?;
+    wait_until(|| has_value(&mut client, "key1", Some("value1")))?;
+
+    let result = client.set("key1".to_owned(), "other".to_owned());
+    assert!(matches!(result, Err(KvError::ReadOnly(_))), "{:?}", result);
+    let result = client.remove("key1".to_owned());
+    assert!(matches!(result, Err(KvError::ReadOnly(_))), "{:?}", result);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let stats = client.stats()?;
+    assert_eq!(stats.primary, Some(primary.address.to_string()));
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+// The primary reports how far behind each replica is
+#[test]
+fn lag() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let primary = open_with(Store::open(&dir)?)?.serve();
+    let mut client = KvsClient::connect(primary.address)?;
+    assert!(client.stats()?.replicas.is_empty());
+
+    let replica = open_replica(primary.address)?.serve();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    wait_until(|| {
+        let stats = client.stats()?;
+        Ok(stats.replicas.len() == 1 && stats.replicas[0].lag_bytes == Some(0))
+    })?;
+    let stats = client.stats()?;
+    assert_eq!(stats.primary, None);
+    assert!(stats
+        .to_prometheus()
+        .contains("kvs_replica_lag_bytes{replica="));
+
+    replica.shutdown();
+    wait_until(|| Ok(client.stats()?.replicas.is_empty()))?;
+    primary.shutdown();
+    Ok(())
+}
+
+// A promoted replica accepts writes and stops following its primary
+#[test]
+fn promote() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let primary = open_with(Store::open(&dir)?)?.serve();
+    let mut client = KvsClient::connect(primary.address)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let replica = open_replica(primary.address)?
+        .with_token(TOKEN.to_owned())
+        .serve();
+    let mut promoted = KvsClient::connect_with_options(replica.address, &with_token())?;
+    wait_until(|| has_value(&mut promoted, "key1", Some("value1")))?;
+
+    promoted.promote()?;
+    promoted.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(promoted.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(promoted.stats()?.primary, None);
+    // Primaries cannot be promoted
+    let result = promoted.promote();
+    assert!(
+        matches!(result, Err(KvError::InvalidArgument(_))),
+        "{:?}",
+        result
+    );
+
+    client.set("key1".to_owned(), "changed".to_owned())?;
+    wait_until(|| Ok(client.stats()?.replicas.is_empty()))?;
+    assert_eq!(promoted.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+// Promoting needs the replica's token
+#[test]
+fn unauthorized_promote() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let primary = open_with(Store::open(&dir)?)?.serve();
+    KvsClient::connect(primary.address)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    // Without a token, nobody may promote
+    let replica = open_replica(primary.address)?.serve();
+    let mut client = KvsClient::connect(replica.address)?;
+    wait_until(|| has_value(&mut client, "key1", Some("value1")))?;
+    let result = client.promote();
+    assert!(
+        matches!(result, Err(KvError::Unauthenticated(_))),
+        "{:?}",
+        result
+    );
+    let mut client = KvsClient::connect(replica.address)?;
+    assert_eq!(client.stats()?.primary, Some(primary.address.to_string()));
+    replica.shutdown();
+
+    // With one, clients must present it
+    let replica = open_replica(primary.address)?
+        .with_token(TOKEN.to_owned())
+        .serve();
+    let mut options = ClientOption::new();
+    options.token("guess".to_owned());
+    let result = KvsClient::connect_with_options(replica.address, &options)
+        .and_then(|mut client| client.promote());
+    assert!(
+        matches!(result, Err(KvError::Unauthenticated(_))),
+        "{:?}",
+        result
+    );
+    let mut client = KvsClient::connect_with_options(replica.address, &with_token())?;
+    assert_eq!(client.stats()?.primary, Some(primary.address.to_string()));
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+// Streaming goes on across log rollovers and merges, and resumes once the primary restarts
+#[test]
+fn resume() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let mut options = KvOption::new();
+    options
+        .writer_size(512)
+        .num_log_readers(4)
+        .background_merge(false);
+    let primary = open_with(Store::open_with_options(&dir, options.clone())?)?.serve();
+    let address = primary.address;
+    let mut client = KvsClient::connect(address)?;
+
+    let replica = open_replica(address)?.serve();
+    let mut reader = KvsClient::connect(replica.address)?;
+    for i in 0..200 {
+        client.set(format!("key{}", i % 20), format!("value{}", i))?;
+    }
+    wait_until(|| has_value(&mut reader, "key19", Some("value199")))?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("value180".to_owned()));
+
+    drop(client);
+    primary.shutdown();
+    let primary = open_at(address, Store::open_with_options(&dir, options)?)?.serve();
+    let mut client = KvsClient::connect(address)?;
+    client.remove("key0".to_owned())?;
+    client.set("key1".to_owned(), "restarted".to_owned())?;
+    wait_until(|| has_value(&mut reader, "key1", Some("restarted")))?;
+    assert_eq!(reader.get("key0".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value182".to_owned()));
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+// Only the kvs engine keeps a log replicas can follow
+#[test]
+fn unsupported_engine() -> Result<()> {
+    let primary = open_with(Store::open_in_memory())?.serve();
+    let replica = open_replica(primary.address)?.serve();
+
+    // The replica keeps retrying, its store stays empty and read only.
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::connect(replica.address)?;
+    assert!(client.stats()?.primary.is_some());
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvError::ReadOnly(_))
+    ));
+    assert!(KvsClient::connect(primary.address)?
+        .stats()?
+        .replicas
+        .is_empty());
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
@@ -0,0 +1,326 @@
+mod common;
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KvsServer, Protocol, Result, Store};
+
+fn open() -> Result<KvsServer<Store, SharedQueueThreadPool>> {
+    Ok(common::open()?.with_protocol(Protocol::Resp))
+}
+
+/// A RESP2 value, as read by [`RespClient`].
+#[derive(Debug, PartialEq, Eq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+fn bulk(s: &str) -> Value {
+    Value::Bulk(Some(s.to_owned()))
+}
+
+fn ok() -> Value {
+    Value::Simple("OK".to_owned())
+}
+
+/// Minimal Redis client, sending commands as arrays of bulk strings.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(address: SocketAddr) -> io::Result<RespClient> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(RespClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn encode(arguments: &[&str]) -> Vec<u8> {
+        let mut bytes = format!("*{}\r\n", arguments.len()).into_bytes();
+        for argument in arguments {
+            bytes.extend(format!("${}\r\n{}\r\n", argument.len(), argument).into_bytes());
+        }
+        bytes
+    }
+
+    fn send(&mut self, arguments: &[&str]) -> io::Result<()> {
+        self.writer.write_all(&RespClient::encode(arguments))
+    }
+
+    fn command(&mut self, arguments: &[&str]) -> io::Result<Value> {
+        self.send(arguments)?;
+        self.read()
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        assert!(line.ends_with("\r\n"), "unterminated line {:?}", line);
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+
+    fn read(&mut self) -> io::Result<Value> {
+        let line = self.read_line()?;
+        let (kind, rest) = line.split_at(1);
+        let number = || rest.parse::<i64>().unwrap();
+        Ok(match kind {
+            "+" => Value::Simple(rest.to_owned()),
+            "-" => Value::Error(rest.to_owned()),
+            ":" => Value::Integer(number()),
+            "$" if number() < 0 => Value::Bulk(None),
+            "$" => {
+                let mut bytes = vec![0; number() as usize + 2];
+                self.reader.read_exact(&mut bytes)?;
+                assert!(bytes.ends_with(b"\r\n"));
+                bytes.truncate(bytes.len() - 2);
+                Value::Bulk(Some(String::from_utf8(bytes).unwrap()))
+            }
+            "*" => Value::Array(
+                (0..number())
+                    .map(|_| self.read())
+                    .collect::<io::Result<_>>()?,
+            ),
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+
+    /// Whether the server closed the connection.
+    fn is_closed(&mut self) -> bool {
+        match self.reader.read(&mut [0; 16]) {
+            Ok(n) => n == 0,
+            Err(e) => e.kind() == ErrorKind::ConnectionReset,
+        }
+    }
+}
+
+/// Every key found by scanning with `options`, following the cursor to the end.
+fn scan_all(client: &mut RespClient, options: &[&str]) -> io::Result<Vec<String>> {
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let mut arguments = vec!["SCAN", &cursor];
+        arguments.extend(options);
+        match client.command(&arguments)? {
+            Value::Array(reply) => match &reply[..] {
+                [Value::Bulk(Some(next)), Value::Array(page)] => {
+                    for key in page {
+                        match key {
+                            Value::Bulk(Some(key)) => keys.push(key.clone()),
+                            key => panic!("unexpected key {:?}", key),
+                        }
+                    }
+                    cursor = next.clone();
+                }
+                reply => panic!("unexpected scan reply {:?}", reply),
+            },
+            reply => panic!("unexpected scan reply {:?}", reply),
+        }
+        if cursor == "0" {
+            return Ok(keys);
+        }
+    }
+}
+
+// GET, SET, DEL, EXISTS and PING
+#[test]
+fn commands() -> Result<()> {
+    let server = open()?.serve();
+    let mut client = RespClient::connect(server.address)?;
+
+    assert_eq!(client.command(&["PING"])?, Value::Simple("PONG".to_owned()));
+    assert_eq!(client.command(&["ping", "hello"])?, bulk("hello"));
+
+    assert_eq!(client.command(&["GET", "key1"])?, Value::Bulk(None));
+    assert_eq!(client.command(&["SET", "key1", "value1"])?, ok());
+    assert_eq!(client.command(&["SET", "key2", "value 2\r\n"])?, ok());
+    assert_eq!(client.command(&["GET", "key1"])?, bulk("value1"));
+    assert_eq!(client.command(&["get", "key2"])?, bulk("value 2\r\n"));
+
+    assert_eq!(
+        client.command(&["EXISTS", "key1", "key2", "key3", "key1"])?,
+        Value::Integer(3)
+    );
+    assert_eq!(client.command(&["DEL", "key1", "key3"])?, Value::Integer(1));
+    assert_eq!(client.command(&["EXISTS", "key1"])?, Value::Integer(0));
+    assert_eq!(client.command(&["GET", "key1"])?, Value::Bulk(None));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Keys expire, with EXPIRE or SET options
+#[test]
+fn expire() -> Result<()> {
+    let server = open()?.serve();
+    let mut client = RespClient::connect(server.address)?;
+
+    assert_eq!(client.command(&["EXPIRE", "key1", "1"])?, Value::Integer(0));
+    client.command(&["SET", "key1", "value1"])?;
+    client.command(&["SET", "key2", "value2"])?;
+    client.command(&["SET", "key3", "value3"])?;
+    assert_eq!(client.command(&["EXPIRE", "key1", "1"])?, Value::Integer(1));
+    assert_eq!(
+        client.command(&["SET", "key2", "value2", "PX", "200"])?,
+        ok()
+    );
+    assert_eq!(client.command(&["EXPIRE", "key3", "1"])?, Value::Integer(1));
+    // Setting a key again clears its expiration
+    client.command(&["SET", "key3", "value3"])?;
+    assert_eq!(client.command(&["GET", "key1"])?, bulk("value1"));
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.command(&["GET", "key1"])?, Value::Bulk(None));
+    assert_eq!(client.command(&["EXISTS", "key2"])?, Value::Integer(0));
+    assert_eq!(client.command(&["DEL", "key2"])?, Value::Integer(0));
+    assert_eq!(client.command(&["GET", "key3"])?, bulk("value3"));
+    assert_eq!(scan_all(&mut client, &[])?, vec!["key3".to_owned()]);
+
+    // A timeout in the past removes the key
+    assert_eq!(client.command(&["EXPIRE", "key3", "0"])?, Value::Integer(1));
+    assert_eq!(client.command(&["GET", "key3"])?, Value::Bulk(None));
+
+    server.shutdown();
+    Ok(())
+}
+
+// SCAN walks every key with a cursor
+#[test]
+fn scan() -> Result<()> {
+    let server = open()?.serve();
+    let mut client = RespClient::connect(server.address)?;
+
+    let mut expected = Vec::new();
+    for i in 0..25 {
+        client.command(&["SET", &format!("key{:02}", i), "value"])?;
+        expected.push(format!("key{:02}", i));
+    }
+    client.command(&["SET", "other", "value"])?;
+
+    match client.command(&["SCAN", "0"])? {
+        Value::Array(reply) => {
+            assert_eq!(reply[0], bulk("10"));
+            assert!(matches!(&reply[1], Value::Array(keys) if keys.len() == 10));
+        }
+        reply => panic!("unexpected scan reply {:?}", reply),
+    }
+
+    let mut keys = scan_all(&mut client, &["MATCH", "key*", "COUNT", "7"])?;
+    keys.sort();
+    assert_eq!(keys, expected);
+    assert_eq!(
+        scan_all(&mut client, &["MATCH", "key1?"])?,
+        (10..20).map(|i| format!("key{}", i)).collect::<Vec<_>>()
+    );
+    assert_eq!(scan_all(&mut client, &["COUNT", "100"])?.len(), 26);
+
+    assert!(matches!(client.command(&["SCAN", "abc"])?, Value::Error(_)));
+    assert!(matches!(
+        client.command(&["SCAN", "0", "COUNT"])?,
+        Value::Error(_)
+    ));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Bad commands are answered with errors, the connection stays open
+#[test]
+fn command_errors() -> Result<()> {
+    let server = open()?.serve();
+    let mut client = RespClient::connect(server.address)?;
+
+    for command in [
+        &["FLUSHALL"][..],
+        &["GET"],
+        &["GET", "key1", "key2"],
+        &["SET", "key1"],
+        &["SET", "key1", "value1", "EX", "soon"],
+        &["SET", "key1", "value1", "KEEPTTL"],
+        &["DEL"],
+        &["EXPIRE", "key1", "never"],
+    ] {
+        match client.command(command)? {
+            Value::Error(message) => assert!(message.starts_with("ERR"), "{}", message),
+            reply => panic!("{:?} answered {:?}", command, reply),
+        }
+    }
+    assert_eq!(client.command(&["GET", "key1"])?, Value::Bulk(None));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Several commands sent at once, and inline commands as typed in telnet
+#[test]
+fn pipelined_and_inline() -> Result<()> {
+    let server = open()?.serve();
+    let mut client = RespClient::connect(server.address)?;
+
+    let mut bytes = Vec::new();
+    for i in 0..100 {
+        bytes.extend(RespClient::encode(&["SET", &format!("key{}", i), "value"]));
+    }
+    bytes.extend(b"GET key1\r\n\r\nEXISTS key1 key2\n");
+    client.writer.write_all(&bytes)?;
+    for _ in 0..100 {
+        assert_eq!(client.read()?, ok());
+    }
+    assert_eq!(client.read()?, bulk("value"));
+    assert_eq!(client.read()?, Value::Integer(2));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Malformed input ends the connection
+#[test]
+fn protocol_error() -> Result<()> {
+    let server = open()?.serve();
+
+    for bytes in [&b"*1\r\n:1\r\n"[..], b"*1\r\n$3\r\nGETX\r\n", b"*x\r\n"] {
+        let mut client = RespClient::connect(server.address)?;
+        client.writer.write_all(bytes)?;
+        match client.read()? {
+            Value::Error(message) => assert!(message.starts_with("ERR Protocol error")),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        assert!(client.is_closed());
+    }
+
+    server.shutdown();
+    Ok(())
+}
+
+// With a token, commands need AUTH first
+#[test]
+fn auth() -> Result<()> {
+    let server = open()?.with_token("secret".to_owned()).serve();
+    let mut client = RespClient::connect(server.address)?;
+
+    match client.command(&["GET", "key1"])? {
+        Value::Error(message) => assert!(message.starts_with("NOAUTH")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match client.command(&["AUTH", "wrong"])? {
+        Value::Error(message) => assert!(message.starts_with("WRONGPASS")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    assert_eq!(client.command(&["AUTH", "default", "secret"])?, ok());
+    assert_eq!(client.command(&["SET", "key1", "value1"])?, ok());
+    assert_eq!(client.command(&["GET", "key1"])?, bulk("value1"));
+
+    server.shutdown();
+    Ok(())
+}
@@ -0,0 +1,276 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::local_address;
+use kvs::thread_pool::{QueuePolicy, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    KvError, KvsClient, KvsEngine, KvsRequest, KvsResponse, KvsServer, Result, Store,
+    ThreadPoolOption,
+};
+
+/// Engine whose writes take a while, to keep requests in flight.
+#[derive(Debug, Clone)]
+struct SlowEngine {
+    store: Store,
+    delay: Duration,
+}
+
+impl SlowEngine {
+    fn new(delay: Duration) -> SlowEngine {
+        SlowEngine {
+            store: Store::open_in_memory(),
+            delay,
+        }
+    }
+}
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        thread::sleep(self.delay);
+        self.store.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.store.keys()
+    }
+}
+
+fn set_request() -> KvsRequest {
+    KvsRequest::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    }
+}
+
+/// Whether the server closed the connection, after sending whatever it had left.
+fn is_closed(stream: &mut TcpStream) -> bool {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    match stream.read_to_end(&mut Vec::new()) {
+        Ok(_) => true,
+        Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+    }
+}
+
+// CPU time of the named threads of this process, in clock ticks.
+#[cfg(target_os = "linux")]
+fn threads_cpu_time(name: &str) -> u64 {
+    let mut ticks = 0;
+    for task in std::fs::read_dir("/proc/self/task").unwrap() {
+        let task = task.unwrap().path();
+        let comm = std::fs::read_to_string(task.join("comm")).unwrap_or_default();
+        if comm.trim() != name {
+            continue;
+        }
+        let stat = std::fs::read_to_string(task.join("stat")).unwrap_or_default();
+        // Fields after the command name, which is in parentheses.
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect())
+            .unwrap_or_default();
+        // utime and stime are the 14th and 15th fields, the 12th and 13th after the name.
+        ticks += fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    }
+    ticks
+}
+
+// An idle server should not spin on accept
+#[cfg(target_os = "linux")]
+#[test]
+fn idle_server_sleeps() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let server = KvsServer::open(local_address(), Store::open_in_memory(), pool)?
+        .with_metrics(local_address())?
+        .serve();
+
+    let before = threads_cpu_time("kvs-accept") + threads_cpu_time("kvs-metrics");
+    thread::sleep(Duration::from_millis(500));
+    let after = threads_cpu_time("kvs-accept") + threads_cpu_time("kvs-metrics");
+    // A spinning thread would use about 50 ticks.
+    assert!(after - before < 5, "used {} ticks", after - before);
+
+    server.shutdown();
+    Ok(())
+}
+
+// Shutdown should not wait for idle clients
+#[test]
+fn shutdown_with_idle_client() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::open(local_address(), Store::open_in_memory(), pool)?.serve();
+    let address = server.address;
+
+    let mut client = KvsClient::connect(address)?;
+    client.send(set_request())?;
+    client.recv()?;
+    let mut idle = TcpStream::connect(address)?;
+
+    let start = Instant::now();
+    server.shutdown();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    assert!(is_closed(&mut idle));
+    assert!(TcpStream::connect(address).is_err());
+    Ok(())
+}
+
+// Connections the pool has no room for are answered with `Busy`
+#[test]
+fn busy_pool() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_options(
+        1,
+        ThreadPoolOption::new()
+            .queue_size(Some(1))
+            .when_full(QueuePolicy::Reject),
+    )?;
+    let server = KvsServer::open(local_address(), Store::open_in_memory(), pool)?.serve();
+    let address = server.address;
+
+    // One connection holds the worker, the next one waits in the queue.
+    let mut client = KvsClient::connect(address)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let queued = TcpStream::connect(address)?;
+
+    let error = KvsClient::connect(address).err();
+    assert!(matches!(error, Some(KvError::Busy(_))), "{:?}", error);
+
+    drop(client);
+    drop(queued);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut client = loop {
+        match KvsClient::connect(address) {
+            Ok(client) => break client,
+            Err(KvError::Busy(_)) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(20))
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    server.shutdown();
+    Ok(())
+}
+
+// A pool running jobs on the spawning thread would serve connections on the accept thread
+#[test]
+fn caller_runs_pool_refused() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_options(
+        1,
+        ThreadPoolOption::new()
+            .queue_size(Some(1))
+            .when_full(QueuePolicy::CallerRuns),
+    )?;
+    let result = KvsServer::open(local_address(), Store::open_in_memory(), pool);
+    assert!(matches!(result, Err(KvError::InvalidArgument(_))));
+    Ok(())
+}
+
+// Requests in flight finish before the server stops
+#[test]
+fn shutdown_drains_requests() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let engine = SlowEngine::new(Duration::from_millis(500));
+    let server = KvsServer::open(local_address(), engine, pool)?.serve();
+
+    let mut client = KvsClient::connect(server.address)?;
+    client.send(set_request())?;
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    server.shutdown();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    assert!(matches!(client.recv()?, KvsResponse::Ok(None)));
+    Ok(())
+}
+
+// Requests still in flight at the deadline are cut off
+#[test]
+fn shutdown_drain_deadline() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let engine = SlowEngine::new(Duration::from_secs(3));
+    let server = KvsServer::open(local_address(), engine, pool)?
+        .with_drain_timeout(Duration::from_millis(200))
+        .serve();
+
+    let mut client = KvsClient::connect(server.address)?;
+    client.send(set_request())?;
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    server.shutdown();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    assert!(client.recv().is_err());
+    Ok(())
+}
+
+// Idle or slow clients are disconnected
+#[test]
+fn read_timeout() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::open(local_address(), Store::open_in_memory(), pool)?
+        .with_read_timeout(Duration::from_millis(200))
+        .serve();
+
+    let mut idle = TcpStream::connect(server.address)?;
+    let start = Instant::now();
+    assert!(is_closed(&mut idle));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // Only the start of a request
+    let mut slow = TcpStream::connect(server.address)?;
+    slow.write_all(&[64, 0])?;
+    assert!(is_closed(&mut slow));
+
+    // Active clients are not affected
+    let mut client = KvsClient::connect(server.address)?;
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(100));
+        client.send(set_request())?;
+        assert!(matches!(client.recv()?, KvsResponse::Ok(None)));
+    }
+
+    server.shutdown();
+    Ok(())
+}
+
+// Scans list keys in order, a page at a time
+#[test]
+fn scan() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let server = KvsServer::open(local_address(), Store::open_in_memory(), pool)?.serve();
+    let mut client = KvsClient::connect(server.address)?;
+    for key in ["b2", "a1", "b1", "b3", "c1"] {
+        client.set(key.to_owned(), "value".to_owned())?;
+    }
+
+    let (keys, cursor) = client.scan("b".to_owned(), None, 2)?;
+    assert_eq!(keys, ["b1", "b2"]);
+    assert_eq!(cursor, Some("b2".to_owned()));
+    let (keys, cursor) = client.scan("b".to_owned(), cursor, 2)?;
+    assert_eq!(keys, ["b3"]);
+    assert_eq!(cursor, None);
+    assert!(matches!(
+        client.scan(String::new(), None, 0),
+        Err(kvs::KvError::InvalidArgument(_))
+    ));
+
+    server.shutdown();
+    Ok(())
+}
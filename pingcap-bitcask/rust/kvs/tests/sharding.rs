@@ -0,0 +1,153 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use common::open;
+use kvs::{KvError, KvsClient, Result, RunningServer, ShardedKvsClient};
+
+fn serve() -> Result<RunningServer> {
+    Ok(open()?.serve())
+}
+
+/// Number of keys stored by each server.
+fn counts(servers: &[&RunningServer]) -> Result<Vec<usize>> {
+    servers
+        .iter()
+        .map(|server| {
+            let (keys, _) = KvsClient::connect(server.address)?.scan(String::new(), None, 1000)?;
+            Ok(keys.len())
+        })
+        .collect()
+}
+
+// Keys are spread over every server, and clients agree on where each key lives
+#[test]
+fn routing() -> Result<()> {
+    let servers = [serve()?, serve()?, serve()?];
+    let addresses: Vec<SocketAddr> = servers.iter().map(|server| server.address).collect();
+    let mut client = ShardedKvsClient::connect(&addresses)?;
+    for i in 0..300 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert!(matches!(
+        client.remove("key0".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+
+    let counts = counts(&servers.iter().collect::<Vec<_>>())?;
+    assert_eq!(counts.iter().sum::<usize>(), 299);
+    assert!(counts.iter().all(|count| *count > 50), "{:?}", counts);
+
+    let reversed: Vec<SocketAddr> = addresses.iter().rev().copied().collect();
+    let mut other = ShardedKvsClient::connect(&reversed)?;
+    for i in 1..300 {
+        let key = format!("key{}", i);
+        assert_eq!(client.server_for(&key), other.server_for(&key));
+        assert_eq!(other.get(key)?, Some(format!("value{}", i)));
+    }
+    assert_eq!(other.get("key0".to_owned())?, None);
+
+    for server in servers {
+        server.shutdown();
+    }
+    Ok(())
+}
+
+// Scans merge the keys of every server in order, page after page
+#[test]
+fn scan() -> Result<()> {
+    let servers = [serve()?, serve()?, serve()?];
+    let addresses: Vec<SocketAddr> = servers.iter().map(|server| server.address).collect();
+    let mut client = ShardedKvsClient::connect(&addresses)?;
+    for i in 0..100 {
+        client.set(format!("key{:03}", i), "value".to_owned())?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+
+    let mut listed = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (keys, next) = client.scan("key".to_owned(), cursor, 7)?;
+        assert!(keys.len() <= 7);
+        listed.extend(keys);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let expected: Vec<String> = (0..100).map(|i| format!("key{:03}", i)).collect();
+    assert_eq!(listed, expected);
+    assert_eq!(client.keys("key".to_owned())?, expected);
+    assert_eq!(client.keys(String::new())?.len(), 101);
+
+    for server in servers {
+        server.shutdown();
+    }
+    Ok(())
+}
+
+// Adding a server only moves the keys it now holds, removing one moves only its keys
+#[test]
+fn add_and_remove_servers() -> Result<()> {
+    let servers = [serve()?, serve()?, serve()?];
+    let added = serve()?;
+    let addresses: Vec<SocketAddr> = servers.iter().map(|server| server.address).collect();
+    let mut client = ShardedKvsClient::connect(&addresses)?;
+    for i in 0..400 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let before: HashMap<String, SocketAddr> = (0..400)
+        .map(|i| format!("key{}", i))
+        .map(|key| (key.clone(), client.server_for(&key)))
+        .collect();
+
+    let moved = client.add_server(added.address)?;
+    let all: Vec<&RunningServer> = servers.iter().chain([&added]).collect();
+    let counts = counts(&all)?;
+    assert_eq!(moved, counts[3]);
+    assert!(moved > 40 && moved < 200, "moved {}", moved);
+    assert_eq!(counts.iter().sum::<usize>(), 400);
+    for (key, server) in &before {
+        let now = client.server_for(key);
+        assert!(
+            now == *server || now == added.address,
+            "{} moved between old servers",
+            key
+        );
+    }
+    for i in 0..400 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    let moved = client.remove_server(servers[0].address)?;
+    let counts = self::counts(&all)?;
+    assert_eq!(counts[0], 0);
+    assert!(moved > 0);
+    assert_eq!(counts.iter().sum::<usize>(), 400);
+    assert_eq!(client.servers().len(), 3);
+    for i in 0..400 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    for server in servers {
+        server.shutdown();
+    }
+    added.shutdown();
+    Ok(())
+}
+
+#[test]
+fn no_servers() {
+    assert!(matches!(
+        ShardedKvsClient::connect(&[]),
+        Err(KvError::InvalidArgument(_))
+    ));
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::local_address;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvsClient, KvsEngine, KvsRequest, KvsResponse, KvsServer, Result, Stats, Store};
+use tempfile::TempDir;
+
+fn workload(store: &Store) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    Ok(())
+}
+
+// Should count operations and disk usage of the kvs engine
+#[test]
+fn kvs_engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open_with_kvs(&temp_dir)?;
+    assert_eq!(store.stats()?.set.count, 0);
+
+    workload(&store)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.set.count, 3);
+    assert_eq!(stats.get.count, 1);
+    assert_eq!(stats.remove.count, 2);
+    assert_eq!(stats.set.buckets.iter().sum::<u64>(), 3);
+    assert!(stats.live_bytes > 0);
+    assert!(stats.dead_bytes > stats.live_bytes);
+    assert_eq!(stats.bytes_written, stats.live_bytes + stats.dead_bytes);
+    assert_eq!(stats.log_files, 1);
+    assert_eq!(stats.merge.count, 0);
+    Ok(())
+}
+
+// Should count operations of the sled engine
+#[test]
+fn sled_engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open_with_sled(&temp_dir)?;
+
+    workload(&store)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.set.count, 3);
+    assert_eq!(stats.get.count, 1);
+    assert_eq!(stats.remove.count, 2);
+    Ok(())
+}
+
+#[test]
+fn prometheus_format() {
+    let mut stats = Stats::default();
+    stats.get.count = 2;
+    stats.get.sum_micros = 1500;
+    stats.get.buckets[0] = 1;
+    stats.get.buckets[6] = 1;
+    stats.total_connections = 3;
+    stats.pool.queued = 2;
+
+    let text = stats.to_prometheus();
+    assert!(text.contains("# TYPE kvs_op_duration_seconds histogram\n"));
+    assert!(text.contains("kvs_op_duration_seconds_bucket{op=\"get\",le=\"0.00001\"} 1\n"));
+    assert!(text.contains("kvs_op_duration_seconds_bucket{op=\"get\",le=\"0.001\"} 2\n"));
+    assert!(text.contains("kvs_op_duration_seconds_bucket{op=\"get\",le=\"+Inf\"} 2\n"));
+    assert!(text.contains("kvs_op_duration_seconds_sum{op=\"get\"} 0.0015\n"));
+    assert!(text.contains("kvs_op_duration_seconds_count{op=\"get\"} 2\n"));
+    assert!(text.contains("kvs_merge_duration_seconds_count{} 0\n"));
+    assert!(text.contains("kvs_connections_total 3\n"));
+    assert!(text.contains("# TYPE kvs_pool_queued_jobs gauge\nkvs_pool_queued_jobs 2\n"));
+}
+
+// Should serve stats through the protocol and the metrics endpoint
+#[test]
+fn server_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(&temp_dir)?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let server = KvsServer::open(local_address(), store, pool)?
+        .with_metrics(local_address())?
+        .serve();
+
+    let mut client = KvsClient::connect(server.address)?;
+    client.send(KvsRequest::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    client.recv()?;
+    client.send(KvsRequest::Stats {})?;
+    let stats = match client.recv()? {
+        KvsResponse::Stats(stats) => stats,
+        response => panic!("unexpected response {:?}", response),
+    };
+    assert_eq!(stats.set.count, 1);
+    assert_eq!(stats.active_connections, 1);
+    assert_eq!(stats.total_connections, 1);
+    assert_eq!(stats.pool.workers, 4);
+    assert_eq!(stats.pool.active_workers, 1);
+
+    let metrics_address = server.metrics_address.expect("metrics endpoint is enabled");
+    let mut stream = TcpStream::connect(metrics_address)?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("kvs_op_duration_seconds_count{op=\"set\"} 1\n"));
+    assert!(response.contains("kvs_active_connections 1\n"));
+    assert!(response.contains("kvs_pool_workers 4\n"));
+
+    let mut stream = TcpStream::connect(metrics_address)?;
+    stream.write_all(b"GET /other HTTP/1.1\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let mut stream = TcpStream::connect(metrics_address)?;
+    stream.write_all(b"POST /metrics HTTP/1.1\r\nContent-Length: 1000000000000\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+
+    drop(client);
+    server.shutdown();
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvError, Result, ThreadPoolOption, ThreadPoolStats};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +48,53 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+// Shut down pools run the jobs already spawned, and refuse new ones
+fn shutdown_join<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.try_spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })?;
+    }
+
+    pool.shutdown();
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvError::PoolShutdown)));
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    // Nothing is left to wait for
+    pool.join();
+    Ok(())
+}
+
+fn spawn_handles<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handles = (0..10)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect::<Result<Vec<_>>>()?;
+    let results = handles
+        .into_iter()
+        .map(JobHandle::join)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+
+    let handle = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    })?;
+    assert!(matches!(handle.join(), Err(KvError::JobPanicked)));
+
+    // The pool still works, and waits for panicked jobs too
+    assert_eq!(pool.spawn_with_handle(|| "ok")?.join()?, "ok");
+    pool.join();
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -64,7 +113,287 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_shutdown_join() -> Result<()> {
+    shutdown_join::<NaiveThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_spawn_handles() -> Result<()> {
+    spawn_handles::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_join() -> Result<()> {
+    shutdown_join::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_handles() -> Result<()> {
+    spawn_handles::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown_join() -> Result<()> {
+    shutdown_join::<RayonThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_handles() -> Result<()> {
+    spawn_handles::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_shutdown_join() -> Result<()> {
+    shutdown_join::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_handles() -> Result<()> {
+    spawn_handles::<WorkStealingThreadPool>()
+}
+
+// Jobs queued behind a blocked one are run by the other workers
+#[test]
+fn work_stealing_thread_pool_blocked_job() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(2)?;
+    for _ in 0..100 {
+        let (sender, receiver) = mpsc::channel();
+        let (done, finished) = mpsc::channel();
+        pool.spawn(move || {
+            receiver.recv().unwrap();
+            done.send(()).unwrap();
+        });
+        pool.spawn(move || sender.send(()).unwrap());
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("blocked job never resumed");
+    }
+    Ok(())
+}
+
+/// Pool with its only worker blocked until `release` is dropped, and its queue of 1 full.
+fn full_pool(policy: QueuePolicy) -> Result<(SharedQueueThreadPool, mpsc::Sender<()>)> {
+    let pool = SharedQueueThreadPool::with_options(
+        1,
+        ThreadPoolOption::new()
+            .queue_size(Some(1))
+            .when_full(policy),
+    )?;
+    let (release, blocked) = mpsc::channel::<()>();
+    let (started, running) = mpsc::channel();
+    pool.try_spawn(move || {
+        started.send(()).unwrap();
+        let _ = blocked.recv();
+    })?;
+    running.recv().unwrap();
+    pool.try_spawn(|| {})?;
+    Ok((pool, release))
+}
+
+#[test]
+fn shared_queue_thread_pool_full_queue_rejects() -> Result<()> {
+    let (pool, release) = full_pool(QueuePolicy::Reject)?;
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvError::Busy(_))));
+
+    drop(release);
+    pool.join();
+    pool.try_spawn(|| {})?;
+    pool.join();
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_full_queue_caller_runs() -> Result<()> {
+    let (pool, release) = full_pool(QueuePolicy::CallerRuns)?;
+    let caller = thread::current().id();
+    let handle = pool.spawn_with_handle(|| thread::current().id())?;
+    assert_eq!(handle.join()?, caller);
+
+    drop(release);
+    pool.join();
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_full_queue_blocks() -> Result<()> {
+    let (pool, release) = full_pool(QueuePolicy::Block)?;
+    let pool = Arc::new(pool);
+    let spawning = {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || pool.try_spawn(|| {}))
+    };
+    thread::sleep(Duration::from_millis(100));
+    assert!(!spawning.is_finished());
+
+    drop(release);
+    spawning.join().unwrap()?;
+    pool.join();
+    Ok(())
+}
+
+// Shutting down does not wait for a spawn blocked on the full queue
+#[test]
+fn shared_queue_thread_pool_shutdown_while_spawn_blocks() -> Result<()> {
+    let (pool, release) = full_pool(QueuePolicy::Block)?;
+    let pool = Arc::new(pool);
+    let spawning = {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || pool.try_spawn(|| {}))
+    };
+    thread::sleep(Duration::from_millis(100));
+
+    let (done, shut_down) = mpsc::channel();
+    let shutting_down = {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || {
+            pool.shutdown();
+            done.send(()).unwrap();
+        })
+    };
+    shut_down
+        .recv_timeout(Duration::from_secs(1))
+        .expect("shutdown waited for the blocked spawn");
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvError::PoolShutdown)));
+
+    drop(release);
+    shutting_down.join().unwrap();
+    spawning.join().unwrap()?;
+    pool.join();
+    Ok(())
+}
+
+/// Spawn `count` jobs blocked until `release` is dropped, once they all run.
+fn spawn_blocked<P: ThreadPool>(pool: &P, count: usize) -> Result<mpsc::Sender<()>> {
+    let (release, blocked) = mpsc::channel::<()>();
+    let blocked = Arc::new(std::sync::Mutex::new(blocked));
+    let (started, running) = mpsc::channel();
+    for _ in 0..count {
+        let blocked = Arc::clone(&blocked);
+        let started = started.clone();
+        pool.try_spawn(move || {
+            started.send(()).unwrap();
+            let _ = blocked.lock().unwrap().recv();
+        })?;
+    }
+    for _ in 0..count {
+        running
+            .recv_timeout(Duration::from_secs(5))
+            .expect("blocked jobs did not start");
+    }
+    Ok(release)
+}
+
+/// Wait until the pool's stats match `check`.
+fn wait_for_stats<P: ThreadPool>(pool: &P, check: impl Fn(&ThreadPoolStats) -> bool) {
+    let metrics = pool.metrics();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check(&metrics.stats()) {
+        assert!(Instant::now() < deadline, "{:?}", metrics.stats());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn pool_stats<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    wait_for_stats(&pool, |stats| stats.workers == 2);
+    let release = spawn_blocked(&pool, 2)?;
+    pool.try_spawn(|| {})?;
+    let stats = pool.metrics().stats();
+    assert_eq!((stats.queued, stats.active_workers), (1, 2));
+
+    drop(release);
+    pool.try_spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    })?;
+    pool.join();
+    let stats = pool.metrics().stats();
+    assert_eq!((stats.queued, stats.active_workers), (0, 0));
+    assert_eq!((stats.completed, stats.panicked), (4, 1));
+    // The worker of the panicked job is replaced
+    wait_for_stats(&pool, |stats| stats.workers == 2);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    pool_stats::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_stats() -> Result<()> {
+    pool_stats::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_stats() -> Result<()> {
+    pool_stats::<WorkStealingThreadPool>()
+}
+
+// Elastic pools start workers while jobs wait, and stop them once idle
+#[test]
+fn shared_queue_thread_pool_elastic() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_options(
+        1,
+        ThreadPoolOption::new()
+            .max_threads(3)
+            .idle_timeout(Duration::from_millis(100)),
+    )?;
+    wait_for_stats(&pool, |stats| stats.workers == 1);
+
+    let release = spawn_blocked(&pool, 3)?;
+    pool.try_spawn(|| {})?;
+    let stats = pool.metrics().stats();
+    assert_eq!((stats.queued, stats.active_workers), (1, 3));
+    assert_eq!(stats.workers, 3);
+
+    drop(release);
+    pool.join();
+    wait_for_stats(&pool, |stats| stats.workers == 1);
+    pool.try_spawn(|| {})?;
+    pool.join();
+    Ok(())
+}
+
+// Idle workers stop together, not one idle timeout after the other
+#[test]
+fn shared_queue_thread_pool_shrinks_at_once() -> Result<()> {
+    let idle_timeout = Duration::from_millis(500);
+    let pool = SharedQueueThreadPool::with_options(
+        1,
+        ThreadPoolOption::new()
+            .max_threads(6)
+            .idle_timeout(idle_timeout),
+    )?;
+    let release = spawn_blocked(&pool, 6)?;
+    assert_eq!(pool.metrics().stats().workers, 6);
+
+    drop(release);
+    pool.join();
+    let idle = Instant::now();
+    wait_for_stats(&pool, |stats| stats.workers == 1);
+    assert!(idle.elapsed() < idle_timeout * 2, "{:?}", idle.elapsed());
+    Ok(())
+}
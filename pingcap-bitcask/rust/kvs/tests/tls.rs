@@ -0,0 +1,198 @@
+mod common;
+mod wire;
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use common::open;
+use kvs::{ClientOption, ErrorCode, Feature, KvError, KvsClient, KvsRequest, KvsResponse, Result};
+use tempfile::TempDir;
+use wire::read_frame;
+
+/// Self-signed certificate for localhost, written as `cert.pem` and `key.pem` in `dir`.
+fn self_signed(dir: &Path) -> (PathBuf, PathBuf) {
+    let names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+    let certified = rcgen::generate_simple_self_signed(names).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (cert, key)
+}
+
+fn check_round_trip(client: &mut KvsClient) -> Result<()> {
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let responses = client
+        .pipeline()
+        .set("key2".to_owned(), "value2".to_owned())
+        .get("key2".to_owned())
+        .execute()?;
+    assert!(matches!(&responses[1], KvsResponse::Ok(Some(v)) if v == "value2"));
+    Ok(())
+}
+
+// Requests and responses go through TLS
+#[test]
+fn tls() -> Result<()> {
+    let dir = TempDir::new()?;
+    let (cert, key) = self_signed(dir.path());
+    let server = open()?.with_tls(&cert, &key)?.serve();
+
+    let mut client =
+        KvsClient::connect_with_options(server.address, ClientOption::new().tls_ca(&cert))?;
+    check_round_trip(&mut client)?;
+
+    // Checked against a name instead of the ip address
+    let mut client = KvsClient::connect_with_options(
+        server.address,
+        ClientOption::new()
+            .tls_ca(&cert)
+            .server_name("localhost".to_owned()),
+    )?;
+    check_round_trip(&mut client)?;
+
+    server.shutdown();
+    Ok(())
+}
+
+// Clients not speaking TLS, or not trusting the certificate, cannot connect
+#[test]
+fn tls_rejected() -> Result<()> {
+    let dir = TempDir::new()?;
+    let (cert, key) = self_signed(dir.path());
+    let server = open()?.with_tls(&cert, &key)?.serve();
+
+    assert!(KvsClient::connect(server.address).is_err());
+
+    let other = TempDir::new()?;
+    let (other_cert, _) = self_signed(other.path());
+    let result =
+        KvsClient::connect_with_options(server.address, ClientOption::new().tls_ca(&other_cert));
+    assert!(result.is_err());
+
+    let result = KvsClient::connect_with_options(
+        server.address,
+        ClientOption::new()
+            .tls_ca(&cert)
+            .server_name("example.com".to_owned()),
+    );
+    assert!(result.is_err());
+
+    // The server keeps serving
+    let mut client =
+        KvsClient::connect_with_options(server.address, ClientOption::new().tls_ca(&cert))?;
+    check_round_trip(&mut client)?;
+
+    server.shutdown();
+    Ok(())
+}
+
+// Missing or unreadable certificate files
+#[test]
+fn tls_invalid_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    let (cert, key) = self_signed(dir.path());
+
+    assert!(open()?
+        .with_tls(dir.path().join("missing.pem"), &key)
+        .is_err());
+    assert!(open()?.with_tls(&cert, &cert).is_err());
+    assert!(open()?.with_tls(&key, &key).is_err());
+    Ok(())
+}
+
+// The token is checked during the handshake
+#[test]
+fn token() -> Result<()> {
+    let server = open()?.with_token("secret".to_owned()).serve();
+
+    let mut client = KvsClient::connect_with_options(
+        server.address,
+        ClientOption::new().token("secret".to_owned()),
+    )?;
+    assert!(client.features().contains(&Feature::Auth));
+    check_round_trip(&mut client)?;
+
+    assert!(matches!(
+        KvsClient::connect(server.address),
+        Err(KvError::Unauthenticated(_))
+    ));
+    assert!(matches!(
+        KvsClient::connect_with_options(
+            server.address,
+            ClientOption::new().token("wrong".to_owned())
+        ),
+        Err(KvError::Unauthenticated(_))
+    ));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Requests before the handshake are rejected, and the connection closed
+#[test]
+fn unauthenticated_request() -> Result<()> {
+    let server = open()?.with_token("secret".to_owned()).serve();
+    let mut stream = TcpStream::connect(server.address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let get = KvsRequest::Get {
+        key: "key1".to_owned(),
+    };
+    stream.write_all(&wire::encode(1, &get))?;
+    let (id, response) = read_frame(&mut stream)?;
+    assert_eq!(id, 1);
+    // Without a handshake the connection speaks version 1
+    assert!(matches!(response, KvsResponse::ServerError {}));
+    match stream.read(&mut [0; 16]) {
+        Ok(n) => assert_eq!(n, 0),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+
+    // A handshake without token, on a version knowing about authentication
+    let mut stream = TcpStream::connect(server.address)?;
+    let hello = KvsRequest::Hello {
+        version: 3,
+        features: vec![Feature::Auth],
+        token: None,
+    };
+    stream.write_all(&wire::encode(1, &hello))?;
+    let (_, response) = read_frame(&mut stream)?;
+    assert!(matches!(
+        response,
+        KvsResponse::Error {
+            code: ErrorCode::Unauthenticated,
+            ..
+        }
+    ));
+
+    server.shutdown();
+    Ok(())
+}
+
+// Both together
+#[test]
+fn tls_and_token() -> Result<()> {
+    let dir = TempDir::new()?;
+    let (cert, key) = self_signed(dir.path());
+    let server = open()?
+        .with_tls(&cert, &key)?
+        .with_token("secret".to_owned())
+        .serve();
+
+    let mut client = KvsClient::connect_with_options(
+        server.address,
+        ClientOption::new().tls_ca(&cert).token("secret".to_owned()),
+    )?;
+    check_round_trip(&mut client)?;
+
+    let result = KvsClient::connect_with_options(server.address, ClientOption::new().tls_ca(&cert));
+    assert!(matches!(result, Err(KvError::Unauthenticated(_))));
+
+    server.shutdown();
+    Ok(())
+}
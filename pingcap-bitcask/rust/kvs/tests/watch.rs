@@ -0,0 +1,196 @@
+mod common;
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use common::open_with;
+use kvs::{KeyChange, KvError, KvsClient, KvsEngine, Result, RunningServer, Store};
+use tempfile::TempDir;
+
+fn change(key: &str, value: Option<&str>) -> KeyChange {
+    KeyChange {
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+fn serve(store: Store) -> Result<RunningServer> {
+    Ok(open_with(store)?.serve())
+}
+
+// Subscribers get the writes to their prefix, in order
+#[test]
+fn subscribe() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let store = Store::open(&dir)?;
+    let config = store.subscribe("config.".to_owned(), 16);
+    let all = store.subscribe(String::new(), 16);
+
+    store.set("config.port".to_owned(), "4000".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store
+        .clone()
+        .set("config.port".to_owned(), "4001".to_owned())?;
+    store.remove("config.port".to_owned())?;
+    assert!(store.remove("config.missing".to_owned()).is_err());
+
+    assert_eq!(config.recv()?, change("config.port", Some("4000")));
+    assert_eq!(config.recv()?, change("config.port", Some("4001")));
+    assert_eq!(config.recv()?, change("config.port", None));
+    assert_eq!(config.try_recv()?, None);
+    assert_eq!(config.recv_timeout(Duration::from_millis(10))?, None);
+
+    let changes: Vec<String> = (0..4).map(|_| all.recv().unwrap().key).collect();
+    assert_eq!(
+        changes,
+        ["config.port", "other", "config.port", "config.port"]
+    );
+
+    // Writes go on once subscriptions are dropped
+    drop(config);
+    drop(all);
+    store.set("config.port".to_owned(), "4002".to_owned())?;
+    Ok(())
+}
+
+// Concurrent writes reach subscribers in the order the store applied them
+#[test]
+fn concurrent_writes() -> Result<()> {
+    let store = Store::open_in_memory();
+    let subscription = store.subscribe("key".to_owned(), 10_000);
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..500 {
+                    store.set("key".to_owned(), format!("{}-{}", i, j)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut last = None;
+    while let Some(change) = subscription.try_recv()? {
+        last = change.value;
+    }
+    assert_eq!(last, store.get("key".to_owned())?);
+    Ok(())
+}
+
+// Writers to different keys do not wait for each other, each key's changes stay in order
+#[test]
+fn concurrent_keys() -> Result<()> {
+    let store = Store::open_in_memory();
+    let subscription = store.subscribe(String::new(), 10_000);
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..500 {
+                    let key = format!("key{}", j % 8);
+                    store.set(key, format!("{}-{}", i, j)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut last = HashMap::new();
+    let mut count = 0;
+    while let Some(change) = subscription.try_recv()? {
+        last.insert(change.key, change.value);
+        count += 1;
+    }
+    assert_eq!(count, 2000);
+    for (key, value) in last {
+        assert_eq!(value, store.get(key)?);
+    }
+    Ok(())
+}
+
+// A subscriber which does not keep up is dropped after the changes it buffered
+#[test]
+fn overflow() -> Result<()> {
+    let store = Store::open_in_memory();
+    let slow = store.subscribe(String::new(), 2);
+    let fast = store.subscribe(String::new(), 16);
+
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        assert_eq!(fast.recv()?.key, format!("key{}", i));
+    }
+    assert_eq!(slow.recv()?.key, "key0");
+    assert_eq!(slow.recv()?.key, "key1");
+    assert!(matches!(slow.recv(), Err(KvError::Overflowed(_))));
+
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(fast.recv()?.key, "key3");
+    assert!(matches!(slow.try_recv(), Err(KvError::Overflowed(_))));
+    Ok(())
+}
+
+// Clients watching a prefix get the changes made through any connection
+#[test]
+fn watch() -> Result<()> {
+    let server = serve(Store::open_in_memory())?;
+    let mut watcher = KvsClient::connect(server.address)?.watch("config.".to_owned())?;
+    assert_eq!(watcher.recv_timeout(Duration::from_millis(50))?, None);
+
+    let mut client = KvsClient::connect(server.address)?;
+    client.set("config.port".to_owned(), "4000".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.remove("config.port".to_owned())?;
+
+    assert_eq!(watcher.recv()?, change("config.port", Some("4000")));
+    assert_eq!(
+        watcher.recv_timeout(Duration::from_secs(5))?,
+        Some(change("config.port", None))
+    );
+    assert_eq!(watcher.recv_timeout(Duration::from_millis(50))?, None);
+
+    // The watch ends with the server
+    client.set("config.host".to_owned(), "localhost".to_owned())?;
+    server.shutdown();
+    let changes: Vec<KeyChange> = watcher.collect::<Result<_>>()?;
+    assert_eq!(changes, [change("config.host", Some("localhost"))]);
+    Ok(())
+}
+
+// A client which does not read its changes is told some were dropped
+#[test]
+fn slow_watcher() -> Result<()> {
+    let store = Store::open_in_memory();
+    let server = serve(store.clone())?;
+    let mut watcher = KvsClient::connect(server.address)?.watch(String::new())?;
+    thread::sleep(Duration::from_millis(100));
+
+    // Enough to fill the socket buffers, then the server's.
+    let value = "v".repeat(64 * 1024);
+    for i in 0..3000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+
+    let mut received = 0;
+    let error = loop {
+        match watcher.recv() {
+            Ok(change) => {
+                assert_eq!(change.key, format!("key{}", received));
+                received += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(error, KvError::Overflowed(_)), "{:?}", error);
+    assert!(received < 3000);
+
+    server.shutdown();
+    Ok(())
+}
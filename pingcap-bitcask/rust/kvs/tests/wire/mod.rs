@@ -0,0 +1,44 @@
+//! Raw frames, to talk to servers without going through the clients.
+
+use std::convert::TryInto;
+use std::io::Read;
+use std::net::TcpStream;
+
+use kvs::{KvsResponse, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Frame layout version.
+pub const VERSION: u8 = 1;
+
+pub fn frame(version: u8, id: u64, body: &[u8]) -> Vec<u8> {
+    let mut bytes = b"KVSF".to_vec();
+    bytes.push(version);
+    bytes.extend_from_slice(&id.to_le_bytes());
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// Frame a request or a response.
+pub fn encode<T: Serialize>(id: u64, message: &T) -> Vec<u8> {
+    frame(VERSION, id, &bson::to_vec(message).unwrap())
+}
+
+/// Read one framed response, returning its request id.
+pub fn read_frame(stream: &mut TcpStream) -> Result<(u64, KvsResponse)> {
+    read(stream)
+}
+
+/// Read one framed message, returning its request id.
+pub fn read<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<(u64, T)> {
+    let mut header = [0; 17];
+    stream.read_exact(&mut header)?;
+    assert_eq!(&header[..4], b"KVSF");
+    assert_eq!(header[4], VERSION);
+    let id = u64::from_le_bytes(header[5..13].try_into().unwrap());
+    let size = u32::from_le_bytes(header[13..].try_into().unwrap());
+
+    let mut body = vec![0; size as usize];
+    stream.read_exact(&mut body)?;
+    Ok((id, bson::from_slice(&body)?))
+}